@@ -0,0 +1,111 @@
+use crate::config::DumpLayout;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// File name of the checkpoint kept next to the dumps of an in-progress backup
+pub const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// Progress of a backup run, saved after each database so an interrupted run can be resumed
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Checkpoint {
+    pub backup_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<DumpLayout>, // Dump layout the completed dumps are arranged in; None in checkpoints from before it was recorded
+    pub completed: Vec<CompletedDump>,
+}
+
+/// A database whose dump finished and was moved into the backup directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompletedDump {
+    pub engine: String,
+    pub database: String,
+    pub entries: Vec<String>, // Paths of its dump files relative to the backup directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped_key: Option<String>, // Data key the entries are encrypted with, wrapped by the database's master key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub differential_base: Option<String>, // Backup the dump is a delta against (SQLite `differential`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub collection_hashes: BTreeMap<String, String>, // Hash of each collection's contents (MongoDB `skip_unchanged`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unchanged_collections: BTreeMap<String, String>, // Collections left out as unchanged, with the backup holding their dump
+}
+
+impl Checkpoint {
+    pub fn new(backup_id: &str, layout: DumpLayout) -> Self {
+        Checkpoint {
+            backup_id: backup_id.to_string(),
+            layout: Some(layout),
+            completed: Vec::new(),
+        }
+    }
+
+    /// Load the checkpoint of an interrupted backup, which must have been taken with `layout`:
+    /// the dumps it already holds are arranged that way
+    pub fn load(path: &Path, backup_id: &str, layout: DumpLayout) -> Result<Self> {
+        let contents = fs::read(path).map_err(|e| {
+            Error::Backup(format!("No checkpoint for backup {} at {:?}: {}", backup_id, path, e))
+        })?;
+        let checkpoint: Checkpoint = serde_json::from_slice(&contents)
+            .map_err(|e| Error::Backup(format!("Unreadable checkpoint {:?}: {}", path, e)))?;
+        if checkpoint.backup_id != backup_id {
+            return Err(Error::Backup(format!(
+                "Checkpoint {:?} belongs to backup {}, not {}",
+                path, checkpoint.backup_id, backup_id
+            )));
+        }
+        if let Some(started_with) = checkpoint.layout.filter(|started_with| *started_with != layout) {
+            return Err(Error::Config(format!(
+                "Backup {} was started with dump layout {:?}, not {:?}; resume it with the same dump_layout",
+                backup_id, started_with, layout
+            )));
+        }
+        Ok(checkpoint)
+    }
+
+    /// Write the checkpoint, replacing the previous one atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Backup(format!("Failed to serialize checkpoint: {}", e)))?;
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, contents).map_err(Error::Io)?;
+        fs::rename(&temp_path, path).map_err(Error::Io)?;
+        Ok(())
+    }
+
+    /// The completed dump of a database, if it was already taken
+    pub fn completed(&self, engine: &str, database: &str) -> Option<&CompletedDump> {
+        self.completed.iter().find(|dump| dump.engine == engine && dump.database == database)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_checks_backup_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CHECKPOINT_FILE);
+        let mut checkpoint = Checkpoint::new("backup-1", DumpLayout::Flat);
+        checkpoint.completed.push(CompletedDump {
+            engine: "mysql".to_string(),
+            database: "shop".to_string(),
+            entries: vec!["shop.sql".to_string()],
+            wrapped_key: None,
+            differential_base: None,
+            collection_hashes: BTreeMap::new(),
+            unchanged_collections: BTreeMap::new(),
+        });
+        checkpoint.save(&path).unwrap();
+
+        let loaded = Checkpoint::load(&path, "backup-1", DumpLayout::Flat).unwrap();
+        assert!(loaded.completed("mysql", "shop").is_some());
+        assert!(loaded.completed("mysql", "crm").is_none());
+        assert!(Checkpoint::load(&path, "backup-2", DumpLayout::Flat).is_err());
+        // The dumps it holds are arranged flat
+        assert!(matches!(Checkpoint::load(&path, "backup-1", DumpLayout::ByDatabase), Err(Error::Config(_))));
+    }
+}
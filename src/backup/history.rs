@@ -0,0 +1,95 @@
+use crate::backup::manifest::DumpRecord;
+use crate::database::connection::DatabaseInfo;
+use crate::storage::StoredBackup;
+use std::time::Duration;
+
+/// Number of recent dumps of a database that predictions are based on
+const HISTORY_DEPTH: usize = 5;
+
+/// Durations of earlier dumps, gathered from the manifests of stored backups
+#[derive(Debug, Default)]
+pub struct DumpHistory {
+    records: Vec<DumpRecord>, // Oldest first
+}
+
+impl DumpHistory {
+    /// History from stored backups, which must be sorted oldest first
+    pub fn from_backups(backups: &[StoredBackup]) -> Self {
+        DumpHistory {
+            records: backups.iter()
+                .filter_map(|backup| backup.manifest.as_ref())
+                .flat_map(|manifest| manifest.dumps.iter().cloned())
+                .collect(),
+        }
+    }
+
+    /// Predicted time to dump a database that is `size` bytes on the server now.
+    /// Recent dumps are scaled by size when both sides know it, else their durations are averaged.
+    pub fn predict(&self, engine: &str, database: &str, size: Option<u64>) -> Option<Duration> {
+        let recent: Vec<&DumpRecord> = self.records.iter()
+            .rev()
+            .filter(|record| record.engine == engine && record.database == database)
+            .take(HISTORY_DEPTH)
+            .collect();
+        if recent.is_empty() {
+            return None;
+        }
+
+        let sized: Vec<(f64, u64)> = recent.iter()
+            .filter_map(|record| record.source_size.filter(|s| *s > 0).map(|s| (record.duration_secs, s)))
+            .collect();
+        let secs = match size {
+            Some(size) if !sized.is_empty() => {
+                let secs_per_byte = sized.iter().map(|(d, _)| d).sum::<f64>()
+                    / sized.iter().map(|(_, s)| *s as f64).sum::<f64>();
+                secs_per_byte * size as f64
+            }
+            _ => recent.iter().map(|record| record.duration_secs).sum::<f64>() / recent.len() as f64,
+        };
+        Some(Duration::from_secs_f64(secs.max(0.0)))
+    }
+
+    /// Predicted time to dump all of an engine's databases one after another; None unless every
+    /// database has history
+    pub fn predict_engine(&self, engine: &str, databases: &[DatabaseInfo]) -> Option<Duration> {
+        databases.iter()
+            .map(|info| self.predict(engine, &info.name, info.size))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(database: &str, source_size: Option<u64>, duration_secs: f64) -> DumpRecord {
+        DumpRecord {
+            engine: "mysql".to_string(),
+            database: database.to_string(),
+            source_size,
+            dump_size: 0,
+            duration_secs,
+        }
+    }
+
+    #[test]
+    fn predicts_from_recent_dumps() {
+        let history = DumpHistory {
+            records: vec![
+                record("shop", Some(1000), 10.0),
+                record("shop", Some(3000), 30.0),
+                record("crm", None, 4.0),
+                record("crm", None, 8.0),
+            ],
+        };
+
+        // 40s over 4000 bytes scales to 20s for 2000 bytes
+        assert_eq!(history.predict("mysql", "shop", Some(2000)), Some(Duration::from_secs(20)));
+        assert_eq!(history.predict("mysql", "crm", Some(2000)), Some(Duration::from_secs(6)));
+        assert_eq!(history.predict("postgres", "shop", Some(2000)), None);
+
+        let info = |name: &str| DatabaseInfo { name: name.to_string(), size: Some(2000), schema_version: None, table_count: None };
+        assert_eq!(history.predict_engine("mysql", &[info("shop"), info("crm")]), Some(Duration::from_secs(26)));
+        assert_eq!(history.predict_engine("mysql", &[info("shop"), info("billing")]), None);
+    }
+}
@@ -0,0 +1,71 @@
+use crate::config::DumpLayout;
+
+/// Path, relative to the backup root, where a dump entry is placed under `layout`.
+/// `entry` is a top-level name written by an engine (`shop.sql`, `app.db.bak`, or a
+/// mongodump directory such as `app_data`), `databases` the engine's configured databases.
+fn layout_path(layout: DumpLayout, engine: &str, databases: &[String], entry: &str) -> String {
+    match layout {
+        DumpLayout::Flat => entry.to_string(),
+        DumpLayout::ByEngine => format!("{}/{}", engine, entry),
+        DumpLayout::EnginePrefix => format!("{}-{}", engine, entry),
+        DumpLayout::ByDatabase => match split_database(databases, entry) {
+            Some((database, suffix)) => format!("{}/{}{}", database, engine, suffix),
+            None => format!("{}-{}", engine, entry),
+        },
+    }
+}
+
+/// Archive path of a file or directory an engine wrote at `path`, relative to its dump
+/// directory. The layout places the top-level entry the path starts with, as it does when the
+/// dump is moved into the backup, and the rest of the path stays below it. Looking up a dump in
+/// an archive goes through this too, so it is found where the backup put it.
+pub fn member_path(layout: DumpLayout, engine: &str, databases: &[String], path: &str) -> String {
+    match path.split_once('/') {
+        Some((top, rest)) => format!("{}/{}", layout_path(layout, engine, databases, top), rest),
+        None => layout_path(layout, engine, databases, path),
+    }
+}
+
+/// Split an entry into the database it belongs to and the rest of its name (`.sql`, or empty
+/// for a directory), preferring the longest database name when several match
+fn split_database<'a>(databases: &'a [String], entry: &'a str) -> Option<(&'a str, &'a str)> {
+    databases.iter()
+        .filter_map(|database| {
+            let suffix = entry.strip_prefix(database.as_str())?;
+            (suffix.is_empty() || suffix.starts_with('.')).then_some((database.as_str(), suffix))
+        })
+        .max_by_key(|(database, _)| database.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn places_entries_per_layout() {
+        let databases = vec!["shop".to_string(), "shop.v2".to_string()];
+        let path = |layout, entry| layout_path(layout, "mysql", &databases, entry);
+
+        assert_eq!(path(DumpLayout::Flat, "shop.sql"), "shop.sql");
+        assert_eq!(path(DumpLayout::ByEngine, "shop.sql"), "mysql/shop.sql");
+        assert_eq!(path(DumpLayout::EnginePrefix, "shop.sql"), "mysql-shop.sql");
+        assert_eq!(path(DumpLayout::ByDatabase, "shop.sql"), "shop/mysql.sql");
+        assert_eq!(path(DumpLayout::ByDatabase, "shop.v2.sql"), "shop.v2/mysql.sql");
+        assert_eq!(path(DumpLayout::ByDatabase, "shop"), "shop/mysql");
+        assert_eq!(path(DumpLayout::ByDatabase, "other.sql"), "mysql-other.sql");
+    }
+
+    #[test]
+    fn places_nested_paths_under_their_top_level_entry() {
+        // SQLite files matched in subdirectories, dumped as sub/app.db.bak
+        let databases = vec!["sub/app.db".to_string()];
+        let path = |layout, entry| member_path(layout, "sqlite", &databases, entry);
+
+        assert_eq!(path(DumpLayout::Flat, "sub/app.db.bak"), "sub/app.db.bak");
+        assert_eq!(path(DumpLayout::ByEngine, "sub/app.db.bak"), "sqlite/sub/app.db.bak");
+        assert_eq!(path(DumpLayout::ByDatabase, "sub/app.db.bak"), "sqlite-sub/app.db.bak");
+
+        let shop = vec!["shop".to_string()];
+        assert_eq!(member_path(DumpLayout::ByDatabase, "mongodb", &shop, "shop/users.bson.gz"), "shop/mongodb/users.bson.gz");
+    }
+}
@@ -0,0 +1,222 @@
+use crate::config::{ChecksumAlgorithm, EntryCompression, DumpLayout, DumpMode};
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// File name of the manifest stored at the root of every archive
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Limits that keep tags useful as labels rather than a data store
+const MAX_TAGS: usize = 32;
+const MAX_TAG_KEY_LEN: usize = 64;
+const MAX_TAG_VALUE_LEN: usize = 256;
+
+/// Describes what an archive contains so restore knows how to replay it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub backup_id: String,
+    pub created_at: String,
+    pub kronos_version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>, // `[[hosts]]` entry the backup was taken from; None for single-host configs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>, // Schedule that took this backup of a subset (`engines`/`databases`); None for full backups
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub layout: DumpLayout, // Older manifests predate layouts and are flat
+    pub engines: Vec<EngineManifest>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dumps: Vec<DumpRecord>, // Timing of each database dumped by this run
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub incomplete: bool, // Rows were deliberately left out (`max_blob_bytes`), so a restore lacks some data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transformed_from: Option<String>, // Backup this archive was repacked from by `kronos transform`
+    #[serde(default)]
+    pub checksum_algorithm: ChecksumAlgorithm, // What the archive's recorded checksum was computed with; older manifests are SHA-256
+}
+
+/// Per-engine section of the manifest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EngineManifest {
+    pub engine: String,
+    pub dump_mode: DumpMode,
+    pub databases: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collections: Vec<String>, // MongoDB collections dumped from each database; empty means all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>, // MongoDB filter the collections were dumped with; None means every document
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_tables: Vec<String>, // Tables (MongoDB: collections) deliberately left out of the dumps
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub wrapped_keys: BTreeMap<String, String>, // Per database: data key its dump is encrypted with, wrapped by its master key
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub differential_bases: BTreeMap<String, String>, // Per database: backup whose full copy its <db>.bak.delta applies to
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub separate_routines: bool, // MySQL stored programs are in <db>.routines.sql rather than each database's main dump
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_blobs: Option<bool>, // PostgreSQL large objects forced in or out; None means pg_dump's default
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped_empty: Vec<String>, // Databases left out because they had no tables (`skip_empty`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub row_counts: BTreeMap<String, BTreeMap<String, u64>>, // Rows per table of each database before its dump (`capture_counts`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub checksums: BTreeMap<String, BTreeMap<String, String>>, // Content checksum per table of each database before its dump (`capture_checksums`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_blob_bytes: Option<u64>, // Rows with a longer binary value were left out of the dumps
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub oversized_rows: BTreeMap<String, BTreeMap<String, u64>>, // Per database: rows of each table left out by max_blob_bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry_compression: Option<EntryCompression>, // How the dumps are compressed in a zip archive, when not with deflate
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub collection_hashes: BTreeMap<String, BTreeMap<String, String>>, // Per database: hash of each collection's contents (`skip_unchanged`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unchanged_collections: BTreeMap<String, BTreeMap<String, String>>, // Per database: collections not dumped, with the backup holding their dump
+}
+
+impl EngineManifest {
+    /// Earlier backups holding data this one relies on: bases of SQLite deltas and dumps of
+    /// unchanged MongoDB collections
+    pub fn referenced_backups(&self) -> impl Iterator<Item = &str> {
+        self.differential_bases.values()
+            .chain(self.unchanged_collections.values().flat_map(|collections| collections.values()))
+            .map(String::as_str)
+    }
+
+    /// Whether `max_blob_bytes` left rows out, or may have: databases whose count is missing
+    /// (it failed, or they were dumped by an interrupted run) are assumed to have lost rows
+    pub fn left_out_rows(&self) -> bool {
+        self.max_blob_bytes.is_some()
+            && self.databases.iter().any(|database| {
+                self.oversized_rows.get(database).is_none_or(|tables| tables.values().any(|rows| *rows > 0))
+            })
+    }
+}
+
+/// Size and timing of one database's dump, kept so later runs can predict their duration
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DumpRecord {
+    pub engine: String,
+    pub database: String,
+    pub source_size: Option<u64>, // Size the server reported before the dump
+    pub dump_size: u64,
+    pub duration_secs: f64,
+}
+
+impl Manifest {
+    pub fn new(
+        backup_id: &str,
+        tags: BTreeMap<String, String>,
+        layout: DumpLayout,
+        engines: Vec<EngineManifest>,
+    ) -> Self {
+        let incomplete = engines.iter().any(EngineManifest::left_out_rows);
+        Manifest {
+            backup_id: backup_id.to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            kronos_version: env!("CARGO_PKG_VERSION").to_string(),
+            host: None,
+            schedule: None,
+            tags,
+            layout,
+            engines,
+            dumps: Vec::new(),
+            incomplete,
+            transformed_from: None,
+            checksum_algorithm: ChecksumAlgorithm::default(),
+        }
+    }
+
+    /// Whether the manifest carries every one of the given tags
+    pub fn matches_tags(&self, filter: &BTreeMap<String, String>) -> bool {
+        filter.iter().all(|(key, value)| self.tags.get(key) == Some(value))
+    }
+
+    /// Write the manifest into the backup directory, returning the JSON written
+    pub fn write(&self, backup_path: &Path) -> Result<String> {
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Backup(format!("Failed to serialize manifest: {}", e)))?;
+        fs::write(backup_path.join(MANIFEST_FILE), &contents).map_err(Error::Io)?;
+        Ok(contents)
+    }
+}
+
+
+/// Parse repeated `key=value` tag arguments, validating syntax and size limits
+pub fn parse_tags(args: &[String]) -> Result<BTreeMap<String, String>> {
+    if args.len() > MAX_TAGS {
+        return Err(Error::Config(format!("At most {} tags may be set", MAX_TAGS)));
+    }
+
+    let mut tags = BTreeMap::new();
+    for arg in args {
+        let (key, value) = arg.split_once('=')
+            .ok_or_else(|| Error::Config(format!("Invalid tag {:?}: expected key=value", arg)))?;
+        if key.is_empty() || key.len() > MAX_TAG_KEY_LEN
+            || !key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        {
+            return Err(Error::Config(format!(
+                "Invalid tag key {:?}: use 1-{} characters from [A-Za-z0-9_.-]",
+                key, MAX_TAG_KEY_LEN
+            )));
+        }
+        if value.len() > MAX_TAG_VALUE_LEN {
+            return Err(Error::Config(format!("Tag {:?} value exceeds {} bytes", key, MAX_TAG_VALUE_LEN)));
+        }
+        tags.insert(key.to_string(), value.to_string());
+    }
+
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_validates_tags() {
+        let tags = parse_tags(&["release=4.2".to_string(), "note=pre-migration".to_string()]).unwrap();
+        assert_eq!(tags.get("release").map(String::as_str), Some("4.2"));
+
+        assert!(parse_tags(&["missing-separator".to_string()]).is_err());
+        assert!(parse_tags(&["bad key=x".to_string()]).is_err());
+        assert!(parse_tags(&[format!("k={}", "v".repeat(MAX_TAG_VALUE_LEN + 1))]).is_err());
+    }
+
+    #[test]
+    fn marks_rows_left_out_by_max_blob_bytes() {
+        let mut engine = EngineManifest {
+            engine: "postgres".to_string(),
+            dump_mode: DumpMode::Full,
+            databases: vec!["shop".to_string(), "crm".to_string()],
+            collections: Vec::new(),
+            query: None,
+            excluded_tables: Vec::new(),
+            wrapped_keys: BTreeMap::new(),
+            differential_bases: BTreeMap::new(),
+            separate_routines: false,
+            include_blobs: None,
+            skipped_empty: Vec::new(),
+            row_counts: BTreeMap::new(),
+            checksums: BTreeMap::new(),
+            max_blob_bytes: None,
+            oversized_rows: BTreeMap::new(),
+            entry_compression: None,
+            collection_hashes: BTreeMap::new(),
+            unchanged_collections: BTreeMap::new(),
+        };
+        assert!(!engine.left_out_rows());
+
+        engine.max_blob_bytes = Some(1024);
+        engine.oversized_rows.insert("shop".to_string(), BTreeMap::new());
+        // crm wasn't counted, so rows may be missing from it
+        assert!(engine.left_out_rows());
+
+        engine.oversized_rows.insert("crm".to_string(), BTreeMap::new());
+        assert!(!engine.left_out_rows());
+        engine.oversized_rows.insert("crm".to_string(), BTreeMap::from([("public.files".to_string(), 3)]));
+        assert!(Manifest::new("backup-1", BTreeMap::new(), DumpLayout::default(), vec![engine]).incomplete);
+    }
+}
@@ -1 +1,7 @@
-pub mod performer;
\ No newline at end of file
+pub mod checkpoint;
+pub mod history;
+pub mod layout;
+pub mod manifest;
+pub mod performer;
+pub mod report;
+pub mod usage;
\ No newline at end of file
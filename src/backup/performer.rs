@@ -1,51 +1,368 @@
-use crate::config::Config;
-use crate::database::connection::{DatabaseConnectionFactory, DatabaseConnection};
+use crate::backup::checkpoint::{Checkpoint, CompletedDump};
+use crate::backup::layout::member_path;
+use crate::backup::history::DumpHistory;
+use crate::backup::manifest::{DumpRecord, EngineManifest};
+use crate::backup::report::DumpStats;
+use crate::backup::usage::dump_started;
+use crate::config::{ArchiveFormat, Config, DatabaseConfig, DumpLayout, EntryCompression, MissingDatabase};
+use crate::database::connection::{ConnectionStatus, DatabaseConnectionFactory, DatabaseConnection, DatabaseInfo, ExportedSnapshots};
 use crate::error::{Error, Result};
-use std::path::Path;
-use log::info;
+use crate::storage::local::LocalStorage;
+use crate::storage::{StorageBackend, StoredBackup};
+use crate::utils::compression::copy_archive_file;
+use crate::utils::delta::{write_delta, Signature, BLOCK_SIZE};
+use crate::utils::encryption::{encrypt_file, load_encryption_key, wrap_key, EncryptionKey};
+use crate::utils::failpoint::{fail_point, FailurePhase};
+use crate::utils::user::{hand_over_scratch_dir, run_as_user};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+
+/// How often readiness is re-checked while waiting for databases
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Collections of a MongoDB database hashed for `skip_unchanged`, and those whose hash matches an
+/// earlier backup, mapped to the backup holding their dump
+#[derive(Debug, Default)]
+struct CollectionChanges {
+    hashes: BTreeMap<String, String>,
+    unchanged: BTreeMap<String, String>,
+}
+
+/// Restricts a run to a subset of the configured engines and databases; empty lists mean everything
+#[derive(Debug, Clone, Default)]
+pub struct BackupFilter {
+    pub engines: Vec<String>,
+    pub databases: Vec<String>,
+}
+
+impl BackupFilter {
+    fn includes_engine(&self, db_type: &str) -> bool {
+        self.engines.is_empty() || self.engines.iter().any(|e| e == db_type)
+    }
+
+    fn includes_database(&self, database: &str) -> bool {
+        self.databases.is_empty() || self.databases.iter().any(|d| d == database)
+    }
+
+    /// The configured engines this filter includes, each narrowed to its included databases
+    pub(crate) fn select(&self, config: &Config) -> Vec<(&'static str, DatabaseConfig)> {
+        config.databases.configured().into_iter()
+            .filter(|(db_type, _)| self.includes_engine(db_type))
+            .filter_map(|(db_type, db_config)| {
+                let mut db_config = db_config.clone();
+                db_config.databases.retain(|db| self.includes_database(db));
+                (!db_config.databases.is_empty()).then_some((db_type, db_config))
+            })
+            .collect()
+    }
+}
 
 pub struct BackupPerformer<'a> {
     config: &'a Config,
     backup_path: &'a Path,
+    filter: &'a BackupFilter,
+    layout: DumpLayout,
+    engines: Vec<EngineManifest>,
+    dumps: Vec<DumpStats>,
+    entry_compression: BTreeMap<String, EntryCompression>,
+    checkpoint: Checkpoint,
+    checkpoint_path: Option<PathBuf>, // Where progress is saved after each database; unsaved when None
+    timeout: Option<Duration>, // Replaces every engine's timeout_secs when set
+    warnings: Vec<String>, // Problems that didn't stop the backup, for the run report
+    host: Option<String>, // `[[hosts]]` entry being backed up, whose backups hold differential bases
 }
 
 impl<'a> BackupPerformer<'a> {
-    pub fn new(config: &'a Config, backup_path: &'a Path) -> Self {
-        BackupPerformer { config, backup_path }
+    pub fn new(config: &'a Config, backup_path: &'a Path, filter: &'a BackupFilter) -> Self {
+        BackupPerformer {
+            config,
+            backup_path,
+            filter,
+            layout: config.dump_layout,
+            engines: Vec::new(),
+            dumps: Vec::new(),
+            entry_compression: BTreeMap::new(),
+            checkpoint: Checkpoint::default(),
+            checkpoint_path: None,
+            timeout: None,
+            warnings: Vec::new(),
+            host: None,
+        }
     }
 
-    pub async fn execute(&mut self) -> Result<()> {
-        let mut backup_completed = false;
+    /// Save progress to `path` after each database, skipping databases `checkpoint` already completed
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint, path: PathBuf) -> Self {
+        self.checkpoint = checkpoint;
+        self.checkpoint_path = Some(path);
+        self
+    }
 
-        // Handle SQLite databases
-        if let Some(sqlite_config) = &self.config.databases.sqlite {
-            info!("Starting SQLite backup");
-            let db = DatabaseConnectionFactory::create_connection("sqlite", sqlite_config)?;
-            self.perform_backup(&*db, "sqlite").await?;
-            backup_completed = true;
+    /// Limit each database's dump but SQLite's to `timeout` instead of the configured `timeout_secs`
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Back up the `[[hosts]]` entry `host`, so differential dumps are taken against its backups
+    pub fn with_host(mut self, host: Option<String>) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// Arrange dumps with `layout` instead of the configured `dump_layout`
+    pub fn with_layout(mut self, layout: DumpLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Manifest entries for the engines backed up so far
+    pub fn engines(&self) -> &[EngineManifest] {
+        &self.engines
+    }
+
+    /// Size and duration of each database dumped so far
+    pub fn dumps(&self) -> &[DumpStats] {
+        &self.dumps
+    }
+
+    /// Timings of the databases dumped by this run, for the manifest's dump history
+    pub fn dump_records(&self) -> Vec<DumpRecord> {
+        self.dumps.iter()
+            .filter_map(|dump| Some(DumpRecord {
+                engine: dump.engine.clone(),
+                database: dump.database.clone(),
+                source_size: dump.source_size,
+                dump_size: dump.size,
+                duration_secs: dump.duration?.as_secs_f64(),
+            }))
+            .collect()
+    }
+
+    /// Problems so far that didn't stop the backup, e.g. skipped databases or failed size queries
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Backup entries (files or directories) of engines whose dumps aren't deflated in zip
+    /// archives, with how they are compressed instead
+    pub fn entry_compression(&self) -> &BTreeMap<String, EntryCompression> {
+        &self.entry_compression
+    }
+
+    /// Block until every configured database accepts connections or the timeout elapses
+    pub async fn wait_for_databases(&self, timeout: Duration) -> Result<()> {
+        let started = Instant::now();
+
+        for (db_type, db_config) in self.config.databases.configured() {
+            let db = DatabaseConnectionFactory::create_connection(db_type, db_config)?;
+            loop {
+                let last_error = match db.test_connection().await? {
+                    ConnectionStatus::Connected => break,
+                    ConnectionStatus::Error(e) => e,
+                    ConnectionStatus::Disconnected => "disconnected".to_string(),
+                };
+                if started.elapsed() >= timeout {
+                    return Err(Error::Database(format!(
+                        "Timed out after {}s waiting for {} database: {}",
+                        timeout.as_secs(), db_type, last_error
+                    )));
+                }
+                info!("Waiting for {} database to become reachable", db_type);
+                tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+            }
         }
 
-        // Handle MySQL databases
-        if let Some(mysql_config) = &self.config.databases.mysql {
-            info!("Starting MySQL backup");
-            let db = DatabaseConnectionFactory::create_connection("mysql", mysql_config)?;
-            self.perform_backup(&*db, "mysql").await?;
-            backup_completed = true;
+        info!("Databases reachable after waiting {:.1}s", started.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    pub async fn execute(&mut self) -> Result<()> {
+        if self.config.consistent_snapshot != Some(true) {
+            return self.dump_engines().await;
         }
 
-        // Handle PostgreSQL databases
-        if let Some(postgres_config) = &self.config.databases.postgres {
-            info!("Starting PostgreSQL backup");
-            let db = DatabaseConnectionFactory::create_connection("postgres", postgres_config)?;
-            self.perform_backup(&*db, "postgres").await?;
-            backup_completed = true;
+        // Lock every engine up front so all dumps see the same moment, then release them all
+        let configs = self.filter.select(self.config);
+        let connections = configs.iter()
+            .map(|(db_type, db_config)| DatabaseConnectionFactory::create_connection(db_type, db_config))
+            .collect::<Result<Vec<_>>>()?;
+        warn!("consistent_snapshot is set: writes are blocked on locked engines until every dump has finished");
+        let mut locked = Vec::new();
+        let mut result = Ok(());
+        for db in &connections {
+            match db.lock().await {
+                Ok(true) => {
+                    info!("Locked {} for a consistent snapshot", db.database_type());
+                    locked.push(db);
+                }
+                Ok(false) => self.warn(format!(
+                    "{} cannot be locked; its dumps are only consistent per database",
+                    db.database_type()
+                )),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
         }
+        if result.is_ok() {
+            result = self.dump_engines().await;
+        }
+        for db in locked.into_iter().rev() {
+            match db.unlock().await {
+                Ok(()) => info!("Unlocked {}", db.database_type()),
+                Err(e) => self.warn(format!("Failed to unlock {}: {}", db.database_type(), e)),
+            }
+        }
+        result
+    }
+
+    /// Log a problem that doesn't stop the backup and keep it for the run report
+    fn warn(&mut self, message: String) {
+        record_warning(&mut self.warnings, message);
+    }
 
-        // Handle MongoDB databases
-        if let Some(mongodb_config) = &self.config.databases.mongodb {
-            info!("Starting MongoDB backup");
-            let db = DatabaseConnectionFactory::create_connection("mongodb", mongodb_config)?;
-            self.perform_backup(&*db, "mongodb").await?;
+    async fn dump_engines(&mut self) -> Result<()> {
+        let mut backup_completed = false;
+
+        for (db_type, mut db_config) in self.filter.select(self.config) {
+            info!("Starting {} backup", db_type);
+            let db = DatabaseConnectionFactory::create_connection(db_type, &db_config)?;
+            let resolved = db.resolve_databases().await?;
+            drop(db);
+            if let Some(resolved) = resolved.filter(|resolved| *resolved != db_config.databases) {
+                info!("Resolved {} databases {} to: {}", db_type, db_config.databases.join(", "), resolved.join(", "));
+                db_config.databases = resolved;
+            }
+            let pending: Vec<String> = db_config.databases.iter()
+                .filter(|db| self.checkpoint.completed(db_type, db).is_none())
+                .cloned()
+                .collect();
+            let mut source_sizes = BTreeMap::new();
+            let mut skipped_empty = Vec::new();
+            let mut row_counts = BTreeMap::new();
+            let mut checksums = BTreeMap::new();
+            let mut oversized_rows = BTreeMap::new();
+            if pending.is_empty() {
+                info!("All {} databases were dumped by the interrupted run", db_type);
+            } else {
+                let db = DatabaseConnectionFactory::create_connection(db_type, &db_config)?;
+                self.check_connection(&*db, &db_config, db_type).await?;
+                let missing = db.find_missing_databases().await?;
+                drop(db);
+                if !missing.is_empty() {
+                    handle_missing_databases(&mut db_config, db_type, &missing, &mut self.warnings)?;
+                    if db_config.databases.is_empty() {
+                        continue;
+                    }
+                }
+                let db = DatabaseConnectionFactory::create_connection(db_type, &db_config)?;
+                let db_info = self.prepare_backup(&*db, &db_config, db_type).await?;
+                drop(db);
+                if db_config.skip_empty == Some(true) {
+                    skipped_empty = empty_databases(&db_info, &pending);
+                    if !skipped_empty.is_empty() {
+                        info!("Skipping empty {} databases: {}", db_type, skipped_empty.join(", "));
+                        db_config.databases.retain(|db| !skipped_empty.contains(db));
+                    }
+                }
+                source_sizes = db_info.into_iter()
+                    .filter_map(|info| Some((info.name, info.size?)))
+                    .collect();
+            }
+
+            let master_keys = load_master_keys(db_type, &db_config, &mut self.warnings)?;
+            let mut snapshots = ExportedSnapshots::default();
+            // Checksums are taken in the snapshot the dump reads, so they describe what it holds
+            if db_config.consistent_snapshot == Some(true) || (db_type == "postgres" && db_config.capture_checksums == Some(true)) {
+                let mut exporting = db_config.clone();
+                exporting.databases.retain(|db| self.checkpoint.completed(db_type, db).is_none());
+                let db = DatabaseConnectionFactory::create_connection(db_type, &exporting)?;
+                snapshots = db.export_snapshots().await?;
+                if !snapshots.is_empty() {
+                    info!("Exported {} snapshots for {}", db_type, exporting.databases.join(", "));
+                }
+            }
+
+            // Dump one database at a time so each finished dump can be checkpointed
+            for database in &db_config.databases {
+                if let Some(done) = self.checkpoint.completed(db_type, database) {
+                    info!("Skipping {} database {}: already dumped", db_type, database);
+                    let compression = db_config.entry_compression();
+                    if compression != EntryCompression::Deflate {
+                        self.entry_compression.extend(done.entries.iter().map(|entry| (entry.clone(), compression)));
+                    }
+                    self.dumps.push(DumpStats {
+                        engine: db_type.to_string(),
+                        database: database.clone(),
+                        size: entries_size(self.backup_path, &done.entries),
+                        source_size: None,
+                        duration: None,
+                    });
+                    continue;
+                }
+                if db_config.capture_counts == Some(true) {
+                    if let Some(counts) = capture_row_counts(db_type, &db_config, database, &mut self.warnings).await {
+                        row_counts.insert(database.clone(), counts);
+                    }
+                }
+                if db_config.capture_checksums == Some(true) {
+                    if let Some(sums) = capture_checksums(db_type, &db_config, database, snapshots.get(database), &mut self.warnings).await {
+                        checksums.insert(database.clone(), sums);
+                    }
+                }
+                if let Some(max_bytes) = db_config.max_blob_bytes {
+                    if let Some(left_out) = count_oversized_rows(db_type, &db_config, database, max_bytes, &mut self.warnings).await {
+                        oversized_rows.insert(database.clone(), left_out);
+                    }
+                }
+                let source_size = source_sizes.get(database).copied();
+                self.dump_database(db_type, &db_config, database, source_size, master_keys.get(database), snapshots.get(database)).await?;
+                snapshots.release(database);
+            }
+
+            self.engines.push(EngineManifest {
+                engine: db_type.to_string(),
+                dump_mode: db_config.dump_mode,
+                databases: db_config.databases.clone(),
+                collections: db_config.collections.clone(),
+                query: db_config.query.clone(),
+                separate_routines: db_config.separate_routines == Some(true),
+                excluded_tables: db_config.excluded_tables(),
+                wrapped_keys: self.checkpoint.completed.iter()
+                    .filter(|dump| dump.engine == db_type)
+                    .filter_map(|dump| Some((dump.database.clone(), dump.wrapped_key.clone()?)))
+                    .collect(),
+                differential_bases: self.checkpoint.completed.iter()
+                    .filter(|dump| dump.engine == db_type)
+                    .filter_map(|dump| Some((dump.database.clone(), dump.differential_base.clone()?)))
+                    .collect(),
+                include_blobs: db_config.include_blobs,
+                skipped_empty,
+                row_counts,
+                checksums,
+                max_blob_bytes: db_config.max_blob_bytes,
+                oversized_rows,
+                // Only zip archives compress entries one by one
+                entry_compression: Some(db_config.entry_compression()).filter(|compression| {
+                    *compression != EntryCompression::Deflate
+                        && self.config.storage.archive_format == ArchiveFormat::Zip
+                        && self.config.storage.compressor_command().is_none()
+                }),
+                collection_hashes: self.checkpoint.completed.iter()
+                    .filter(|dump| dump.engine == db_type && !dump.collection_hashes.is_empty())
+                    .map(|dump| (dump.database.clone(), dump.collection_hashes.clone()))
+                    .collect(),
+                unchanged_collections: self.checkpoint.completed.iter()
+                    .filter(|dump| dump.engine == db_type && !dump.unchanged_collections.is_empty())
+                    .map(|dump| (dump.database.clone(), dump.unchanged_collections.clone()))
+                    .collect(),
+            });
             backup_completed = true;
         }
 
@@ -56,21 +373,46 @@ impl<'a> BackupPerformer<'a> {
         Ok(())
     }
 
-    async fn perform_backup(&self, db: &dyn DatabaseConnection, db_type: &str) -> Result<()> {
+    /// Validate the engine's configuration and make sure it accepts connections
+    async fn check_connection(&self, db: &dyn DatabaseConnection, db_config: &DatabaseConfig, db_type: &str) -> Result<()> {
+        // Validate configuration before touching the database
+        db.validate_config(db_config)?;
+        fail_point(FailurePhase::Connect)?;
+        if let Some(user) = run_as_user(db_config)? {
+            info!("Running {} commands as OS user {}", db_type, user.name);
+        }
+
         // Test connection first
         let status = db.test_connection().await?;
         match status {
-            crate::database::connection::ConnectionStatus::Connected => {
+            ConnectionStatus::Connected => {
                 info!("Successfully connected to {} database", db_type);
             }
-            crate::database::connection::ConnectionStatus::Error(e) => {
+            ConnectionStatus::Error(e) => {
                 return Err(Error::Database(format!("Failed to connect to {} database: {}", db_type, e)));
             }
-            crate::database::connection::ConnectionStatus::Disconnected => {
+            ConnectionStatus::Disconnected => {
                 return Err(Error::Database(format!("{} database is disconnected", db_type)));
             }
         }
 
+        Ok(())
+    }
+
+    /// Check privileges and size up an engine before any of its databases are dumped
+    async fn prepare_backup(&mut self, db: &dyn DatabaseConnection, db_config: &DatabaseConfig, db_type: &str) -> Result<Vec<DatabaseInfo>> {
+        // Catch "connects fine, dump fails" before spending time on the dump
+        if db_config.verify_privileges == Some(true) {
+            let missing = db.verify_privileges().await?;
+            if !missing.is_empty() {
+                return Err(Error::Database(format!(
+                    "{} user {:?} is missing privileges needed for the dump: {}",
+                    db_type, db_config.user, missing.join("; ")
+                )));
+            }
+            info!("Verified {} dump privileges", db_type);
+        }
+
         // Get database info
         let db_info = db.get_database_info().await?;
         info!("Found {} databases for backup:", db_info.len());
@@ -82,15 +424,646 @@ impl<'a> BackupPerformer<'a> {
             info!("  - {} ({})", info.name, size_str);
         }
 
-        // Estimate backup size
-        let estimated_size = db.estimate_backup_size().await?;
-        info!("Estimated backup size: {} bytes", estimated_size);
+        // The estimate is informational only, so a failed size query shouldn't stop the backup
+        match db.estimate_backup_size().await {
+            Ok(estimated_size) => info!("Estimated backup size: {} bytes", estimated_size),
+            Err(e) => self.warn(format!("Failed to estimate {} backup size, continuing without it: {}", db_type, e)),
+        }
+
+        Ok(db_info)
+    }
 
-        // Perform the backup
-        info!("Starting backup for {} databases", db_type);
-        db.backup(self.backup_path).await?;
-        info!("Backup completed successfully for {} databases", db_type);
+    /// Dump a single database, move its files into the layout, encrypt them when it has a master
+    /// key and checkpoint it
+    async fn dump_database(
+        &mut self,
+        db_type: &str,
+        db_config: &DatabaseConfig,
+        database: &str,
+        source_size: Option<u64>,
+        master_key: Option<&EncryptionKey>,
+        snapshot: Option<&str>,
+    ) -> Result<()> {
+        let changes = match db_config.skip_unchanged {
+            Some(true) => self.collection_changes(db_type, db_config, database).await,
+            _ => None,
+        };
+        let mut single = db_config.clone();
+        single.databases = vec![database.to_string()];
+        single.snapshot_id = snapshot.map(str::to_string);
+        let CollectionChanges { hashes: collection_hashes, unchanged: unchanged_collections } = changes.unwrap_or_default();
+        if !unchanged_collections.is_empty() {
+            single.collections = collection_hashes.keys()
+                .filter(|collection| !unchanged_collections.contains_key(*collection))
+                .cloned()
+                .collect();
+        }
+        let all_unchanged = !unchanged_collections.is_empty() && single.collections.is_empty();
+        let db = DatabaseConnectionFactory::create_connection(db_type, &single)?;
+
+        // Dump into a scratch directory, then move each entry to its place in the layout
+        let scratch = self.backup_path.join(format!(".{}", db_type));
+        if scratch.exists() {
+            // Left over from an interrupted dump
+            fs::remove_dir_all(&scratch).map_err(Error::Io)?;
+        }
+        // Dump commands running as another user must be able to write their output
+        if let (Some(user), Some(run_dir)) = (run_as_user(db_config)?, self.backup_path.parent()) {
+            hand_over_scratch_dir(&user, &scratch, run_dir)?;
+        }
+        info!("Starting backup of {} database {}", db_type, database);
+        let _running = dump_started();
+        let started = Instant::now();
+        if all_unchanged {
+            info!("No collection of {} database {} changed since the last backup; nothing to dump", db_type, database);
+            fs::create_dir_all(&scratch).map_err(Error::Io)?;
+        } else {
+            let warnings = &mut self.warnings;
+            let dump = async {
+                match db_config.liveness_check_interval {
+                    Some(secs) => {
+                        let label = format!("{} database {}", db_type, database);
+                        check_liveness_during(&*db, db.backup(&scratch), Duration::from_secs(secs), &label, warnings).await
+                    }
+                    None => db.backup(&scratch).await,
+                }
+            };
+            // SQLite is copied in-process rather than by a command that could be killed, so
+            // --timeout-per-db leaves it alone (and config validation refuses timeout_secs)
+            let timeout = match db_type {
+                "sqlite" => None,
+                _ => self.timeout.or(db_config.timeout_secs.map(Duration::from_secs)),
+            };
+            match timeout {
+                // Dropping the dump future kills its dump command
+                Some(timeout) => tokio::time::timeout(timeout, dump).await.map_err(|_| {
+                    Error::Backup(format!("{} database {} was not dumped within {}s", db_type, database, timeout.as_secs()))
+                })??,
+                None => dump.await?,
+            }
+        }
+        let duration = started.elapsed();
+        fail_point(FailurePhase::Dump)?;
+        let differential_base = match db_config.differential {
+            Some(true) => self.write_differential(&scratch, database)?,
+            _ => None,
+        };
+
+        let mut entries = Vec::new();
+        for name in top_level_entries(&scratch)? {
+            let relative = member_path(self.layout, db_type, &db_config.databases, &name);
+            let target = self.backup_path.join(&relative);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).map_err(Error::Io)?;
+            }
+            fs::rename(scratch.join(&name), &target).map_err(Error::Io)?;
+            if db_config.entry_compression() != EntryCompression::Deflate {
+                self.entry_compression.insert(relative.clone(), db_config.entry_compression());
+            }
+            entries.push(relative);
+        }
+        fs::remove_dir(&scratch).map_err(Error::Io)?;
+        let wrapped_key = master_key.map(|master| encrypt_entries(self.backup_path, &entries, master)).transpose()?;
+        if wrapped_key.is_some() {
+            info!("Encrypted {} database {} with its own data key", db_type, database);
+        }
+        info!("Backup completed successfully for {} database {}", db_type, database);
+        self.dumps.push(DumpStats {
+            engine: db_type.to_string(),
+            database: database.to_string(),
+            size: entries_size(self.backup_path, &entries),
+            source_size,
+            duration: Some(duration),
+        });
+
+        self.checkpoint.completed.push(CompletedDump {
+            engine: db_type.to_string(),
+            database: database.to_string(),
+            entries,
+            wrapped_key,
+            differential_base,
+            collection_hashes,
+            unchanged_collections,
+        });
+        if let Some(path) = &self.checkpoint_path {
+            self.checkpoint.save(path)?;
+        }
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Hash the collections of a MongoDB database for `skip_unchanged` and match them against the
+    /// newest stored backup with hashes of it. None, dumping every collection, when hashing fails.
+    async fn collection_changes(&mut self, db_type: &str, db_config: &DatabaseConfig, database: &str) -> Option<CollectionChanges> {
+        let db = DatabaseConnectionFactory::create_connection(db_type, db_config).ok()?;
+        warn!(
+            "Hashing the collections of {} database {} for skip_unchanged: this reads every document, and writes to the database wait until it's done",
+            db_type, database
+        );
+        let mut hashes = match db.content_hashes(database).await {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                self.warn(format!("Failed to hash the collections of {} database {}, dumping all of them: {}", db_type, database, e));
+                return None;
+            }
+        };
+        let excluded = db_config.excluded_tables();
+        hashes.retain(|collection, _| {
+            !excluded.contains(collection) && (db_config.collections.is_empty() || db_config.collections.contains(collection))
+        });
+        let storage = LocalStorage::from_config(&self.config.storage);
+        let unchanged = match find_unchanged_collections(&storage, self.host.as_deref(), database, db_config.query.as_deref(), &hashes) {
+            Ok(unchanged) => unchanged,
+            Err(e) => {
+                self.warn(format!("Failed to compare {} database {} with stored backups, dumping every collection: {}", db_type, database, e));
+                BTreeMap::new()
+            }
+        };
+        if !unchanged.is_empty() {
+            info!(
+                "{} of {} collections of {} database {} are unchanged since an earlier backup",
+                unchanged.len(), hashes.len(), db_type, database
+            );
+        }
+        Some(CollectionChanges { hashes, unchanged })
+    }
+
+    /// Replace the fresh copy of a SQLite database in `scratch` with a delta against its newest
+    /// full copy in storage, returning the id of the backup holding that copy. The full copy is
+    /// kept when there is none to diff against or more than half of the database changed.
+    fn write_differential(&self, scratch: &Path, database: &str) -> Result<Option<String>> {
+        let storage = LocalStorage::from_config(&self.config.storage);
+        let Some((base, member)) = find_differential_base(&storage, self.host.as_deref(), database)? else {
+            info!("No full copy of sqlite database {} to diff against; storing a full copy", database);
+            return Ok(None);
+        };
+
+        let mut signature = Signature::new(BLOCK_SIZE);
+        if !copy_archive_file(&base.path, &member, &mut signature, storage.read_options())? {
+            return Err(Error::Backup(format!("Backup {} has no {} to diff against", base.backup_id, member)));
+        }
+        let full_path = scratch.join(format!("{}.bak", database));
+        let delta_path = scratch.join(format!("{}.bak.delta", database));
+        let full = File::open(&full_path).map_err(Error::Io)?;
+        let delta = File::create(&delta_path).map_err(Error::Io)?;
+        let stats = write_delta(&signature, BufReader::new(full), BufWriter::new(delta))
+            .map_err(|e| Error::Backup(format!("Failed to diff sqlite database {}: {}", database, e)))?;
+
+        let total = stats.copied + stats.literal;
+        if stats.literal * 2 > total {
+            info!("{} of {} bytes of sqlite database {} changed; storing a full copy", stats.literal, total, database);
+            fs::remove_file(&delta_path).map_err(Error::Io)?;
+            return Ok(None);
+        }
+        fs::remove_file(&full_path).map_err(Error::Io)?;
+        info!(
+            "Stored sqlite database {} as a delta against {}: {} of {} bytes changed",
+            database, base.backup_id, stats.literal, total
+        );
+        Ok(Some(base.backup_id))
+    }
+}
+
+/// Newest stored backup of `host` holding a full, unencrypted copy of SQLite `database`, with
+/// that copy's path in the archive
+fn find_differential_base(storage: &LocalStorage, host: Option<&str>, database: &str) -> Result<Option<(StoredBackup, String)>> {
+    for backup in storage.list()?.into_iter().rev() {
+        let Some(manifest) = &backup.manifest else {
+            continue;
+        };
+        if manifest.host.as_deref() != host {
+            continue;
+        }
+        let Some(engine) = manifest.engines.iter()
+            .find(|engine| engine.engine == "sqlite" && engine.databases.iter().any(|db| db == database))
+        else {
+            continue;
+        };
+        if engine.differential_bases.contains_key(database) || engine.wrapped_keys.contains_key(database) {
+            continue;
+        }
+        let member = member_path(manifest.layout, "sqlite", &engine.databases, &format!("{}.bak", database));
+        return Ok(Some((backup, member)));
+    }
+    Ok(None)
+}
+
+/// Collections of MongoDB `database` whose `hashes` match the newest stored backup of `host` that
+/// recorded hashes for it, mapped to the backup holding their dump. Nothing matches when that
+/// backup was dumped with a different `query`.
+fn find_unchanged_collections(
+    storage: &LocalStorage,
+    host: Option<&str>,
+    database: &str,
+    query: Option<&str>,
+    hashes: &BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>> {
+    for backup in storage.list()?.into_iter().rev() {
+        let Some(manifest) = &backup.manifest else {
+            continue;
+        };
+        if manifest.host.as_deref() != host {
+            continue;
+        }
+        let Some(engine) = manifest.engines.iter()
+            .find(|engine| engine.engine == "mongodb" && engine.collection_hashes.contains_key(database))
+        else {
+            continue;
+        };
+        if engine.query.as_deref() != query {
+            return Ok(BTreeMap::new());
+        }
+        return Ok(unchanged_collections(hashes, &backup.backup_id, engine, database));
+    }
+    Ok(BTreeMap::new())
+}
+
+/// Collections of `database` whose hash is the same in the manifest of backup `base_id`, mapped
+/// to the backup holding their dump: the base itself, or whichever backup it referred to
+fn unchanged_collections(
+    hashes: &BTreeMap<String, String>,
+    base_id: &str,
+    base: &EngineManifest,
+    database: &str,
+) -> BTreeMap<String, String> {
+    let (Some(base_hashes), held_elsewhere) = (base.collection_hashes.get(database), base.unchanged_collections.get(database)) else {
+        return BTreeMap::new();
+    };
+    hashes.iter()
+        .filter(|(collection, hash)| base_hashes.get(*collection) == Some(*hash))
+        .map(|(collection, _)| {
+            let holder = held_elsewhere.and_then(|held| held.get(collection)).map_or(base_id, String::as_str);
+            (collection.clone(), holder.to_string())
+        })
+        .collect()
+}
+
+/// Predicted duration of a backup run from the dump history, or None when any included engine
+/// can't be predicted
+pub async fn estimate_run_duration(config: &Config, filter: &BackupFilter, history: &DumpHistory) -> Result<Option<Duration>> {
+    if config.hosts.is_empty() {
+        return estimate_engines_duration(config, filter, history).await;
+    }
+    // Hosts are backed up concurrently, so at best the run takes as long as the slowest host
+    let mut longest = Duration::ZERO;
+    for host in &config.hosts {
+        match estimate_engines_duration(&config.for_host(host), filter, history).await? {
+            Some(duration) => longest = longest.max(duration),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(longest))
+}
+
+async fn estimate_engines_duration(config: &Config, filter: &BackupFilter, history: &DumpHistory) -> Result<Option<Duration>> {
+    let mut total = Duration::ZERO;
+    for (db_type, db_config) in filter.select(config) {
+        let db = DatabaseConnectionFactory::create_connection(db_type, &db_config)?;
+        match db.estimate_duration(history).await? {
+            Some(duration) => total += duration,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(total))
+}
+
+/// Row counts of the tables of one database that go into its dump. Counting is only a record
+/// for checking restores, so a failure is logged and leaves the database out.
+async fn capture_row_counts(
+    db_type: &str,
+    db_config: &DatabaseConfig,
+    database: &str,
+    warnings: &mut Vec<String>,
+) -> Option<BTreeMap<String, u64>> {
+    let db = DatabaseConnectionFactory::create_connection(db_type, db_config).ok()?;
+    match db.row_counts(database).await {
+        Ok(mut counts) => {
+            let excluded = db_config.excluded_tables();
+            counts.retain(|table, _| {
+                !excluded.contains(table) && (db_config.collections.is_empty() || db_config.collections.contains(table))
+            });
+            Some(counts)
+        }
+        Err(e) => {
+            record_warning(warnings, format!("Failed to count rows of {} database {}: {}", db_type, database, e));
+            None
+        }
+    }
+}
+
+/// Content checksums of the tables of one database that go into its dump, for `kronos compare`
+/// to check a restore against, read in the dump's `snapshot` when it has one. Like row counts, a
+/// failure is logged and leaves the database out.
+async fn capture_checksums(
+    db_type: &str,
+    db_config: &DatabaseConfig,
+    database: &str,
+    snapshot: Option<&str>,
+    warnings: &mut Vec<String>,
+) -> Option<BTreeMap<String, String>> {
+    let mut single = db_config.clone();
+    single.snapshot_id = snapshot.map(str::to_string);
+    let db = DatabaseConnectionFactory::create_connection(db_type, &single).ok()?;
+    match db.content_hashes(database).await {
+        Ok(mut checksums) => {
+            let excluded = db_config.excluded_tables();
+            checksums.retain(|table, _| {
+                !excluded.contains(table) && (db_config.collections.is_empty() || db_config.collections.contains(table))
+            });
+            Some(checksums)
+        }
+        Err(e) => {
+            record_warning(warnings, format!("Failed to checksum tables of {} database {}: {}", db_type, database, e));
+            None
+        }
+    }
+}
+
+/// Rows of one database that `max_blob_bytes` leaves out, recorded as a warning so the run's
+/// report says the backup is incomplete. None when counting fails; the manifest then assumes rows
+/// were left out.
+async fn count_oversized_rows(
+    db_type: &str,
+    db_config: &DatabaseConfig,
+    database: &str,
+    max_bytes: u64,
+    warnings: &mut Vec<String>,
+) -> Option<BTreeMap<String, u64>> {
+    let db = DatabaseConnectionFactory::create_connection(db_type, db_config).ok()?;
+    match db.oversized_rows(database).await {
+        Ok(left_out) => {
+            if !left_out.is_empty() {
+                let tables: Vec<String> = left_out.iter().map(|(table, rows)| format!("{} ({})", table, rows)).collect();
+                record_warning(warnings, format!(
+                    "Leaving rows with binary values over {} bytes out of {} database {}: {}",
+                    max_bytes, db_type, database, tables.join(", ")
+                ));
+            }
+            Some(left_out)
+        }
+        Err(e) => {
+            record_warning(warnings, format!("Failed to count oversized rows of {} database {}: {}", db_type, database, e));
+            None
+        }
+    }
+}
+
+/// Apply the engine's `missing_database` policy, dropping missing databases unless it is "error"
+fn handle_missing_databases(
+    db_config: &mut DatabaseConfig,
+    db_type: &str,
+    missing: &[String],
+    warnings: &mut Vec<String>,
+) -> Result<()> {
+    match db_config.missing_database {
+        MissingDatabase::Error => {
+            return Err(Error::Database(format!(
+                "{} databases not found on the server: {}",
+                db_type, missing.join(", ")
+            )));
+        }
+        MissingDatabase::Skip => info!("Skipping {} databases not found on the server: {}", db_type, missing.join(", ")),
+        MissingDatabase::Warn => record_warning(
+            warnings,
+            format!("Skipping {} databases not found on the server: {}", db_type, missing.join(", ")),
+        ),
+    }
+    db_config.databases.retain(|db| !missing.contains(db));
+    Ok(())
+}
+
+/// Databases still to be dumped that hold no tables
+fn empty_databases(db_info: &[DatabaseInfo], pending: &[String]) -> Vec<String> {
+    db_info.iter()
+        .filter(|info| info.is_empty() && pending.contains(&info.name))
+        .map(|info| info.name.clone())
+        .collect()
+}
+
+/// Names of the files and directories directly inside a directory
+fn top_level_entries(dir: &Path) -> Result<BTreeSet<String>> {
+    let mut names = BTreeSet::new();
+    for entry in fs::read_dir(dir).map_err(Error::Io)? {
+        names.insert(entry.map_err(Error::Io)?.file_name().to_string_lossy().to_string());
+    }
+    Ok(names)
+}
+
+/// Total size on disk of backup entries, descending into directories
+fn entries_size(backup_path: &Path, entries: &[String]) -> u64 {
+    fn size(path: &Path) -> u64 {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => fs::read_dir(path)
+                .map(|entries| entries.flatten().map(|entry| size(&entry.path())).sum())
+                .unwrap_or(0),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        }
+    }
+    entries.iter().map(|entry| size(&backup_path.join(entry))).sum()
+}
+
+/// Load the master key of each database listed in `encryption_key_files`
+fn load_master_keys(
+    db_type: &str,
+    db_config: &DatabaseConfig,
+    warnings: &mut Vec<String>,
+) -> Result<BTreeMap<String, EncryptionKey>> {
+    let mut keys = BTreeMap::new();
+    for (database, path) in &db_config.encryption_key_files {
+        if !db_config.databases.contains(database) {
+            record_warning(warnings, format!("{} encryption_key_files names {}, which isn't being backed up", db_type, database));
+            continue;
+        }
+        keys.insert(database.clone(), load_encryption_key(Path::new(path))?);
+    }
+    Ok(keys)
+}
+
+/// Encrypt every file of a database's dump with a fresh data key, returning it wrapped by `master`
+fn encrypt_entries(backup_path: &Path, entries: &[String], master: &EncryptionKey) -> Result<String> {
+    fn encrypt(path: &Path, key: &EncryptionKey) -> Result<()> {
+        if fs::symlink_metadata(path).map_err(Error::Io)?.is_dir() {
+            for entry in fs::read_dir(path).map_err(Error::Io)? {
+                encrypt(&entry.map_err(Error::Io)?.path(), key)?;
+            }
+            return Ok(());
+        }
+        encrypt_file(path, key)
+    }
+
+    let data_key = EncryptionKey::generate();
+    for entry in entries {
+        encrypt(&backup_path.join(entry), &data_key)?;
+    }
+    wrap_key(master, &data_key)
+}
+
+/// Log a problem that doesn't stop the backup and add it to `warnings`
+fn record_warning(warnings: &mut Vec<String>, message: String) {
+    warn!("{}", message);
+    warnings.push(message);
+}
+
+/// Run a dump while pinging its server every `interval`, warning when it stops answering so a
+/// partial or failed dump can be traced to the server going away. The dump is never interrupted.
+async fn check_liveness_during<F>(
+    db: &dyn DatabaseConnection,
+    dump: F,
+    interval: Duration,
+    label: &str,
+    warnings: &mut Vec<String>,
+) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    let pings = async {
+        let mut reachable = true;
+        loop {
+            tokio::time::sleep(interval).await;
+            let problem = match db.test_connection().await {
+                Ok(ConnectionStatus::Connected) => None,
+                Ok(ConnectionStatus::Disconnected) => Some("disconnected".to_string()),
+                Ok(ConnectionStatus::Error(e)) => Some(e),
+                Err(e) => Some(e.to_string()),
+            };
+            match problem {
+                Some(problem) if reachable => {
+                    record_warning(warnings, format!("Server became unreachable while dumping {}: {}", label, problem.trim()));
+                    reachable = false;
+                }
+                Some(_) => {}
+                None if !reachable => {
+                    info!("Server is reachable again while dumping {}", label);
+                    reachable = true;
+                }
+                None => {}
+            }
+        }
+    };
+    tokio::select! {
+        result = dump => result,
+        never = pings => never,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DumpMode;
+
+    #[test]
+    fn missing_database_policy() {
+        let missing = vec!["crm".to_string()];
+        let mut db_config = DatabaseConfig {
+            databases: vec!["shop".to_string(), "crm".to_string()],
+            ..Default::default()
+        };
+        let mut warnings = Vec::new();
+        assert!(handle_missing_databases(&mut db_config, "mysql", &missing, &mut warnings).is_err());
+        assert_eq!(db_config.databases.len(), 2);
+
+        db_config.missing_database = MissingDatabase::Skip;
+        handle_missing_databases(&mut db_config, "mysql", &missing, &mut warnings).unwrap();
+        assert_eq!(db_config.databases, ["shop"]);
+        assert!(warnings.is_empty());
+
+        db_config.databases.push("crm".to_string());
+        db_config.missing_database = MissingDatabase::Warn;
+        handle_missing_databases(&mut db_config, "mysql", &missing, &mut warnings).unwrap();
+        assert_eq!(warnings, ["Skipping mysql databases not found on the server: crm"]);
+    }
+
+    /// Engine whose size estimate always fails
+    struct UnsizedEngine;
+
+    #[async_trait::async_trait]
+    impl DatabaseConnection for UnsizedEngine {
+        async fn test_connection(&self) -> Result<ConnectionStatus> {
+            Ok(ConnectionStatus::Connected)
+        }
+        async fn get_database_info(&self) -> Result<Vec<DatabaseInfo>> {
+            Ok(vec![DatabaseInfo { name: "shop".to_string(), size: None, schema_version: None, table_count: None }])
+        }
+        async fn backup(&self, _backup_path: &Path) -> Result<()> {
+            Ok(())
+        }
+        fn database_type(&self) -> &'static str {
+            "unsized"
+        }
+        fn validate_config_static(&self, _config: &DatabaseConfig) -> Result<()> {
+            Ok(())
+        }
+        async fn estimate_backup_size(&self) -> Result<u64> {
+            Err(Error::Database("size query timed out".to_string()))
+        }
+        async fn find_missing_databases(&self) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_size_estimate_does_not_stop_the_backup() {
+        let config: Config = toml::from_str("[databases]\n[storage]\ntype_ = \"local\"\n").unwrap();
+        let filter = BackupFilter::default();
+        let mut performer = BackupPerformer::new(&config, Path::new("/nonexistent"), &filter);
+
+        let db_info = performer.prepare_backup(&UnsizedEngine, &DatabaseConfig::default(), "unsized").await.unwrap();
+        assert_eq!(db_info.len(), 1);
+        assert_eq!(performer.warnings().len(), 1);
+    }
+
+    #[test]
+    fn finds_empty_pending_databases() {
+        let info = |name: &str, size: Option<u64>, table_count: Option<u64>| DatabaseInfo {
+            name: name.to_string(),
+            size,
+            schema_version: None,
+            table_count,
+        };
+        let db_info = [
+            info("shop", Some(8192), Some(0)),
+            info("crm", Some(8192), Some(3)),
+            info("cache", Some(0), None),
+            info("logs", None, None),
+            info("done", Some(0), Some(0)),
+        ];
+        let pending: Vec<String> = ["shop", "crm", "cache", "logs"].map(String::from).to_vec();
+
+        assert_eq!(empty_databases(&db_info, &pending), ["shop", "cache"]);
+    }
+
+    #[test]
+    fn unchanged_collections_point_at_the_backup_holding_them() {
+        let hashes = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs.iter().map(|(collection, hash)| (collection.to_string(), hash.to_string())).collect()
+        };
+        let base = EngineManifest {
+            engine: "mongodb".to_string(),
+            dump_mode: DumpMode::Full,
+            databases: vec!["shop".to_string()],
+            collections: Vec::new(),
+            query: None,
+            excluded_tables: Vec::new(),
+            wrapped_keys: BTreeMap::new(),
+            differential_bases: BTreeMap::new(),
+            separate_routines: false,
+            include_blobs: None,
+            skipped_empty: Vec::new(),
+            row_counts: BTreeMap::new(),
+            checksums: BTreeMap::new(),
+            max_blob_bytes: None,
+            oversized_rows: BTreeMap::new(),
+            entry_compression: None,
+            collection_hashes: BTreeMap::from([("shop".to_string(), hashes(&[("orders", "a1"), ("users", "b2"), ("logs", "c3")]))]),
+            unchanged_collections: BTreeMap::from([("shop".to_string(), hashes(&[("users", "backup-1")]))]),
+        };
+        let current = hashes(&[("orders", "a1"), ("users", "b2"), ("logs", "c4"), ("carts", "d5")]);
+
+        assert_eq!(
+            unchanged_collections(&current, "backup-2", &base, "shop"),
+            hashes(&[("orders", "backup-2"), ("users", "backup-1")])
+        );
+        assert!(unchanged_collections(&current, "backup-2", &base, "crm").is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+use crate::backup::manifest::MANIFEST_FILE;
+use crate::backup::usage::ResourceUsage;
+use crate::config::{Config, ReportConfig};
+use crate::error::{Error, Result};
+use crate::storage::StoredArchive;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde_json::json;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Size and timing of one database's dump
+#[derive(Debug, Clone)]
+pub struct DumpStats {
+    pub engine: String,
+    pub database: String,
+    pub size: u64,
+    pub source_size: Option<u64>, // Size reported by the server before the dump, when known
+    pub duration: Option<Duration>, // None when the dump was taken by an interrupted run and resumed
+}
+
+/// What happened during a backup run, as reported to operators
+#[derive(Debug)]
+pub struct RunReport {
+    pub backup_id: String,
+    pub started_at: DateTime<Utc>,
+    pub duration: Duration,
+    pub attempts: u32, // Runs it took, counting retries of the whole backup
+    pub dumps: Vec<DumpStats>,
+    pub archive: Option<StoredArchive>, // None when the run failed before storing the archive
+    pub manifest: Option<String>, // manifest.json as written into the archive
+    pub error: Option<String>,
+    pub warnings: Vec<String>, // Problems that didn't stop the run, e.g. skipped databases or failed attempts
+    pub usage: Option<ResourceUsage>, // Peak resource use, with `--concurrency-report`
+}
+
+impl RunReport {
+    pub fn new(backup_id: &str) -> Self {
+        RunReport {
+            backup_id: backup_id.to_string(),
+            started_at: Utc::now(),
+            duration: Duration::ZERO,
+            attempts: 1,
+            dumps: Vec::new(),
+            archive: None,
+            manifest: None,
+            error: None,
+            warnings: Vec::new(),
+            usage: None,
+        }
+    }
+
+    fn subject(&self, prefix: &str) -> String {
+        let outcome = if self.error.is_some() { "FAILED".to_string() } else { self.outcome() };
+        format!("{} Backup {} {}", prefix, self.backup_id, outcome).trim_start().to_string()
+    }
+
+    /// "failed", "succeeded" or e.g. "succeeded with 3 warnings"
+    fn outcome(&self) -> String {
+        match (&self.error, self.warnings.len()) {
+            (Some(_), _) => "failed".to_string(),
+            (None, 0) => "succeeded".to_string(),
+            (None, 1) => "succeeded with 1 warning".to_string(),
+            (None, count) => format!("succeeded with {} warnings", count),
+        }
+    }
+
+    /// Human-readable summary of the run
+    fn summary(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "Backup: {}", self.backup_id);
+        let _ = writeln!(text, "Status: {}", self.outcome());
+        let _ = writeln!(text, "Started: {}", self.started_at.to_rfc3339());
+        let _ = writeln!(text, "Duration: {:.1}s", self.duration.as_secs_f64());
+        if self.attempts > 1 {
+            let _ = writeln!(text, "Attempts: {}", self.attempts);
+        }
+        if let Some(error) = &self.error {
+            let _ = writeln!(text, "Error: {}", error);
+        }
+
+        if let Some(archive) = &self.archive {
+            let _ = writeln!(text, "Destination: {} ({} bytes)", archive.location, archive.size);
+            let _ = writeln!(text, "{}: {}", archive.checksum_algorithm.label(), archive.checksum);
+        }
+
+        if !self.warnings.is_empty() {
+            let _ = writeln!(text, "\nWarnings:");
+            for warning in &self.warnings {
+                let _ = writeln!(text, "  {}", warning);
+            }
+        }
+
+        let _ = writeln!(text, "\nDatabases:");
+        if self.dumps.is_empty() {
+            let _ = writeln!(text, "  (none dumped)");
+        }
+        for dump in &self.dumps {
+            let duration = match dump.duration {
+                Some(duration) => format!("{:.1}s", duration.as_secs_f64()),
+                None => "resumed".to_string(),
+            };
+            let _ = writeln!(text, "  {}/{}: {} bytes, {}", dump.engine, dump.database, dump.size, duration);
+        }
+
+        if let Some(usage) = &self.usage {
+            let _ = writeln!(text, "\nResource usage (peak over {} samples):", usage.samples);
+            let _ = writeln!(text, "  Concurrent dumps: {}", usage.peak_concurrent_dumps);
+            if let Some(rss) = usage.peak_rss_bytes {
+                let _ = writeln!(text, "  Memory (RSS): {} bytes", rss);
+            }
+            let _ = writeln!(text, "  Scratch disk: {} bytes", usage.peak_temp_bytes);
+        }
+        text
+    }
+
+    /// The report as a MIME message with the manifest attached, secrets scrubbed throughout
+    fn to_email(&self, report_config: &ReportConfig, config: &Config) -> String {
+        let boundary = format!("kronos-{}", self.backup_id);
+        let mut message = String::new();
+        if let Some(from) = &report_config.email_from {
+            let _ = write!(message, "From: {}\r\n", from);
+        }
+        let _ = write!(message, "To: {}\r\n", report_config.email_to.join(", "));
+        let _ = write!(message, "Subject: {}\r\n", self.subject(report_config.subject_prefix()));
+        let _ = write!(message, "MIME-Version: 1.0\r\n");
+        let _ = write!(message, "Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n", boundary);
+
+        let _ = write!(message, "--{}\r\n", boundary);
+        let _ = write!(message, "Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n");
+        let _ = write!(message, "{}\r\n", config.scrub(&self.summary()));
+
+        if let Some(manifest) = &self.manifest {
+            let _ = write!(message, "--{}\r\n", boundary);
+            let _ = write!(message, "Content-Type: application/json; name=\"{}\"\r\n", MANIFEST_FILE);
+            let _ = write!(message, "Content-Disposition: attachment; filename=\"{}\"\r\n", MANIFEST_FILE);
+            let _ = write!(message, "Content-Transfer-Encoding: 8bit\r\n\r\n");
+            let _ = write!(message, "{}\r\n", config.scrub(manifest));
+        }
+        let _ = write!(message, "--{}--\r\n", boundary);
+        message
+    }
+
+    /// The report as JSON for monitoring, secrets scrubbed. The manifest is left out; it is in the archive.
+    fn to_json(&self, config: &Config) -> serde_json::Value {
+        let dumps: Vec<_> = self.dumps.iter()
+            .map(|dump| json!({
+                "engine": dump.engine,
+                "database": dump.database,
+                "size": dump.size,
+                "source_size": dump.source_size,
+                "duration_secs": dump.duration.map(|duration| duration.as_secs_f64()),
+            }))
+            .collect();
+        json!({
+            "backup_id": self.backup_id,
+            "status": if self.error.is_some() { "failed" } else { "succeeded" },
+            "started_at": self.started_at.to_rfc3339(),
+            "duration_secs": self.duration.as_secs_f64(),
+            "attempts": self.attempts,
+            "error": self.error.as_deref().map(|error| config.scrub(error)),
+            "warnings": self.warnings.iter().map(|warning| config.scrub(warning)).collect::<Vec<_>>(),
+            "archive": self.archive.as_ref().map(|archive| json!({
+                "location": archive.location,
+                "size": archive.size,
+                "checksum": archive.checksum,
+                "checksum_algorithm": archive.checksum_algorithm,
+            })),
+            "dumps": dumps,
+            "usage": self.usage,
+        })
+    }
+
+    /// Write the report as JSON to `path`, replacing the previous one atomically so watchers never
+    /// see a partial file
+    pub fn write_file(&self, path: &Path, config: &Config) -> Result<()> {
+        write_json_file(path, &self.to_json(config))
+    }
+
+    /// Email the report through the configured sendmail command
+    pub fn send(&self, report_config: &ReportConfig, config: &Config) -> Result<()> {
+        let command = report_config.sendmail_command();
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::Backup(format!("Failed to run sendmail command {:?}: {}", command, e)))?;
+        let written = child.stdin.take()
+            .ok_or_else(|| Error::Backup("Failed to open sendmail input".to_string()))
+            .and_then(|mut stdin| {
+                stdin.write_all(self.to_email(report_config, config).as_bytes()).map_err(Error::Io)
+            });
+        let status = child.wait().map_err(Error::Io)?;
+        written?;
+        if !status.success() {
+            return Err(Error::Backup(format!("Sendmail command {:?} failed with {}", command, status)));
+        }
+
+        info!("Emailed backup report to {}", report_config.email_to.join(", "));
+        Ok(())
+    }
+}
+
+/// Write the reports of a `[[hosts]]` run to `path` as one JSON document, each host's report
+/// tagged with its name
+pub fn write_fleet_file(path: &Path, reports: &[(String, RunReport)], config: &Config) -> Result<()> {
+    let hosts: Vec<_> = reports.iter()
+        .map(|(host, report)| {
+            let mut report = report.to_json(config);
+            report["host"] = json!(host);
+            report
+        })
+        .collect();
+    let failed = reports.iter().filter(|(_, report)| report.error.is_some()).count();
+    write_json_file(path, &json!({
+        "status": if failed == 0 { "succeeded" } else { "failed" },
+        "failed_hosts": failed,
+        "hosts": hosts,
+    }))
+}
+
+fn write_json_file(path: &Path, report: &serde_json::Value) -> Result<()> {
+    let contents = serde_json::to_vec_pretty(report)
+        .map_err(|e| Error::Backup(format!("Failed to serialize backup report: {}", e)))?;
+    let file_name = path.file_name()
+        .ok_or_else(|| Error::Config(format!("Report file {:?} is not a file path", path)))?;
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    let mut file = File::create(&tmp_path)
+        .map_err(|e| Error::Backup(format!("Failed to write report file {:?}: {}", tmp_path, e)))?;
+    file.write_all(&contents).map_err(Error::Io)?;
+    file.sync_all().map_err(Error::Io)?;
+    fs::rename(&tmp_path, path)
+        .map_err(|e| Error::Backup(format!("Failed to replace report file {:?}: {}", path, e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+
+    #[test]
+    fn email_attaches_manifest_without_secrets() {
+        let mut config: Config = toml::from_str("[databases]\n[storage]\ntype_ = \"local\"\n").unwrap();
+        config.databases.mysql = Some(DatabaseConfig { password: "hunter2".to_string(), ..Default::default() });
+        let report_config = ReportConfig {
+            email_to: vec!["ops@example.com".to_string()],
+            subject_prefix: Some("[prod]".to_string()),
+            ..Default::default()
+        };
+        let mut report = RunReport::new("backup-test");
+        report.manifest = Some("{\"tags\": {\"note\": \"hunter2\"}}".to_string());
+        report.error = Some("mysqldump -phunter2 exited with 2".to_string());
+
+        let email = report.to_email(&report_config, &config);
+
+        assert!(email.contains("Subject: [prod] Backup backup-test FAILED\r\n"));
+        assert!(email.contains("filename=\"manifest.json\""));
+        assert!(!email.contains("hunter2"));
+
+        let json = report.to_json(&config);
+        assert_eq!(json["status"], "failed");
+        assert!(!json.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn warnings_mark_a_degraded_success() {
+        let config: Config = toml::from_str("[databases]\n[storage]\ntype_ = \"local\"\n").unwrap();
+        let mut report = RunReport::new("backup-test");
+        report.warnings = vec!["Skipping mysql databases not found on the server: crm".to_string(); 3];
+
+        assert_eq!(report.subject("[kronos]"), "[kronos] Backup backup-test succeeded with 3 warnings");
+        assert!(report.summary().contains("\nWarnings:\n  Skipping mysql databases"));
+        let json = report.to_json(&config);
+        assert_eq!(json["status"], "succeeded");
+        assert_eq!(json["warnings"].as_array().unwrap().len(), 3);
+    }
+}
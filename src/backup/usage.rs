@@ -0,0 +1,135 @@
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often the sampler reads memory and scratch disk use
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Dumps running right now, across every host of the process
+static ACTIVE_DUMPS: AtomicUsize = AtomicUsize::new(0);
+/// Peak-dump counters of the running samplers, raised whenever a dump starts
+static DUMP_WATCHERS: Mutex<Vec<Arc<AtomicUsize>>> = Mutex::new(Vec::new());
+
+/// Peak resource use over a backup run, for tuning the concurrency limits (`--concurrency-report`)
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResourceUsage {
+    pub peak_concurrent_dumps: usize,
+    pub peak_rss_bytes: Option<u64>, // None where the process's memory can't be read (only Linux is supported)
+    pub peak_temp_bytes: u64,        // Staging and archive temp directories, including other runs sharing them
+    pub samples: u64,
+}
+
+/// Marks a dump as running until dropped
+pub struct RunningDump(());
+
+impl Drop for RunningDump {
+    fn drop(&mut self) {
+        ACTIVE_DUMPS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Count a dump as running; counted exactly rather than sampled, so short dumps aren't missed
+pub fn dump_started() -> RunningDump {
+    let active = ACTIVE_DUMPS.fetch_add(1, Ordering::SeqCst) + 1;
+    for peak in DUMP_WATCHERS.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        peak.fetch_max(active, Ordering::SeqCst);
+    }
+    RunningDump(())
+}
+
+/// Samples resource use on its own thread, so it never competes with the backup for the runtime
+pub struct ResourceSampler {
+    peak_dumps: Arc<AtomicUsize>,
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<ResourceUsage>,
+}
+
+impl ResourceSampler {
+    /// Start sampling; `temp_dirs` are measured for the scratch disk high-water mark
+    pub fn start(temp_dirs: Vec<PathBuf>) -> Self {
+        let peak_dumps = Arc::new(AtomicUsize::new(ACTIVE_DUMPS.load(Ordering::SeqCst)));
+        DUMP_WATCHERS.lock().unwrap_or_else(|e| e.into_inner()).push(peak_dumps.clone());
+
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut usage = ResourceUsage::default();
+            loop {
+                usage.samples += 1;
+                if let Some(rss) = resident_bytes() {
+                    usage.peak_rss_bytes = Some(usage.peak_rss_bytes.unwrap_or(0).max(rss));
+                }
+                let temp: u64 = temp_dirs.iter().map(|dir| tree_size(dir)).sum();
+                usage.peak_temp_bytes = usage.peak_temp_bytes.max(temp);
+                match stopped.recv_timeout(SAMPLE_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    _ => break usage,
+                }
+            }
+        });
+        ResourceSampler { peak_dumps, stop, thread }
+    }
+
+    /// Stop sampling and return the peaks seen
+    pub fn finish(self) -> ResourceUsage {
+        DUMP_WATCHERS.lock().unwrap_or_else(|e| e.into_inner())
+            .retain(|peak| !Arc::ptr_eq(peak, &self.peak_dumps));
+        let _ = self.stop.send(());
+        let mut usage = self.thread.join().unwrap_or_default();
+        usage.peak_concurrent_dumps = self.peak_dumps.load(Ordering::SeqCst);
+        usage
+    }
+}
+
+/// Resident memory of this process, from /proc
+fn resident_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kib: u64 = status.lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// Total size of the files under `dir`; files vanishing mid-walk are skipped
+fn tree_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries.flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => tree_size(&entry.path()),
+            Ok(_) => entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_peak_dumps_and_scratch_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let sampler = ResourceSampler::start(vec![dir.path().to_path_buf()]);
+        fs::create_dir(dir.path().join("dumps")).unwrap();
+        fs::write(dir.path().join("dumps/app.sql"), vec![0; 4096]).unwrap();
+        let first = dump_started();
+        let second = dump_started();
+        drop((first, second));
+        thread::sleep(SAMPLE_INTERVAL * 2);
+
+        let usage = sampler.finish();
+        assert!(usage.peak_concurrent_dumps >= 2);
+        assert!(usage.peak_temp_bytes >= 4096);
+        assert!(usage.samples >= 2);
+    }
+}
@@ -1,25 +1,598 @@
-use crate::backup::performer::BackupPerformer;
-use crate::config::Config;
-use crate::error::Result;
-use crate::storage::local::LocalStorage;
-use log::info;
+use crate::backup::checkpoint::{Checkpoint, CHECKPOINT_FILE};
+use crate::backup::manifest::Manifest;
+use crate::backup::performer::{BackupFilter, BackupPerformer};
+use crate::backup::report::{write_fleet_file, RunReport};
+use crate::backup::usage::ResourceSampler;
+use crate::config::{Config, DumpLayout};
+use crate::database::sources::resolve_database_sources;
+use crate::error::{Error, Result};
+use crate::storage::{create_backend, StorageBackend};
+use crate::utils::clock::{Clock, SystemClock};
+use crate::utils::command::{ensure_command_exists, run_hook};
+use crate::utils::compression::{ArchiveOptions, ExternalCompressor};
+use crate::utils::permissions::{create_private_dir, ensure_writable_dir, try_create_private_dir};
+use crate::utils::encryption::load_encryption_key;
+use crate::utils::signing::{load_signing_key, sign_archive};
+use chrono::NaiveDateTime;
+use futures::stream::{self, StreamExt};
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
 
-pub async fn run_backup(config: &Config) -> Result<()> {
+/// Partial archives untouched this long are assumed to be left by a run that died
+const DEFAULT_PARTIAL_GRACE_SECS: u64 = 3600;
+
+/// Caps concurrent archive compression across runs in this process, e.g. overlapping schedules,
+/// with one set of slots per `compression_threads` value so each run gets the cap it configures
+static COMPRESSION_PERMITS: Mutex<BTreeMap<usize, Arc<Semaphore>>> = Mutex::new(BTreeMap::new());
+
+/// Per-invocation options for a backup run, supplied on the command line
+#[derive(Debug, Default, Clone)]
+pub struct BackupOptions {
+    pub tags: BTreeMap<String, String>,
+    pub wait_for_db: Option<Duration>, // Readiness gate before the first connection test
+    pub filter: BackupFilter,           // Subset of engines/databases to back up
+    pub progress: bool,                 // Show a progress bar while compressing
+    pub layout: Option<DumpLayout>,     // Overrides the configured dump_layout
+    pub timeout_per_db: Option<Duration>, // Overrides every engine's timeout_secs
+    pub resume: Option<String>,         // Continue this interrupted backup from its checkpoint
+    pub report_file: Option<PathBuf>,   // Write the run report here as JSON, whether or not the run succeeded
+    pub host: Option<String>,           // `[[hosts]]` entry being backed up, set for each host of a fleet run
+    pub schedule: Option<String>,       // Schedule whose `engines`/`databases` filter this run backs up
+    pub concurrency_report: bool,       // Sample resource use during the run into the report
+}
+
+/// Back up the configured databases, or every `[[hosts]]` entry, pinging the heartbeat URLs
+/// when the run starts and when it succeeds
+pub async fn run_backup(config: &Config, options: &BackupOptions) -> Result<()> {
+    if let Some(backup_id) = &options.resume {
+        check_resume_id(backup_id)?;
+    }
+    if let Some(url) = &config.heartbeat_start_url {
+        ping_heartbeat("heartbeat_start_url", url).await;
+    }
+    let result = if config.hosts.is_empty() {
+        run_single_backup(config, options).await
+    } else {
+        run_fleet_backup(config, options).await
+    };
+    if let (Ok(()), Some(url)) = (&result, &config.heartbeat_url) {
+        ping_heartbeat("heartbeat_url", url).await;
+    }
+    result
+}
+
+/// A `--resume` id names a directory under the staging root, so it must be a backup id
+/// (`backup-…`) and nothing that could point elsewhere
+fn check_resume_id(backup_id: &str) -> Result<()> {
+    let plain = backup_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !backup_id.starts_with("backup-") || !plain || backup_id.contains("..") {
+        return Err(Error::Config(format!(
+            "{:?} is not a backup id; --resume takes the id of an interrupted backup, e.g. backup-20250101T020000",
+            backup_id
+        )));
+    }
+    Ok(())
+}
+
+async fn run_single_backup(config: &Config, options: &BackupOptions) -> Result<()> {
     info!("Starting backup process");
 
-    // Generate a unique backup ID using timestamp
-    let backup_id = chrono::Utc::now().format("backup-%Y%m%dT%H%M%S").to_string();
-    let temp_dir = tempfile::tempdir().map_err(|e| crate::error::Error::Io(e))?;
-    let backup_path = temp_dir.path();
+    let (report, result) = attempt_backup(config, options, &SystemClock).await;
+    send_report(config, &report);
+    if let Some(path) = &options.report_file {
+        if let Err(e) = report.write_file(path, config) {
+            warn!("Failed to write report file {:?}: {}", path, e);
+        }
+    }
+    result?;
 
-    // Perform backup
-    let mut performer = BackupPerformer::new(config, backup_path);
-    performer.execute().await?;
+    info!("Backup completed successfully: {}", report.backup_id);
+    Ok(())
+}
 
-    // Compress and store
-    let local_storage = LocalStorage::new(config.storage.path.as_ref().unwrap_or(&String::from("/backups")));
-    local_storage.store(backup_path, &backup_id).await?;
+/// Back up every `[[hosts]]` entry, up to `host_concurrency` at once, each into its own archive.
+/// One host failing doesn't stop the others; the run fails if any host did.
+async fn run_fleet_backup(config: &Config, options: &BackupOptions) -> Result<()> {
+    // A resumed backup id ends with the name of the host it was taken from
+    let hosts: Vec<_> = match &options.resume {
+        Some(backup_id) => {
+            let host = config.hosts.iter()
+                .find(|host| {
+                    backup_id.strip_suffix(host.name.as_str())
+                        .and_then(|stamp| stamp.strip_suffix('-'))
+                        .is_some_and(|stamp| NaiveDateTime::parse_from_str(stamp, "backup-%Y%m%dT%H%M%S").is_ok())
+                })
+                .ok_or_else(|| Error::Config(format!("Backup {} doesn't belong to any configured host", backup_id)))?;
+            vec![host]
+        }
+        None => config.hosts.iter()
+            .filter(|host| !options.filter.select(&config.for_host(host)).is_empty())
+            .collect(),
+    };
+    if hosts.is_empty() {
+        return Err(Error::Config("No configured host has databases matching the backup filter".to_string()));
+    }
+    let concurrency = config.host_concurrency.unwrap_or(4);
+    info!("Starting backup of {} hosts, {} at a time", hosts.len(), concurrency);
+
+    // The pre and post backup commands run once for the whole fleet rather than per host
+    let host_names = hosts.iter().map(|host| host.name.as_str()).collect::<Vec<_>>().join(" ");
+    let hook_env = [("KRONOS_BACKUP_HOSTS", host_names.as_str())];
+    if let Err(e) = run_pre_backup_hook(config, &hook_env).await {
+        run_post_backup_hook(config, &hook_env, false).await;
+        return Err(e);
+    }
+    let reports: Vec<(String, RunReport)> = stream::iter(hosts)
+        .map(|host| async move {
+            let host_config = Config {
+                pre_backup_command: None,
+                post_backup_command: None,
+                ..config.for_host(host)
+            };
+            let host_options = BackupOptions { host: Some(host.name.clone()), ..options.clone() };
+            let (report, result) = attempt_backup(&host_config, &host_options, &SystemClock).await;
+            match result {
+                Ok(()) => info!("Backup of host {} completed: {}", host.name, report.backup_id),
+                Err(e) => error!("Backup of host {} failed: {}", host.name, e),
+            }
+            send_report(config, &report);
+            (host.name.clone(), report)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    let all_succeeded = reports.iter().all(|(_, report)| report.error.is_none());
+    run_post_backup_hook(config, &hook_env, all_succeeded).await;
+    if let Some(path) = &options.report_file {
+        if let Err(e) = write_fleet_file(path, &reports, config) {
+            warn!("Failed to write report file {:?}: {}", path, e);
+        }
+    }
 
-    info!("Backup completed successfully: {}", backup_id);
+    let failed: Vec<_> = reports.iter()
+        .filter(|(_, report)| report.error.is_some())
+        .map(|(host, _)| host.as_str())
+        .collect();
+
+    // Every host's backups share the store, so retention is applied once for the whole fleet
+    if failed.len() < reports.len() {
+        if let Some(retention) = &config.storage.retention {
+            create_backend(&config.storage)?.prune(retention, false, &SystemClock)?;
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(Error::Backup(format!(
+            "{} of {} hosts failed: {}", failed.len(), reports.len(), failed.join(", ")
+        )));
+    }
+    info!("Backed up {} hosts", reports.len());
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Run the backup, retrying it as configured, and report how the last attempt went
+async fn attempt_backup(config: &Config, options: &BackupOptions, clock: &dyn Clock) -> (RunReport, Result<()>) {
+    let started = Instant::now();
+    let started_at = clock.now();
+    let retries = config.run_retries.unwrap_or(0);
+    let mut attempt = 1;
+    let mut failed_attempts = Vec::new();
+    let sampler = options.concurrency_report.then(|| {
+        let temp_dirs = std::iter::once(staging_root(config))
+            .chain(config.storage.archive_temp_dir.iter().map(PathBuf::from))
+            .collect();
+        ResourceSampler::start(temp_dirs)
+    });
+    let (mut report, result) = loop {
+        // Generate a unique backup ID using timestamp and host, unless resuming an earlier one
+        let backup_id = match &options.resume {
+            Some(backup_id) => backup_id.clone(),
+            None => {
+                let run_root = staging_root(config);
+                new_backup_id(clock, options.host.as_deref(), |id| reserve_run_dir(&run_root.join(id)))
+            }
+        };
+
+        let mut report = RunReport::new(&backup_id);
+        let result = take_backup(config, options, &mut report).await;
+        match result {
+            // Configuration problems won't go away by trying again
+            Err(e) if attempt <= retries && !matches!(e, Error::Config(_)) => {
+                let delay = config.run_retry_delay(attempt);
+                warn!(
+                    "Backup {} failed: {}; retrying from scratch in {}s (retry {} of {})",
+                    backup_id, e, delay.as_secs(), attempt, retries
+                );
+                failed_attempts.push(format!("Attempt {} ({}) failed: {}", attempt, backup_id, e));
+                // A resumed backup keeps its checkpoint; otherwise each attempt starts from scratch
+                if options.resume.is_none() {
+                    let run_dir = staging_root(config).join(&backup_id);
+                    if run_dir.exists() {
+                        remove_run_dir(&run_dir);
+                    }
+                }
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => break (report, result),
+        }
+    };
+    // Once the archive is stored, a failure afterwards is reported rather than retried: another
+    // attempt would only store a second copy of the same backup
+    let result = match result {
+        Ok(()) => sign_and_prune(config, options, &report).await,
+        Err(e) => Err(e),
+    };
+    report.started_at = started_at;
+    report.duration = started.elapsed();
+    report.attempts = attempt;
+    report.warnings.splice(0..0, failed_attempts);
+    report.error = result.as_ref().err().map(|e| e.to_string());
+    report.usage = sampler.map(ResourceSampler::finish);
+    if let Some(usage) = &report.usage {
+        info!(
+            "Peak resource use of {}: {} concurrent dumps, {} of memory, {} bytes of scratch disk",
+            report.backup_id,
+            usage.peak_concurrent_dumps,
+            usage.peak_rss_bytes.map_or("unknown".to_string(), |rss| format!("{} bytes", rss)),
+            usage.peak_temp_bytes
+        );
+    }
+    (report, result)
+}
+
+fn send_report(config: &Config, report: &RunReport) {
+    if let Some(report_config) = &config.report {
+        if let Err(e) = report.send(report_config, config) {
+            warn!("Failed to send backup report: {}", e);
+        }
+    }
+}
+
+/// Fetch a heartbeat URL with curl. The URL usually embeds the monitor's token, so it is given
+/// to curl as a config file on stdin rather than on its command line, where `ps` would show it.
+/// The monitor notices missing pings by itself, so a failed ping is only logged.
+async fn ping_heartbeat(name: &str, url: &str) {
+    info!("Pinging {}", name);
+    let ping = async {
+        let mut child = tokio::process::Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--max-time", "10", "--retry", "2", "--config", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(curl_url_config(url).as_bytes()).await?;
+        }
+        child.wait_with_output().await
+    };
+    match ping.await {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => warn!(
+            "Failed to ping {} ({}): {}",
+            name,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => warn!("Failed to run curl to ping {}: {}", name, e),
+    }
+}
+
+/// curl config file setting `url`, quoted as curl's config syntax requires
+fn curl_url_config(url: &str) -> String {
+    format!("url = \"{}\"\n", url.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Run `dumps` between the configured pre and post backup commands. The post command runs
+/// whatever happened, including a failed pre command, so a quiesced application is always resumed.
+async fn with_backup_hooks<T, F: Future<Output = Result<T>>>(config: &Config, backup_id: &str, dumps: F) -> Result<T> {
+    let env = [("KRONOS_BACKUP_ID", backup_id)];
+    let result = match run_pre_backup_hook(config, &env).await {
+        Ok(()) => dumps.await,
+        Err(e) => Err(e),
+    };
+    run_post_backup_hook(config, &env, result.is_ok()).await;
+    result
+}
+
+async fn run_pre_backup_hook(config: &Config, env: &[(&str, &str)]) -> Result<()> {
+    match &config.pre_backup_command {
+        Some(command) => run_hook("pre_backup_command", command, env).await,
+        None => Ok(()),
+    }
+}
+
+/// Run the post backup command with `env` and the outcome; a failure is only logged
+async fn run_post_backup_hook(config: &Config, env: &[(&str, &str)], succeeded: bool) {
+    if let Some(command) = &config.post_backup_command {
+        let status = if succeeded { "success" } else { "failure" };
+        let mut env = env.to_vec();
+        env.push(("KRONOS_BACKUP_STATUS", status));
+        if let Err(e) = run_hook("post_backup_command", command, &env).await {
+            error!("{}; the application may still be quiesced", e);
+        }
+    }
+}
+
+/// Take and store the backup described by `report`, recording progress in `report`
+async fn take_backup(config: &Config, options: &BackupOptions, report: &mut RunReport) -> Result<()> {
+    let backup_id = report.backup_id.clone();
+    let storage = create_backend(&config.storage)?;
+    let grace = Duration::from_secs(config.storage.partial_grace_secs.unwrap_or(DEFAULT_PARTIAL_GRACE_SECS));
+    if let Err(e) = storage.remove_stale_partials(grace) {
+        let warning = format!("Failed to clean up partial archives left by interrupted runs: {}", e);
+        warn!("{}", warning);
+        report.warnings.push(warning);
+    }
+    if options.resume.is_some() && storage.list()?.iter().any(|backup| backup.backup_id == backup_id) {
+        return Err(Error::Backup(format!("Backup {} is already stored; nothing to resume", backup_id)));
+    }
+
+    // Check scratch space before spending time on dumps
+    let staging_root = staging_root(config);
+    ensure_writable_dir(&staging_root)?;
+    if let Some(dir) = &config.storage.archive_temp_dir {
+        ensure_writable_dir(Path::new(dir))?;
+    }
+    for command in [&config.storage.compressor_command, &config.storage.decompressor_command].into_iter().flatten() {
+        ensure_command_exists(command)?;
+    }
+    // The key is used once the archive is stored; an unusable one should fail the run before the dumps
+    if let Some(path) = &config.storage.signing_key_file {
+        load_signing_key(Path::new(path))?;
+    }
+
+    // Dumps and the checkpoint live under a directory named after the backup so a rerun can find them
+    let run_dir = staging_root.join(&backup_id);
+    let checkpoint_path = run_dir.join(CHECKPOINT_FILE);
+    let layout = options.layout.unwrap_or(config.dump_layout);
+    let checkpoint = if options.resume.is_some() {
+        let checkpoint = Checkpoint::load(&checkpoint_path, &backup_id, layout)?;
+        info!("Resuming backup {}: {} databases already dumped", backup_id, checkpoint.completed.len());
+        checkpoint
+    } else {
+        // Normally reserved already by new_backup_id
+        if !run_dir.is_dir() {
+            create_private_dir(&run_dir)?;
+        }
+        Checkpoint::new(&backup_id, layout)
+    };
+    let backup_path = run_dir.join("dumps");
+    fs::create_dir_all(&backup_path).map_err(Error::Io)?;
+
+    let result = perform_and_store(
+        config, options, report, &backup_path, &*storage, checkpoint, checkpoint_path.clone(),
+    ).await;
+    match &result {
+        Ok(()) => remove_run_dir(&run_dir),
+        Err(_) if checkpoint_path.exists() => warn!(
+            "Completed dumps kept in {:?}; rerun with `kronos backup --resume {}` to continue",
+            run_dir, backup_id
+        ),
+        Err(_) => remove_run_dir(&run_dir),
+    }
+    result
+}
+
+/// Sign the backup stored by `take_backup`, then apply retention now that it is safely stored. A
+/// fleet run prunes once after every host is done.
+async fn sign_and_prune(config: &Config, options: &BackupOptions, report: &RunReport) -> Result<()> {
+    let storage = create_backend(&config.storage)?;
+    if let (Some(path), Some(archive)) = (&config.storage.signing_key_file, &report.archive) {
+        let key = load_signing_key(Path::new(path))?;
+        storage.store_signature(&report.backup_id, &sign_archive(&key, &report.backup_id, &archive.checksum))?;
+        info!("Signed backup {}", report.backup_id);
+    }
+    if let (Some(retention), None) = (&config.storage.retention, &options.host) {
+        storage.prune(retention, false, &SystemClock)?;
+    }
+    Ok(())
+}
+
+/// Id of a new backup: the current second, followed by the `[[hosts]]` entry's name in fleet runs.
+/// When `reserve` can't claim that id (another run in the same second has), the next second is
+/// tried, so ids keep their timestamp form and one run's archive never replaces another's.
+fn new_backup_id(clock: &dyn Clock, host: Option<&str>, reserve: impl Fn(&str) -> bool) -> String {
+    let mut time = clock.now();
+    loop {
+        let stamp = time.format("backup-%Y%m%dT%H%M%S");
+        let backup_id = match host {
+            Some(host) => format!("{}-{}", stamp, host),
+            None => stamp.to_string(),
+        };
+        if reserve(&backup_id) {
+            return backup_id;
+        }
+        time += chrono::Duration::seconds(1);
+    }
+}
+
+/// Claim a backup id by creating its run directory, which fails for every run but one. False
+/// only when the directory exists already; any other failure is left for `take_backup` to report
+/// when it prepares the staging directory.
+fn reserve_run_dir(run_dir: &Path) -> bool {
+    if let Some(root) = run_dir.parent() {
+        let _ = fs::create_dir_all(root);
+    }
+    match try_create_private_dir(run_dir) {
+        Err(e) => e.kind() != std::io::ErrorKind::AlreadyExists,
+        Ok(()) => true,
+    }
+}
+
+/// Dump every database that isn't checkpointed yet, then write the manifest and store the archive
+async fn perform_and_store(
+    config: &Config,
+    options: &BackupOptions,
+    report: &mut RunReport,
+    backup_path: &Path,
+    storage: &dyn StorageBackend,
+    checkpoint: Checkpoint,
+    checkpoint_path: PathBuf,
+) -> Result<()> {
+    let encryption_key = config.storage.encryption_key_file.as_deref()
+        .map(|path| load_encryption_key(Path::new(path)))
+        .transpose()?;
+
+    // The pre and post backup commands only bracket the dumps; the archive is compressed and
+    // stored once the application is running again
+    let backup_id = report.backup_id.clone();
+    let (manifest, entry_compression) = with_backup_hooks(config, &backup_id, async {
+        // Databases listed by a file or query are resolved once, after the pre backup command
+        let config = &resolve_database_sources(config).await?;
+
+        // Perform backup
+        let layout = options.layout.unwrap_or(config.dump_layout);
+        let mut performer = BackupPerformer::new(config, backup_path, &options.filter)
+            .with_layout(layout)
+            .with_host(options.host.clone())
+            .with_timeout(options.timeout_per_db)
+            .with_checkpoint(checkpoint, checkpoint_path);
+        if let Some(timeout) = options.wait_for_db {
+            performer.wait_for_databases(timeout).await?;
+        }
+        let executed = performer.execute().await;
+        report.dumps = performer.dumps().to_vec();
+        report.warnings.extend_from_slice(performer.warnings());
+        executed?;
+
+        // Record what the archive contains
+        let mut manifest = Manifest::new(&backup_id, options.tags.clone(), layout, performer.engines().to_vec());
+        manifest.host = options.host.clone();
+        manifest.schedule = options.schedule.clone();
+        manifest.dumps = performer.dump_records();
+        manifest.checksum_algorithm = config.storage.checksum_algorithm;
+        report.manifest = Some(manifest.write(backup_path)?);
+        Ok((manifest, performer.entry_compression().clone()))
+    }).await?;
+
+    // Compress and store
+    let backup_id = backup_id.as_str();
+    let archive_options = ArchiveOptions {
+        format: config.storage.archive_format,
+        entry_compression,
+        show_progress: options.progress,
+        root: config.storage.archive_root.as_ref().map(|root| root.replace("{backup_id}", backup_id)),
+        external: config.storage.compressor_command().map(|command| ExternalCompressor {
+            command,
+            extension: config.storage.compressor_extension.clone().unwrap_or_default(),
+        }),
+        encryption: encryption_key,
+        comment: archive_comment(config, &manifest),
+        checksum: config.storage.checksum_algorithm,
+    };
+    let _permit = match config.storage.compression_threads {
+        Some(threads) => {
+            let permits = compression_permits(threads);
+            if permits.available_permits() == 0 {
+                info!("Waiting for one of {} compression slots", threads);
+            }
+            Some(permits.acquire_owned().await
+                .map_err(|e| Error::Backup(format!("Failed to acquire a compression slot: {}", e)))?)
+        }
+        None => None,
+    };
+    report.archive = Some(storage.store(backup_path, backup_id, &archive_options).await?);
+    Ok(())
+}
+
+/// Compression slots shared by the runs configured with `threads` of them
+fn compression_permits(threads: usize) -> Arc<Semaphore> {
+    let mut permits = COMPRESSION_PERMITS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    permits.entry(threads).or_insert_with(|| Arc::new(Semaphore::new(threads))).clone()
+}
+
+/// Archive comment for `archive_comment` (default "kronos <version> backup <id> created <time>"); None when set to ""
+pub fn archive_comment(config: &Config, manifest: &Manifest) -> Option<String> {
+    let template = config.storage.archive_comment.as_deref()
+        .unwrap_or("kronos {version} backup {backup_id} created {created_at}");
+    let comment = template
+        .replace("{backup_id}", &manifest.backup_id)
+        .replace("{created_at}", &manifest.created_at)
+        .replace("{version}", &manifest.kronos_version);
+    (!comment.is_empty()).then_some(comment)
+}
+
+/// Where each run's staging directory (dumps and checkpoint, named after the backup) is created
+pub fn staging_root(config: &Config) -> PathBuf {
+    config.storage.staging_dir.as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("kronos"))
+}
+
+fn remove_run_dir(run_dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(run_dir) {
+        warn!("Failed to remove staging directory {:?}: {}", run_dir, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::MockClock;
+
+    #[test]
+    fn new_backup_id_moves_past_taken_ids() {
+        let clock = MockClock::at("2024-01-10T02:00:00Z");
+        assert_eq!(new_backup_id(&clock, None, |_| true), "backup-20240110T020000");
+        assert_eq!(new_backup_id(&clock, Some("db1"), |_| true), "backup-20240110T020000-db1");
+
+        let taken = ["backup-20240110T020000", "backup-20240110T020001"];
+        assert_eq!(new_backup_id(&clock, None, |id| !taken.contains(&id)), "backup-20240110T020002");
+    }
+
+    #[test]
+    fn only_one_run_reserves_an_id() {
+        let staging = tempfile::tempdir().unwrap();
+        let run_dir = staging.path().join("staging").join("backup-20240110T020000");
+        assert!(reserve_run_dir(&run_dir));
+        assert!(run_dir.is_dir());
+        assert!(!reserve_run_dir(&run_dir));
+    }
+
+    #[tokio::test]
+    async fn post_backup_command_follows_a_failed_pre_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let status_file = dir.path().join("status");
+        let config: Config = toml::from_str(&format!(
+            "pre_backup_command = \"exit 3\"\npost_backup_command = \"echo $KRONOS_BACKUP_STATUS > {}\"\n\
+             [databases]\n[storage]\ntype_ = \"local\"\n",
+            status_file.display()
+        ))
+        .unwrap();
+
+        let dumped = with_backup_hooks(&config, "backup-20240110T020000", async { Ok(()) }).await;
+        assert!(dumped.is_err());
+        assert_eq!(fs::read_to_string(&status_file).unwrap().trim(), "failure");
+    }
+
+    #[test]
+    fn resumes_only_backup_ids() {
+        assert!(check_resume_id("backup-20240110T020000").is_ok());
+        assert!(check_resume_id("backup-20240110T020000-db1.internal").is_ok());
+        for id in ["../backup-1", "backup-1/../../etc", "backup-..", "20240110T020000", "backup-1\\x"] {
+            assert!(matches!(check_resume_id(id), Err(Error::Config(_))), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn quotes_heartbeat_urls_for_curls_config() {
+        assert_eq!(curl_url_config("https://hc-ping.com/abc"), "url = \"https://hc-ping.com/abc\"\n");
+        assert_eq!(curl_url_config("https://x/\"a\\b"), "url = \"https://x/\\\"a\\\\b\"\n");
+    }
+
+    #[test]
+    fn compression_slots_follow_each_runs_setting() {
+        assert_eq!(compression_permits(2).available_permits(), 2);
+        assert_eq!(compression_permits(5).available_permits(), 5);
+        assert!(Arc::ptr_eq(&compression_permits(2), &compression_permits(2)));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::backup::layout::member_path;
+use crate::backup::manifest::{Manifest, MANIFEST_FILE};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::storage::local::LocalStorage;
+use crate::utils::compression::{copy_archive_file, list_archive_files, read_archive_file, ReadOptions};
+use crate::utils::delta::apply_delta;
+use crate::utils::encryption::{load_encryption_key, unwrap_key, DecryptReader, EncryptionKey};
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+
+/// Extensions of single-file dumps written by the engines
+const DUMP_EXTENSIONS: &[&str] = &[".sql", ".dump", ".bak", ".bak.delta", ".structure.json"];
+
+/// Stream one database's dump from a stored archive to stdout
+pub fn run_cat(config: &Config, backup_id: &str, database: Option<&str>) -> Result<()> {
+    if config.storage.type_ != "local" {
+        return Err(Error::Config(format!("`cat` reads local storage only, not {:?}", config.storage.type_)));
+    }
+    let local_storage = LocalStorage::from_config(&config.storage);
+    let archive_path = local_storage.archive_path(backup_id)?;
+
+    let files = list_archive_files(&archive_path, local_storage.read_options())?;
+    let manifest: Option<Manifest> = read_archive_file(&archive_path, MANIFEST_FILE, local_storage.read_options())?
+        .and_then(|contents| serde_json::from_slice(&contents).ok());
+
+    let wrapped_keys: Vec<(&str, &str, &str)> = manifest.iter()
+        .flat_map(|manifest| manifest.engines.iter())
+        .flat_map(|engine| engine.wrapped_keys.iter().map(|(db, key)| (engine.engine.as_str(), db.as_str(), key.as_str())))
+        .collect();
+    let dumps: Vec<(String, String)> = match &manifest {
+        // Work out where each database's dump would be under the recorded layout
+        Some(manifest) => manifest.engines.iter()
+            .flat_map(|engine| engine.databases.iter().flat_map(move |db| {
+                DUMP_EXTENSIONS.iter().map(move |ext| {
+                    let entry = format!("{}{}", db, ext);
+                    (db.clone(), member_path(manifest.layout, &engine.engine, &engine.databases, &entry))
+                })
+            }))
+            .filter(|(_, member)| files.contains(member))
+            .collect(),
+        // Archives without a manifest are flat
+        None => files.into_iter()
+            .filter(|name| name != MANIFEST_FILE && !name.contains('/'))
+            .filter_map(|name| {
+                DUMP_EXTENSIONS.iter()
+                    .find_map(|ext| name.strip_suffix(ext))
+                    .map(|db| (db.to_string(), name.clone()))
+            })
+            .collect(),
+    };
+    let available = || dumps.iter().map(|(db, _)| db.as_str()).collect::<Vec<_>>().join(", ");
+
+    let (db, member) = match database {
+        Some(db) => dumps.iter()
+            .find(|(name, _)| name == db)
+            .cloned()
+            .ok_or_else(|| Error::Storage(format!(
+                "No single-file dump for database {:?} in {} (available: {})",
+                db, backup_id, available()
+            )))?,
+        None => match dumps.as_slice() {
+            [dump] => dump.clone(),
+            _ => return Err(Error::Config(format!(
+                "Backup {} contains several dumps; choose one with --db (available: {})",
+                backup_id, available()
+            ))),
+        },
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let wrapped_key = wrapped_keys.iter().find(|(_, name, _)| *name == db);
+    let differential_base = manifest.iter()
+        .flat_map(|manifest| manifest.engines.iter())
+        .find_map(|engine| engine.differential_bases.get(&db));
+    match (wrapped_key, differential_base) {
+        (_, Some(base_id)) => {
+            copy_rebuilt(&local_storage, &archive_path, &member, base_id, &db, &mut out)?;
+        }
+        (Some((engine, _, wrapped_key)), None) => {
+            let data_key = unwrap_data_key(config, engine, &db, wrapped_key)?;
+            copy_decrypted(&archive_path, &member, &mut out, local_storage.read_options(), &data_key)?;
+        }
+        (None, None) => {
+            copy_archive_file(&archive_path, &member, &mut out, local_storage.read_options())?;
+        }
+    }
+    out.flush().map_err(Error::Io)?;
+
+    Ok(())
+}
+
+/// Data key of a database encrypted with its own key, unwrapped with the master key from
+/// `encryption_key_files`. Every configured engine of the same type is tried, since with
+/// `[[hosts]]` several may list the database.
+pub(crate) fn unwrap_data_key(config: &Config, engine: &str, database: &str, wrapped_key: &str) -> Result<EncryptionKey> {
+    let key_files: Vec<&String> = config.all_configured().into_iter()
+        .filter(|(db_type, _)| *db_type == engine)
+        .filter_map(|(_, db_config)| db_config.encryption_key_files.get(database))
+        .collect();
+    if key_files.is_empty() {
+        return Err(Error::Config(format!(
+            "{} database {} is encrypted with its own key; add its master key to encryption_key_files",
+            engine, database
+        )));
+    }
+    for path in key_files {
+        if let Ok(data_key) = unwrap_key(&load_encryption_key(Path::new(path))?, wrapped_key) {
+            return Ok(data_key);
+        }
+    }
+    Err(Error::Config(format!("No configured master key unwraps the data key of {} database {}", engine, database)))
+}
+
+/// Rebuild a SQLite database stored as a delta (`differential`) from the full copy in the base
+/// backup, which is extracted to a temporary file first since the delta reads it out of order
+pub(crate) fn copy_rebuilt<W: Write>(
+    local_storage: &LocalStorage,
+    archive_path: &Path,
+    member: &str,
+    base_id: &str,
+    database: &str,
+    out: &mut W,
+) -> Result<()> {
+    let options = local_storage.read_options();
+    let base_path = local_storage.archive_path(base_id)
+        .map_err(|e| Error::Storage(format!("{} is a delta against backup {}, which can't be read: {}", member, base_id, e)))?;
+    let base_manifest: Manifest = read_archive_file(&base_path, MANIFEST_FILE, options)?
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .ok_or_else(|| Error::Storage(format!("Base backup {} has no readable manifest", base_id)))?;
+    let base_member = base_manifest.engines.iter()
+        .find(|engine| engine.engine == "sqlite" && engine.databases.iter().any(|db| db == database))
+        .map(|engine| member_path(base_manifest.layout, "sqlite", &engine.databases, &format!("{}.bak", database)))
+        .ok_or_else(|| Error::Storage(format!("Base backup {} has no copy of {}", base_id, database)))?;
+
+    let mut base = tempfile::tempfile().map_err(Error::Io)?;
+    if !copy_archive_file(&base_path, &base_member, &mut base, options)? {
+        return Err(Error::Storage(format!("Base backup {} has no {}", base_id, base_member)));
+    }
+
+    let (reader, mut writer) = io::pipe().map_err(Error::Io)?;
+    thread::scope(|scope| {
+        let extract = scope.spawn(move || copy_archive_file(archive_path, member, &mut writer, options));
+        let rebuilt = apply_delta(base, io::BufReader::new(reader), out)
+            .map_err(|e| Error::Storage(format!("Failed to rebuild {} from backup {}: {}", database, base_id, e)));
+        let extracted = extract.join()
+            .map_err(|_| Error::Storage(format!("Reading {} from the archive panicked", member)))?;
+        rebuilt?;
+        extracted.map(|_| ())
+    })
+}
+
+/// Stream an archive member through decryption, reading the archive on a separate thread
+pub(crate) fn copy_decrypted<W: Write>(archive_path: &Path, member: &str, out: &mut W, options: &ReadOptions, key: &EncryptionKey) -> Result<()> {
+    let (reader, mut writer) = io::pipe().map_err(Error::Io)?;
+    thread::scope(|scope| {
+        let extract = scope.spawn(move || copy_archive_file(archive_path, member, &mut writer, options));
+        let decrypted = DecryptReader::new(reader, key)
+            .and_then(|mut reader| io::copy(&mut reader, out))
+            .map_err(|e| Error::Storage(format!("Failed to decrypt {}: {}", member, e)));
+        let extracted = extract.join()
+            .map_err(|_| Error::Storage(format!("Reading {} from the archive panicked", member)))?;
+        // A missing member leaves nothing to decrypt, which would otherwise read as a bad key
+        if let Ok(false) = extracted {
+            return Err(Error::Storage(format!("{} is not in archive {:?}", member, archive_path)));
+        }
+        // A decryption failure closes the pipe early, so report it before the resulting write error
+        decrypted?;
+        extracted.map(|_| ())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::compression::{compress_directory, ArchiveOptions};
+
+    #[test]
+    fn missing_members_are_not_reported_as_decryption_failures() {
+        let source = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join(MANIFEST_FILE), b"{}").unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let archive_path = output.path().join("backup.tar.gz");
+        compress_directory(source.path(), &archive_path, &ArchiveOptions::default()).unwrap();
+
+        let mut out = Vec::new();
+        let key = EncryptionKey::generate();
+        let error = copy_decrypted(&archive_path, "shop.sql", &mut out, &ReadOptions::default(), &key).unwrap_err();
+        assert!(error.to_string().contains("shop.sql is not in archive"), "{}", error);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::backup::manifest::{Manifest, MANIFEST_FILE};
+use crate::commands::output::{print_rows, OutputFormat};
+use crate::config::Config;
+use crate::database::connection::DatabaseConnectionFactory;
+use crate::error::{Error, Result};
+use crate::storage::local::LocalStorage;
+use crate::utils::compression::read_archive_file;
+use log::info;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// One table as `compare` checked it
+#[derive(Debug, PartialEq, Serialize)]
+struct ComparedTable {
+    engine: String,
+    database: String,
+    table: String,
+    status: &'static str, // "ok", "differs", "missing" (recorded but not on the server) or "extra"
+    recorded: Option<String>,
+    current: Option<String>,
+}
+
+const COMPARE_COLUMNS: &[&str] = &["engine", "database", "table", "status", "recorded", "current"];
+
+/// Check databases restored from a backup against the table checksums recorded in its manifest
+/// by `capture_checksums`. The configured servers are checksummed now, so point the config at the
+/// restore target; any table that differs, is missing or wasn't in the backup fails the command.
+pub async fn run_compare(config: &Config, backup_id: &str, format: OutputFormat) -> Result<()> {
+    if config.storage.type_ != "local" {
+        return Err(Error::Config(format!("`compare` reads local storage only, not {:?}", config.storage.type_)));
+    }
+    let local_storage = LocalStorage::from_config(&config.storage);
+    let archive_path = local_storage.archive_path(backup_id)?;
+    let manifest: Manifest = read_archive_file(&archive_path, MANIFEST_FILE, local_storage.read_options())?
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .ok_or_else(|| Error::Storage(format!("Backup {} has no readable manifest", backup_id)))?;
+    if manifest.engines.iter().all(|engine| engine.checksums.is_empty()) {
+        return Err(Error::Config(format!(
+            "Backup {} recorded no table checksums; set capture_checksums to record them",
+            backup_id
+        )));
+    }
+    // A host's backup is compared with that host's databases
+    let databases = match &manifest.host {
+        Some(name) => &config.hosts.iter()
+            .find(|host| &host.name == name)
+            .ok_or_else(|| Error::Config(format!("Backup {} is of host {:?}, which isn't configured", backup_id, name)))?
+            .databases,
+        None => &config.databases,
+    };
+
+    let mut rows = Vec::new();
+    for engine in manifest.engines.iter().filter(|engine| !engine.checksums.is_empty()) {
+        let (db_type, db_config) = databases.configured().into_iter()
+            .find(|(db_type, _)| *db_type == engine.engine)
+            .ok_or_else(|| Error::Config(format!("Backup {} has {} checksums, but no {} is configured", backup_id, engine.engine, engine.engine)))?;
+        for (database, recorded) in &engine.checksums {
+            info!("Checksumming {} database {}", db_type, database);
+            let mut single = db_config.clone();
+            single.databases = vec![database.clone()];
+            let db = DatabaseConnectionFactory::create_connection(db_type, &single)?;
+            let mut current = db.content_hashes(database).await?;
+            let excluded = db_config.excluded_tables();
+            current.retain(|table, _| {
+                !excluded.contains(table) && (engine.collections.is_empty() || engine.collections.contains(table))
+            });
+            rows.extend(compare_checksums(db_type, database, recorded, &current));
+        }
+    }
+    print_rows(format, COMPARE_COLUMNS, &rows)?;
+
+    let differing = rows.iter().filter(|row| row.status != "ok").count();
+    if differing > 0 {
+        return Err(Error::Restore(format!(
+            "{} of {} tables don't match the checksums recorded in backup {}",
+            differing,
+            rows.len(),
+            backup_id
+        )));
+    }
+    Ok(())
+}
+
+/// Tables of one database with their recorded and current checksums, in table order
+fn compare_checksums(
+    engine: &str,
+    database: &str,
+    recorded: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> Vec<ComparedTable> {
+    let mut tables: Vec<&String> = recorded.keys().chain(current.keys()).collect();
+    tables.sort();
+    tables.dedup();
+    tables.into_iter()
+        .map(|table| {
+            let (recorded, current) = (recorded.get(table), current.get(table));
+            let status = match (recorded, current) {
+                (Some(recorded), Some(current)) if recorded == current => "ok",
+                (Some(_), Some(_)) => "differs",
+                (Some(_), None) => "missing",
+                _ => "extra",
+            };
+            ComparedTable {
+                engine: engine.to_string(),
+                database: database.to_string(),
+                table: table.clone(),
+                status,
+                recorded: recorded.cloned(),
+                current: current.cloned(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_each_table_that_does_not_match() {
+        let checksums = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs.iter().map(|(table, sum)| (table.to_string(), sum.to_string())).collect()
+        };
+        let recorded = checksums(&[("orders", "1"), ("users", "2"), ("carts", "3")]);
+        let current = checksums(&[("orders", "1"), ("users", "9"), ("logs", "4")]);
+
+        let statuses: Vec<(String, &str)> = compare_checksums("mysql", "shop", &recorded, &current).into_iter()
+            .map(|row| (row.table, row.status))
+            .collect();
+        assert_eq!(statuses, [
+            ("carts".to_string(), "missing"),
+            ("logs".to_string(), "extra"),
+            ("orders".to_string(), "ok"),
+            ("users".to_string(), "differs"),
+        ]);
+    }
+}
@@ -0,0 +1,23 @@
+use crate::commands::output::{print_rows, OutputFormat};
+use crate::config::DatabaseConfig;
+use crate::database::connection::DatabaseConnectionFactory;
+use crate::error::Result;
+use serde::Serialize;
+
+/// A supported engine as `engines` shows it
+#[derive(Serialize)]
+struct SupportedEngine {
+    engine: String,
+    features: Vec<&'static str>,
+}
+
+/// Print the database engines this build supports and the optional features of each
+pub fn run_engines(format: OutputFormat) -> Result<()> {
+    let config = DatabaseConfig::default();
+    let mut rows = Vec::new();
+    for db_type in DatabaseConnectionFactory::supported_types() {
+        let db = DatabaseConnectionFactory::create_connection(&db_type, &config)?;
+        rows.push(SupportedEngine { engine: db_type.to_string(), features: db.capabilities().names() });
+    }
+    print_rows(format, &["engine", "features"], &rows)
+}
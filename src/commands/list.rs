@@ -0,0 +1,78 @@
+use crate::commands::output::{print_rows, OutputFormat};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::storage::create_backend;
+use crate::storage::retention::backup_time;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A stored backup as `list` shows it
+#[derive(Serialize)]
+struct ListedBackup {
+    backup_id: String,
+    created_at: Option<String>, // None for archives without a manifest
+    size: u64,                  // Bytes
+    host: Option<String>,
+    tags: BTreeMap<String, String>,
+}
+
+const LIST_COLUMNS: &[&str] = &["backup_id", "created_at", "size", "host", "tags"];
+
+pub fn run_list(
+    config: &Config,
+    tag_filter: &BTreeMap<String, String>,
+    since: Option<DateTime<Utc>>,
+    format: OutputFormat,
+) -> Result<()> {
+    let storage = create_backend(&config.storage)?;
+
+    let mut rows = Vec::new();
+    for backup in storage.list()? {
+        // Backups whose time can't be determined are left out of a --since listing
+        if let Some(since) = since {
+            if backup_time(&backup).is_none_or(|time| time < since) {
+                continue;
+            }
+        }
+        let row = match backup.manifest {
+            Some(manifest) => {
+                if !manifest.matches_tags(tag_filter) {
+                    continue;
+                }
+                ListedBackup {
+                    backup_id: backup.backup_id,
+                    created_at: Some(manifest.created_at),
+                    size: backup.size,
+                    host: manifest.host,
+                    tags: manifest.tags,
+                }
+            }
+            // Archives without a manifest carry no tags, so they never match a tag filter
+            None if !tag_filter.is_empty() => continue,
+            None => ListedBackup {
+                backup_id: backup.backup_id,
+                created_at: None,
+                size: backup.size,
+                host: None,
+                tags: BTreeMap::new(),
+            },
+        };
+        rows.push(row);
+    }
+
+    print_rows(format, LIST_COLUMNS, &rows)
+}
+
+/// Parse a `--since` argument: an RFC 3339 timestamp or a date (midnight UTC)
+pub fn parse_since(arg: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(arg) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(arg, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+        .map_err(|_| Error::Config(format!(
+            "Invalid --since {:?}: expected YYYY-MM-DD or an RFC 3339 timestamp",
+            arg
+        )))
+}
@@ -1 +1,12 @@
-pub mod backup;
\ No newline at end of file
+pub mod backup;
+pub mod cat;
+pub mod compare;
+pub mod engines;
+pub mod list;
+pub mod output;
+pub mod print_config;
+pub mod prune;
+pub mod restore;
+pub mod transform;
+pub mod validate;
+pub mod verify;
\ No newline at end of file
@@ -0,0 +1,119 @@
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Output format of the listing commands
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned columns with a header, for people
+    #[default]
+    Table,
+    /// A JSON array of objects, with numbers and lists kept as such
+    Json,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+/// Print `rows` in `format`. Table and CSV show `columns`, which name fields of the serialized
+/// rows; JSON has every field.
+pub fn print_rows<T: Serialize>(format: OutputFormat, columns: &[&str], rows: &[T]) -> Result<()> {
+    print!("{}", render_rows(format, columns, rows)?);
+    Ok(())
+}
+
+fn render_rows<T: Serialize>(format: OutputFormat, columns: &[&str], rows: &[T]) -> Result<String> {
+    let values = serde_json::to_value(rows)
+        .map_err(|e| Error::Config(format!("Failed to serialize output: {}", e)))?;
+    let rows = values.as_array().cloned().unwrap_or_default();
+    if format == OutputFormat::Json {
+        return serde_json::to_string_pretty(&rows)
+            .map(|json| json + "\n")
+            .map_err(|e| Error::Config(format!("Failed to serialize output: {}", e)));
+    }
+
+    let cells: Vec<Vec<String>> = rows.iter()
+        .map(|row| columns.iter().map(|column| cell(&row[*column])).collect())
+        .collect();
+    let mut output = String::new();
+    match format {
+        OutputFormat::Csv => {
+            for line in std::iter::once(columns.iter().map(|column| column.to_string()).collect()).chain(cells) {
+                let line: Vec<String> = line.iter().map(|value| csv_field(value)).collect();
+                output.push_str(&line.join(","));
+                output.push('\n');
+            }
+        }
+        _ => {
+            let header: Vec<String> = columns.iter().map(|column| column.to_uppercase()).collect();
+            let widths: Vec<usize> = (0..columns.len())
+                .map(|i| cells.iter().map(|row| row[i].chars().count()).chain([header[i].len()]).max().unwrap_or(0))
+                .collect();
+            for line in std::iter::once(header).chain(cells) {
+                let line: Vec<String> = line.iter().zip(&widths)
+                    .map(|(value, width)| format!("{:<width$}", if value.is_empty() { "-" } else { value }, width = width))
+                    .collect();
+                output.push_str(line.join("  ").trim_end());
+                output.push('\n');
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// A field as text: lists joined with commas, maps as key=value pairs, null as empty
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items.iter().map(cell).collect::<Vec<_>>().join(","),
+        Value::Object(map) => map.iter()
+            .map(|(key, value)| format!("{}={}", key, cell(value)))
+            .collect::<Vec<_>>()
+            .join(","),
+        other => other.to_string(),
+    }
+}
+
+/// Quote a CSV field when it holds a separator, quote or line break (RFC 4180)
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize)]
+    struct Row {
+        id: &'static str,
+        size: u64,
+        tags: BTreeMap<&'static str, &'static str>,
+    }
+
+    #[test]
+    fn renders_each_format() {
+        let rows = [
+            Row { id: "backup-a", size: 5, tags: BTreeMap::from([("env", "prod"), ("team", "db")]) },
+            Row { id: "backup-long", size: 1200, tags: BTreeMap::new() },
+        ];
+        let columns = ["id", "size", "tags"];
+
+        assert_eq!(
+            render_rows(OutputFormat::Table, &columns, &rows).unwrap(),
+            "ID           SIZE  TAGS\nbackup-a     5     env=prod,team=db\nbackup-long  1200  -\n"
+        );
+        assert_eq!(
+            render_rows(OutputFormat::Csv, &columns, &rows).unwrap(),
+            "id,size,tags\nbackup-a,5,\"env=prod,team=db\"\nbackup-long,1200,\n"
+        );
+        let json: Value = serde_json::from_str(&render_rows(OutputFormat::Json, &columns, &rows).unwrap()).unwrap();
+        assert_eq!(json[1]["size"], 1200);
+        assert_eq!(json[0]["tags"]["env"], "prod");
+    }
+}
@@ -0,0 +1,23 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+
+/// Output format for the printed config
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+/// Print the effective config, after defaults are applied, with secrets redacted
+pub fn run_print_config(config: &Config, format: ConfigFormat) -> Result<()> {
+    let redacted = config.redacted();
+    let output = match format {
+        ConfigFormat::Toml => toml::to_string_pretty(&redacted)
+            .map_err(|e| Error::Config(format!("Failed to serialize config: {}", e)))?,
+        ConfigFormat::Json => serde_json::to_string_pretty(&redacted)
+            .map_err(|e| Error::Config(format!("Failed to serialize config: {}", e)))?,
+    };
+    println!("{}", output);
+    Ok(())
+}
@@ -0,0 +1,21 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::storage::create_backend;
+use crate::utils::clock::SystemClock;
+use log::info;
+
+pub fn run_prune(config: &Config, dry_run: bool) -> Result<()> {
+    let retention = config.storage.retention.as_ref()
+        .ok_or_else(|| Error::Config("No [storage.retention] policy configured".to_string()))?;
+
+    let storage = create_backend(&config.storage)?;
+    let removed = storage.prune(retention, dry_run, &SystemClock)?;
+
+    let action = if dry_run { "Would remove" } else { "Removed" };
+    for backup_id in &removed {
+        println!("{} {}", action, backup_id);
+    }
+    info!("{} {} backup(s)", action, removed.len());
+
+    Ok(())
+}
@@ -0,0 +1,636 @@
+use crate::backup::layout::member_path;
+use crate::backup::manifest::{EngineManifest, Manifest, MANIFEST_FILE};
+use crate::commands::backup::staging_root;
+use crate::commands::cat::{copy_decrypted, copy_rebuilt, unwrap_data_key};
+use crate::config::{Config, DatabaseConfig, DumpLayout, DumpMode};
+use crate::database::connection::{DatabaseConnection, DatabaseConnectionFactory};
+use crate::error::{Error, Result};
+use crate::storage::local::LocalStorage;
+use crate::utils::compression::{copy_archive_file, extract_archive, list_archive_files, read_archive_file};
+use crate::utils::encryption::EncryptionKey;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// How `restore` treats the databases it loads into
+#[derive(Debug, Default)]
+pub struct RestoreOptions {
+    pub force: bool,               // Overwrite databases that exist already
+    pub yes: bool,                 // Skip the confirmation prompt; required when stdin isn't a terminal
+    pub target_db: Option<String>, // Restore the backup's one database under this name instead
+}
+
+/// One engine of a backup, checked and ready to be restored
+struct EngineRestore<'a> {
+    engine: &'a EngineManifest,
+    db_type: &'static str,
+    config: DatabaseConfig,                     // The engine's config, narrowed to the databases restored
+    data_keys: BTreeMap<String, EncryptionKey>, // Unwrapped keys of the databases encrypted with their own
+}
+
+/// Endings each engine's dump entries have after the database name (empty for a directory).
+/// The first is the dump itself, which every restored database needs; the others are loaded
+/// after it when the backup has them. Engines not listed here have nothing `restore` knows how
+/// to replay.
+fn dump_suffixes(engine: &str) -> &'static [&'static str] {
+    match engine {
+        "sqlite" => &[".bak"],
+        // The directory holds tab_format's per-table files
+        "mysql" => &[".sql", ".schema.sql", ".filtered.sql", ".routines.sql", ""],
+        "postgres" => &[".dump", ".filtered.sql"],
+        "mongodb" => &[""],
+        _ => &[],
+    }
+}
+
+/// Load a stored backup back into the configured servers. Every engine in the backup must be
+/// configured; each replays the dumps of its databases, as recorded in the manifest. Databases
+/// that exist already are only overwritten with `force`, and nothing is restored unless every
+/// engine's databases can be and the restore is confirmed (or `yes` is given). Row counts the
+/// backup recorded are checked against the restored databases afterwards.
+pub async fn run_restore(config: &Config, backup_id: &str, options: &RestoreOptions) -> Result<()> {
+    if config.storage.type_ != "local" {
+        return Err(Error::Config(format!("`restore` reads local storage only, not {:?}", config.storage.type_)));
+    }
+    let local_storage = LocalStorage::from_config(&config.storage);
+    let archive_path = local_storage.archive_path(backup_id)?;
+    let manifest: Manifest = read_archive_file(&archive_path, MANIFEST_FILE, local_storage.read_options())?
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .ok_or_else(|| Error::Storage(format!("Backup {} has no readable manifest", backup_id)))?;
+    let members = list_archive_files(&archive_path, local_storage.read_options())?;
+    // A host's backup goes back to that host's databases
+    let databases = match &manifest.host {
+        Some(name) => &config.hosts.iter()
+            .find(|host| &host.name == name)
+            .ok_or_else(|| Error::Config(format!("Backup {} is of host {:?}, which isn't configured", backup_id, name)))?
+            .databases,
+        None => &config.databases,
+    };
+
+    // Check every engine before anything is overwritten
+    let mut targets: Vec<EngineRestore> = Vec::new();
+    let mut engines = Vec::new();
+    for engine in &manifest.engines {
+        if let Some(reason) = unrestorable(engine) {
+            return Err(Error::Restore(format!("Backup {} can't be restored: {}", backup_id, reason)));
+        }
+        let (db_type, db_config) = databases.configured().into_iter()
+            .find(|(db_type, _)| *db_type == engine.engine)
+            .ok_or_else(|| Error::Config(format!("Backup {} has {} dumps, but no {} is configured", backup_id, engine.engine, engine.engine)))?;
+        if db_config.ssh_target.is_some() {
+            return Err(Error::Config(format!(
+                "{} is dumped over ssh_target; restore with a config that reaches the server directly",
+                db_type
+            )));
+        }
+        let mut single = db_config.clone();
+        single.databases = engine.databases.iter()
+            .filter(|database| !engine.skipped_empty.contains(database))
+            .cloned()
+            .collect();
+        single.dump_mode = engine.dump_mode;
+        if single.databases.is_empty() {
+            continue;
+        }
+        if let Some(reason) = missing_dump(manifest.layout, engine, &single.databases, &members) {
+            return Err(Error::Restore(format!("Backup {} can't be restored: {}", backup_id, reason)));
+        }
+        let mut data_keys = BTreeMap::new();
+        for database in &single.databases {
+            if let Some(wrapped_key) = engine.wrapped_keys.get(database) {
+                data_keys.insert(database.clone(), unwrap_data_key(config, db_type, database, wrapped_key)?);
+            }
+            if let Some(base_id) = engine.differential_bases.get(database) {
+                local_storage.archive_path(base_id).map_err(|e| Error::Restore(format!(
+                    "Backup {} can't be restored: {} is stored as a delta against backup {}: {}",
+                    backup_id, database, base_id, e
+                )))?;
+            }
+            for (collection, holder_id) in engine.unchanged_collections.get(database).into_iter().flatten() {
+                let (_, held) = held_collection(&local_storage, holder_id, database, collection).map_err(|e| Error::Restore(format!(
+                    "Backup {} can't be restored: collection {}.{} is kept in backup {}: {}",
+                    backup_id, database, collection, holder_id, e
+                )))?;
+                if held.is_empty() {
+                    return Err(Error::Restore(format!(
+                        "Backup {} can't be restored: backup {} has no dump of collection {}.{}",
+                        backup_id, holder_id, database, collection
+                    )));
+                }
+            }
+        }
+        engines.push(EngineRestore { engine, db_type, config: single, data_keys });
+    }
+
+    if let Some(target_db) = &options.target_db {
+        let count: usize = engines.iter().map(|restore| restore.config.databases.len()).sum();
+        if count != 1 {
+            return Err(Error::Config(format!(
+                "--target-db renames one database, but backup {} holds {}",
+                backup_id, count
+            )));
+        }
+        check_target_name(engines[0].db_type, target_db)?;
+        engines[0].config.restore_as = Some(target_db.clone());
+    }
+
+    for restore in engines {
+        // A renamed restore overwrites the target, not the database the dump came from
+        let mut checked = restore.config.clone();
+        if let Some(target_db) = &restore.config.restore_as {
+            checked.databases = vec![target_db.clone()];
+        }
+        let missing = DatabaseConnectionFactory::create_connection(restore.db_type, &checked)?
+            .find_missing_databases()
+            .await?;
+        let existing: Vec<&str> = checked.databases.iter()
+            .filter(|database| !missing.contains(database))
+            .map(String::as_str)
+            .collect();
+        if !existing.is_empty() && !options.force {
+            return Err(Error::Restore(format!(
+                "{} databases {} already exist; pass --force to overwrite them",
+                restore.db_type,
+                existing.join(", ")
+            )));
+        }
+        targets.push(restore);
+    }
+
+    if !options.yes {
+        confirm(&restore_summary(backup_id, &targets))?;
+    }
+
+    let staging_root = staging_root(config);
+    fs::create_dir_all(&staging_root).map_err(Error::Io)?;
+    let work_dir = tempfile::Builder::new()
+        .prefix(".restore-")
+        .tempdir_in(&staging_root)
+        .map_err(Error::Io)?;
+    let unpacked = work_dir.path().join("archive");
+    info!("Unpacking backup {} from {:?}", backup_id, archive_path);
+    extract_archive(&archive_path, &unpacked, local_storage.read_options())?;
+
+    for restore in &targets {
+        // Engines read their dumps under the names they wrote them with, whatever the layout
+        let dumps = work_dir.path().join(restore.db_type);
+        fs::create_dir_all(&dumps).map_err(Error::Io)?;
+        let source = ArchiveSource { storage: &local_storage, archive_path: &archive_path, unpacked: &unpacked, members: &members };
+        for database in &restore.config.databases {
+            stage_dumps(&source, manifest.layout, restore, database, &dumps)?;
+        }
+        let single = &restore.config;
+        match &single.restore_as {
+            Some(target_db) => info!("Restoring {} database {} as {}", restore.db_type, single.databases.join(", "), target_db),
+            None => info!("Restoring {} databases {}", restore.db_type, single.databases.join(", ")),
+        }
+        let db = DatabaseConnectionFactory::create_connection(restore.db_type, single)?;
+        db.restore(&dumps).await?;
+        check_row_counts(restore, &*db).await;
+    }
+    info!("Restored backup {}", backup_id);
+    Ok(())
+}
+
+/// Compare the row counts `capture_counts` recorded with those of the restored databases, warning
+/// about tables that came back missing or with a different count. MySQL and PostgreSQL counts are
+/// statistics rather than exact, so a difference is reported for a look rather than failing the
+/// restore.
+async fn check_row_counts(restore: &EngineRestore<'_>, db: &dyn DatabaseConnection) {
+    for database in &restore.config.databases {
+        let Some(recorded) = restore.engine.row_counts.get(database) else {
+            continue;
+        };
+        let target = restore.config.restore_as.as_deref().unwrap_or(database);
+        let current = match db.row_counts(target).await {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("Failed to count the rows of restored {} database {}: {}", restore.db_type, target, e);
+                continue;
+            }
+        };
+        let differences = row_count_differences(recorded, &current);
+        if differences.is_empty() {
+            info!("Row counts of {} tables in {} match the backup", recorded.len(), target);
+        }
+        for difference in differences {
+            warn!("Restored {} database {}: {}", restore.db_type, target, difference);
+        }
+    }
+}
+
+/// Tables whose restored row count doesn't match the one recorded in the backup. Tables that
+/// weren't recorded, such as excluded ones, are not compared.
+fn row_count_differences(recorded: &BTreeMap<String, u64>, current: &BTreeMap<String, u64>) -> Vec<String> {
+    recorded.iter()
+        .filter_map(|(table, &rows)| match current.get(table) {
+            None => Some(format!("table {} is missing; the backup recorded {} rows", table, rows)),
+            Some(&restored) if restored != rows => Some(format!(
+                "table {} has {} rows; the backup recorded {}",
+                table, restored, rows
+            )),
+            Some(_) => None,
+        })
+        .collect()
+}
+
+/// The backup being restored: its archive, unpacked, and the files in it
+struct ArchiveSource<'a> {
+    storage: &'a LocalStorage,
+    archive_path: &'a Path,
+    unpacked: &'a Path,
+    members: &'a [String],
+}
+
+/// Put the dumps of one database in `dumps` under the names its engine wrote them with: moved
+/// out of the unpacked archive, decrypted with the database's own key, or rebuilt from a delta
+/// and the full copy in its base backup. MongoDB collections left out as unchanged are copied
+/// from the backups holding them, next to the collections this backup dumped.
+fn stage_dumps(source: &ArchiveSource, layout: DumpLayout, restore: &EngineRestore, database: &str, dumps: &Path) -> Result<()> {
+    let engine = restore.engine;
+    let path = |entry: &str| member_path(layout, &engine.engine, &engine.databases, entry);
+    if let Some(base_id) = engine.differential_bases.get(database) {
+        let mut rebuilt = create_file(&dumps.join(format!("{}.bak", database)))?;
+        return copy_rebuilt(source.storage, source.archive_path, &path(&format!("{}.bak.delta", database)), base_id, database, &mut rebuilt);
+    }
+    for suffix in dump_suffixes(restore.db_type) {
+        let entry = format!("{}{}", database, suffix);
+        let entry_path = path(&entry);
+        let dir = format!("{}/", entry_path);
+        for member in source.members.iter().filter(|member| **member == entry_path || member.starts_with(&dir)) {
+            let staged = dumps.join(format!("{}{}", entry, &member[entry_path.len()..]));
+            match restore.data_keys.get(database) {
+                Some(key) => {
+                    let mut decrypted = create_file(&staged)?;
+                    copy_decrypted(source.archive_path, member, &mut decrypted, source.storage.read_options(), key)?;
+                }
+                None => {
+                    if let Some(parent) = staged.parent() {
+                        fs::create_dir_all(parent).map_err(Error::Io)?;
+                    }
+                    fs::rename(source.unpacked.join(member), &staged).map_err(Error::Io)?;
+                }
+            }
+        }
+    }
+    for (collection, holder_id) in engine.unchanged_collections.get(database).into_iter().flatten() {
+        let (holder_path, held) = held_collection(source.storage, holder_id, database, collection)?;
+        for (member, file_name) in held {
+            let mut staged = create_file(&dumps.join(database).join(file_name))?;
+            copy_archive_file(&holder_path, &member, &mut staged, source.storage.read_options())?;
+        }
+    }
+    Ok(())
+}
+
+/// Files mongodump writes per collection with --gzip
+const COLLECTION_FILES: [&str; 2] = [".bson.gz", ".metadata.json.gz"];
+
+/// Archive of backup `holder_id` and the dump files of MongoDB collection `database.collection`
+/// in it, as (archive member, file name in the database's dump directory)
+fn held_collection(storage: &LocalStorage, holder_id: &str, database: &str, collection: &str) -> Result<(PathBuf, Vec<(String, String)>)> {
+    let holder_path = storage.archive_path(holder_id)?;
+    let manifest: Manifest = read_archive_file(&holder_path, MANIFEST_FILE, storage.read_options())?
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .ok_or_else(|| Error::Storage(format!("Backup {} has no readable manifest", holder_id)))?;
+    let Some(engine) = manifest.engines.iter().find(|engine| engine.engine == "mongodb") else {
+        return Ok((holder_path, Vec::new()));
+    };
+    let members = list_archive_files(&holder_path, storage.read_options())?;
+    let held = COLLECTION_FILES.iter()
+        .map(|suffix| {
+            let file_name = format!("{}{}", collection, suffix);
+            (member_path(manifest.layout, "mongodb", &engine.databases, &format!("{}/{}", database, file_name)), file_name)
+        })
+        .filter(|(member, _)| members.contains(member))
+        .collect();
+    Ok((holder_path, held))
+}
+
+/// Create a file, and the directories it is in
+fn create_file(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(Error::Io)?;
+    }
+    File::create(path).map_err(Error::Io)
+}
+
+/// Why the archive `members` can't restore an engine's `databases`, if one's dump is missing or
+/// not in a form `restore` loads. Checked for every engine before any is restored.
+fn missing_dump(layout: DumpLayout, engine: &EngineManifest, databases: &[String], members: &[String]) -> Option<String> {
+    let path = |entry: &str| member_path(layout, &engine.engine, &engine.databases, entry);
+    let under = |dir: &str| format!("{}/", dir);
+    for database in databases {
+        let Some(mut suffix) = dump_suffixes(&engine.engine).first().copied() else {
+            return Some(format!("restoring {} backups is not supported", engine.engine));
+        };
+        if engine.differential_bases.contains_key(database) {
+            suffix = ".bak.delta";
+        }
+        let dump = path(&format!("{}{}", database, suffix));
+        let found = match suffix {
+            // Every collection may have been left out as unchanged
+            "" if engine.unchanged_collections.contains_key(database) => true,
+            "" => members.iter().any(|member| member.starts_with(&under(&dump))),
+            _ => members.contains(&dump),
+        };
+        if !found {
+            return Some(format!("it has no {} dump of {} ({:?} is missing)", engine.engine, database, dump));
+        }
+    }
+    None
+}
+
+/// What a restore will replace: the backup and, per engine, the server and the databases loaded into
+fn restore_summary(backup_id: &str, targets: &[EngineRestore]) -> String {
+    let mut summary = format!("Restoring backup {} replaces these databases:\n", backup_id);
+    for EngineRestore { db_type, config: single, .. } in targets {
+        let server = match *db_type {
+            "sqlite" => single.host.clone(),
+            _ => format!("{}:{}", single.host, single.port),
+        };
+        let databases = match &single.restore_as {
+            Some(target_db) => format!("{} (from {})", target_db, single.databases.join(", ")),
+            None => single.databases.join(", "),
+        };
+        summary.push_str(&format!("  {} on {}: {}\n", db_type, server, databases));
+    }
+    summary
+}
+
+/// Ask on the terminal before replacing anything; without one, `--yes` has to say so up front
+fn confirm(summary: &str) -> Result<()> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Err(Error::Restore("Not asking for confirmation without a terminal; pass --yes to restore".to_string()));
+    }
+    eprint!("{}Type \"yes\" to continue: ", summary);
+    io::stderr().flush().map_err(Error::Io)?;
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer).map_err(Error::Io)?;
+    if answer.trim() != "yes" {
+        return Err(Error::Restore("Restore cancelled".to_string()));
+    }
+    Ok(())
+}
+
+/// A `--target-db` name has to be usable as a database (SQLite: file) name as it is
+fn check_target_name(db_type: &str, name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        // MongoDB namespaces are <database>.<collection>
+        && !(db_type == "mongodb" && name.contains('.'));
+    if !valid {
+        return Err(Error::Config(format!(
+            "Invalid --target-db {:?} for {}: use up to 63 characters from [A-Za-z0-9_.-]{}, not starting with a dot",
+            name,
+            db_type,
+            if db_type == "mongodb" { " except dots" } else { "" }
+        )));
+    }
+    Ok(())
+}
+
+/// Why an engine's dumps can't be replayed, if they can't
+fn unrestorable(engine: &EngineManifest) -> Option<String> {
+    if engine.engine == "mongodb" && engine.dump_mode == DumpMode::SchemaOnly {
+        return Some("its MongoDB dumps hold collection structure only".to_string());
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageBackend;
+    use crate::utils::compression::ArchiveOptions;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    const BACKUP_ID: &str = "backup-20260101T020000";
+
+    fn engine_manifest(name: &str, databases: &[&str]) -> EngineManifest {
+        serde_json::from_value(serde_json::json!({ "engine": name, "dump_mode": "full", "databases": databases })).unwrap()
+    }
+
+    /// Store an archive of `files` with a manifest of `engines` as `backup_id` in `storage_dir`
+    async fn store_backup(storage_dir: &Path, backup_id: &str, engines: Vec<EngineManifest>, files: &[(&str, &[u8])]) {
+        let source = tempfile::tempdir().unwrap();
+        Manifest::new(backup_id, BTreeMap::new(), DumpLayout::Flat, engines).write(source.path()).unwrap();
+        for (name, contents) in files {
+            let path = source.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        LocalStorage::new(&storage_dir.to_string_lossy())
+            .store(source.path(), backup_id, &ArchiveOptions::default())
+            .await
+            .unwrap();
+    }
+
+    /// Config restoring into SQLite databases in `sqlite_dir`, plus an unreachable MySQL server
+    fn restore_config(storage_dir: &Path, sqlite_dir: &Path) -> Config {
+        DatabaseConnectionFactory::register_builtins();
+        toml::from_str(&format!(
+            "[databases.sqlite]\nhost = {:?}\nport = 0\nuser = \"\"\n\
+             [databases.mysql]\nhost = \"db.invalid\"\nport = 3306\nuser = \"kronos\"\n\
+             [storage]\ntype_ = \"local\"\npath = {:?}\nstaging_dir = {:?}\n",
+            sqlite_dir, storage_dir, storage_dir.join("staging")
+        ))
+        .unwrap()
+    }
+
+    fn options(force: bool) -> RestoreOptions {
+        RestoreOptions { force, yes: true, target_db: None }
+    }
+
+    #[test]
+    fn refuses_dumps_that_need_more_than_the_archive() {
+        let engine = |name: &str, dump_mode: &str| -> EngineManifest {
+            serde_json::from_value(serde_json::json!({ "engine": name, "dump_mode": dump_mode, "databases": ["app.db"] })).unwrap()
+        };
+        assert_eq!(unrestorable(&engine("sqlite", "full")), None);
+        assert_eq!(unrestorable(&engine("postgres", "schema_only")), None);
+        assert!(unrestorable(&engine("mongodb", "schema_only")).is_some());
+
+        // Deltas are rebuilt from their base backup
+        let mut differential = engine("sqlite", "full");
+        differential.differential_bases.insert("app.db".to_string(), "backup-20260101T020000".to_string());
+        assert_eq!(unrestorable(&differential), None);
+    }
+
+    #[test]
+    fn reports_tables_whose_row_counts_changed() {
+        let counts = |pairs: &[(&str, u64)]| -> BTreeMap<String, u64> {
+            pairs.iter().map(|(table, rows)| (table.to_string(), *rows)).collect()
+        };
+        let recorded = counts(&[("orders", 10), ("users", 3), ("carts", 1)]);
+        let restored = counts(&[("orders", 10), ("users", 2), ("sessions", 0)]);
+
+        let differences = row_count_differences(&recorded, &restored);
+        assert_eq!(differences.len(), 2);
+        assert!(differences[0].contains("carts is missing"));
+        assert!(differences[1].contains("users has 2 rows"));
+        assert!(row_count_differences(&recorded, &recorded).is_empty());
+    }
+
+    #[test]
+    fn checks_target_names() {
+        assert!(check_target_name("postgres", "shop_restored").is_ok());
+        assert!(check_target_name("sqlite", "app.restored.db").is_ok());
+        assert!(check_target_name("mongodb", "app.restored").is_err());
+        assert!(check_target_name("mysql", "shop; DROP DATABASE shop").is_err());
+        assert!(check_target_name("sqlite", "../app.db").is_err());
+        assert!(check_target_name("postgres", "").is_err());
+    }
+
+    #[test]
+    fn finds_dumps_where_the_layout_put_them() {
+        let members = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let databases = vec!["shop".to_string()];
+        let mysql = engine_manifest("mysql", &["shop"]);
+        assert_eq!(missing_dump(DumpLayout::Flat, &mysql, &databases, &members(&["shop.sql", "shop.routines.sql"])), None);
+        assert_eq!(missing_dump(DumpLayout::ByDatabase, &mysql, &databases, &members(&["shop/mysql.sql"])), None);
+        assert!(missing_dump(DumpLayout::ByEngine, &mysql, &databases, &members(&["shop.sql"])).unwrap().contains("mysql/shop.sql"));
+        // Only the optional dumps are there
+        assert!(missing_dump(DumpLayout::Flat, &mysql, &databases, &members(&["shop.filtered.sql"])).is_some());
+        let tab_format = members(&["shop.sql", "shop/orders.sql", "shop/orders.txt"]);
+        assert_eq!(missing_dump(DumpLayout::Flat, &mysql, &databases, &tab_format), None);
+
+        let mut sqlite = engine_manifest("sqlite", &["sub/app.db"]);
+        let nested = vec!["sub/app.db".to_string()];
+        assert_eq!(missing_dump(DumpLayout::ByDatabase, &sqlite, &nested, &members(&["sqlite-sub/app.db.bak"])), None);
+        sqlite.differential_bases.insert("sub/app.db".to_string(), "backup-20251231T020000".to_string());
+        assert!(missing_dump(DumpLayout::ByDatabase, &sqlite, &nested, &members(&["sqlite-sub/app.db.bak"])).is_some());
+        assert_eq!(missing_dump(DumpLayout::ByDatabase, &sqlite, &nested, &members(&["sqlite-sub/app.db.bak.delta"])), None);
+
+        let mongodb = engine_manifest("mongodb", &["shop"]);
+        assert_eq!(missing_dump(DumpLayout::EnginePrefix, &mongodb, &databases, &members(&["mongodb-shop/orders.bson.gz"])), None);
+        assert!(missing_dump(DumpLayout::EnginePrefix, &mongodb, &databases, &members(&["mongodb-shop.bson.gz"])).is_some());
+
+        let postgres = engine_manifest("postgres", &["shop", "crm"]);
+        let both = vec!["shop".to_string(), "crm".to_string()];
+        assert!(missing_dump(DumpLayout::Flat, &postgres, &both, &members(&["shop.dump"])).unwrap().contains("crm"));
+    }
+
+    #[tokio::test]
+    async fn existing_databases_need_force() {
+        let storage_dir = tempfile::tempdir().unwrap();
+        let sqlite_dir = tempfile::tempdir().unwrap();
+        store_backup(storage_dir.path(), BACKUP_ID, vec![engine_manifest("sqlite", &["app.db"])], &[("app.db.bak", b"backed up")]).await;
+        fs::write(sqlite_dir.path().join("app.db"), "live").unwrap();
+        let config = restore_config(storage_dir.path(), sqlite_dir.path());
+
+        let refused = run_restore(&config, BACKUP_ID, &options(false)).await.unwrap_err();
+        assert!(refused.to_string().contains("--force"));
+        assert_eq!(fs::read_to_string(sqlite_dir.path().join("app.db")).unwrap(), "live");
+
+        run_restore(&config, BACKUP_ID, &options(true)).await.unwrap();
+        assert_eq!(fs::read_to_string(sqlite_dir.path().join("app.db")).unwrap(), "backed up");
+
+        // Restored next to the original instead, which the target name mustn't overwrite
+        let renamed = RestoreOptions { target_db: Some("app.restored.db".to_string()), ..options(false) };
+        run_restore(&config, BACKUP_ID, &renamed).await.unwrap();
+        assert_eq!(fs::read_to_string(sqlite_dir.path().join("app.restored.db")).unwrap(), "backed up");
+        assert!(run_restore(&config, BACKUP_ID, &renamed).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn restores_deltas_nested_files_and_databases_with_their_own_key() {
+        use crate::utils::delta::{write_delta, Signature, BLOCK_SIZE};
+        use crate::utils::encryption::{encrypt_file, load_encryption_key, wrap_key};
+        use std::os::unix::fs::PermissionsExt;
+
+        let storage_dir = tempfile::tempdir().unwrap();
+        let sqlite_dir = tempfile::tempdir().unwrap();
+        let scratch = tempfile::tempdir().unwrap();
+        let base_copy = vec![7u8; BLOCK_SIZE * 4];
+        let mut new_copy = base_copy.clone();
+        new_copy[BLOCK_SIZE..BLOCK_SIZE + 5].copy_from_slice(b"fresh");
+
+        // The base backup holds a full copy of app.db, the next one a delta against it
+        store_backup(storage_dir.path(), "backup-20251231T020000", vec![engine_manifest("sqlite", &["app.db"])], &[("app.db.bak", &base_copy)]).await;
+        let mut signature = Signature::new(BLOCK_SIZE);
+        signature.write_all(&base_copy).unwrap();
+        let mut delta = Vec::new();
+        write_delta(&signature, new_copy.as_slice(), &mut delta).unwrap();
+
+        // crm.db is encrypted with its own data key, and sub/app.db was matched in a subdirectory
+        let master_file = scratch.path().join("crm.key");
+        fs::write(&master_file, "ab".repeat(32)).unwrap();
+        fs::set_permissions(&master_file, fs::Permissions::from_mode(0o600)).unwrap();
+        let master = load_encryption_key(&master_file).unwrap();
+        let data_key = EncryptionKey::generate();
+        let encrypted = scratch.path().join("crm.db.bak");
+        fs::write(&encrypted, b"crm data").unwrap();
+        encrypt_file(&encrypted, &data_key).unwrap();
+
+        let mut engine = engine_manifest("sqlite", &["app.db", "crm.db", "sub/app.db"]);
+        engine.differential_bases.insert("app.db".to_string(), "backup-20251231T020000".to_string());
+        engine.wrapped_keys.insert("crm.db".to_string(), wrap_key(&master, &data_key).unwrap());
+        let encrypted = fs::read(&encrypted).unwrap();
+        let files: [(&str, &[u8]); 3] = [("app.db.bak.delta", &delta), ("crm.db.bak", &encrypted), ("sub/app.db.bak", b"nested")];
+        store_backup(storage_dir.path(), BACKUP_ID, vec![engine], &files).await;
+
+        let mut config = restore_config(storage_dir.path(), sqlite_dir.path());
+        let sqlite = config.databases.sqlite.as_mut().unwrap();
+        sqlite.encryption_key_files.insert("crm.db".to_string(), master_file.to_string_lossy().to_string());
+        run_restore(&config, BACKUP_ID, &options(false)).await.unwrap();
+
+        assert_eq!(fs::read(sqlite_dir.path().join("app.db")).unwrap(), new_copy);
+        assert_eq!(fs::read(sqlite_dir.path().join("crm.db")).unwrap(), b"crm data");
+        assert_eq!(fs::read(sqlite_dir.path().join("sub/app.db")).unwrap(), b"nested");
+    }
+
+    #[tokio::test]
+    async fn stages_unchanged_collections_from_the_backup_holding_them() {
+        let storage_dir = tempfile::tempdir().unwrap();
+        let holder_id = "backup-20251231T020000";
+        store_backup(storage_dir.path(), holder_id, vec![engine_manifest("mongodb", &["shop"])], &[
+            ("shop/users.bson.gz", b"users"),
+            ("shop/users.metadata.json.gz", b"users metadata"),
+            ("shop/orders.bson.gz", b"old orders"),
+        ]).await;
+        let mut engine = engine_manifest("mongodb", &["shop"]);
+        engine.unchanged_collections.insert("shop".to_string(), BTreeMap::from([("users".to_string(), holder_id.to_string())]));
+        store_backup(storage_dir.path(), BACKUP_ID, vec![engine.clone()], &[("shop/orders.bson.gz", b"new orders")]).await;
+
+        let storage = LocalStorage::new(&storage_dir.path().to_string_lossy());
+        let archive_path = storage.archive_path(BACKUP_ID).unwrap();
+        let members = list_archive_files(&archive_path, storage.read_options()).unwrap();
+        assert_eq!(missing_dump(DumpLayout::Flat, &engine, &["shop".to_string()], &members), None);
+        let work_dir = tempfile::tempdir().unwrap();
+        let unpacked = work_dir.path().join("archive");
+        extract_archive(&archive_path, &unpacked, storage.read_options()).unwrap();
+        let source = ArchiveSource { storage: &storage, archive_path: &archive_path, unpacked: &unpacked, members: &members };
+        let restore = EngineRestore { engine: &engine, db_type: "mongodb", config: DatabaseConfig::default(), data_keys: BTreeMap::new() };
+        let dumps = work_dir.path().join("mongodb");
+        stage_dumps(&source, DumpLayout::Flat, &restore, "shop", &dumps).unwrap();
+
+        let read = |name: &str| fs::read_to_string(dumps.join("shop").join(name)).unwrap();
+        assert_eq!(read("orders.bson.gz"), "new orders");
+        assert_eq!(read("users.bson.gz"), "users");
+        assert_eq!(read("users.metadata.json.gz"), "users metadata");
+    }
+
+    #[tokio::test]
+    async fn nothing_is_restored_when_a_later_engine_cant_be() {
+        let storage_dir = tempfile::tempdir().unwrap();
+        let sqlite_dir = tempfile::tempdir().unwrap();
+        // The MySQL dump is missing, and the server is never reached
+        let engines = vec![engine_manifest("sqlite", &["app.db"]), engine_manifest("mysql", &["shop"])];
+        store_backup(storage_dir.path(), BACKUP_ID, engines, &[("app.db.bak", b"backed up")]).await;
+        fs::write(sqlite_dir.path().join("app.db"), "live").unwrap();
+        let config = restore_config(storage_dir.path(), sqlite_dir.path());
+
+        let refused = run_restore(&config, BACKUP_ID, &options(true)).await.unwrap_err();
+        assert!(refused.to_string().contains("no mysql dump of shop"));
+        assert_eq!(fs::read_to_string(sqlite_dir.path().join("app.db")).unwrap(), "live");
+        // Nothing was unpacked either
+        assert!(!storage_dir.path().join("staging").exists());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::backup::manifest::{Manifest, MANIFEST_FILE};
+use crate::commands::backup::{archive_comment, staging_root};
+use crate::config::{ArchiveFormat, Config};
+use crate::error::{Error, Result};
+use crate::storage::local::LocalStorage;
+use crate::storage::{create_backend, StoredBackup};
+use crate::utils::command::ensure_command_exists;
+use crate::utils::compression::{extract_archive, ArchiveOptions, ExternalCompressor};
+use crate::utils::encryption::load_encryption_key;
+use crate::utils::signing::{load_signing_key, sign_archive};
+use log::{info, warn};
+use std::fs;
+use std::path::Path;
+
+/// Added to a backup's id to name its transformed copy
+const TRANSFORMED_SUFFIX: &str = "-transformed";
+
+/// How `transform` repacks a backup; anything unset follows the `[storage]` section
+#[derive(Debug, Default)]
+pub struct TransformOptions {
+    pub to_format: Option<ArchiveFormat>,
+    pub to_compression: Option<String>, // Compressor command, or "gzip" for the built-in compression
+    pub rekey: Option<String>,          // Key file the new archive is encrypted with instead of encryption_key_file
+    pub replace: bool,                  // Remove the original once the new archive is stored
+}
+
+/// Repack a stored backup with another archive format, compression or encryption key, without
+/// dumping the databases again. The archive is unpacked into the staging directory and stored
+/// again as `<backup_id>-transformed`, keeping its manifest (creation time, tags, wrapped keys).
+/// Dumps encrypted with their own data keys are carried over as they are.
+pub async fn run_transform(config: &Config, backup_id: &str, options: &TransformOptions) -> Result<()> {
+    if config.storage.type_ != "local" {
+        return Err(Error::Config(format!("`transform` reads local storage only, not {:?}", config.storage.type_)));
+    }
+    let target = target_archive(config, options)?;
+    let signing_key = config.storage.signing_key_file.as_deref().map(|path| load_signing_key(Path::new(path))).transpose()?;
+    let local_storage = LocalStorage::from_config(&config.storage);
+    let archive_path = local_storage.archive_path(backup_id)?;
+    let storage = create_backend(&config.storage)?;
+    let stored = storage.list()?;
+    if options.replace {
+        let dependents = dependents(&stored, backup_id);
+        if !dependents.is_empty() {
+            return Err(Error::Config(format!(
+                "Backups {} refer to data in backup {}; transform it without --replace",
+                dependents.join(", "),
+                backup_id
+            )));
+        }
+    }
+    let new_id = transformed_id(backup_id, |id| stored.iter().any(|backup| backup.backup_id == id));
+
+    let staging_root = staging_root(config);
+    fs::create_dir_all(&staging_root).map_err(Error::Io)?;
+    let work_dir = tempfile::Builder::new()
+        .prefix(".transform-")
+        .tempdir_in(&staging_root)
+        .map_err(Error::Io)?;
+    info!("Unpacking backup {} from {:?}", backup_id, archive_path);
+    let entry_compression = extract_archive(&archive_path, work_dir.path(), local_storage.read_options())?;
+
+    let mut manifest: Manifest = fs::read(work_dir.path().join(MANIFEST_FILE)).ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .ok_or_else(|| Error::Storage(format!("Backup {} has no readable manifest to carry over", backup_id)))?;
+    manifest.backup_id = new_id.clone();
+    manifest.transformed_from = Some(backup_id.to_string());
+    manifest.checksum_algorithm = config.storage.checksum_algorithm;
+    manifest.write(work_dir.path())?;
+
+    let archive_options = ArchiveOptions {
+        entry_compression,
+        root: config.storage.archive_root.as_ref().map(|root| root.replace("{backup_id}", &new_id)),
+        comment: archive_comment(config, &manifest),
+        checksum: config.storage.checksum_algorithm,
+        ..target
+    };
+    // Stored through a backend reading with the new key, so the index can record the manifest
+    let mut target_storage = config.storage.clone();
+    if let Some(key_file) = &options.rekey {
+        target_storage.encryption_key_file = Some(key_file.clone());
+    }
+    let target_backend = create_backend(&target_storage)?;
+    let archive = target_backend.store(work_dir.path(), &new_id, &archive_options).await?;
+    info!("Stored backup {} as {} ({} bytes)", backup_id, archive.location, archive.size);
+    if let Some(key) = &signing_key {
+        target_backend.store_signature(&new_id, &sign_archive(key, &new_id, &archive.checksum))?;
+        info!("Signed backup {}", new_id);
+    }
+    if let Some(key_file) = &options.rekey {
+        warn!(
+            "Backup {} is encrypted with {}; set encryption_key_file to it to restore, verify or read the backup",
+            new_id, key_file
+        );
+        let own_keys: Vec<&str> = manifest.engines.iter()
+            .flat_map(|engine| engine.wrapped_keys.keys())
+            .map(String::as_str)
+            .collect();
+        if !own_keys.is_empty() {
+            warn!(
+                "Dumps of {} keep their data keys, still wrapped by their encryption_key_files; --rekey doesn't change those",
+                own_keys.join(", ")
+            );
+        }
+    }
+
+    if options.replace {
+        if let Some(original) = stored.iter().find(|backup| backup.backup_id == backup_id) {
+            storage.remove(original)?;
+            info!("Removed backup {}, replaced by {}", backup_id, new_id);
+        }
+    }
+    println!("{}", new_id);
+    Ok(())
+}
+
+/// Archive format, compressor and encryption key of the transformed archive
+fn target_archive(config: &Config, options: &TransformOptions) -> Result<ArchiveOptions> {
+    let storage = &config.storage;
+    let format = options.to_format.unwrap_or(storage.archive_format);
+    let command = match options.to_compression.as_deref() {
+        Some("gzip") => None,
+        Some(command) => Some(command.to_string()),
+        // A format given on its own uses that format's built-in compression
+        None if options.to_format.is_some() => None,
+        None => storage.compressor_command(),
+    };
+    let external = match command {
+        Some(command) => {
+            if format == ArchiveFormat::Zip {
+                return Err(Error::Config("A compressor command pipes a tar stream and can't write zip archives".to_string()));
+            }
+            // Stored archives are only found again by the configured extension
+            let extension = storage.compressor_extension.clone()
+                .filter(|extension| !extension.is_empty())
+                .ok_or_else(|| Error::Config(format!(
+                    "Compressing with {:?} needs compressor_extension set, e.g. \"tar.zst\", to find the archive by",
+                    command
+                )))?;
+            ensure_command_exists(&command)?;
+            Some(ExternalCompressor { command, extension })
+        }
+        None => None,
+    };
+    let encryption = options.rekey.as_deref()
+        .or(storage.encryption_key_file.as_deref())
+        .map(|path| load_encryption_key(Path::new(path)))
+        .transpose()?;
+    Ok(ArchiveOptions { format, external, encryption, ..Default::default() })
+}
+
+/// Stored backups referring to data held by `backup_id`
+fn dependents<'a>(stored: &'a [StoredBackup], backup_id: &str) -> Vec<&'a str> {
+    stored.iter()
+        .filter(|backup| {
+            backup.manifest.iter()
+                .flat_map(|manifest| manifest.engines.iter())
+                .any(|engine| engine.referenced_backups().any(|base| base == backup_id))
+        })
+        .map(|backup| backup.backup_id.as_str())
+        .collect()
+}
+
+/// Id of the transformed copy of `backup_id`, numbered when an earlier transform `taken` it.
+/// Copies of a transformed backup are named after the original, so suffixes don't pile up.
+fn transformed_id(backup_id: &str, taken: impl Fn(&str) -> bool) -> String {
+    let original = backup_id.split(TRANSFORMED_SUFFIX).next().unwrap_or(backup_id);
+    let mut new_id = format!("{}{}", original, TRANSFORMED_SUFFIX);
+    let mut number = 2;
+    while taken(&new_id) {
+        new_id = format!("{}{}-{}", original, TRANSFORMED_SUFFIX, number);
+        number += 1;
+    }
+    new_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transformed_ids_follow_the_original() {
+        let stored = ["backup-20260101T020000-transformed", "backup-20260101T020000-transformed-2"];
+        let taken = |id: &str| stored.contains(&id);
+        assert_eq!(transformed_id("backup-20260102T020000-db01", taken), "backup-20260102T020000-db01-transformed");
+        assert_eq!(transformed_id("backup-20260101T020000", taken), "backup-20260101T020000-transformed-3");
+        assert_eq!(transformed_id("backup-20260101T020000-transformed-2", taken), "backup-20260101T020000-transformed-3");
+    }
+}
@@ -0,0 +1,50 @@
+use crate::config::Config;
+use crate::database::connection::DatabaseConnectionFactory;
+use crate::database::sources::check_database_source;
+use crate::error::{Error, Result};
+use crate::scheduler::check_schedules;
+use crate::utils::clock::SystemClock;
+use crate::utils::user::run_as_user;
+use log::error;
+
+/// Check a config without running a backup. By default only its structure is checked, so a
+/// config meant for another host can be linted anywhere; `strict` also checks the files it
+/// refers to on this host (SQLite databases, credential files).
+pub fn run_validate(config: &Config, strict: bool) -> Result<()> {
+    let mut failed = Vec::new();
+    let groups = std::iter::once((None, &config.databases))
+        .chain(config.hosts.iter().map(|host| (Some(host.name.as_str()), &host.databases)));
+    for (host, databases) in groups {
+        for (db_type, db_config) in databases.configured() {
+            let label = match host {
+                Some(host) => format!("{}/{}", host, db_type),
+                None => db_type.to_string(),
+            };
+            let db = DatabaseConnectionFactory::create_connection(db_type, db_config)?;
+            let result = if strict {
+                db.validate_config(db_config)
+                    .and_then(|()| run_as_user(db_config).map(|_| ()))
+                    .and_then(|()| check_database_source(db_config))
+            } else {
+                db.validate_config_static(db_config)
+            };
+            match result {
+                Ok(()) => println!("{}: ok", label),
+                Err(e) => {
+                    error!("{} config is invalid: {}", label, e);
+                    failed.push(label);
+                }
+            }
+        }
+    }
+    if let Err(e) = check_schedules(config, &SystemClock) {
+        error!("Schedules are invalid: {}", e);
+        failed.push("schedules".to_string());
+    }
+
+    if !failed.is_empty() {
+        return Err(Error::Config(format!("Invalid config for {}", failed.join(", "))));
+    }
+    println!("Config is valid{}", if strict { "" } else { " (run with --strict to also check local files)" });
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use crate::backup::manifest::{Manifest, MANIFEST_FILE};
+use crate::commands::output::{print_rows, OutputFormat};
+use crate::config::{ChecksumAlgorithm, Config};
+use crate::error::{Error, Result};
+use crate::storage::local::LocalStorage;
+use crate::storage::StorageBackend;
+use crate::utils::checksum::{checksum_file, sha256_file};
+use crate::utils::compression::{list_archive_files, read_archive_comment, read_archive_file};
+use crate::utils::signing::{load_verifying_key, verify_archive};
+use ed25519_dalek::VerifyingKey;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+
+/// The outcome of checking one backup, as `verify` shows it
+#[derive(Default, Serialize)]
+struct VerifiedBackup {
+    backup_id: String,
+    status: &'static str, // "ok" or "failed"
+    files: Option<usize>,
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    checksum: Option<String>,
+    signature: Option<&'static str>, // "valid", or None when no verify_key_file is configured
+    comment: Option<String>,
+    error: Option<String>,
+}
+
+const VERIFY_COLUMNS: &[&str] = &["backup_id", "status", "files", "signature", "checksum_algorithm", "checksum", "comment", "error"];
+
+/// Check that stored backups are intact: each archive reads end to end and, when
+/// `verify_key_file` is configured, its detached signature matches. Up to `jobs` archives are
+/// checked at once; every failure is reported before the command fails.
+pub fn run_verify(config: &Config, backup_ids: &[String], all: bool, jobs: usize, format: OutputFormat) -> Result<()> {
+    if config.storage.type_ != "local" {
+        return Err(Error::Config(format!("`verify` reads local storage only, not {:?}", config.storage.type_)));
+    }
+    let local_storage = LocalStorage::from_config(&config.storage);
+    let backup_ids = if all {
+        local_storage.list()?.into_iter().map(|backup| backup.backup_id).collect()
+    } else {
+        backup_ids.to_vec()
+    };
+    let key = match &config.storage.verify_key_file {
+        Some(key_file) => Some(load_verifying_key(Path::new(key_file))?),
+        None => {
+            info!("No verify_key_file configured; signatures not checked");
+            None
+        }
+    };
+
+    let pending = Mutex::new(backup_ids.iter().enumerate());
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, backup_ids.len().max(1)) {
+            scope.spawn(|| loop {
+                let Some((index, backup_id)) = pending.lock().unwrap_or_else(|e| e.into_inner()).next() else {
+                    break;
+                };
+                let result = verify_backup(&local_storage, key.as_ref(), backup_id).unwrap_or_else(|e| {
+                    error!("Backup {} failed verification: {}", backup_id, e);
+                    VerifiedBackup {
+                        backup_id: backup_id.clone(),
+                        status: "failed",
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    }
+                });
+                results.lock().unwrap_or_else(|e| e.into_inner()).push((index, result));
+            });
+        }
+    });
+
+    // Rows come back in completion order; show them in the order they were asked for
+    let mut results = results.into_inner().unwrap_or_else(|e| e.into_inner());
+    results.sort_by_key(|(index, _)| *index);
+    let rows: Vec<VerifiedBackup> = results.into_iter().map(|(_, row)| row).collect();
+    print_rows(format, VERIFY_COLUMNS, &rows)?;
+
+    let mut failed: Vec<&str> = rows.iter()
+        .filter(|row| row.error.is_some())
+        .map(|row| row.backup_id.as_str())
+        .collect();
+    if !failed.is_empty() {
+        failed.sort();
+        return Err(Error::Storage(format!(
+            "{} of {} backups failed verification: {}",
+            failed.len(),
+            backup_ids.len(),
+            failed.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+fn verify_backup(local_storage: &LocalStorage, key: Option<&VerifyingKey>, backup_id: &str) -> Result<VerifiedBackup> {
+    let archive_path = local_storage.archive_path(backup_id)?;
+
+    let files = list_archive_files(&archive_path, local_storage.read_options())?;
+    // The manifest names the algorithm the archive was checksummed with when it was stored
+    let algorithm = if files.iter().any(|name| name == MANIFEST_FILE) {
+        read_archive_file(&archive_path, MANIFEST_FILE, local_storage.read_options())?
+            .and_then(|contents| serde_json::from_slice::<Manifest>(&contents).ok())
+            .map(|manifest| manifest.checksum_algorithm)
+            .unwrap_or_default()
+    } else {
+        warn!("Backup {} has no {}; it predates manifests or was not written by kronos", backup_id, MANIFEST_FILE);
+        ChecksumAlgorithm::default()
+    };
+    let checksum = checksum_file(&archive_path, algorithm)?;
+    let mut verified = VerifiedBackup {
+        backup_id: backup_id.to_string(),
+        status: "ok",
+        files: Some(files.len()),
+        checksum_algorithm: Some(algorithm),
+        checksum: Some(checksum.clone()),
+        comment: read_archive_comment(&archive_path)?,
+        ..Default::default()
+    };
+
+    let Some(key) = key else {
+        return Ok(verified);
+    };
+    let signature_path = local_storage.signature_path(backup_id);
+    let signature = match fs::read(&signature_path) {
+        Ok(signature) => signature,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::Storage(format!("Backup {} is not signed: {:?} is missing", backup_id, signature_path)));
+        }
+        Err(e) => return Err(Error::Io(e)),
+    };
+    // Signatures always cover the SHA-256
+    let sha256 = match algorithm {
+        ChecksumAlgorithm::Sha256 => checksum,
+        _ => sha256_file(&archive_path)?,
+    };
+    verify_archive(key, backup_id, &sha256, &signature)?;
+    verified.signature = Some("valid");
+
+    Ok(verified)
+}
@@ -1,16 +1,76 @@
-use serde::Deserialize;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use crate::database::sources::QUERY_PREFIX;
+use crate::database::template::check_command_template;
 use crate::error::{Error, Result};
+use crate::logger::{compile_redact_patterns, set_redact_patterns};
+use crate::utils::compression::check_archive_root;
+use crate::utils::priority::{can_raise_priority, NICE_RANGE};
+use log::{info, warn};
 
-#[derive(Deserialize, Debug)]
+/// Config schema version understood by this binary
+pub const CONFIG_VERSION: u32 = 4;
+
+/// Scheduler state file, next to the config file unless `scheduler_state_file` says otherwise
+pub const DEFAULT_SCHEDULER_STATE_FILE: &str = "scheduler-state.json";
+
+/// Stored archives are readable by their owner only unless `archive_mode` says otherwise
+const DEFAULT_ARCHIVE_MODE: u32 = 0o600;
+
+/// What changed in each config schema version, reported when loading an older config. The
+/// version only moves for changes to how an existing config behaves. New optional keys whose
+/// defaults keep the old behaviour (`[[hosts]]`, encryption and signing keys, `capture_checksums`,
+/// `nice_level`/`ionice_class`, `compression`, `checksum_algorithm`, heartbeat URLs and the like)
+/// are additive: an older config means the same without them.
+const CONFIG_CHANGES: &[(u32, &str)] = &[
+    (1, "added `version`; Postgres `pgpass_file` and MySQL `defaults_file` credential files"),
+    (2, "added `[[schedules]]`: named schedules with their own cron and `engines`/`databases` filters (`[schedule]` still works)"),
+    (3, "a relative `storage.path` is taken from the config file's directory, not the working directory; \
+         Postgres dumps are compressed by the archive only, not also by pg_dump (set `pg_dump_compression = true` for both)"),
+    (4, "added per-engine `dump_mode`; tables excluded by default keep their schema in the dump, only their rows are \
+         skipped (list them in `exclude_tables` to leave them out entirely)"),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
+    pub version: Option<u32>, // Config schema version; missing means a pre-versioning config
+    #[serde(default)]
     pub databases: Databases,
-    pub schedule: Option<Schedule>,
+    #[serde(default)]
+    pub hosts: Vec<HostGroup>, // Database hosts backed up concurrently, one archive each, instead of `databases`
+    pub host_concurrency: Option<usize>, // Hosts backed up at once (default 4)
+    pub schedule: Option<Schedule>, // Single schedule covering everything
+    #[serde(default)]
+    pub schedules: Vec<Schedule>, // Named schedules, each backing up a subset
+    pub scheduler_state_file: Option<String>, // Last fire and success of each schedule, kept across scheduler restarts
+    #[serde(default)]
+    pub dump_layout: DumpLayout, // How dumps are arranged inside the archive
     pub storage: Storage,
+    pub report: Option<ReportConfig>, // Email a summary of each run
+    pub run_retries: Option<u32>, // Re-run a failed backup from scratch up to this many times before giving up
+    pub run_retry_delay_secs: Option<u64>, // Wait before the first retry, doubling for each one after (default 60)
+    pub consistent_snapshot: Option<bool>, // Lock every engine that supports it for the whole run so all dumps match
+    pub pre_backup_command: Option<String>, // Shell command run before each attempt's dumps, e.g. to pause application writes; failing aborts it
+    pub post_backup_command: Option<String>, // Shell command run after each attempt's dumps, even failed ones, e.g. to resume writes
+    pub heartbeat_url: Option<String>, // Fetched (with curl) after each successful backup run, for a dead man's switch monitor
+    pub heartbeat_start_url: Option<String>, // Fetched when a backup run starts, so the monitor can tell a run that never finished
+    #[serde(default)]
+    pub log_redact_patterns: Vec<String>, // Regexes whose matches are replaced with *** in every log message
+}
+
+/// One database host of a fleet, backed up into its own archive
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostGroup {
+    pub name: String, // Appended to the backup id, e.g. backup-20250101T000000-db01
+    pub databases: Databases,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Databases {
     pub mysql: Option<DatabaseConfig>,
     pub postgres: Option<DatabaseConfig>,
@@ -18,40 +78,1183 @@ pub struct Databases {
     pub mongodb: Option<DatabaseConfig>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct DatabaseConfig {
     pub host: String,
     pub port: u16,
     pub user: String,
+    #[serde(default)]
     pub password: String,
+    #[serde(default)]
     pub databases: Vec<String>, // List of database names to back up
+    pub databases_from: Option<String>, // File with one database per line, or "query:<SQL>" (MySQL/PostgreSQL), added to `databases` at backup time
+    pub databases_from_database: Option<String>, // PostgreSQL: database the `databases_from` query runs in (default "postgres")
+    pub pgpass_file: Option<String>, // Postgres: credentials file exported as PGPASSFILE instead of PGPASSWORD
+    pub defaults_file: Option<String>, // MySQL: option file passed as --defaults-extra-file instead of --password
+    #[serde(default)]
+    pub dump_mode: DumpMode, // What to dump: "full", "schema_only" or "data_only"
+    pub io_buffer_bytes: Option<usize>, // Copy buffer between a dump tool's stdout and the dump file
+    pub lock_tables: Option<bool>, // MySQL: use --lock-tables when MyISAM tables are present
+    pub separate_routines: Option<bool>, // MySQL: write routines, triggers and events to <db>.routines.sql instead of the main dump
+    pub tab_format: Option<bool>, // MySQL: dump with --tab, a schema .sql and a data .txt file per table (server must be local)
+    pub deep_verify: Option<bool>, // SQLite: compare schema and row counts of source and backup (warns instead if the source is written to meanwhile)
+    pub differential: Option<bool>, // SQLite: store only the blocks changed since the last full copy (local storage only)
+    pub skip_unchanged: Option<bool>, // MongoDB: dump only collections changed since the last backup, referencing it for the rest (local storage only)
+    pub compress: Option<bool>, // Store this engine's dumps uncompressed when false (zip archives only)
+    pub compression: Option<EntryCompression>, // How this engine's dumps are compressed inside zip archives; replaces `compress`
+    pub verify_privileges: Option<bool>, // MySQL/PostgreSQL: check dump privileges before dumping
+    #[serde(default)]
+    pub missing_database: MissingDatabase, // What to do when a listed database doesn't exist: "error", "skip" or "warn"
+    #[serde(default)]
+    pub collections: Vec<String>, // MongoDB: dump only these collections of each database; empty means all
+    pub query: Option<String>, // MongoDB: JSON filter passed to mongodump --query for every collection (needs `collections`)
+    #[serde(default)]
+    pub exclude_tables: Vec<String>, // Tables (MongoDB: collections) left out of every database's dump
+    pub use_default_excludes: Option<bool>, // Also leave out DEFAULT_EXCLUDED_TABLES (session, cache and job queue tables)
+    pub include_blobs: Option<bool>, // PostgreSQL: force large objects into (true) or out of (false) the dump
+    pub pg_dump_compression: Option<bool>, // PostgreSQL: compress in pg_dump too; by default only the archive compresses, unless compress = false
+    pub max_blob_bytes: Option<u64>, // MySQL/PostgreSQL: leave out rows with a binary (BLOB, bytea) value longer than this; marks the backup incomplete
+    pub capture_grants: Option<bool>, // PostgreSQL: also write <db>.grants.sql with object owners, grants and object counts
+    pub skip_empty: Option<bool>, // Leave databases without any tables out of the backup (listed in the manifest)
+    pub capture_counts: Option<bool>, // Record each table's row count in the manifest before dumping it
+    pub capture_checksums: Option<bool>, // MySQL/PostgreSQL/MongoDB: record a checksum of each table's contents in the manifest, as of its dump
+    pub timeout_secs: Option<u64>, // Fail a database's dump (killing the dump command) once it runs longer than this
+    pub health_query: Option<String>, // Query that checks the connection instead of the default, run in the first listed database
+    pub liveness_check_interval: Option<u64>, // Ping the server every this many seconds during a dump, warning when it stops answering
+    pub command_template: Option<String>, // Shell command that dumps one database instead of the built-in one ({host}, {port}, {user}, {db}, {output})
+    pub run_as_user: Option<String>, // Unix: run the engine's client and dump commands as this OS user instead of kronos's own
+    pub nice_level: Option<i32>, // Unix: run dump commands at this nice value (-20 to 19; higher yields more CPU to others)
+    pub ionice_class: Option<IoniceClass>, // Linux: I/O scheduling class of dump commands
+    pub ssh_target: Option<String>, // MySQL/PostgreSQL: run the dump and every query on this SSH host (user@host or a ssh_config alias), the dump gzipped there before it crosses the network
+    pub consistent_snapshot: Option<bool>, // PostgreSQL: export a snapshot in every database up front and dump each from it, so they match closely
+    #[serde(default)]
+    pub encryption_key_files: BTreeMap<String, String>, // Master key file per database; its dump is encrypted with a data key wrapped by it
+    #[serde(skip)]
+    pub snapshot_id: Option<String>, // Set at backup time: snapshot exported by consistent_snapshot for this database's dump
+    #[serde(skip)]
+    pub restore_as: Option<String>, // Set at restore time (`--target-db`): database the one configured database is restored into
+}
+
+/// Session stores, caches and job queues of common frameworks (Django, Rails, Laravel), left out
+/// when `use_default_excludes = true`. Their contents are transient and can be large.
+pub const DEFAULT_EXCLUDED_TABLES: &[&str] = &[
+    "django_session",
+    "sessions",
+    "cache",
+    "cache_locks",
+    "solid_cache_entries",
+    "jobs",
+    "job_batches",
+    "failed_jobs",
+    "delayed_jobs",
+];
+
+/// Default copy buffer for streaming dump output to disk
+pub const DEFAULT_IO_BUFFER_BYTES: usize = 64 * 1024;
+
+impl DatabaseConfig {
+    /// Buffer size used when streaming subprocess output to a file
+    pub fn io_buffer_bytes(&self) -> usize {
+        self.io_buffer_bytes.unwrap_or(DEFAULT_IO_BUFFER_BYTES)
+    }
+
+    /// Whether this engine's dumps are compressed inside the archive
+    pub fn compress(&self) -> bool {
+        self.entry_compression() != EntryCompression::Store
+    }
+
+    /// How this engine's dumps are compressed inside zip archives: `compression`, or per `compress`
+    pub fn entry_compression(&self) -> EntryCompression {
+        match (self.compression, self.compress) {
+            (Some(compression), _) => compression,
+            (None, Some(false)) => EntryCompression::Store,
+            (None, _) => EntryCompression::Deflate,
+        }
+    }
+
+    /// Tables to leave out of dumps: `exclude_tables` plus the defaults when enabled
+    pub fn excluded_tables(&self) -> Vec<String> {
+        let defaults = match self.use_default_excludes {
+            Some(true) => DEFAULT_EXCLUDED_TABLES,
+            _ => &[],
+        };
+        let mut tables: Vec<String> = defaults.iter().map(|t| t.to_string()).collect();
+        for table in &self.exclude_tables {
+            if !tables.contains(table) {
+                tables.push(table.clone());
+            }
+        }
+        tables
+    }
+
+    /// Default excludes whose rows are left out but whose schema is still dumped, so a restored
+    /// application finds its session and queue tables empty instead of missing. Tables also listed
+    /// in `exclude_tables` are left out entirely.
+    pub fn schema_only_tables(&self) -> Vec<String> {
+        match self.use_default_excludes {
+            Some(true) => DEFAULT_EXCLUDED_TABLES.iter()
+                .filter(|t| !self.exclude_tables.iter().any(|e| e == *t))
+                .map(|t| t.to_string())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
 }
 
-#[derive(Deserialize, Debug)]
+/// I/O scheduling class dump commands run in, as for ionice(1)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IoniceClass {
+    Idle,       // Only gets disk time when nothing else wants it
+    BestEffort, // Level follows nice_level
+    Realtime,   // Needs root
+}
+
+/// How a zip archive entry is compressed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryCompression {
+    #[default]
+    Deflate,
+    Zstd,  // Better ratio and speed on SQL text; needs an unzip that supports method 93
+    Store, // Kept as is, e.g. dumps that are compressed already
+}
+
+/// Which parts of a database a dump contains
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DumpMode {
+    #[default]
+    Full,
+    SchemaOnly,
+    DataOnly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Schedule {
+    #[serde(default = "default_schedule_name")]
+    pub name: String,
     pub cron: String, // Cron expression, e.g., "0 0 * * *" (daily at midnight)
+    #[serde(default)]
+    pub engines: Vec<String>, // Engines this schedule backs up; empty means all
+    #[serde(default)]
+    pub databases: Vec<String>, // Databases this schedule backs up; empty means all
+    pub max_consecutive_failures: Option<u32>, // Stop the scheduler after this many failed runs in a row
+    pub min_interval_secs: Option<u64>, // Reject crons that would fire more often than this
+    pub catchup: Option<bool>, // On start, run once right away if a fire time passed while the scheduler was down, or the last run didn't succeed
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Storage {
-    pub type_: String, // "local" or "s3"
-    pub path: Option<String>, // Local storage path
+    pub type_: String, // "local", "sftp" or "s3"
+    pub path: Option<String>, // Local storage path, or the remote directory for SFTP
     pub bucket: Option<String>, // S3 bucket
     pub region: Option<String>, // S3 region
     pub access_key: Option<String>, // S3 access key
     pub secret_key: Option<String>, // S3 secret key
+    pub sftp_host: Option<String>, // SFTP host
+    pub sftp_port: Option<u16>, // SFTP port; defaults to 22
+    pub sftp_user: Option<String>, // SFTP user
+    pub sftp_password: Option<String>, // SFTP password, or the passphrase of sftp_key_file
+    pub sftp_key_file: Option<String>, // Private key for SFTP; without a key or password the SSH agent is used
+    pub sftp_known_hosts: Option<String>, // Host keys the SFTP server is checked against; defaults to ~/.ssh/known_hosts
+    pub sftp_timeout_secs: Option<u64>, // Give up on an SFTP connect or operation the server doesn't answer within this long (default 60)
+    pub retention: Option<RetentionConfig>, // Pruning policy applied after each backup and by `kronos prune`
+    #[serde(default)]
+    pub archive_format: ArchiveFormat, // "tar_gz" or "zip"
+    pub staging_dir: Option<String>, // Scratch directory for raw dumps; defaults to the system temp dir
+    pub archive_temp_dir: Option<String>, // Where the archive is assembled before moving into place; defaults to `path`
+    pub partial_grace_secs: Option<u64>, // Partial archives left by interrupted runs are removed once untouched this long (default 3600)
+    pub archive_root: Option<String>, // Directory archive entries are nested under ("{backup_id}" is expanded); `./` when unset
+    pub archive_comment: Option<String>, // Comment in the gzip header or zip archive ({backup_id}, {created_at}, {version}); "" for none
+    pub compressor_command: Option<String>, // Shell command the tar stream is piped through instead of gzip/zip
+    pub compressor_extension: Option<String>, // Archive extension used with compressor_command, e.g. "tar.lz4"
+    pub decompressor_command: Option<String>, // Shell command that reverses compressor_command; used first for archives named with compressor_extension
+    pub compression_threads: Option<usize>, // Max archives compressed at once; also fills "{threads}" in compressor_command
+    pub signing_key_file: Option<String>, // Ed25519 private key (PKCS#8 PEM) that signs each archive into <backup_id>.sig
+    pub verify_key_file: Option<String>, // Ed25519 public key (PEM) `kronos verify` checks signatures against
+    pub encryption_key_file: Option<String>, // 256-bit key (64 hex characters) archives are encrypted with (AES-256-GCM)
+    pub archive_mode: Option<u32>, // Permission bits of stored archives, e.g. 0o640 to let a group read them (default 0o600)
+    pub content_addressed: Option<bool>, // Local: store archives as objects/<ab>/<cd>/<sha256 of the dumps> with a refs/<backup_id> pointer each
+    #[serde(default)]
+    pub checksum_algorithm: ChecksumAlgorithm, // Digest of each stored archive: "sha256" or "blake3" (faster on large archives)
+}
+
+/// Emailed summary sent after every backup run
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReportConfig {
+    pub email_to: Vec<String>, // Recipients
+    pub email_from: Option<String>, // From address; left to the mail transport when unset
+    pub subject_prefix: Option<String>, // Prepended to the subject; defaults to "[kronos]"
+    pub sendmail_command: Option<String>, // Command that reads the message on stdin; defaults to "sendmail -t -i"
+}
+
+impl ReportConfig {
+    pub fn subject_prefix(&self) -> &str {
+        self.subject_prefix.as_deref().unwrap_or("[kronos]")
+    }
+
+    pub fn sendmail_command(&self) -> &str {
+        self.sendmail_command.as_deref().unwrap_or("sendmail -t -i")
+    }
+}
+
+/// What to do when a configured database doesn't exist on the server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingDatabase {
+    /// Fail the backup
+    #[default]
+    Error,
+    /// Leave the database out, logging at info level
+    Skip,
+    /// Leave the database out, logging a warning
+    Warn,
+}
+
+/// How dump files are arranged inside a backup
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum DumpLayout {
+    /// All dumps at the top level (`shop.sql`)
+    #[default]
+    Flat,
+    /// One directory per engine (`mysql/shop.sql`)
+    ByEngine,
+    /// One directory per database (`shop/mysql.sql`)
+    ByDatabase,
+    /// Top level, prefixed with the engine (`mysql-shop.sql`)
+    EnginePrefix,
+}
+
+/// Container format of stored backup archives
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    /// A gzip stream over a tar archive; every entry is compressed
+    #[default]
+    TarGz,
+    /// A zip archive; entries are deflated individually, so some can be stored as-is
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// File extension of archives in this format, without the leading dot
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+/// Hash function archives are checksummed with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Sha256,
+    Blake3, // Several times faster on multi-GB archives; needs the `blake3` feature
+}
+
+impl ChecksumAlgorithm {
+    /// Name of the algorithm as reports and `verify` print it
+    pub fn label(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "SHA-256",
+            ChecksumAlgorithm::Blake3 => "BLAKE3",
+        }
+    }
+}
+
+/// Which stored backups to keep; anything outside the policy is pruned
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RetentionConfig {
+    pub keep_last: Option<usize>, // Keep this many most recent backups
+    pub max_age_days: Option<u64>, // Remove backups older than this
+}
+
+fn default_schedule_name() -> String {
+    "default".to_string()
+}
+
+impl Databases {
+    /// Configured engines as (database type, config) pairs, in backup order
+    pub fn configured(&self) -> Vec<(&'static str, &DatabaseConfig)> {
+        [
+            ("sqlite", &self.sqlite),
+            ("mysql", &self.mysql),
+            ("postgres", &self.postgres),
+            ("mongodb", &self.mongodb),
+        ]
+        .into_iter()
+        .filter_map(|(db_type, config)| config.as_ref().map(|c| (db_type, c)))
+        .collect()
+    }
+}
+
+impl Storage {
+    /// Local directory backups are stored in
+    pub fn local_path(&self) -> &str {
+        self.path.as_deref().unwrap_or("/backups")
+    }
+
+    /// Compressor command with "{threads}" expanded to `compression_threads` (1 when unset)
+    pub fn compressor_command(&self) -> Option<String> {
+        let threads = self.compression_threads.unwrap_or(1).to_string();
+        self.compressor_command.as_ref().map(|command| command.replace("{threads}", &threads))
+    }
+
+    /// Archives are signed over their SHA-256, whatever algorithm checksums them
+    fn check_checksum_algorithm(&self) -> Result<()> {
+        if self.checksum_algorithm != ChecksumAlgorithm::Blake3 {
+            return Ok(());
+        }
+        if cfg!(not(feature = "blake3")) {
+            return Err(Error::Config("checksum_algorithm = \"blake3\" needs kronos built with the `blake3` feature".to_string()));
+        }
+        if self.signing_key_file.is_some() {
+            return Err(Error::Config(
+                "signatures cover the archive's SHA-256; signing_key_file can't be combined with checksum_algorithm = \"blake3\"".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Permission bits stored archives get
+    pub fn archive_mode(&self) -> u32 {
+        self.archive_mode.unwrap_or(DEFAULT_ARCHIVE_MODE)
+    }
+
+    /// Archives must stay readable and writable by their owner, and only permission bits can be set
+    fn check_archive_mode(&self) -> Result<()> {
+        let mode = self.archive_mode();
+        if mode & !0o777 != 0 || mode & 0o600 != 0o600 {
+            return Err(Error::Config(format!(
+                "archive_mode {:#o} must be permission bits (at most 0o777) including owner read and write (0o600)",
+                mode
+            )));
+        }
+        Ok(())
+    }
+
+    /// Backup ids are plain names, so an `archive_root` valid with a sample id is valid with any
+    fn check_archive_root(&self) -> Result<()> {
+        match &self.archive_root {
+            Some(root) => check_archive_root(&root.replace("{backup_id}", "backup-20240101T000000")),
+            None => Ok(()),
+        }
+    }
+
+    /// An external compressor needs its own archive extension and replaces gzip/zip entirely
+    fn check_compressor(&self) -> Result<()> {
+        if self.compression_threads == Some(0) {
+            return Err(Error::Config("compression_threads must be at least 1".to_string()));
+        }
+        if self.encryption_key_file.is_some() && self.archive_format == ArchiveFormat::Zip {
+            return Err(Error::Config(
+                "zip archives cannot be encrypted; encryption_key_file needs archive_format = \"tar_gz\"".to_string(),
+            ));
+        }
+        let Some(command) = &self.compressor_command else {
+            return Ok(());
+        };
+        let builtin = [ArchiveFormat::TarGz.extension(), ArchiveFormat::Zip.extension()];
+        match self.compressor_extension.as_deref() {
+            None | Some("") => Err(Error::Config(format!(
+                "compressor_command {:?} requires compressor_extension, e.g. \"tar.lz4\"",
+                command
+            ))),
+            Some(extension) if builtin.contains(&extension) => Err(Error::Config(format!(
+                "compressor_extension {:?} is reserved for built-in archive formats",
+                extension
+            ))),
+            Some(_) if self.archive_format == ArchiveFormat::Zip => Err(Error::Config(
+                "compressor_command pipes a tar stream and cannot be combined with archive_format = \"zip\"".to_string(),
+            )),
+            Some(_) => Ok(()),
+        }
+    }
 }
 
 impl Config {
-    pub fn load(path: &str) -> Result<Self> {
+    /// How long to wait before retry number `retry` (1-based) of a failed run
+    pub fn run_retry_delay(&self, retry: u32) -> Duration {
+        let base = self.run_retry_delay_secs.unwrap_or(60);
+        Duration::from_secs(base.saturating_mul(2u64.saturating_pow(retry.saturating_sub(1))))
+    }
+
+    /// All configured schedules, including the single `[schedule]` table
+    pub fn all_schedules(&self) -> Vec<&Schedule> {
+        self.schedule.iter().chain(self.schedules.iter()).collect()
+    }
+
+    /// Load and validate a config file. With `env_prefix`, environment variables named
+    /// `<prefix>_<KEY>` then override values from the file (see `apply_env_overrides`).
+    pub fn load(path: &str, env_prefix: Option<&str>) -> Result<Self> {
         let mut file = File::open(path).map_err(|e| Error::Config(format!("Failed to open config file: {}", e)))?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)
             .map_err(|e| Error::Config(format!("Failed to read config file: {}", e)))?;
 
-        let config: Config = toml::from_str(&contents)
-            .map_err(|e| Error::Config(format!("Failed to parse config: {}", e)))?;
+        let mut config: Config = match env_prefix {
+            Some(prefix) => {
+                let mut table: toml::Table = toml::from_str(&contents)
+                    .map_err(|e| Error::Config(format!("Failed to parse config: {}", e)))?;
+                apply_env_overrides(&mut table, prefix, std::env::vars());
+                table.try_into()
+                    .map_err(|e| Error::Config(format!("Failed to parse config with {}_* overrides: {}", prefix, e)))?
+            }
+            None => toml::from_str(&contents)
+                .map_err(|e| Error::Config(format!("Failed to parse config: {}", e)))?,
+        };
+        set_redact_patterns(compile_redact_patterns(&config.log_redact_patterns)?);
+        config.check_version()?;
+        config.check_hosts()?;
+        config.check_engine_options()?;
+        config.storage.check_compressor()?;
+        if config.storage.content_addressed == Some(true) && config.storage.type_ != "local" {
+            return Err(Error::Config("content_addressed is only supported for local storage".to_string()));
+        }
+        config.storage.check_checksum_algorithm()?;
+        config.storage.check_archive_root()?;
+        config.storage.check_archive_mode()?;
+        if let Some(report) = &config.report {
+            if report.email_to.is_empty() {
+                return Err(Error::Config("[report] needs at least one address in email_to".to_string()));
+            }
+        }
+        for (name, url) in [("heartbeat_url", &config.heartbeat_url), ("heartbeat_start_url", &config.heartbeat_start_url)] {
+            if let Some(url) = url {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    return Err(Error::Config(format!("`{}` must be an http:// or https:// URL", name)));
+                }
+                // It is passed to curl as a line of its config
+                if url.chars().any(char::is_control) {
+                    return Err(Error::Config(format!("`{}` contains a control character such as a line break", name)));
+                }
+            }
+        }
+        let cwd = std::env::current_dir().map_err(Error::Io)?;
+        if config.storage.type_ == "local" {
+            let resolved = resolve_local_path(config.storage.local_path(), Path::new(path), &cwd);
+            info!("Local storage path: {}", resolved.display());
+            config.storage.path = Some(resolved.to_string_lossy().into_owned());
+        }
+        // Outlives the staging directory, which a reboot may clear along with the temp dir
+        let state_file = config.scheduler_state_file.as_deref().unwrap_or(DEFAULT_SCHEDULER_STATE_FILE);
+        config.scheduler_state_file = Some(resolve_local_path(state_file, Path::new(path), &cwd).to_string_lossy().into_owned());
 
         Ok(config)
     }
-}
\ No newline at end of file
+
+    /// Config for backing up one host of the fleet: its databases and no other hosts
+    pub fn for_host(&self, host: &HostGroup) -> Config {
+        Config {
+            databases: host.databases.clone(),
+            hosts: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Engines configured at the top level and on every host, as (database type, config) pairs
+    pub fn all_configured(&self) -> Vec<(&'static str, &DatabaseConfig)> {
+        std::iter::once(&self.databases)
+            .chain(self.hosts.iter().map(|host| &host.databases))
+            .flat_map(Databases::configured)
+            .collect()
+    }
+
+    /// Copy of the config with passwords and keys replaced, safe to print or log
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        for databases in std::iter::once(&mut config.databases).chain(config.hosts.iter_mut().map(|host| &mut host.databases)) {
+            for db_config in [&mut databases.mysql, &mut databases.postgres, &mut databases.sqlite, &mut databases.mongodb]
+                .into_iter()
+                .flatten()
+            {
+                redact(&mut db_config.password);
+            }
+        }
+        // Heartbeat URLs embed the monitor's token
+        for key in [
+            &mut config.storage.access_key,
+            &mut config.storage.secret_key,
+            &mut config.storage.sftp_password,
+            &mut config.heartbeat_url,
+            &mut config.heartbeat_start_url,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            redact(key);
+        }
+        config
+    }
+
+    /// Replace every configured password and key occurring in `text`, for output that leaves the host
+    pub fn scrub(&self, text: &str) -> String {
+        let passwords = self.all_configured().into_iter().map(|(_, db_config)| db_config.password.as_str());
+        let keys = [&self.storage.access_key, &self.storage.secret_key, &self.storage.sftp_password]
+            .into_iter()
+            .flatten()
+            .map(String::as_str);
+        passwords.chain(keys)
+            .filter(|secret| !secret.is_empty())
+            .fold(text.to_string(), |text, secret| text.replace(secret, REDACTED))
+    }
+
+    /// Hosts need distinct names usable in backup ids, and replace the top-level databases
+    fn check_hosts(&self) -> Result<()> {
+        if self.hosts.is_empty() {
+            return Ok(());
+        }
+        if !self.databases.configured().is_empty() {
+            return Err(Error::Config("Configure databases under each [[hosts]] entry, not in [databases], when hosts are set".to_string()));
+        }
+        if self.host_concurrency == Some(0) {
+            return Err(Error::Config("host_concurrency must be at least 1".to_string()));
+        }
+        let mut names = std::collections::HashSet::new();
+        for host in &self.hosts {
+            if host.name.is_empty() || !host.name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+                return Err(Error::Config(format!("Invalid host name {:?}: use characters from [A-Za-z0-9_.-]", host.name)));
+            }
+            if !names.insert(host.name.as_str()) {
+                return Err(Error::Config(format!("Duplicate host name {:?}", host.name)));
+            }
+            if host.databases.configured().is_empty() {
+                return Err(Error::Config(format!("Host {:?} has no databases configured", host.name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject options set on engines that don't support them
+    fn check_engine_options(&self) -> Result<()> {
+        for (db_type, db_config) in self.all_configured() {
+            if db_type == "sqlite" && db_config.run_as_user.is_some() {
+                return Err(Error::Config(
+                    "`run_as_user` is not supported for sqlite, which is read in-process rather than by a dump command".to_string(),
+                ));
+            }
+            if let Some(source) = &db_config.databases_from {
+                if source.trim().is_empty() {
+                    return Err(Error::Config(format!("{} `databases_from` is empty", db_type)));
+                }
+                if source.starts_with(QUERY_PREFIX) && !matches!(db_type, "mysql" | "postgres") {
+                    return Err(Error::Config(format!(
+                        "`databases_from` queries are only supported for mysql and postgres, not {}",
+                        db_type
+                    )));
+                }
+            }
+            if db_config.compress == Some(false) && db_config.compression.is_some_and(|c| c != EntryCompression::Store) {
+                return Err(Error::Config(format!(
+                    "{} `compress = false` conflicts with `compression`; set only one of them",
+                    db_type
+                )));
+            }
+            if db_type == "sqlite" && (db_config.nice_level.is_some() || db_config.ionice_class.is_some()) {
+                return Err(Error::Config(
+                    "`nice_level` and `ionice_class` are not supported for sqlite, which is read in-process rather than by a dump command".to_string(),
+                ));
+            }
+            if let Some(level) = db_config.nice_level {
+                if !NICE_RANGE.contains(&level) {
+                    return Err(Error::Config(format!(
+                        "{} `nice_level` must be between {} and {}, got {}",
+                        db_type, NICE_RANGE.start(), NICE_RANGE.end(), level
+                    )));
+                }
+                // The level is set after the switch to run_as_user, and only root may raise it;
+                // over ssh_target it is the remote user's business
+                if level < 0 && db_config.run_as_user.is_some() {
+                    return Err(Error::Config(format!(
+                        "{} negative `nice_level` can't be combined with `run_as_user`, which isn't allowed to raise its priority",
+                        db_type
+                    )));
+                }
+                if level < 0 && db_config.ssh_target.is_none() && !can_raise_priority() {
+                    return Err(Error::Config(format!(
+                        "{} negative `nice_level` needs kronos to run as root",
+                        db_type
+                    )));
+                }
+            }
+            if db_config.capture_checksums == Some(true) {
+                match db_type {
+                    "sqlite" => return Err(Error::Config("`capture_checksums` is not supported for sqlite".to_string())),
+                    // Checksums are read before the dump; only the run-wide lock keeps the tables
+                    // from changing in between
+                    "mysql" | "mongodb" if self.consistent_snapshot != Some(true) => {
+                        return Err(Error::Config(format!(
+                            "{} `capture_checksums` needs the top-level `consistent_snapshot = true`, or writes between checksumming and dumping would make the checksums wrong",
+                            db_type
+                        )));
+                    }
+                    // The checksums are read in the snapshot passed to pg_dump, which a template wouldn't get
+                    "postgres" if db_config.command_template.is_some() => {
+                        return Err(Error::Config(
+                            "postgres `capture_checksums` can't be combined with command_template".to_string(),
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+            if db_type != "postgres" && db_config.databases_from_database.is_some() {
+                return Err(Error::Config(format!(
+                    "`databases_from_database` is only supported for postgres, but is set for {}",
+                    db_type
+                )));
+            }
+            if db_config.differential == Some(true) {
+                if db_type != "sqlite" {
+                    return Err(Error::Config(format!("`differential` is only supported for sqlite, but is set for {}", db_type)));
+                }
+                // The base copy is read back from the stored archives
+                if self.storage.type_ != "local" {
+                    return Err(Error::Config(format!(
+                        "sqlite `differential` needs local storage, not {:?}",
+                        self.storage.type_
+                    )));
+                }
+                if !db_config.encryption_key_files.is_empty() {
+                    return Err(Error::Config(
+                        "sqlite `differential` can't be combined with `encryption_key_files`".to_string(),
+                    ));
+                }
+            }
+            if db_config.skip_unchanged == Some(true) {
+                if db_type != "mongodb" {
+                    return Err(Error::Config(format!("`skip_unchanged` is only supported for mongodb, but is set for {}", db_type)));
+                }
+                // Collection hashes are compared with the manifests of stored backups
+                if self.storage.type_ != "local" {
+                    return Err(Error::Config(format!(
+                        "mongodb `skip_unchanged` needs local storage, not {:?}",
+                        self.storage.type_
+                    )));
+                }
+                if db_config.dump_mode == DumpMode::SchemaOnly
+                    || db_config.command_template.is_some()
+                    || !db_config.encryption_key_files.is_empty()
+                {
+                    return Err(Error::Config(
+                        "mongodb `skip_unchanged` can't be combined with dump_mode \"schema_only\", command_template or encryption_key_files".to_string(),
+                    ));
+                }
+            }
+            if db_type != "mongodb" && !db_config.collections.is_empty() {
+                return Err(Error::Config(format!(
+                    "`collections` is only supported for mongodb, but is set for {}",
+                    db_type
+                )));
+            }
+            if let Some(query) = &db_config.query {
+                if db_type != "mongodb" {
+                    return Err(Error::Config(format!("`query` is only supported for mongodb, but is set for {}", db_type)));
+                }
+                if !matches!(serde_json::from_str(query), Ok(serde_json::Value::Object(_))) {
+                    return Err(Error::Config(format!("mongodb `query` must be a JSON object, got {:?}", query)));
+                }
+                // mongodump only applies --query together with --collection
+                if db_config.collections.is_empty() {
+                    return Err(Error::Config("mongodb `query` needs `collections`; mongodump filters one collection at a time".to_string()));
+                }
+                if db_config.dump_mode == DumpMode::SchemaOnly {
+                    return Err(Error::Config("mongodb `query` conflicts with dump_mode \"schema_only\", which dumps no documents".to_string()));
+                }
+            }
+            if db_type != "mysql" && db_config.separate_routines.is_some() {
+                return Err(Error::Config(format!(
+                    "`separate_routines` is only supported for mysql, but is set for {}",
+                    db_type
+                )));
+            }
+            if db_config.separate_routines == Some(true) && db_config.dump_mode == DumpMode::DataOnly {
+                return Err(Error::Config(
+                    "mysql `separate_routines` conflicts with dump_mode \"data_only\", which dumps no stored programs".to_string(),
+                ));
+            }
+            if db_type != "mysql" && db_config.tab_format.is_some() {
+                return Err(Error::Config(format!(
+                    "`tab_format` is only supported for mysql, but is set for {}",
+                    db_type
+                )));
+            }
+            if db_type != "postgres" && db_config.pg_dump_compression.is_some() {
+                return Err(Error::Config(format!(
+                    "`pg_dump_compression` is only supported for postgres, but is set for {}",
+                    db_type
+                )));
+            }
+            if db_type != "postgres" && db_config.capture_grants.is_some() {
+                return Err(Error::Config(format!(
+                    "`capture_grants` is only supported for postgres, but is set for {}",
+                    db_type
+                )));
+            }
+            if db_config.consistent_snapshot.is_some() {
+                if db_type != "postgres" {
+                    return Err(Error::Config(format!(
+                        "`consistent_snapshot` is only supported for postgres, but is set for {}; the top-level option locks mysql and mongodb",
+                        db_type
+                    )));
+                }
+                // The snapshot is passed to pg_dump as --snapshot, which a template wouldn't get
+                if db_config.command_template.is_some() {
+                    return Err(Error::Config(
+                        "postgres `consistent_snapshot` can't be combined with command_template".to_string(),
+                    ));
+                }
+            }
+            if db_type != "postgres" && db_config.include_blobs.is_some() {
+                return Err(Error::Config(format!(
+                    "`include_blobs` is only supported for postgres, but is set for {}",
+                    db_type
+                )));
+            }
+            if db_config.include_blobs == Some(true) && db_config.dump_mode == DumpMode::SchemaOnly {
+                return Err(Error::Config(
+                    "postgres `include_blobs = true` conflicts with dump_mode \"schema_only\", which dumps no data".to_string(),
+                ));
+            }
+            if let Some(template) = &db_config.command_template {
+                if db_type == "sqlite" {
+                    return Err(Error::Config(
+                        "sqlite backups copy files with the SQLite backup API; `command_template` is not supported".to_string(),
+                    ));
+                }
+                check_command_template(db_type, template)?;
+                // The template replaces the whole command, so options that only add flags to it can't apply
+                if db_config.dump_mode != DumpMode::Full
+                    || !db_config.excluded_tables().is_empty()
+                    || !db_config.collections.is_empty()
+                    || db_config.query.is_some()
+                    || db_config.include_blobs.is_some()
+                    || db_config.tab_format.is_some()
+                    || db_config.separate_routines.is_some()
+                    || db_config.pg_dump_compression.is_some()
+                {
+                    return Err(Error::Config(format!(
+                        "{} `command_template` replaces the dump command; express dump_mode, exclude_tables, collections, query, include_blobs, tab_format, separate_routines and pg_dump_compression in the template instead",
+                        db_type
+                    )));
+                }
+            }
+            if db_config.health_query.as_deref().is_some_and(|query| query.trim().is_empty()) {
+                return Err(Error::Config(format!("{} `health_query` is empty; remove it to use the default check", db_type)));
+            }
+            if db_config.max_blob_bytes.is_some() {
+                if db_type != "mysql" && db_type != "postgres" {
+                    return Err(Error::Config(format!(
+                        "`max_blob_bytes` is only supported for mysql and postgres, but is set for {}",
+                        db_type
+                    )));
+                }
+                if db_config.dump_mode == DumpMode::SchemaOnly {
+                    return Err(Error::Config(format!(
+                        "{} `max_blob_bytes` conflicts with dump_mode \"schema_only\", which dumps no rows",
+                        db_type
+                    )));
+                }
+                // Filtered tables are dumped by separate commands on this host
+                if db_config.command_template.is_some() || db_config.ssh_target.is_some() {
+                    return Err(Error::Config(format!(
+                        "{} `max_blob_bytes` can't be combined with command_template or ssh_target",
+                        db_type
+                    )));
+                }
+            }
+            if db_config.ssh_target.is_some() {
+                if db_type != "mysql" && db_type != "postgres" {
+                    return Err(Error::Config(format!(
+                        "`ssh_target` is only supported for mysql and postgres, but is set for {}",
+                        db_type
+                    )));
+                }
+                // Both write files on the host running the dump rather than to its output
+                if db_config.command_template.is_some() || db_config.tab_format == Some(true) {
+                    return Err(Error::Config(format!(
+                        "{} `ssh_target` can't be combined with command_template or tab_format",
+                        db_type
+                    )));
+                }
+                // Client commands run on the SSH host, where these local paths mean nothing
+                if db_config.defaults_file.is_some() || db_config.pgpass_file.is_some() {
+                    return Err(Error::Config(format!(
+                        "{} `ssh_target` can't be combined with defaults_file or pgpass_file; set `password`, which is sent over ssh's stdin",
+                        db_type
+                    )));
+                }
+                // Snapshots and the global read lock are held by a session kept open on this host
+                let holds_session = db_config.consistent_snapshot.is_some()
+                    || (db_type == "postgres" && db_config.capture_checksums == Some(true))
+                    || (db_type == "mysql" && self.consistent_snapshot == Some(true));
+                if holds_session {
+                    return Err(Error::Config(format!(
+                        "{} `ssh_target` can't be combined with consistent_snapshot{}",
+                        db_type,
+                        if db_type == "postgres" { " or capture_checksums" } else { " (top-level)" }
+                    )));
+                }
+            }
+            if db_type == "sqlite" && db_config.liveness_check_interval.is_some() {
+                return Err(Error::Config(
+                    "`liveness_check_interval` needs a database server; it is not supported for sqlite".to_string(),
+                ));
+            }
+            // SQLite copies run in-process and can't be interrupted
+            if db_type == "sqlite" && db_config.timeout_secs.is_some() {
+                return Err(Error::Config("`timeout_secs` is not supported for sqlite".to_string()));
+            }
+            if db_config.timeout_secs == Some(0) {
+                return Err(Error::Config(format!("{} `timeout_secs` must be at least 1 second", db_type)));
+            }
+            if db_config.liveness_check_interval == Some(0) {
+                return Err(Error::Config(format!("{} `liveness_check_interval` must be at least 1 second", db_type)));
+            }
+            // SQLite backups copy whole database files
+            if db_type == "sqlite" && !db_config.excluded_tables().is_empty() {
+                return Err(Error::Config(
+                    "sqlite backups copy whole files; `exclude_tables` and `use_default_excludes` are not supported".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject configs newer than this binary and warn about changes since older ones
+    fn check_version(&self) -> Result<()> {
+        let version = self.version.unwrap_or(0);
+        if version > CONFIG_VERSION {
+            return Err(Error::Config(format!(
+                "Config version {} is newer than supported version {}; upgrade kronos",
+                version, CONFIG_VERSION
+            )));
+        }
+
+        if version < CONFIG_VERSION {
+            match self.version {
+                Some(v) => warn!("Config version {} is older than current version {}", v, CONFIG_VERSION),
+                None => warn!("Config has no `version` field; set `version = {}` after reviewing changes", CONFIG_VERSION),
+            }
+            for (change_version, change) in CONFIG_CHANGES.iter().filter(|(v, _)| *v > version) {
+                warn!("  version {}: {}", change_version, change);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Placeholder shown in place of secrets
+pub const REDACTED: &str = "***";
+
+fn redact(secret: &mut String) {
+    if !secret.is_empty() {
+        *secret = REDACTED.to_string();
+    }
+}
+
+/// Absolute form of a local path set in the config, such as the storage path. A relative path is taken from the directory of
+/// `config_file` rather than the working directory, so it names the same place wherever kronos
+/// is started. Nothing is created, since every command (`validate` included) loads the config:
+/// an existing directory is canonicalized, while one that doesn't exist yet keeps its path with
+/// `.` and `..` folded away and is created by the first backup stored into it.
+fn resolve_local_path(storage_path: &str, config_file: &Path, cwd: &Path) -> PathBuf {
+    let config_dir = cwd.join(config_file).parent().map(Path::to_path_buf).unwrap_or_else(|| cwd.to_path_buf());
+    let absolute = config_dir.join(storage_path);
+    absolute.canonicalize().unwrap_or_else(|_| normalize(&absolute))
+}
+
+/// `path` with `.` components dropped and `..` applied lexically
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Override config values with environment variables named `<prefix>_<KEY>`, where KEY is the
+/// value's path in upper case with `_` between levels: with prefix `KRONOS`,
+/// `KRONOS_STORAGE_PATH` sets `storage.path` and `KRONOS_DATABASES_MYSQL_PASSWORD` sets
+/// `databases.mysql.password`. Each `_` is read as a level separator when the name before it is
+/// a table in the file, so only tables that already exist can be reached. Values replacing a
+/// string stay strings; anything else is read as a TOML value (`7`, `true`, `["a", "b"]`),
+/// falling back to a string. Overrides always win over the file.
+fn apply_env_overrides<I: IntoIterator<Item = (String, String)>>(root: &mut toml::Table, prefix: &str, vars: I) {
+    let prefix = format!("{}_", prefix);
+    let mut vars: Vec<(String, String)> = vars.into_iter()
+        .filter(|(name, _)| name.starts_with(&prefix) && name.len() > prefix.len())
+        .collect();
+    vars.sort();
+
+    for (name, raw) in vars {
+        let segments: Vec<String> = name[prefix.len()..].split('_').map(str::to_lowercase).collect();
+        let mut table = &mut *root;
+        let mut start = 0;
+        // Descend through the longest run of segments naming an existing table, leaving at least one for the key
+        while let Some(end) = (start + 1..segments.len())
+            .rev()
+            .find(|&end| matches!(table.get(&segments[start..end].join("_")), Some(toml::Value::Table(_))))
+        {
+            let Some(toml::Value::Table(next)) = table.get_mut(&segments[start..end].join("_")) else {
+                unreachable!();
+            };
+            table = next;
+            start = end;
+        }
+
+        let key = segments[start..].join("_");
+        let value = match table.get(&key) {
+            Some(toml::Value::String(_)) => toml::Value::String(raw),
+            Some(toml::Value::Table(_)) => {
+                warn!("Ignoring {}: it names a table, not a value", name);
+                continue;
+            }
+            _ => toml::from_str::<toml::Table>(&format!("value = {}", raw))
+                .ok()
+                .and_then(|mut parsed| parsed.remove("value"))
+                .unwrap_or(toml::Value::String(raw)),
+        };
+        info!("Config value {:?} overridden by {}", segments[..start].iter().chain([&key]).cloned().collect::<Vec<_>>().join("."), name);
+        table.insert(key, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(version_line: &str) -> Config {
+        let contents = format!(
+            "{}\n[databases]\n[storage]\ntype_ = \"local\"\n",
+            version_line
+        );
+        toml::from_str(&contents).unwrap()
+    }
+
+    #[test]
+    fn accepts_current_and_older_versions() {
+        assert!(parse("").check_version().is_ok());
+        assert!(parse(&format!("version = {}", CONFIG_VERSION)).check_version().is_ok());
+    }
+
+    #[test]
+    fn env_overrides_follow_existing_tables() {
+        let mut table: toml::Table = toml::from_str(
+            "[databases.mysql]\nport = 3306\npassword = \"x\"\n[storage]\ntype_ = \"local\"\npath = \"/a\"\n",
+        ).unwrap();
+        let vars = [
+            ("KRONOS_STORAGE_PATH", "/b"),
+            ("KRONOS_STORAGE_TYPE_", "sftp"),
+            ("KRONOS_DATABASES_MYSQL_PASSWORD", "123"),
+            ("KRONOS_DATABASES_MYSQL_PORT", "3307"),
+            ("KRONOS_STORAGE_KEEP_LAST", "7"),
+            ("KRONOS_STORAGE", "ignored"),
+            ("OTHER_STORAGE_PATH", "/c"),
+        ];
+        apply_env_overrides(&mut table, "KRONOS", vars.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+
+        let storage = table["storage"].as_table().unwrap();
+        assert_eq!(storage["path"].as_str(), Some("/b"));
+        assert_eq!(storage["type_"].as_str(), Some("sftp"));
+        assert_eq!(storage["keep_last"].as_integer(), Some(7));
+        let mysql = table["databases"]["mysql"].as_table().unwrap();
+        assert_eq!(mysql["password"].as_str(), Some("123"));
+        assert_eq!(mysql["port"].as_integer(), Some(3307));
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let mut config = parse("");
+        config.databases.mysql = Some(DatabaseConfig { password: "hunter2".to_string(), ..Default::default() });
+        config.storage.secret_key = Some("s3cret".to_string());
+
+        let printed = toml::to_string(&config.redacted()).unwrap();
+        assert!(!printed.contains("hunter2"));
+        assert!(!printed.contains("s3cret"));
+        assert_eq!(config.scrub("mysql -phunter2 s3cret"), "mysql -p*** ***");
+    }
+
+    #[test]
+    fn merges_default_excludes() {
+        let mut db_config = DatabaseConfig {
+            exclude_tables: vec!["audit_log".to_string(), "sessions".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(db_config.excluded_tables(), ["audit_log", "sessions"]);
+
+        db_config.use_default_excludes = Some(true);
+        let excluded = db_config.excluded_tables();
+        assert_eq!(excluded.len(), DEFAULT_EXCLUDED_TABLES.len() + 1);
+        assert_eq!(excluded.last().map(String::as_str), Some("audit_log"));
+    }
+
+    #[test]
+    fn default_excludes_keep_their_schema_unless_listed() {
+        let mut db_config = DatabaseConfig { exclude_tables: vec!["sessions".to_string()], ..Default::default() };
+        assert!(db_config.schema_only_tables().is_empty());
+
+        db_config.use_default_excludes = Some(true);
+        let schema_only = db_config.schema_only_tables();
+        assert_eq!(schema_only.len(), DEFAULT_EXCLUDED_TABLES.len() - 1);
+        assert!(!schema_only.contains(&"sessions".to_string()));
+    }
+
+    #[test]
+    fn negative_nice_levels_need_kronos_own_privileges() {
+        let mut config = parse("");
+        let lowered = DatabaseConfig {
+            nice_level: Some(10),
+            run_as_user: Some("postgres".to_string()),
+            ..Default::default()
+        };
+        config.databases.postgres = Some(lowered.clone());
+        assert!(config.check_engine_options().is_ok());
+
+        config.databases.postgres = Some(DatabaseConfig { nice_level: Some(-5), ..lowered });
+        assert!(matches!(config.check_engine_options(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn rejects_collections_outside_mongodb() {
+        let mut config = parse("");
+        let with_collections = DatabaseConfig { collections: vec!["orders".to_string()], ..Default::default() };
+        config.databases.mongodb = Some(with_collections.clone());
+        assert!(config.check_engine_options().is_ok());
+
+        config.databases.postgres = Some(with_collections);
+        assert!(matches!(config.check_engine_options(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn validates_include_blobs() {
+        let mut config = parse("");
+        let with_blobs = DatabaseConfig { include_blobs: Some(true), ..Default::default() };
+        config.databases.postgres = Some(with_blobs.clone());
+        assert!(config.check_engine_options().is_ok());
+
+        config.databases.postgres = Some(DatabaseConfig { dump_mode: DumpMode::SchemaOnly, ..with_blobs.clone() });
+        assert!(matches!(config.check_engine_options(), Err(Error::Config(_))));
+
+        config.databases.postgres = None;
+        config.databases.mysql = Some(with_blobs);
+        assert!(matches!(config.check_engine_options(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn checksums_on_locking_engines_need_the_run_wide_lock() {
+        let mut config = parse("");
+        let with_checksums = DatabaseConfig { capture_checksums: Some(true), ..Default::default() };
+        config.databases.postgres = Some(with_checksums.clone());
+        assert!(config.check_engine_options().is_ok());
+
+        config.databases.mysql = Some(with_checksums);
+        assert!(matches!(config.check_engine_options(), Err(Error::Config(_))));
+
+        config.consistent_snapshot = Some(true);
+        assert!(config.check_engine_options().is_ok());
+    }
+
+    #[test]
+    fn rejects_local_files_and_sessions_with_ssh_target() {
+        let mut config = parse("");
+        let remote = DatabaseConfig { ssh_target: Some("backup@db1".to_string()), ..Default::default() };
+        config.databases.mysql = Some(remote.clone());
+        assert!(config.check_engine_options().is_ok());
+
+        config.databases.mysql = Some(DatabaseConfig { defaults_file: Some("/etc/kronos/my.cnf".to_string()), ..remote.clone() });
+        assert!(matches!(config.check_engine_options(), Err(Error::Config(_))));
+
+        config.databases.mysql = Some(remote);
+        config.consistent_snapshot = Some(true);
+        assert!(matches!(config.check_engine_options(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn resolves_relative_storage_path_from_the_config_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("etc/kronos")).unwrap();
+        std::fs::create_dir_all(root.join("home")).unwrap();
+
+        let from_root = resolve_local_path("../backups", Path::new("etc/kronos/config.toml"), &root);
+        let from_home = resolve_local_path("../backups", Path::new("../etc/kronos/config.toml"), &root.join("home"));
+        assert_eq!(from_root, root.join("etc/backups"));
+        assert_eq!(from_home, from_root);
+        // Loading a config never creates the storage directory
+        assert!(!from_root.exists());
+        std::fs::create_dir_all(&from_root).unwrap();
+        assert_eq!(resolve_local_path("../backups", Path::new("../etc/kronos/config.toml"), &root.join("home")), from_root);
+        let absolute = root.join("home/../archive");
+        assert_eq!(resolve_local_path(&absolute.to_string_lossy(), Path::new("config.toml"), &root), root.join("archive"));
+    }
+
+    #[test]
+    fn rejects_empty_health_query() {
+        let mut config = parse("");
+        config.databases.mysql = Some(DatabaseConfig { health_query: Some("SELECT 1 FROM ops.heartbeat".to_string()), ..Default::default() });
+        assert!(config.check_engine_options().is_ok());
+
+        config.databases.mysql = Some(DatabaseConfig { health_query: Some("  ".to_string()), ..Default::default() });
+        assert!(matches!(config.check_engine_options(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let config = parse(&format!("version = {}", CONFIG_VERSION + 1));
+        assert!(matches!(config.check_version(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn keeps_blake3_away_from_sha256_signatures() {
+        let mut storage = parse("").storage;
+        assert_eq!(storage.checksum_algorithm, ChecksumAlgorithm::Sha256);
+        storage.checksum_algorithm = ChecksumAlgorithm::Blake3;
+        assert!(storage.check_checksum_algorithm().is_ok());
+
+        storage.content_addressed = Some(true);
+        assert!(storage.check_checksum_algorithm().is_ok());
+        storage.signing_key_file = Some("kronos.pem".to_string());
+        assert!(matches!(storage.check_checksum_algorithm(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn archive_mode_keeps_the_owner_bits() {
+        let mut storage = parse("").storage;
+        assert_eq!(storage.archive_mode(), 0o600);
+        for mode in [0o600, 0o640, 0o644] {
+            storage.archive_mode = Some(mode);
+            assert!(storage.check_archive_mode().is_ok(), "{:o}", mode);
+        }
+        for mode in [0o440, 0o040, 0o4600] {
+            storage.archive_mode = Some(mode);
+            assert!(matches!(storage.check_archive_mode(), Err(Error::Config(_))), "{:o}", mode);
+        }
+    }
+
+    #[test]
+    fn archive_root_must_be_one_directory_name() {
+        let mut storage = parse("").storage;
+        for root in ["{backup_id}", "db-{backup_id}", "kronos"] {
+            storage.archive_root = Some(root.to_string());
+            assert!(storage.check_archive_root().is_ok(), "{}", root);
+        }
+        for root in ["", "..", "backups/{backup_id}", "/tmp", "a\\b"] {
+            storage.archive_root = Some(root.to_string());
+            assert!(matches!(storage.check_archive_root(), Err(Error::Config(_))), "{}", root);
+        }
+    }
+}
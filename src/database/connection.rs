@@ -1,7 +1,12 @@
+use crate::backup::history::DumpHistory;
 use crate::config::DatabaseConfig;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use async_trait::async_trait;
+use std::collections::BTreeMap;
 use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::process::Child;
 
 /// Database connection metadata
 #[derive(Debug, Clone)]
@@ -9,6 +14,73 @@ pub struct DatabaseInfo {
     pub name: String,
     pub size: Option<u64>, // Size in bytes, if available
     pub schema_version: Option<String>,
+    pub table_count: Option<u64>, // Tables and views (MongoDB: collections), if available
+}
+
+impl DatabaseInfo {
+    /// Whether the database holds no tables, judged by size when the engine can't count them
+    pub fn is_empty(&self) -> bool {
+        match self.table_count {
+            Some(count) => count == 0,
+            None => self.size == Some(0),
+        }
+    }
+}
+
+/// Optional features an engine supports, listed by `kronos engines`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    pub schema_only: bool,         // dump_mode = "schema_only"
+    pub data_only: bool,           // dump_mode = "data_only"
+    pub exclude_tables: bool,      // exclude_tables and use_default_excludes
+    pub database_patterns: bool,   // Glob patterns in `databases`
+    pub privilege_check: bool,     // Missing dump privileges are reported before backing up
+    pub consistent_snapshot: bool, // Server-wide write lock for consistent_snapshot
+}
+
+impl Capabilities {
+    /// Names of the supported features, in the config's spelling
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (self.schema_only, "schema_only"),
+            (self.data_only, "data_only"),
+            (self.exclude_tables, "exclude_tables"),
+            (self.database_patterns, "database_patterns"),
+            (self.privilege_check, "privilege_check"),
+            (self.consistent_snapshot, "consistent_snapshot"),
+        ]
+        .into_iter()
+        .filter_map(|(supported, name)| supported.then_some(name))
+        .collect()
+    }
+}
+
+/// Snapshots exported for dumping several databases as of one moment, by database. Each stays
+/// importable while the session that exported it is kept here; dropping it ends that session.
+#[derive(Debug, Default)]
+pub struct ExportedSnapshots {
+    snapshots: BTreeMap<String, (String, Child)>,
+}
+
+impl ExportedSnapshots {
+    /// Keep `session`, which holds the snapshot `id` exported in `database` open
+    pub fn insert(&mut self, database: &str, id: String, session: Child) {
+        self.snapshots.insert(database.to_string(), (id, session));
+    }
+
+    /// Snapshot id for a database's dump to import
+    pub fn get(&self, database: &str) -> Option<&str> {
+        self.snapshots.get(database).map(|(id, _)| id.as_str())
+    }
+
+    /// End the session holding a database's snapshot once its dump has imported it
+    pub fn release(&mut self, database: &str) {
+        self.snapshots.remove(database);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
 }
 
 /// Connection health status
@@ -31,31 +103,153 @@ pub trait DatabaseConnection: Send + Sync {
     /// Perform backup of specified databases to the given path
     async fn backup(&self, backup_path: &Path) -> Result<()>;
     
+    /// Load the dumps `backup` wrote to `backup_path` back into the configured databases,
+    /// replacing what they hold. Engines that can't replay their dumps refuse.
+    async fn restore(&self, _backup_path: &Path) -> Result<()> {
+        Err(Error::Restore(format!("Restoring {} backups is not supported", self.database_type())))
+    }
+    
     /// Get the database type name (e.g., "mysql", "postgres", "sqlite", "mongodb")
     fn database_type(&self) -> &'static str;
     
-    /// Validate configuration for this database type
-    fn validate_config(&self, config: &DatabaseConfig) -> Result<()>;
+    /// Optional features this engine supports
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+    
+    /// Check the configuration's structure only, without touching the filesystem or network,
+    /// so a config meant for another host can be linted anywhere
+    fn validate_config_static(&self, config: &DatabaseConfig) -> Result<()>;
+    
+    /// Validate configuration for this database type, including local files it refers to
+    fn validate_config(&self, config: &DatabaseConfig) -> Result<()> {
+        self.validate_config_static(config)
+    }
     
     /// Get estimated backup size for planning purposes
     async fn estimate_backup_size(&self) -> Result<u64>;
+    
+    /// Configured databases that don't exist on the server
+    async fn find_missing_databases(&self) -> Result<Vec<String>>;
+    
+    /// Check that the configured user holds the privileges a dump needs, returning the missing ones.
+    /// Engines without privilege checks report nothing missing.
+    async fn verify_privileges(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+    
+    /// Concrete names of the configured databases when entries can be patterns, resolved at
+    /// backup time. None means the configured names are used as they are.
+    async fn resolve_databases(&self) -> Result<Option<Vec<String>>> {
+        Ok(None)
+    }
+    
+    /// Database names returned by a `databases_from = "query:..."` query, the first column of each
+    /// row. Only engines with a SQL client support it.
+    async fn list_from_query(&self, _query: &str) -> Result<Vec<String>> {
+        Err(Error::Config(format!("`databases_from` queries are not supported for {}", self.database_type())))
+    }
+    
+    /// Row count of each table (MongoDB: collection) in one database, for checking a restore
+    /// against. Engines that can't count report nothing.
+    async fn row_counts(&self, _database: &str) -> Result<BTreeMap<String, u64>> {
+        Ok(BTreeMap::new())
+    }
+    
+    /// Hash of each table's (MongoDB: collection's) contents in one database, to tell which
+    /// changed since an earlier backup. Engines that can't hash report nothing.
+    async fn content_hashes(&self, _database: &str) -> Result<BTreeMap<String, String>> {
+        Ok(BTreeMap::new())
+    }
+    
+    /// Rows of each table in one database that `max_blob_bytes` leaves out of its dump, for tables
+    /// where there are any. Engines without binary column filtering report nothing.
+    async fn oversized_rows(&self, _database: &str) -> Result<BTreeMap<String, u64>> {
+        Ok(BTreeMap::new())
+    }
+    
+    /// Block writes to the whole server until `unlock`, so dumps of several engines can be taken
+    /// at one consistent moment. Returns false for engines that have no such lock.
+    async fn lock(&self) -> Result<bool> {
+        Ok(false)
+    }
+    
+    /// Release the lock taken by `lock`
+    async fn unlock(&self) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Export a snapshot in each configured database, all taken back to back, for their dumps to
+    /// share. Engines without exportable snapshots return none.
+    async fn export_snapshots(&self) -> Result<ExportedSnapshots> {
+        Ok(ExportedSnapshots::default())
+    }
+    
+    /// Predict how long dumping the configured databases will take from earlier dumps in `history`.
+    /// None when there is nothing to go on.
+    async fn estimate_duration(&self, _history: &DumpHistory) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+}
+
+/// Parse `name<separator>count` lines from a client's unaligned output, skipping anything else
+pub fn parse_row_counts(output: &str, separator: char) -> BTreeMap<String, u64> {
+    output.lines()
+        .filter_map(|line| {
+            let (name, count) = line.rsplit_once(separator)?;
+            Some((name.to_string(), count.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// SQL condition that holds for rows whose binary `columns` (already quoted) are all NULL or at
+/// most `max_bytes` long, the rows `max_blob_bytes` keeps
+pub fn blob_size_filter(columns: &[String], max_bytes: u64) -> String {
+    columns.iter()
+        .map(|column| format!("({} IS NULL OR OCTET_LENGTH({}) <= {})", column, column, max_bytes))
+        .collect::<Vec<_>>()
+        .join(" AND ")
 }
 
-/// Factory for creating database connections
+/// Constructor that builds a connection for one database type
+pub type ConnectionConstructor =
+    Box<dyn for<'a> Fn(&'a DatabaseConfig) -> Box<dyn DatabaseConnection + 'a> + Send + Sync>;
+
+/// Registered database types, keyed by name
+static REGISTRY: RwLock<BTreeMap<String, ConnectionConstructor>> = RwLock::new(BTreeMap::new());
+
+/// Factory for creating database connections from the engine registry
 pub struct DatabaseConnectionFactory;
 
 impl DatabaseConnectionFactory {
+    /// Register a database type; registering an existing name replaces its constructor
+    pub fn register<F>(name: &str, constructor: F)
+    where
+        F: for<'a> Fn(&'a DatabaseConfig) -> Box<dyn DatabaseConnection + 'a> + Send + Sync + 'static,
+    {
+        REGISTRY
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), Box::new(constructor));
+    }
+
+    /// Register the engines built into kronos; called once at startup
+    pub fn register_builtins() {
+        Self::register("mysql", |config| Box::new(super::mysql::MySQLDatabase::new(config)));
+        Self::register("postgres", |config| Box::new(super::postgres::PostgreSQLDatabase::new(config)));
+        Self::register("sqlite", |config| Box::new(super::sqlite::SQLiteDatabase::new(config)));
+        Self::register("mongodb", |config| Box::new(super::mongodb::MongoDatabase::new(config)));
+    }
+
     /// Create a database connection based on type
     pub fn create_connection<'a>(
         db_type: &str,
         config: &'a DatabaseConfig,
     ) -> Result<Box<dyn DatabaseConnection + 'a>> {
-        match db_type {
-            "mysql" => Ok(Box::new(super::mysql::MySQLDatabase::new(config))),
-            "postgres" => Ok(Box::new(super::postgres::PostgreSQLDatabase::new(config))),
-            "sqlite" => Ok(Box::new(super::sqlite::SQLiteDatabase::new(config))),
-            "mongodb" => Ok(Box::new(super::mongodb::MongoDatabase::new(config))),
-            _ => Err(crate::error::Error::Database(format!(
+        let registry = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+        match registry.get(db_type) {
+            Some(constructor) => Ok(constructor(config)),
+            None => Err(crate::error::Error::Database(format!(
                 "Unsupported database type: {}",
                 db_type
             ))),
@@ -63,7 +257,33 @@ impl DatabaseConnectionFactory {
     }
 
     /// Get list of supported database types
-    pub fn supported_types() -> Vec<&'static str> {
-        vec!["mysql", "postgres", "sqlite", "mongodb"]
+    pub fn supported_types() -> Vec<String> {
+        REGISTRY
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_row_counts() {
+        let counts = parse_row_counts("public.orders|120\n\"odd|name\".t|3\nnot a row\n(2 rows)\n", '|');
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["public.orders"], 120);
+        assert_eq!(counts["\"odd|name\".t"], 3);
+    }
+
+    #[test]
+    fn builds_blob_size_filter() {
+        assert_eq!(
+            blob_size_filter(&["`data`".to_string(), "`thumb`".to_string()], 1024),
+            "(`data` IS NULL OR OCTET_LENGTH(`data`) <= 1024) AND (`thumb` IS NULL OR OCTET_LENGTH(`thumb`) <= 1024)"
+        );
     }
-}
\ No newline at end of file
+}
@@ -2,7 +2,10 @@ pub mod connection;
 pub mod sqlite;
 pub mod mysql;
 pub mod postgres;
+pub mod remote;
 pub mod mongodb;
+pub mod sources;
+pub mod template;
 
 #[cfg(test)]
 pub mod test_framework;
\ No newline at end of file
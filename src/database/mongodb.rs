@@ -1,19 +1,30 @@
-use crate::config::DatabaseConfig;
-use crate::database::connection::{DatabaseConnection, DatabaseInfo, ConnectionStatus};
+use crate::backup::history::DumpHistory;
+use crate::config::{DatabaseConfig, DumpMode};
+use crate::database::connection::{Capabilities, DatabaseConnection, DatabaseInfo, ConnectionStatus};
+use crate::database::template::{run_template_command, template_command};
 use crate::error::{Error, Result};
+use crate::utils::priority::apply_priority;
+use crate::utils::user::apply_run_as_user;
 use async_trait::async_trait;
+use std::collections::BTreeMap;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use tokio::fs;
 use tokio::process::Command as AsyncCommand;
 use serde_json::Value;
 
+/// Shell command that releases an fsyncLock left behind, repeated until `lockCount` is 0
+const FSYNC_UNLOCK_HINT: &str = "mongosh admin --eval 'db.fsyncUnlock()'";
+
 pub struct MongoDatabase<'a> {
     config: &'a DatabaseConfig,
+    locked: AtomicBool, // fsyncLock is held by this connection; it outlives the client that took it
 }
 
 impl<'a> MongoDatabase<'a> {
     pub fn new(config: &'a DatabaseConfig) -> Self {
-        MongoDatabase { config }
+        MongoDatabase { config, locked: AtomicBool::new(false) }
     }
 
     fn get_connection_string(&self) -> String {
@@ -36,14 +47,25 @@ impl<'a> MongoDatabase<'a> {
     }
 
     async fn execute_mongo_command(&self, database: &str, command: &str) -> Result<String> {
+        self.run_mongo(database, command, &[]).await
+    }
+
+    fn mongo_command(&self, database: &str, command: &str, extra_args: &[&str]) -> Result<AsyncCommand> {
         let mut cmd = AsyncCommand::new("mongo");
+        apply_run_as_user(&mut cmd, self.config)?;
         cmd.args(&self.get_connection_args());
+        cmd.args(extra_args);
         cmd.args(&[
             database,
             "--quiet",
             "--eval",
             command,
         ]);
+        Ok(cmd)
+    }
+
+    async fn run_mongo(&self, database: &str, command: &str, extra_args: &[&str]) -> Result<String> {
+        let mut cmd = self.mongo_command(database, command, extra_args)?;
         
         let output = cmd.output().await
             .map_err(|e| Error::Database(format!("Failed to execute mongo command: {}", e)))?;
@@ -58,14 +80,38 @@ impl<'a> MongoDatabase<'a> {
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
     }
 
-    async fn execute_mongodump(&self, database: &str, output_path: &Path) -> Result<()> {
+    async fn execute_mongodump(&self, database: &str, collection: Option<&str>, output_path: &Path) -> Result<()> {
+        // Like mongodump's --out, {output} is the directory the database's dump directory goes in
+        if let Some(mut cmd) = template_command(self.config, database, output_path) {
+            apply_run_as_user(&mut cmd, self.config)?;
+            apply_priority(&mut cmd, self.config);
+            return run_template_command(cmd).await;
+        }
+
         let mut cmd = AsyncCommand::new("mongodump");
+        apply_run_as_user(&mut cmd, self.config)?;
+        apply_priority(&mut cmd, self.config);
+        cmd.kill_on_drop(true);
         cmd.args(&self.get_connection_args());
         cmd.args(&[
             format!("--db={}", database),
             format!("--out={}", output_path.to_string_lossy()),
             "--gzip".to_string(),
         ]);
+        // mongodump rejects --excludeCollection alongside --collection
+        match collection {
+            Some(collection) => {
+                cmd.arg(format!("--collection={}", collection));
+                if let Some(query) = &self.config.query {
+                    cmd.arg(format!("--query={}", query));
+                }
+            }
+            None => {
+                for excluded in self.config.excluded_tables() {
+                    cmd.arg(format!("--excludeCollection={}", excluded));
+                }
+            }
+        }
         
         let output = cmd.output().await
             .map_err(|e| Error::Database(format!("Failed to execute mongodump: {}", e)))?;
@@ -80,24 +126,62 @@ impl<'a> MongoDatabase<'a> {
         Ok(())
     }
 
+    /// JSON printed by a read-only shell command. Startup scripts and shell warnings can end up
+    /// on stdout, so when no JSON is found the command is re-run without ~/.mongorc.js (--norc).
+    async fn mongo_json(&self, database: &str, command: &str) -> Result<Value> {
+        let output = self.execute_mongo_command(database, command).await?;
+        if let Some(reply) = parse_json_reply(&output) {
+            return Ok(reply);
+        }
+        log::debug!("No JSON in mongo output {:?}; retrying with --norc", output);
+        let output = self.run_mongo(database, command, &["--norc"]).await?;
+        parse_json_reply(&output)
+            .ok_or_else(|| Error::Database(format!("Unexpected mongo output: {}", output.trim())))
+    }
+
+    async fn export_collection_structure(&self, database: &str, output_path: &Path) -> Result<()> {
+        // mongodump has no schema-only mode, so export collection options and indexes instead
+        let (operator, names) = if self.config.collections.is_empty() {
+            ("$nin", self.config.excluded_tables())
+        } else {
+            ("$in", self.config.collections.clone())
+        };
+        let names = serde_json::to_string(&names)
+            .map_err(|e| Error::Database(format!("Failed to encode collection names: {}", e)))?;
+        let filter = format!("{{ name: {{ {}: {} }} }}", operator, names);
+        let structure_command = format!(
+            "JSON.stringify(db.getCollectionInfos({}).map(function(c) {{ \
+            return {{ name: c.name, type: c.type, options: c.options, \
+                indexes: c.type === 'collection' ? db.getCollection(c.name).getIndexes() : [] }}; }}))",
+            filter
+        );
+        let structure = self.execute_mongo_command(database, &structure_command).await?;
+
+        let output_file = output_path.join(format!("{}.structure.json", database));
+        fs::write(&output_file, structure.trim()).await
+            .map_err(Error::Io)?;
+
+        Ok(())
+    }
+
     async fn get_database_stats(&self, database: &str) -> Result<DatabaseInfo> {
         let stats_command = "JSON.stringify(db.stats())";
-        let stats_result = self.execute_mongo_command(database, stats_command).await?;
-        
-        let size = if let Ok(stats) = serde_json::from_str::<Value>(&stats_result) {
-            stats["dataSize"].as_u64()
-        } else {
-            None
+        let (size, table_count) = match self.mongo_json(database, stats_command).await {
+            Ok(stats) => (json_u64(&stats["dataSize"]), json_u64(&stats["collections"])),
+            Err(e) => {
+                log::warn!("Failed to read stats of database {}: {}", database, e);
+                (None, None)
+            }
         };
         
         let version_command = "JSON.stringify(db.version())";
-        let version_result = self.execute_mongo_command(database, version_command).await?;
-        let version = version_result.trim().trim_matches('"').to_string();
+        let version = self.mongo_json(database, version_command).await?;
         
         Ok(DatabaseInfo {
             name: database.to_string(),
             size,
-            schema_version: Some(version),
+            schema_version: version.as_str().map(str::to_string),
+            table_count,
         })
     }
 }
@@ -105,7 +189,11 @@ impl<'a> MongoDatabase<'a> {
 #[async_trait]
 impl<'a> DatabaseConnection for MongoDatabase<'a> {
     async fn test_connection(&self) -> Result<ConnectionStatus> {
-        match self.execute_mongo_command("admin", "db.runCommand('ping')").await {
+        let (database, command) = match &self.config.health_query {
+            Some(command) => (self.config.databases.first().map_or("admin", String::as_str), command.as_str()),
+            None => ("admin", "db.runCommand('ping')"),
+        };
+        match self.execute_mongo_command(database, command).await {
             Ok(_) => Ok(ConnectionStatus::Connected),
             Err(e) => Ok(ConnectionStatus::Error(e.to_string())),
         }
@@ -123,6 +211,7 @@ impl<'a> DatabaseConnection for MongoDatabase<'a> {
                         name: db_name.clone(),
                         size: None,
                         schema_version: None,
+                        table_count: None,
                     });
                     log::warn!("Failed to get stats for database {}: {}", db_name, e);
                 }
@@ -137,43 +226,108 @@ impl<'a> DatabaseConnection for MongoDatabase<'a> {
             .map_err(Error::Io)?;
         
         for db_name in &self.config.databases {
-            self.execute_mongodump(db_name, backup_path).await?;
+            match self.config.dump_mode {
+                DumpMode::SchemaOnly => self.export_collection_structure(db_name, backup_path).await?,
+                _ if self.config.collections.is_empty() => self.execute_mongodump(db_name, None, backup_path).await?,
+                _ => {
+                    for collection in &self.config.collections {
+                        self.execute_mongodump(db_name, Some(collection), backup_path).await?;
+                    }
+                }
+            }
         }
         
         Ok(())
     }
 
+    async fn restore(&self, backup_path: &Path) -> Result<()> {
+        for db_name in &self.config.databases {
+            if !backup_path.join(db_name).is_dir() {
+                return Err(Error::Restore(format!("No dump of {} in the backup", db_name)));
+            }
+            let mut cmd = AsyncCommand::new("mongorestore");
+            apply_run_as_user(&mut cmd, self.config)?;
+            cmd.args(self.get_connection_args());
+            // Dumped collections replace their namesakes; other collections are left alone
+            cmd.args(&[
+                format!("--nsInclude={}.*", db_name),
+                format!("--dir={}", backup_path.to_string_lossy()),
+                "--gzip".to_string(),
+                "--drop".to_string(),
+            ]);
+            if let Some(target) = &self.config.restore_as {
+                cmd.args([format!("--nsFrom={}.*", db_name), format!("--nsTo={}.*", target)]);
+            }
+
+            let output = cmd.output().await
+                .map_err(|e| Error::Database(format!("Failed to execute mongorestore: {}", e)))?;
+            if !output.status.success() {
+                return Err(Error::Restore(format!(
+                    "mongorestore of {} failed: {}",
+                    db_name,
+                    String::from_utf8_lossy(&output.stderr)
+                )));
+            }
+        }
+        Ok(())
+    }
+
     fn database_type(&self) -> &'static str {
         "mongodb"
     }
 
-    fn validate_config(&self, config: &DatabaseConfig) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            schema_only: true,
+            exclude_tables: true,
+            consistent_snapshot: true,
+            ..Default::default()
+        }
+    }
+
+    fn validate_config_static(&self, config: &DatabaseConfig) -> Result<()> {
         if config.host.is_empty() {
             return Err(Error::Config("MongoDB host cannot be empty".to_string()));
         }
+        if config.port == 0 {
+            return Err(Error::Config("MongoDB port cannot be 0".to_string()));
+        }
         if config.user.is_empty() {
             return Err(Error::Config("MongoDB user cannot be empty".to_string()));
         }
         if config.password.is_empty() {
             return Err(Error::Config("MongoDB password cannot be empty".to_string()));
         }
-        if config.databases.is_empty() {
+        if config.dump_mode == DumpMode::DataOnly {
+            return Err(Error::Config("MongoDB does not support dump_mode \"data_only\"; dumps always include collection metadata".to_string()));
+        }
+        if config.databases.is_empty() && config.databases_from.is_none() {
             return Err(Error::Config("At least one database must be specified".to_string()));
         }
         Ok(())
     }
 
+    async fn find_missing_databases(&self) -> Result<Vec<String>> {
+        let list_command = "JSON.stringify(db.adminCommand({ listDatabases: 1, nameOnly: true }) \
+            .databases.map(function(d) { return d.name; }))";
+        let existing: Vec<String> = serde_json::from_value(self.mongo_json("admin", list_command).await?)
+            .map_err(|e| Error::Database(format!("Failed to parse MongoDB database list: {}", e)))?;
+        
+        Ok(self.config.databases.iter()
+            .filter(|db| !existing.contains(db))
+            .cloned()
+            .collect())
+    }
+
     async fn estimate_backup_size(&self) -> Result<u64> {
         let mut total_size = 0u64;
         
         for db_name in &self.config.databases {
             let stats_command = "JSON.stringify(db.stats())";
-            match self.execute_mongo_command(db_name, stats_command).await {
-                Ok(stats_result) => {
-                    if let Ok(stats) = serde_json::from_str::<Value>(&stats_result) {
-                        if let Some(size) = stats["dataSize"].as_u64() {
-                            total_size += size;
-                        }
+            match self.mongo_json(db_name, stats_command).await {
+                Ok(stats) => {
+                    if let Some(size) = json_u64(&stats["dataSize"]) {
+                        total_size += size;
                     }
                 }
                 Err(e) => {
@@ -185,4 +339,115 @@ impl<'a> DatabaseConnection for MongoDatabase<'a> {
         // Add 25% overhead for BSON format and compression
         Ok((total_size as f64 * 1.25) as u64)
     }
-}
\ No newline at end of file
+
+    async fn row_counts(&self, database: &str) -> Result<BTreeMap<String, u64>> {
+        let count_command = "JSON.stringify(db.getCollectionNames().reduce(function(counts, name) { \
+            counts[name] = db.getCollection(name).countDocuments({}); return counts; }, {}))";
+        let counts = self.mongo_json(database, count_command).await?;
+        serde_json::from_value(counts)
+            .map_err(|e| Error::Database(format!("Failed to parse collection counts of {}: {}", database, e)))
+    }
+
+    /// dbHash reads every collection and returns an MD5 of each one's documents. It holds a lock
+    /// on the database while it reads, so writes to it wait until every document was hashed.
+    async fn content_hashes(&self, database: &str) -> Result<BTreeMap<String, String>> {
+        let reply = self.mongo_json(database, "JSON.stringify(db.runCommand({ dbHash: 1 }))").await?;
+        if reply["ok"].as_f64() != Some(1.0) {
+            return Err(Error::Database(format!("MongoDB dbHash of {} failed: {}", database, reply)));
+        }
+        serde_json::from_value(reply["collections"].clone())
+            .map_err(|e| Error::Database(format!("Failed to parse collection hashes of {}: {}", database, e)))
+    }
+
+    /// fsyncLock flushes pending writes and blocks new ones server-wide until fsyncUnlock. Unlike
+    /// MySQL's lock it isn't tied to a session, so it is released on drop if `unlock` never runs.
+    async fn lock(&self) -> Result<bool> {
+        let result = self.execute_mongo_command("admin", "JSON.stringify(db.fsyncLock())").await?;
+        check_command_ok(&result, "fsyncLock")?;
+        self.locked.store(true, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    async fn unlock(&self) -> Result<()> {
+        let result = self.execute_mongo_command("admin", "JSON.stringify(db.fsyncUnlock())").await
+            .and_then(|result| check_command_ok(&result, "fsyncUnlock"));
+        if let Err(e) = result {
+            return Err(Error::Database(format!("{}; writes stay blocked until `{}` is run", e, FSYNC_UNLOCK_HINT)));
+        }
+        self.locked.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn estimate_duration(&self, history: &DumpHistory) -> Result<Option<Duration>> {
+        Ok(history.predict_engine(self.database_type(), &self.get_database_info().await?))
+    }
+}
+
+/// A run cut short (timed out, cancelled or panicking) drops its connections without unlocking,
+/// which would leave every write on the server blocked
+impl Drop for MongoDatabase<'_> {
+    fn drop(&mut self) {
+        if !self.locked.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let unlocked = self.mongo_command("admin", "JSON.stringify(db.fsyncUnlock())", &[])
+            .and_then(|mut cmd| cmd.as_std_mut().output().map_err(Error::Io))
+            .and_then(|output| check_command_ok(&String::from_utf8_lossy(&output.stdout), "fsyncUnlock"));
+        match unlocked {
+            Ok(()) => log::warn!("Released the MongoDB fsyncLock of an interrupted backup"),
+            Err(e) => log::error!(
+                "Failed to release the MongoDB fsyncLock of an interrupted backup: {}; writes stay blocked until `{}` is run",
+                e, FSYNC_UNLOCK_HINT
+            ),
+        }
+    }
+}
+
+/// Fail unless a mongo shell command's JSON reply reports `ok: 1`. Not retried like
+/// `mongo_json`: running fsyncLock twice would need two unlocks.
+fn check_command_ok(reply: &str, command: &str) -> Result<()> {
+    let reply = parse_json_reply(reply)
+        .ok_or_else(|| Error::Database(format!("Failed to parse {} reply: {}", command, reply.trim())))?;
+    if reply["ok"].as_f64() != Some(1.0) {
+        return Err(Error::Database(format!("MongoDB {} failed: {}", command, reply)));
+    }
+    Ok(())
+}
+
+/// JSON value in shell output: the whole output, or else the last line that parses, skipping
+/// warnings and banners the shell prints around it
+fn parse_json_reply(output: &str) -> Option<Value> {
+    serde_json::from_str(output.trim()).ok().or_else(|| {
+        output.lines()
+            .rev()
+            .map(str::trim)
+            .filter(|line| line.starts_with(['{', '[', '"']))
+            .find_map(|line| serde_json::from_str(line).ok())
+    })
+}
+
+/// Count or size from a stats reply, which shells print as an integer, a double or an
+/// Extended JSON `{"$numberLong": "..."}` depending on version and magnitude
+fn json_u64(value: &Value) -> Option<u64> {
+    value.as_u64()
+        .or_else(|| value.as_f64().filter(|v| *v >= 0.0).map(|v| v as u64))
+        .or_else(|| value["$numberLong"].as_str()?.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_json_among_shell_noise() {
+        let output = "Warning: Could not access file: ENOENT: no such file or directory, mkdir '/home/kronos/.mongodb'\n\
+            {\"db\":\"shop\",\"collections\":4,\"dataSize\":1.5e3,\"indexes\":{\"$numberLong\":\"9\"},\"ok\":1}\n";
+        let stats = parse_json_reply(output).unwrap();
+        assert_eq!(json_u64(&stats["collections"]), Some(4));
+        assert_eq!(json_u64(&stats["dataSize"]), Some(1500));
+        assert_eq!(json_u64(&stats["indexes"]), Some(9));
+        assert_eq!(parse_json_reply("MongoDB shell version v4.4.6\n\"4.4.6\"\n").unwrap(), "4.4.6");
+        assert!(parse_json_reply("connecting to: mongodb://localhost\n").is_none());
+        assert!(check_command_ok("{\"ok\":0,\"errmsg\":\"not authorized\"}", "fsyncLock").is_err());
+    }
+}
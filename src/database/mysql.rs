@@ -1,36 +1,74 @@
-use crate::config::DatabaseConfig;
-use crate::database::connection::{DatabaseConnection, DatabaseInfo, ConnectionStatus};
+use crate::backup::history::DumpHistory;
+use crate::config::{DatabaseConfig, DumpMode};
+use crate::database::connection::{blob_size_filter, parse_row_counts, Capabilities, DatabaseConnection, DatabaseInfo, ConnectionStatus};
+use crate::database::remote::{output_over_ssh, run_dump_over_ssh};
+use crate::database::template::{run_template_command, template_command};
 use crate::error::{Error, Result};
+use crate::utils::permissions::ensure_private_file;
+use crate::utils::priority::apply_priority;
+use crate::utils::user::apply_run_as_user;
 use async_trait::async_trait;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
 use tokio::fs;
-use tokio::process::Command as AsyncCommand;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::process::{Child, ChildStdin, Command as AsyncCommand};
+use tokio::sync::Mutex;
+
+/// Marker the lock session prints once FLUSH TABLES WITH READ LOCK has been granted
+const LOCKED_MARKER: &str = "kronos-locked";
 
 pub struct MySQLDatabase<'a> {
     config: &'a DatabaseConfig,
+    lock_session: Mutex<Option<(Child, ChildStdin)>>, // mysql client holding the global read lock
 }
 
 impl<'a> MySQLDatabase<'a> {
     pub fn new(config: &'a DatabaseConfig) -> Self {
-        MySQLDatabase { config }
+        MySQLDatabase { config, lock_session: Mutex::new(None) }
     }
 
     fn get_connection_args(&self) -> Vec<String> {
-        vec![
-            format!("--host={}", self.config.host),
-            format!("--port={}", self.config.port),
-            format!("--user={}", self.config.user),
-            format!("--password={}", self.config.password),
-        ]
+        let mut args = Vec::new();
+        // --defaults-extra-file must be the first option on the command line
+        if let Some(defaults_file) = &self.config.defaults_file {
+            args.push(format!("--defaults-extra-file={}", defaults_file));
+        }
+        args.push(format!("--host={}", self.config.host));
+        args.push(format!("--port={}", self.config.port));
+        args.push(format!("--user={}", self.config.user));
+        if self.config.defaults_file.is_none() {
+            args.push(format!("--password={}", self.config.password));
+        }
+        args
     }
 
+    /// Connection options for a client command. Over ssh_target the password goes in MYSQL_PWD,
+    /// which is sent over ssh's stdin, to keep it off the SSH host's command line.
+    fn apply_connection(&self, cmd: &mut AsyncCommand) {
+        if self.config.ssh_target.is_some() {
+            cmd.args(self.get_connection_args().into_iter().filter(|arg| !arg.starts_with("--password=")));
+            cmd.env("MYSQL_PWD", &self.config.password);
+        } else {
+            cmd.args(self.get_connection_args());
+        }
+    }
+
+    /// Run the mysql client with `args`, on the ssh_target when there is one so queries reach
+    /// the server the dumps are taken from
     async fn execute_mysql_command(&self, args: &[String]) -> Result<String> {
         let mut cmd = AsyncCommand::new("mysql");
-        cmd.args(&self.get_connection_args());
+        apply_run_as_user(&mut cmd, self.config)?;
+        self.apply_connection(&mut cmd);
         cmd.args(args);
         
-        let output = cmd.output().await
-            .map_err(|e| Error::Database(format!("Failed to execute mysql command: {}", e)))?;
+        let output = match self.config.ssh_target {
+            Some(_) => output_over_ssh(self.config, &cmd, &[], "mysql").await?,
+            None => cmd.output().await
+                .map_err(|e| Error::Database(format!("Failed to execute mysql command: {}", e)))?,
+        };
         
         if !output.status.success() {
             return Err(Error::Database(format!(
@@ -42,21 +80,256 @@ impl<'a> MySQLDatabase<'a> {
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
     }
 
+    /// Run the statements in `file` against `database`
+    async fn load_sql_file(&self, database: &str, file: &Path) -> Result<()> {
+        let input = std::fs::File::open(file).map_err(Error::Io)?;
+        let mut cmd = AsyncCommand::new("mysql");
+        apply_run_as_user(&mut cmd, self.config)?;
+        cmd.args(self.get_connection_args());
+        cmd.arg(database);
+        cmd.stdin(Stdio::from(input));
+
+        let output = cmd.output().await
+            .map_err(|e| Error::Database(format!("Failed to execute mysql command: {}", e)))?;
+        if !output.status.success() {
+            return Err(Error::Restore(format!(
+                "Loading {:?} into {} failed: {}",
+                file,
+                database,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Load a tab_format dump of `database`: each table's CREATE TABLE from `<table>.sql`, then
+    /// its rows from `<table>.txt` with mysqlimport, which loads each file into its namesake table
+    async fn load_tab_files(&self, database: &str, dir: &Path) -> Result<()> {
+        let mut schemas = Vec::new();
+        let mut rows = Vec::new();
+        for entry in std::fs::read_dir(dir).map_err(Error::Io)? {
+            let path = entry.map_err(Error::Io)?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("sql") => schemas.push(path),
+                Some("txt") => rows.push(path),
+                _ => {}
+            }
+        }
+        schemas.sort();
+        rows.sort();
+        for schema in &schemas {
+            self.load_sql_file(database, schema).await?;
+        }
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut cmd = AsyncCommand::new("mysqlimport");
+        apply_run_as_user(&mut cmd, self.config)?;
+        cmd.args(self.get_connection_args());
+        // The files are read here and sent to the server, which needs local_infile enabled
+        cmd.arg("--local");
+        cmd.arg(database);
+        cmd.args(&rows);
+        let output = cmd.output().await
+            .map_err(|e| Error::Database(format!("Failed to execute mysqlimport: {}", e)))?;
+        if !output.status.success() {
+            return Err(Error::Restore(format!(
+                "mysqlimport of the tab_format rows of {} failed: {}",
+                database,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Size in bytes and table count of every configured database, from a single grouped query
+    /// rather than one information_schema scan per database. Databases without tables are absent.
+    async fn get_table_stats(&self) -> Result<HashMap<String, TableStats>> {
+        let schemas: Vec<String> = self.config.databases.iter()
+            .map(|db| quote_literal(db))
+            .collect();
+        let stats_query = format!(
+            "--execute=SELECT table_schema, COALESCE(SUM(data_length + index_length), 0), COUNT(*) \
+             FROM information_schema.tables WHERE table_schema IN ({}) GROUP BY table_schema",
+            schemas.join(", ")
+        );
+        let result = self.execute_mysql_command(&["--batch".to_string(), "--skip-column-names".to_string(), stats_query]).await?;
+        Ok(parse_table_stats(&result))
+    }
+
+    /// Names of the tables and views in a database
+    async fn table_names(&self, database: &str) -> Result<Vec<String>> {
+        let query = format!(
+            "--execute=SELECT table_name FROM information_schema.tables WHERE table_schema={}",
+            quote_literal(database)
+        );
+        let result = self.execute_mysql_command(&[query, "--skip-column-names".to_string()]).await?;
+        Ok(result.lines().map(|line| line.trim().to_string()).filter(|line| !line.is_empty()).collect())
+    }
+
+    async fn get_myisam_tables(&self, database: &str) -> Result<Vec<String>> {
+        let query = format!(
+            "--execute=SELECT table_name FROM information_schema.tables WHERE table_schema={} AND engine='MyISAM'",
+            quote_literal(database)
+        );
+        let result = self.execute_mysql_command(&[query, "--skip-column-names".to_string()]).await?;
+        Ok(result.lines().map(|line| line.trim().to_string()).filter(|line| !line.is_empty()).collect())
+    }
+
+    /// MyISAM tables in `database`, or None with a warning when they can't be looked up; the
+    /// check only refines how the dump is taken, so it never fails the backup
+    async fn find_myisam_tables(&self, database: &str) -> Option<Vec<String>> {
+        match self.get_myisam_tables(database).await {
+            Ok(tables) => Some(tables),
+            Err(e) => {
+                log::warn!("Could not check database {} for MyISAM tables: {}", database, e);
+                None
+            }
+        }
+    }
+
+    /// Privileges mysqldump needs on each database with the current options
+    fn required_privileges(&self) -> Vec<&'static str> {
+        let mut privileges = vec!["SELECT", "SHOW VIEW"];
+        if self.config.dump_mode != DumpMode::DataOnly {
+            privileges.extend(["TRIGGER", "EVENT"]);
+        }
+        if self.config.lock_tables == Some(true) {
+            privileges.push("LOCK TABLES");
+        }
+        if self.config.tab_format == Some(true) {
+            privileges.push("FILE");
+        }
+        privileges
+    }
+
+    async fn get_granted_privileges(&self, database: &str) -> Result<Vec<String>> {
+        // Global grants apply to every schema; schema grants only to this one
+        let grantee = "CONCAT(\"'\", SUBSTRING_INDEX(CURRENT_USER(), '@', 1), \"'@'\", SUBSTRING_INDEX(CURRENT_USER(), '@', -1), \"'\")";
+        // Table grants count when they cover every table; information_schema only lists the tables
+        // the user has some privilege on, which are also all mysqldump would see
+        let schema = quote_literal(database);
+        let query = format!(
+            "--execute=SELECT privilege_type FROM information_schema.user_privileges WHERE grantee = {grantee} \
+             UNION SELECT privilege_type FROM information_schema.schema_privileges WHERE grantee = {grantee} AND table_schema = {schema} \
+             UNION SELECT privilege_type FROM information_schema.table_privileges WHERE grantee = {grantee} AND table_schema = {schema} \
+             GROUP BY privilege_type \
+             HAVING COUNT(*) = (SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = {schema})",
+        );
+        let result = self.execute_mysql_command(&[query, "--skip-column-names".to_string()]).await?;
+        Ok(result.lines().map(|line| line.trim().to_string()).filter(|line| !line.is_empty()).collect())
+    }
+
     async fn execute_mysqldump(&self, database: &str, output_path: &Path) -> Result<()> {
+        if let Some(mut cmd) = template_command(self.config, database, &output_path.join(format!("{}.sql", database))) {
+            cmd.env("MYSQL_PWD", &self.config.password);
+            apply_run_as_user(&mut cmd, self.config)?;
+            apply_priority(&mut cmd, self.config);
+            return run_template_command(cmd).await;
+        }
+
         let mut cmd = AsyncCommand::new("mysqldump");
-        cmd.args(&self.get_connection_args());
-        cmd.args(&[
-            "--single-transaction",
-            "--routines",
-            "--triggers",
-            "--events",
+        apply_run_as_user(&mut cmd, self.config)?;
+        apply_priority(&mut cmd, self.config);
+        cmd.kill_on_drop(true);
+        let remote = self.config.ssh_target.is_some();
+        self.apply_connection(&mut cmd);
+
+        // --single-transaction only gives a consistent view of InnoDB tables. When the lookup fails,
+        // lock_tables = true still locks, since the user asked for it
+        let lock_tables = self.config.lock_tables == Some(true)
+            && self.find_myisam_tables(database).await.is_none_or(|tables| !tables.is_empty());
+        if lock_tables {
+            log::warn!(
+                "Database {} may contain MyISAM tables; dumping with --lock-tables, which blocks writes while each table is dumped",
+                database
+            );
+            cmd.arg("--lock-tables");
+        } else {
+            cmd.arg("--single-transaction");
+        }
+        cmd.args([
             "--add-drop-database",
             "--create-options",
-            database,
         ]);
+        let separate_routines = self.config.separate_routines == Some(true);
+        match self.config.dump_mode {
+            DumpMode::Full if separate_routines => {
+                cmd.args(["--skip-routines", "--skip-triggers", "--skip-events"]);
+            }
+            DumpMode::Full => {
+                cmd.args(["--routines", "--triggers", "--events"]);
+            }
+            DumpMode::SchemaOnly if separate_routines => {
+                cmd.args(["--no-data", "--skip-routines", "--skip-triggers", "--skip-events"]);
+            }
+            DumpMode::SchemaOnly => {
+                cmd.args(["--no-data", "--routines", "--triggers", "--events"]);
+            }
+            // Stored programs are schema, so leave them out of data-only dumps
+            DumpMode::DataOnly => {
+                cmd.args(["--no-create-info", "--skip-triggers"]);
+            }
+        }
+        // Default excludes keep their schema: a schema-only dump covers them in place, other modes
+        // leave them out here and full dumps write their definitions to <db>.schema.sql
+        let schema_only = self.config.schema_only_tables();
+        for table in self.config.excluded_tables() {
+            if self.config.dump_mode == DumpMode::SchemaOnly && schema_only.contains(&table) {
+                continue;
+            }
+            cmd.arg(format!("--ignore-table={}.{}", database, table));
+        }
+        // Tables with binary columns are dumped separately, filtered by value size
+        let blob_tables = match self.config.max_blob_bytes {
+            Some(_) => self.blob_columns(database).await?,
+            None => BTreeMap::new(),
+        };
+        for table in blob_tables.keys() {
+            cmd.arg(format!("--ignore-table={}.{}", database, table));
+        }
+        // With --tab, stdout only carries what isn't a table, such as routines and events
+        let tab_dir = match self.config.tab_format {
+            Some(true) => {
+                let dir = self.tab_scratch_dir().await?;
+                cmd.arg(format!("--tab={}", dir.path().to_string_lossy()));
+                Some(dir)
+            }
+            _ => None,
+        };
+        cmd.arg(database);
+        if remote {
+            run_dump_over_ssh(self.config, &cmd, &output_path.join(format!("{}.sql", database)), "mysqldump").await?;
+            if self.config.dump_mode == DumpMode::Full {
+                self.dump_table_schemas(database, &schema_only, output_path).await?;
+            }
+            if separate_routines {
+                self.dump_routines(database, output_path).await?;
+            }
+            return Ok(());
+        }
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
         
         let output_file = output_path.join(format!("{}.sql", database));
-        let output = cmd.output().await
+        let mut child = cmd.spawn()
+            .map_err(|e| Error::Database(format!("Failed to execute mysqldump: {}", e)))?;
+        
+        // Stream the dump to disk instead of buffering it in memory
+        let buffer_size = self.config.io_buffer_bytes();
+        let stdout = child.stdout.take()
+            .ok_or_else(|| Error::Database("Failed to capture mysqldump output".to_string()))?;
+        let file = fs::File::create(&output_file).await
+            .map_err(Error::Io)?;
+        let copy = async {
+            let mut reader = BufReader::with_capacity(buffer_size, stdout);
+            let mut writer = BufWriter::with_capacity(buffer_size, file);
+            tokio::io::copy_buf(&mut reader, &mut writer).await?;
+            writer.flush().await
+        };
+        let (copied, output) = tokio::join!(copy, child.wait_with_output());
+        let output = output
             .map_err(|e| Error::Database(format!("Failed to execute mysqldump: {}", e)))?;
         
         if !output.status.success() {
@@ -65,18 +338,261 @@ impl<'a> MySQLDatabase<'a> {
                 String::from_utf8_lossy(&output.stderr)
             )));
         }
-        
-        fs::write(&output_file, &output.stdout).await
-            .map_err(|e| Error::Io(e))?;
+        copied.map_err(Error::Io)?;
+
+        if let Some(tab_dir) = tab_dir {
+            let tables = self.table_names(database).await?;
+            collect_tab_files(tab_dir.path(), &output_path.join(database), &tables).await?;
+        }
+        if self.config.dump_mode == DumpMode::Full {
+            self.dump_table_schemas(database, &schema_only, output_path).await?;
+        }
+        if separate_routines {
+            self.dump_routines(database, output_path).await?;
+        }
+        if let Some(max_bytes) = self.config.max_blob_bytes.filter(|_| !blob_tables.is_empty()) {
+            self.dump_size_filtered(database, &blob_tables, max_bytes, output_path).await?;
+        }
         
         Ok(())
     }
+
+    /// Tables of a database with binary (BLOB, BINARY) columns, mapped to those columns quoted.
+    /// Excluded tables are left out.
+    async fn blob_columns(&self, database: &str) -> Result<BTreeMap<String, Vec<String>>> {
+        let query = format!(
+            "--execute=SELECT c.table_name, c.column_name FROM information_schema.columns c \
+             JOIN information_schema.tables t ON t.table_schema = c.table_schema AND t.table_name = c.table_name \
+             WHERE c.table_schema = {} AND t.table_type = 'BASE TABLE' \
+             AND c.data_type IN ('tinyblob', 'blob', 'mediumblob', 'longblob', 'binary', 'varbinary') \
+             ORDER BY c.table_name, c.ordinal_position",
+            quote_literal(database)
+        );
+        let result = self.execute_mysql_command(&["--batch".to_string(), "--skip-column-names".to_string(), query]).await?;
+        let excluded = self.config.excluded_tables();
+        let mut tables: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (table, column) in result.lines().filter_map(|line| line.split_once('\t')) {
+            if !excluded.iter().any(|excluded| excluded == table) {
+                tables.entry(table.to_string()).or_default().push(quote_identifier(column));
+            }
+        }
+        Ok(tables)
+    }
+
+    /// Write the rows of `tables` whose binary values fit in `max_bytes` to `<db>.filtered.sql`,
+    /// with their CREATE TABLE unless data-only. Each table is a separate mysqldump, so they are
+    /// consistent with each other and the main dump only per table. Restore it after the main dump.
+    async fn dump_size_filtered(
+        &self,
+        database: &str,
+        tables: &BTreeMap<String, Vec<String>>,
+        max_bytes: u64,
+        output_path: &Path,
+    ) -> Result<()> {
+        let file = std::fs::File::create(output_path.join(format!("{}.filtered.sql", database))).map_err(Error::Io)?;
+        for (table, columns) in tables {
+            let mut cmd = AsyncCommand::new("mysqldump");
+            apply_run_as_user(&mut cmd, self.config)?;
+            apply_priority(&mut cmd, self.config);
+            cmd.kill_on_drop(true);
+            cmd.args(self.get_connection_args());
+            cmd.arg("--single-transaction");
+            if self.config.dump_mode == DumpMode::DataOnly {
+                cmd.args(["--no-create-info", "--skip-triggers"]);
+            } else if self.config.separate_routines == Some(true) {
+                cmd.arg("--skip-triggers");
+            }
+            cmd.arg(format!("--where={}", blob_size_filter(columns, max_bytes)));
+            cmd.args([database, table]);
+            // output() would replace stdout with a pipe
+            cmd.stdout(Stdio::from(file.try_clone().map_err(Error::Io)?));
+            cmd.stderr(Stdio::piped());
+            let output = match cmd.spawn() {
+                Ok(child) => child.wait_with_output().await,
+                Err(e) => Err(e),
+            }
+            .map_err(|e| Error::Database(format!("Failed to execute mysqldump: {}", e)))?;
+            if !output.status.success() {
+                return Err(Error::Database(format!(
+                    "mysqldump of {}.{} filtered by max_blob_bytes failed: {}",
+                    database,
+                    table,
+                    String::from_utf8_lossy(&output.stderr)
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the database's stored procedures, functions, triggers and events, and nothing else,
+    /// to `<db>.routines.sql`. Triggers refer to tables, so restore this after the main dump.
+    /// Write the definitions, without rows, of whichever of `tables` exist to `<db>.schema.sql`
+    async fn dump_table_schemas(&self, database: &str, tables: &[String], output_path: &Path) -> Result<()> {
+        let existing = self.table_names(database).await?;
+        let tables: Vec<&String> = tables.iter().filter(|table| existing.contains(*table)).collect();
+        if tables.is_empty() {
+            return Ok(());
+        }
+        let mut cmd = AsyncCommand::new("mysqldump");
+        apply_run_as_user(&mut cmd, self.config)?;
+        apply_priority(&mut cmd, self.config);
+        cmd.kill_on_drop(true);
+        self.apply_connection(&mut cmd);
+        cmd.args(["--single-transaction", "--no-data", "--skip-routines", "--skip-events"]);
+        // <db>.routines.sql already carries every table's triggers
+        if self.config.separate_routines == Some(true) {
+            cmd.arg("--skip-triggers");
+        }
+        let output_file = output_path.join(format!("{}.schema.sql", database));
+        if self.config.ssh_target.is_none() {
+            cmd.arg(format!("--result-file={}", output_file.to_string_lossy()));
+        }
+        cmd.arg(database);
+        cmd.args(tables);
+        if self.config.ssh_target.is_some() {
+            return run_dump_over_ssh(self.config, &cmd, &output_file, "mysqldump of excluded tables' schema").await;
+        }
+
+        let output = cmd.output().await
+            .map_err(|e| Error::Database(format!("Failed to execute mysqldump: {}", e)))?;
+        if !output.status.success() {
+            return Err(Error::Database(format!(
+                "mysqldump of {} excluded tables' schema failed: {}",
+                database,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    async fn dump_routines(&self, database: &str, output_path: &Path) -> Result<()> {
+        let mut cmd = AsyncCommand::new("mysqldump");
+        apply_run_as_user(&mut cmd, self.config)?;
+        apply_priority(&mut cmd, self.config);
+        cmd.kill_on_drop(true);
+        self.apply_connection(&mut cmd);
+        cmd.args([
+            "--single-transaction",
+            "--no-create-info",
+            "--no-data",
+            "--no-create-db",
+            "--skip-opt",
+            "--routines",
+            "--triggers",
+            "--events",
+        ]);
+        let output_file = output_path.join(format!("{}.routines.sql", database));
+        if self.config.ssh_target.is_some() {
+            cmd.arg(database);
+            return run_dump_over_ssh(self.config, &cmd, &output_file, "mysqldump of routines").await;
+        }
+        cmd.arg(format!("--result-file={}", output_file.to_string_lossy()));
+        cmd.arg(database);
+
+        let output = cmd.output().await
+            .map_err(|e| Error::Database(format!("Failed to execute mysqldump: {}", e)))?;
+        if !output.status.success() {
+            return Err(Error::Database(format!(
+                "mysqldump of {} routines failed: {}",
+                database,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Directory the server can write --tab data files into: a fresh one inside secure_file_priv
+    /// when that restricts where files may go, otherwise in the system temp dir. mysqld creates
+    /// the files itself, so the directory must be writable by the server's user.
+    async fn tab_scratch_dir(&self) -> Result<tempfile::TempDir> {
+        let args = ["--execute=SELECT @@secure_file_priv".to_string(), "--skip-column-names".to_string()];
+        let parent = match self.execute_mysql_command(&args).await?.trim() {
+            "NULL" => return Err(Error::Config(
+                "tab_format needs the server to write files, but secure_file_priv is NULL (file writes are disabled)".to_string(),
+            )),
+            "" => std::env::temp_dir(),
+            dir => PathBuf::from(dir),
+        };
+        let dir = tempfile::Builder::new()
+            .prefix("kronos-tab-")
+            .tempdir_in(&parent)
+            .map_err(|e| Error::Config(format!(
+                "tab_format: failed to create a directory in {:?} for the server's data files: {}",
+                parent, e
+            )))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // Others may create files but not list them
+            std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o733)).map_err(Error::Io)?;
+        }
+        Ok(dir)
+    }
+}
+
+/// Move the per-table files mysqldump --tab wrote into the dump directory. The scratch directory
+/// may be on another filesystem (secure_file_priv), so files are copied, then removed. Anyone can
+/// create entries in it, so only regular files named `<table>.sql` or `<table>.txt` for one of
+/// `tables` are taken; symlinks and anything else are left behind.
+async fn collect_tab_files(tab_dir: &Path, target: &Path, tables: &[String]) -> Result<()> {
+    fs::create_dir_all(target).await.map_err(Error::Io)?;
+    let mut entries = fs::read_dir(tab_dir).await.map_err(Error::Io)?;
+    while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+        let name = entry.file_name();
+        let is_table_file = name.to_str()
+            .and_then(|name| name.strip_suffix(".sql").or_else(|| name.strip_suffix(".txt")))
+            .is_some_and(|table| tables.iter().any(|t| t == table));
+        let is_regular = fs::symlink_metadata(entry.path()).await.map_err(Error::Io)?.file_type().is_file();
+        if !is_table_file || !is_regular {
+            log::warn!("Ignoring {:?} in the --tab directory: not a table's dump file", entry.path());
+            continue;
+        }
+        fs::copy(entry.path(), target.join(&name)).await
+            .map_err(|e| Error::Database(format!("Failed to collect --tab file {:?}: {}", entry.path(), e)))?;
+        fs::remove_file(entry.path()).await.map_err(Error::Io)?;
+    }
+    Ok(())
+}
+
+/// Quote a table or column name for a MySQL query
+fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+/// Quote a string value, such as a database name compared against information_schema, for a
+/// MySQL query. Backslashes are escaped too, since MySQL treats them as escapes in literals.
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "''"))
+}
+
+/// Totals for one database from information_schema.tables
+struct TableStats {
+    size: u64,
+    table_count: u64,
+}
+
+/// Parse `--batch --skip-column-names` rows of schema, size and table count
+fn parse_table_stats(output: &str) -> HashMap<String, TableStats> {
+    output.lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let schema = fields.next()?;
+            let size = fields.next()?.trim().parse().ok()?;
+            let table_count = fields.next()?.trim().parse().ok()?;
+            Some((schema.to_string(), TableStats { size, table_count }))
+        })
+        .collect()
 }
 
 #[async_trait]
 impl<'a> DatabaseConnection for MySQLDatabase<'a> {
     async fn test_connection(&self) -> Result<ConnectionStatus> {
-        match self.execute_mysql_command(&["--execute=SELECT 1".to_string()]).await {
+        let args = match (&self.config.health_query, self.config.databases.first()) {
+            (Some(query), Some(database)) => vec![format!("--execute={}", query), database.clone()],
+            (Some(query), None) => vec![format!("--execute={}", query)],
+            (None, _) => vec!["--execute=SELECT 1".to_string()],
+        };
+        match self.execute_mysql_command(&args).await {
             Ok(_) => Ok(ConnectionStatus::Connected),
             Err(e) => Ok(ConnectionStatus::Error(e.to_string())),
         }
@@ -85,30 +601,32 @@ impl<'a> DatabaseConnection for MySQLDatabase<'a> {
     async fn get_database_info(&self) -> Result<Vec<DatabaseInfo>> {
         let mut info = Vec::new();
         
+        let stats = self.get_table_stats().await?;
+        let version_query = "--execute=SELECT VERSION()".to_string();
+        let version_result = self.execute_mysql_command(&[version_query]).await?;
+        let version = version_result.lines()
+            .nth(1)
+            .map(|s| s.to_string());
+        
         for db_name in &self.config.databases {
-            let size_query = format!(
-                "--execute=SELECT ROUND(SUM(data_length + index_length) / 1024 / 1024, 1) AS 'DB Size in MB' FROM information_schema.tables WHERE table_schema='{}'",
-                db_name
-            );
-            
-            let size_result = self.execute_mysql_command(&[size_query]).await?;
-            let size = size_result.lines()
-                .skip(1) // Skip header
-                .next()
-                .and_then(|line| line.parse::<f64>().ok())
-                .map(|mb| (mb * 1024.0 * 1024.0) as u64);
+            let db_stats = stats.get(db_name);
+            let size = db_stats.map(|stats| stats.size);
+            let table_count = Some(db_stats.map_or(0, |stats| stats.table_count));
             
-            let version_query = "--execute=SELECT VERSION()".to_string();
-            let version_result = self.execute_mysql_command(&[version_query]).await?;
-            let version = version_result.lines()
-                .skip(1)
-                .next()
-                .map(|s| s.to_string());
+            let myisam_tables = self.find_myisam_tables(db_name).await.unwrap_or_default();
+            if !myisam_tables.is_empty() && self.config.lock_tables != Some(true) {
+                log::warn!(
+                    "Database {} has MyISAM tables ({}) that --single-transaction cannot snapshot consistently; set lock_tables = true to lock them during the dump",
+                    db_name,
+                    myisam_tables.join(", ")
+                );
+            }
             
             info.push(DatabaseInfo {
                 name: db_name.clone(),
                 size,
-                schema_version: version,
+                schema_version: version.clone(),
+                table_count,
             });
         }
         
@@ -126,41 +644,295 @@ impl<'a> DatabaseConnection for MySQLDatabase<'a> {
         Ok(())
     }
 
+    async fn restore(&self, backup_path: &Path) -> Result<()> {
+        for db_name in &self.config.databases {
+            if !backup_path.join(format!("{}.sql", db_name)).is_file() {
+                return Err(Error::Restore(format!("No dump of {} in the backup", db_name)));
+            }
+            // Dumps are taken without --databases, so they don't create the database themselves
+            // and load into whichever one they are given
+            let target = self.config.restore_as.as_deref().unwrap_or(db_name);
+            self.execute_mysql_command(&[format!("--execute=CREATE DATABASE IF NOT EXISTS {}", quote_identifier(target))]).await?;
+            self.load_sql_file(target, &backup_path.join(format!("{}.sql", db_name))).await?;
+            // tab_format keeps every table in <db>/ instead
+            let tables = backup_path.join(db_name);
+            if tables.is_dir() {
+                self.load_tab_files(target, &tables).await?;
+            }
+            // Then the empty tables default excludes left behind and the rows max_blob_bytes kept out
+            // of the main dump; stored programs come last, so their triggers don't fire on the rows
+            // being loaded
+            for suffix in [".schema.sql", ".filtered.sql", ".routines.sql"] {
+                let file = backup_path.join(format!("{}{}", db_name, suffix));
+                if file.is_file() {
+                    self.load_sql_file(target, &file).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn database_type(&self) -> &'static str {
         "mysql"
     }
 
-    fn validate_config(&self, config: &DatabaseConfig) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            schema_only: true,
+            data_only: true,
+            exclude_tables: true,
+            privilege_check: true,
+            consistent_snapshot: true,
+            ..Default::default()
+        }
+    }
+
+    fn validate_config_static(&self, config: &DatabaseConfig) -> Result<()> {
         if config.host.is_empty() {
             return Err(Error::Config("MySQL host cannot be empty".to_string()));
         }
+        if config.port == 0 {
+            return Err(Error::Config("MySQL port cannot be 0".to_string()));
+        }
         if config.user.is_empty() {
             return Err(Error::Config("MySQL user cannot be empty".to_string()));
         }
-        if config.databases.is_empty() {
+        if config.io_buffer_bytes == Some(0) {
+            return Err(Error::Config("io_buffer_bytes must be greater than zero".to_string()));
+        }
+        // The server writes --tab data files to its own filesystem
+        if config.tab_format == Some(true) && !["localhost", "127.0.0.1", "::1"].contains(&config.host.as_str()) {
+            return Err(Error::Config(format!(
+                "tab_format needs the MySQL server on this host, but host is {:?}; the server writes the data files itself",
+                config.host
+            )));
+        }
+        if config.databases.is_empty() && config.databases_from.is_none() {
             return Err(Error::Config("At least one database must be specified".to_string()));
         }
         Ok(())
     }
 
+    fn validate_config(&self, config: &DatabaseConfig) -> Result<()> {
+        self.validate_config_static(config)?;
+        if let Some(defaults_file) = &config.defaults_file {
+            ensure_private_file(Path::new(defaults_file))?;
+        }
+        Ok(())
+    }
+
     async fn estimate_backup_size(&self) -> Result<u64> {
-        let mut total_size = 0u64;
+        let total_size: u64 = self.get_table_stats().await?.values().map(|stats| stats.size).sum();
         
-        for db_name in &self.config.databases {
-            let size_query = format!(
-                "--execute=SELECT COALESCE(SUM(data_length + index_length), 0) FROM information_schema.tables WHERE table_schema='{}'",
-                db_name
+        // Add 20% overhead for SQL dump format
+        Ok((total_size as f64 * 1.2) as u64)
+    }
+
+    async fn list_from_query(&self, query: &str) -> Result<Vec<String>> {
+        let result = self.execute_mysql_command(&[
+            "--batch".to_string(),
+            "--skip-column-names".to_string(),
+            format!("--execute={}", query),
+        ]).await?;
+        Ok(result.lines()
+            .filter_map(|line| line.split('\t').next())
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// InnoDB's table_rows is the optimizer's estimate; exact counts would scan every table
+    async fn row_counts(&self, database: &str) -> Result<BTreeMap<String, u64>> {
+        let count_query = format!(
+            "--execute=SELECT table_name, COALESCE(table_rows, 0) FROM information_schema.tables \
+             WHERE table_schema = {} AND table_type = 'BASE TABLE'",
+            quote_literal(database)
+        );
+        let result = self.execute_mysql_command(&["--batch".to_string(), "--skip-column-names".to_string(), count_query]).await?;
+        Ok(parse_row_counts(&result, '\t'))
+    }
+
+    /// CHECKSUM TABLE of every base table, which reads each one in full
+    async fn content_hashes(&self, database: &str) -> Result<BTreeMap<String, String>> {
+        let tables = self.execute_mysql_command(&[
+            "--batch".to_string(),
+            "--skip-column-names".to_string(),
+            format!(
+                "--execute=SELECT table_name FROM information_schema.tables WHERE table_schema = {} AND table_type = 'BASE TABLE'",
+                quote_literal(database)
+            ),
+        ]).await?;
+        let tables: Vec<String> = tables.lines()
+            .map(str::trim)
+            .filter(|table| !table.is_empty())
+            .map(|table| format!("{}.{}", quote_identifier(database), quote_identifier(table)))
+            .collect();
+        if tables.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        let result = self.execute_mysql_command(&[
+            "--batch".to_string(),
+            "--skip-column-names".to_string(),
+            format!("--execute=CHECKSUM TABLE {} EXTENDED", tables.join(", ")),
+        ]).await?;
+        // Rows are "<database>.<table>\t<checksum>"; the checksum is NULL for a table that vanished
+        let prefix = format!("{}.", database);
+        Ok(result.lines()
+            .filter_map(|line| {
+                let (name, checksum) = line.rsplit_once('\t')?;
+                let table = name.strip_prefix(&prefix)?;
+                let checksum = checksum.trim();
+                (checksum != "NULL").then(|| (table.to_string(), checksum.to_string()))
+            })
+            .collect())
+    }
+
+    async fn oversized_rows(&self, database: &str) -> Result<BTreeMap<String, u64>> {
+        let Some(max_bytes) = self.config.max_blob_bytes else {
+            return Ok(BTreeMap::new());
+        };
+        let mut left_out = BTreeMap::new();
+        for (table, columns) in self.blob_columns(database).await? {
+            let query = format!(
+                "--execute=SELECT COUNT(*) FROM {}.{} WHERE NOT ({})",
+                quote_identifier(database),
+                quote_identifier(&table),
+                blob_size_filter(&columns, max_bytes)
             );
-            
-            let size_result = self.execute_mysql_command(&[size_query]).await?;
-            if let Some(size_str) = size_result.lines().skip(1).next() {
-                if let Ok(size) = size_str.parse::<u64>() {
-                    total_size += size;
-                }
+            let result = self.execute_mysql_command(&["--batch".to_string(), "--skip-column-names".to_string(), query]).await?;
+            let rows: u64 = result.trim().parse()
+                .map_err(|_| Error::Database(format!("Unexpected row count for {}.{}: {:?}", database, table, result.trim())))?;
+            if rows > 0 {
+                left_out.insert(table, rows);
+            }
+        }
+        Ok(left_out)
+    }
+
+    /// FLUSH TABLES WITH READ LOCK lasts as long as the session that took it, so a mysql client is
+    /// kept running with its input open until `unlock`. Every write on the server waits meanwhile.
+    async fn lock(&self) -> Result<bool> {
+        let mut cmd = AsyncCommand::new("mysql");
+        apply_run_as_user(&mut cmd, self.config)?;
+        let mut child = cmd
+            .args(self.get_connection_args())
+            .args(["--batch", "--skip-column-names"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| Error::Database(format!("Failed to execute mysql command: {}", e)))?;
+        let mut stdin = child.stdin.take()
+            .ok_or_else(|| Error::Database("Failed to open mysql input".to_string()))?;
+        let stdout = child.stdout.take()
+            .ok_or_else(|| Error::Database("Failed to read mysql output".to_string()))?;
+
+        let request = format!("FLUSH TABLES WITH READ LOCK;\nSELECT '{}';\n", LOCKED_MARKER);
+        stdin.write_all(request.as_bytes()).await.map_err(Error::Io)?;
+        let granted = BufReader::new(stdout).lines().next_line().await.map_err(Error::Io)?;
+        if granted.as_deref().map(str::trim) != Some(LOCKED_MARKER) {
+            drop(stdin);
+            let output = child.wait_with_output().await.map_err(Error::Io)?;
+            return Err(Error::Database(format!(
+                "Failed to take the MySQL global read lock (needs the RELOAD privilege): {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        *self.lock_session.lock().await = Some((child, stdin));
+        Ok(true)
+    }
+
+    async fn unlock(&self) -> Result<()> {
+        let Some((mut child, mut stdin)) = self.lock_session.lock().await.take() else {
+            return Ok(());
+        };
+        // Closing the session releases the lock even if UNLOCK TABLES can't be sent
+        let sent = stdin.write_all(b"UNLOCK TABLES;\n").await;
+        drop(stdin);
+        let status = child.wait().await.map_err(Error::Io)?;
+        sent.map_err(Error::Io)?;
+        if !status.success() {
+            return Err(Error::Database(format!("MySQL lock session exited with {}", status)));
+        }
+        Ok(())
+    }
+
+    async fn estimate_duration(&self, history: &DumpHistory) -> Result<Option<Duration>> {
+        Ok(history.predict_engine(self.database_type(), &self.get_database_info().await?))
+    }
+
+    async fn find_missing_databases(&self) -> Result<Vec<String>> {
+        let result = self.execute_mysql_command(&[
+            "--execute=SHOW DATABASES".to_string(),
+            "--skip-column-names".to_string(),
+        ]).await?;
+        let existing: Vec<&str> = result.lines().map(str::trim).collect();
+        
+        Ok(self.config.databases.iter()
+            .filter(|db| !existing.contains(&db.as_str()))
+            .cloned()
+            .collect())
+    }
+
+    async fn verify_privileges(&self) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+        
+        for db_name in &self.config.databases {
+            let granted = self.get_granted_privileges(db_name).await?;
+            let absent: Vec<&str> = self.required_privileges()
+                .into_iter()
+                .filter(|privilege| !granted.iter().any(|g| g == privilege))
+                .collect();
+            if !absent.is_empty() {
+                missing.push(format!("{} on {}", absent.join(", "), db_name));
             }
         }
         
-        // Add 20% overhead for SQL dump format
-        Ok((total_size as f64 * 1.2) as u64)
+        Ok(missing)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_grouped_table_stats() {
+        let stats = parse_table_stats("shop\t1048576\t12\ncrm\t0\t3\nbroken line\n");
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats["shop"].size, 1048576);
+        assert_eq!(stats["shop"].table_count, 12);
+        assert_eq!(stats["crm"].table_count, 3);
+    }
+
+    #[test]
+    fn quotes_literals_with_backslashes_and_quotes() {
+        assert_eq!(quote_literal("shop"), "'shop'");
+        assert_eq!(quote_literal("o'brien\\"), "'o''brien\\\\'");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn collects_only_regular_files_of_existing_tables() {
+        let tab_dir = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let secret = tab_dir.path().join("secret");
+        std::fs::write(&secret, "not a dump").unwrap();
+        std::fs::write(tab_dir.path().join("orders.sql"), "CREATE TABLE orders").unwrap();
+        std::fs::write(tab_dir.path().join("orders.txt"), "1\t2").unwrap();
+        std::fs::write(tab_dir.path().join("planted.txt"), "").unwrap();
+        std::os::unix::fs::symlink(&secret, tab_dir.path().join("users.txt")).unwrap();
+
+        let tables = vec!["orders".to_string(), "users".to_string()];
+        collect_tab_files(tab_dir.path(), &target.path().join("shop"), &tables).await.unwrap();
+
+        let mut collected: Vec<String> = std::fs::read_dir(target.path().join("shop")).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        collected.sort();
+        assert_eq!(collected, ["orders.sql", "orders.txt"]);
+    }
+}
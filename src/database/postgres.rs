@@ -1,10 +1,22 @@
-use crate::config::DatabaseConfig;
-use crate::database::connection::{DatabaseConnection, DatabaseInfo, ConnectionStatus};
+use crate::backup::history::DumpHistory;
+use crate::config::{DatabaseConfig, DumpMode};
+use crate::database::connection::{blob_size_filter, parse_row_counts, Capabilities, DatabaseConnection, DatabaseInfo, ConnectionStatus, ExportedSnapshots};
+use crate::database::remote::{output_over_ssh, run_dump_over_ssh};
+use crate::database::template::{run_template_command, template_command};
 use crate::error::{Error, Result};
+use crate::utils::permissions::ensure_private_file;
+use crate::utils::priority::apply_priority;
+use crate::utils::user::apply_run_as_user;
 use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::io::Write;
 use std::path::Path;
+use std::process::{Output, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
 use tokio::fs;
-use tokio::process::Command as AsyncCommand;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command as AsyncCommand};
 
 pub struct PostgreSQLDatabase<'a> {
     config: &'a DatabaseConfig,
@@ -15,16 +27,6 @@ impl<'a> PostgreSQLDatabase<'a> {
         PostgreSQLDatabase { config }
     }
 
-    fn get_connection_string(&self) -> String {
-        format!(
-            "postgresql://{}:{}@{}:{}/",
-            self.config.user,
-            self.config.password,
-            self.config.host,
-            self.config.port
-        )
-    }
-
     fn get_connection_args(&self) -> Vec<String> {
         vec![
             format!("--host={}", self.config.host),
@@ -33,50 +35,279 @@ impl<'a> PostgreSQLDatabase<'a> {
         ]
     }
 
+    fn apply_credentials(&self, cmd: &mut AsyncCommand) {
+        // Prefer a credentials file so the password never appears in the process environment
+        match &self.config.pgpass_file {
+            Some(pgpass_file) => cmd.env("PGPASSFILE", pgpass_file),
+            None => cmd.env("PGPASSWORD", &self.config.password),
+        };
+    }
+
     async fn execute_psql_command(&self, database: &str, query: &str) -> Result<String> {
+        self.run_psql(database, query, &[]).await
+    }
+
+    /// Run a query, ignoring ~/.psqlrc so its settings can't change the output format. With an
+    /// ssh_target it runs there, reaching the server the dumps are taken from.
+    async fn run_psql(&self, database: &str, query: &str, format_args: &[&str]) -> Result<String> {
+        let mut cmd = self.psql_command(database, query)?;
+        cmd.args(format_args);
+        
+        let output = match self.config.ssh_target {
+            Some(_) => output_over_ssh(self.config, &cmd, &[], "psql").await?,
+            None => cmd.output().await
+                .map_err(|e| Error::Database(format!("Failed to execute psql command: {}", e)))?,
+        };
+        psql_output(output)
+    }
+
+    /// Run the statements of `script` in one psql session fed on stdin, stopping at the first
+    /// error. Like a single query, it runs on the ssh_target when there is one.
+    async fn run_script(&self, database: &str, script: &str) -> Result<String> {
+        let mut cmd = self.psql(database)?;
+        cmd.arg("--set=ON_ERROR_STOP=1");
+        if self.config.ssh_target.is_some() {
+            let output = output_over_ssh(self.config, &cmd, script.as_bytes(), "psql").await?;
+            return psql_output(output);
+        }
+        cmd.stdin(Stdio::piped());
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        let mut session = cmd.spawn()
+            .map_err(|e| Error::Database(format!("Failed to execute psql command: {}", e)))?;
+        let mut input = session.stdin.take()
+            .ok_or_else(|| Error::Database("Failed to open psql input".to_string()))?;
+        // Written alongside reading the output, so a long script can't fill both pipes and stall
+        let (written, output) = tokio::join!(
+            async move { input.write_all(script.as_bytes()).await },
+            session.wait_with_output(),
+        );
+        let output = psql_output(output.map_err(|e| Error::Database(format!("Failed to execute psql command: {}", e)))?)?;
+        written.map_err(|e| Error::Database(format!("Failed to write to the psql session in {}: {}", database, e)))?;
+        Ok(output)
+    }
+
+    /// psql running one query with unaligned, footer-free output
+    fn psql_command(&self, database: &str, query: &str) -> Result<AsyncCommand> {
+        let mut cmd = self.psql(database)?;
+        cmd.arg(format!("--command={}", query));
+        Ok(cmd)
+    }
+
+    /// psql with unaligned, footer-free output that runs whatever it is given after these arguments
+    fn psql(&self, database: &str) -> Result<AsyncCommand> {
         let mut cmd = AsyncCommand::new("psql");
-        cmd.args(&self.get_connection_args());
-        cmd.args(&[
+        apply_run_as_user(&mut cmd, self.config)?;
+        cmd.args(self.get_connection_args());
+        cmd.args([
             format!("--dbname={}", database),
             "--no-password".to_string(),
+            "--no-psqlrc".to_string(),
+            "--quiet".to_string(),
             "--tuples-only".to_string(),
             "--no-align".to_string(),
-            format!("--command={}", query),
         ]);
-        
-        // Set password via environment variable
-        cmd.env("PGPASSWORD", &self.config.password);
-        
+        self.apply_credentials(&mut cmd);
+        Ok(cmd)
+    }
+
+    /// Replay one database's custom-format dump into `database` with pg_restore. Full and
+    /// schema-only dumps drop and recreate the database, or with `restore_as` the objects in the
+    /// renamed target, since the dump's CREATE DATABASE names the original; data-only dumps load
+    /// into the existing database in a single transaction.
+    async fn execute_pg_restore(&self, database: &str, dump: &Path) -> Result<()> {
+        let mut cmd = AsyncCommand::new("pg_restore");
+        apply_run_as_user(&mut cmd, self.config)?;
+        cmd.args(self.get_connection_args());
+        cmd.args(["--no-password", "--exit-on-error"]);
+        match self.config.dump_mode {
+            DumpMode::DataOnly => cmd.args([format!("--dbname={}", database), "--data-only".to_string(), "--single-transaction".to_string()]),
+            _ if self.config.restore_as.is_some() => cmd.args([format!("--dbname={}", database), "--clean".to_string(), "--if-exists".to_string()]),
+            _ => cmd.args(["--dbname=postgres", "--create", "--clean", "--if-exists"]),
+        };
+        cmd.arg(dump);
+        self.apply_credentials(&mut cmd);
+
         let output = cmd.output().await
-            .map_err(|e| Error::Database(format!("Failed to execute psql command: {}", e)))?;
-        
+            .map_err(|e| Error::Database(format!("Failed to execute pg_restore: {}", e)))?;
         if !output.status.success() {
-            return Err(Error::Database(format!(
-                "psql command failed: {}",
+            return Err(Error::Restore(format!(
+                "pg_restore of {} failed: {}",
+                database,
                 String::from_utf8_lossy(&output.stderr)
             )));
         }
-        
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        Ok(())
+    }
+
+    /// Create an empty database for a renamed restore to load into, unless it exists already
+    async fn create_database_if_missing(&self, database: &str) -> Result<()> {
+        let result = self.execute_psql_command("postgres", "SELECT datname FROM pg_database WHERE NOT datistemplate;").await?;
+        if query_rows(&result).contains(&database) {
+            return Ok(());
+        }
+        self.execute_psql_command("postgres", &format!("CREATE DATABASE \"{}\";", database.replace('"', "\"\""))).await?;
+        Ok(())
+    }
+
+    /// Single value returned by a query. When the usual output doesn't parse, the query is re-run
+    /// with CSV output, which psql versions format the same way.
+    async fn query_value<T: FromStr>(&self, database: &str, query: &str) -> Result<Option<T>> {
+        let output = self.execute_psql_command(database, query).await?;
+        if let Some(value) = parse_value(&output) {
+            return Ok(Some(value));
+        }
+        log::debug!("Unexpected psql output {:?} for {:?}; retrying with --csv", output, query);
+        let output = self.run_psql(database, query, &["--csv"]).await?;
+        Ok(parse_value(&output))
+    }
+
+    /// Data-only dumps carry no CREATE EXTENSION statements (only --create dumps recreate the
+    /// database with its extensions), so name the extensions the restore target must already have
+    async fn warn_about_extensions(&self, database: &str) {
+        let query = "SELECT extname FROM pg_extension WHERE extname <> 'plpgsql' ORDER BY extname;";
+        match self.execute_psql_command(database, query).await {
+            Ok(result) => {
+                let extensions = query_rows(&result);
+                if !extensions.is_empty() {
+                    log::warn!(
+                        "Data-only dump of {} uses extensions {}; create them in the target database before restoring",
+                        database, extensions.join(", ")
+                    );
+                }
+            }
+            Err(e) => log::warn!("Failed to list extensions of database {}: {}", database, e),
+        }
+    }
+
+    /// Write `<db>.grants.sql` next to the dump: the owner of the database's schemas and relations,
+    /// the privileges granted on them, and per-kind object counts to compare a restore against.
+    /// It documents what pg_dump's ACLs should recreate, which silently fails for missing roles.
+    async fn capture_grants(&self, database: &str, output_path: &Path) -> Result<()> {
+        let user_schemas = "n.nspname NOT IN ('pg_catalog', 'information_schema') AND n.nspname NOT LIKE 'pg\\_%'";
+        let relkind = "CASE c.relkind WHEN 'v' THEN 'VIEW' WHEN 'm' THEN 'MATERIALIZED VIEW' \
+            WHEN 'S' THEN 'SEQUENCE' ELSE 'TABLE' END";
+        let grantee = "CASE WHEN a.grantee = 0 THEN 'PUBLIC' ELSE quote_ident(pg_get_userbyid(a.grantee)) END";
+
+        let counts_query = format!(
+            "SELECT {} || ' ' || count(*) FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace \
+            WHERE c.relkind IN ('r', 'p', 'v', 'm', 'S') AND {} GROUP BY 1 ORDER BY 1;",
+            relkind, user_schemas
+        );
+        let owners_query = format!(
+            "SELECT format('ALTER SCHEMA %I OWNER TO %I;', n.nspname, pg_get_userbyid(n.nspowner)) \
+            FROM pg_namespace n WHERE {0} \
+            UNION ALL SELECT format('ALTER %s %I.%I OWNER TO %I;', {1}, n.nspname, c.relname, pg_get_userbyid(c.relowner)) \
+            FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace \
+            WHERE c.relkind IN ('r', 'p', 'v', 'm', 'S') AND {0} ORDER BY 1;",
+            user_schemas, relkind
+        );
+        let grants_query = format!(
+            "SELECT format('GRANT %s ON DATABASE %I TO %s;', a.privilege_type, d.datname, {0}) \
+            FROM pg_database d, aclexplode(d.datacl) a WHERE d.datname = current_database() AND a.grantee <> d.datdba \
+            UNION ALL SELECT format('GRANT %s ON SCHEMA %I TO %s;', a.privilege_type, n.nspname, {0}) \
+            FROM pg_namespace n, aclexplode(n.nspacl) a WHERE {1} AND a.grantee <> n.nspowner \
+            UNION ALL SELECT format('GRANT %s ON %s %I.%I TO %s;', a.privilege_type, \
+                CASE c.relkind WHEN 'S' THEN 'SEQUENCE' ELSE 'TABLE' END, n.nspname, c.relname, {0}) \
+            FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace, aclexplode(c.relacl) a \
+            WHERE c.relkind IN ('r', 'p', 'v', 'm', 'S') AND {1} AND a.grantee <> c.relowner ORDER BY 1;",
+            grantee, user_schemas
+        );
+
+        let mut contents = format!("-- Owners and grants of database {}, captured by kronos\n", database);
+        contents.push_str("-- Object counts (compare after restoring):\n");
+        for line in query_rows(&self.execute_psql_command(database, &counts_query).await?) {
+            contents.push_str(&format!("--   {}\n", line));
+        }
+        for query in [owners_query, grants_query] {
+            contents.push('\n');
+            for line in query_rows(&self.execute_psql_command(database, &query).await?) {
+                contents.push_str(line);
+                contents.push('\n');
+            }
+        }
+
+        fs::write(output_path.join(format!("{}.grants.sql", database)), contents).await
+            .map_err(Error::Io)
+    }
+
+    /// Whether pg_dump compresses its custom-format output. The archive compresses every dump
+    /// again, so by default pg_dump doesn't; dumps stored as-is (`compress = false`) are left to it.
+    fn pg_dump_compresses(&self) -> bool {
+        self.config.pg_dump_compression.unwrap_or(!self.config.compress())
     }
 
     async fn execute_pg_dump(&self, database: &str, output_path: &Path) -> Result<()> {
+        if let Some(mut cmd) = template_command(self.config, database, &output_path.join(format!("{}.dump", database))) {
+            self.apply_credentials(&mut cmd);
+            apply_run_as_user(&mut cmd, self.config)?;
+            apply_priority(&mut cmd, self.config);
+            return run_template_command(cmd).await;
+        }
+
         let mut cmd = AsyncCommand::new("pg_dump");
-        cmd.args(&self.get_connection_args());
-        cmd.args(&[
+        apply_run_as_user(&mut cmd, self.config)?;
+        apply_priority(&mut cmd, self.config);
+        cmd.kill_on_drop(true);
+        cmd.args(self.get_connection_args());
+        cmd.args([
             format!("--dbname={}", database),
             "--no-password".to_string(),
             "--verbose".to_string(),
-            "--clean".to_string(),
-            "--create".to_string(),
-            "--if-exists".to_string(),
             "--format=custom".to_string(),
         ]);
+        if !self.pg_dump_compresses() {
+            cmd.arg("--compress=0");
+        }
+        match self.config.dump_mode {
+            DumpMode::Full => {
+                cmd.args(["--clean", "--create", "--if-exists"]);
+            }
+            DumpMode::SchemaOnly => {
+                cmd.args(["--schema-only", "--clean", "--create", "--if-exists"]);
+            }
+            // --clean cannot be combined with --data-only, and there is no database to create
+            DumpMode::DataOnly => {
+                cmd.arg("--data-only");
+            }
+        }
+        let schema_only = self.config.schema_only_tables();
+        for table in self.config.excluded_tables() {
+            if schema_only.contains(&table) {
+                cmd.arg(format!("--exclude-table-data={}", table));
+            } else {
+                cmd.arg(format!("--exclude-table={}", table));
+            }
+        }
+        // Tables with bytea columns keep their schema here; their rows are written separately
+        let blob_tables = match self.config.max_blob_bytes {
+            Some(_) => self.blob_columns(database).await?,
+            None => BTreeMap::new(),
+        };
+        for table in blob_tables.keys() {
+            cmd.arg(format!("--exclude-table-data={}", table));
+        }
+        if let Some(snapshot) = &self.config.snapshot_id {
+            cmd.arg(format!("--snapshot={}", snapshot));
+        }
+        // Without either flag pg_dump includes large objects unless the dump is schema-only
+        match self.config.include_blobs {
+            Some(true) => {
+                cmd.arg("--blobs");
+            }
+            Some(false) => {
+                cmd.arg("--no-blobs");
+            }
+            None => {}
+        }
         
-        // Set password via environment variable
-        cmd.env("PGPASSWORD", &self.config.password);
+        self.apply_credentials(&mut cmd);
         
         let output_file = output_path.join(format!("{}.dump", database));
+        if self.config.ssh_target.is_some() {
+            // Without --file the custom-format archive goes to stdout, which ssh carries back
+            return run_dump_over_ssh(self.config, &cmd, &output_file, "pg_dump").await;
+        }
         cmd.arg(format!("--file={}", output_file.to_string_lossy()));
         
         let output = cmd.output().await
@@ -88,16 +319,97 @@ impl<'a> PostgreSQLDatabase<'a> {
                 String::from_utf8_lossy(&output.stderr)
             )));
         }
+        if let Some(max_bytes) = self.config.max_blob_bytes.filter(|_| !blob_tables.is_empty()) {
+            self.dump_size_filtered(database, &blob_tables, max_bytes, output_path).await?;
+        }
         
         Ok(())
     }
+
+    /// Tables of a database with bytea columns, as quoted `schema.table`, mapped to those columns
+    /// quoted. Excluded tables are left out.
+    async fn blob_columns(&self, database: &str) -> Result<BTreeMap<String, Vec<String>>> {
+        let query = "SELECT format('%I.%I', c.table_schema, c.table_name), c.table_name, format('%I', c.column_name) \
+            FROM information_schema.columns c JOIN information_schema.tables t USING (table_schema, table_name) \
+            WHERE t.table_type = 'BASE TABLE' AND c.data_type = 'bytea' \
+            AND c.table_schema NOT IN ('pg_catalog', 'information_schema') ORDER BY 1, c.ordinal_position;";
+        let result = self.run_psql(database, query, &["--field-separator=\t"]).await?;
+        let excluded = self.config.excluded_tables();
+        let mut tables: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for line in result.lines() {
+            let mut fields = line.split('\t');
+            let (Some(qualified), Some(table), Some(column)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            if !excluded.iter().any(|excluded| excluded == qualified || excluded == table) {
+                tables.entry(qualified.to_string()).or_default().push(column.to_string());
+            }
+        }
+        Ok(tables)
+    }
+
+    /// Write the rows of `tables` whose bytea values fit in `max_bytes` to `<db>.filtered.sql` as
+    /// COPY blocks. The main dump has their schema but no rows, so load this with psql after
+    /// restoring it. Each table is copied in its own transaction, in the exported snapshot when
+    /// there is one and otherwise apart from pg_dump's.
+    async fn dump_size_filtered(
+        &self,
+        database: &str,
+        tables: &BTreeMap<String, Vec<String>>,
+        max_bytes: u64,
+        output_path: &Path,
+    ) -> Result<()> {
+        let mut file = std::fs::File::create(output_path.join(format!("{}.filtered.sql", database))).map_err(Error::Io)?;
+        writeln!(
+            file,
+            "-- Rows of {} without bytea values over {} bytes (max_blob_bytes), captured by kronos.\n\
+             -- Load with psql after restoring {}.dump, which has these tables without their rows.\n",
+            database, max_bytes, database
+        ).map_err(Error::Io)?;
+        for (table, columns) in tables {
+            writeln!(file, "COPY {} FROM stdin;", table).map_err(Error::Io)?;
+            let query = format!("COPY (SELECT * FROM {} WHERE {}) TO STDOUT", table, blob_size_filter(columns, max_bytes));
+            let mut cmd = match &self.config.snapshot_id {
+                Some(snapshot) => {
+                    let mut cmd = self.psql_command(database, "BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY")?;
+                    cmd.arg(format!("--command=SET TRANSACTION SNAPSHOT '{}'", snapshot));
+                    cmd.arg(format!("--command={}", query));
+                    cmd
+                }
+                None => self.psql_command(database, &query)?,
+            };
+            cmd.kill_on_drop(true);
+            // output() would replace stdout with a pipe
+            cmd.stdout(Stdio::from(file.try_clone().map_err(Error::Io)?));
+            cmd.stderr(Stdio::piped());
+            let output = match cmd.spawn() {
+                Ok(child) => child.wait_with_output().await,
+                Err(e) => Err(e),
+            }
+            .map_err(|e| Error::Database(format!("Failed to execute psql command: {}", e)))?;
+            if !output.status.success() {
+                return Err(Error::Database(format!(
+                    "Copying {} of {} filtered by max_blob_bytes failed: {}",
+                    table,
+                    database,
+                    String::from_utf8_lossy(&output.stderr)
+                )));
+            }
+            writeln!(file, "\\.\n").map_err(Error::Io)?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl<'a> DatabaseConnection for PostgreSQLDatabase<'a> {
     async fn test_connection(&self) -> Result<ConnectionStatus> {
-        // Test connection with a simple query on the default postgres database
-        match self.execute_psql_command("postgres", "SELECT 1;").await {
+        // Test connection with a simple query on the default postgres database, unless configured
+        let (database, query) = match &self.config.health_query {
+            Some(query) => (self.config.databases.first().map_or("postgres", String::as_str), query.as_str()),
+            None => ("postgres", "SELECT 1;"),
+        };
+        match self.execute_psql_command(database, query).await {
             Ok(_) => Ok(ConnectionStatus::Connected),
             Err(e) => Ok(ConnectionStatus::Error(e.to_string())),
         }
@@ -109,22 +421,23 @@ impl<'a> DatabaseConnection for PostgreSQLDatabase<'a> {
         for db_name in &self.config.databases {
             // Get database size
             let size_query = "SELECT pg_database_size(current_database());";
-            let size_result = self.execute_psql_command(db_name, size_query).await?;
-            let size = size_result.trim()
-                .parse::<u64>()
-                .ok();
+            let size = self.query_value::<u64>(db_name, size_query).await?;
             
             // Get PostgreSQL version
             let version_query = "SELECT version();";
             let version_result = self.execute_psql_command(db_name, version_query).await?;
-            let version = version_result.lines()
-                .next()
-                .map(|s| s.trim().to_string());
+            let version = query_rows(&version_result).first().map(|s| s.to_string());
+            
+            let count_query = "SELECT count(*) FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace \
+                WHERE c.relkind IN ('r', 'p', 'v', 'm') \
+                AND n.nspname NOT IN ('pg_catalog', 'information_schema') AND n.nspname NOT LIKE 'pg_toast%';";
+            let table_count = self.query_value::<u64>(db_name, count_query).await?;
             
             info.push(DatabaseInfo {
                 name: db_name.clone(),
                 size,
                 schema_version: version,
+                table_count,
             });
         }
         
@@ -134,39 +447,132 @@ impl<'a> DatabaseConnection for PostgreSQLDatabase<'a> {
     async fn backup(&self, backup_path: &Path) -> Result<()> {
         fs::create_dir_all(backup_path).await
             .map_err(Error::Io)?;
+        if self.pg_dump_compresses() {
+            log::info!("pg_dump compresses the custom-format dumps");
+        } else {
+            log::info!("pg_dump writes uncompressed custom-format dumps (--compress=0); the archive compresses them");
+        }
         
         for db_name in &self.config.databases {
+            if self.config.dump_mode == DumpMode::DataOnly {
+                self.warn_about_extensions(db_name).await;
+            }
             self.execute_pg_dump(db_name, backup_path).await?;
+            if self.config.capture_grants == Some(true) {
+                self.capture_grants(db_name, backup_path).await?;
+            }
         }
         
         Ok(())
     }
 
+    async fn restore(&self, backup_path: &Path) -> Result<()> {
+        for db_name in &self.config.databases {
+            let dump = backup_path.join(format!("{}.dump", db_name));
+            if !dump.is_file() {
+                return Err(Error::Restore(format!("No dump of {} in the backup", db_name)));
+            }
+            let target = self.config.restore_as.as_deref().unwrap_or(db_name);
+            if self.config.restore_as.is_some() && self.config.dump_mode != DumpMode::DataOnly {
+                self.create_database_if_missing(target).await?;
+            }
+            self.execute_pg_restore(target, &dump).await?;
+
+            // Rows max_blob_bytes kept out of the dump, as COPY blocks for psql
+            let filtered = backup_path.join(format!("{}.filtered.sql", db_name));
+            if filtered.is_file() {
+                let mut cmd = self.psql(target)?;
+                cmd.args(["--set=ON_ERROR_STOP=1".to_string(), format!("--file={}", filtered.display())]);
+                let output = cmd.output().await
+                    .map_err(|e| Error::Database(format!("Failed to execute psql command: {}", e)))?;
+                if !output.status.success() {
+                    return Err(Error::Restore(format!(
+                        "Loading {:?} into {} failed: {}",
+                        filtered,
+                        target,
+                        String::from_utf8_lossy(&output.stderr)
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn database_type(&self) -> &'static str {
         "postgres"
     }
 
-    fn validate_config(&self, config: &DatabaseConfig) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            schema_only: true,
+            data_only: true,
+            exclude_tables: true,
+            privilege_check: true,
+            ..Default::default()
+        }
+    }
+
+    fn validate_config_static(&self, config: &DatabaseConfig) -> Result<()> {
         if config.host.is_empty() {
             return Err(Error::Config("PostgreSQL host cannot be empty".to_string()));
         }
+        if config.port == 0 {
+            return Err(Error::Config("PostgreSQL port cannot be 0".to_string()));
+        }
         if config.user.is_empty() {
             return Err(Error::Config("PostgreSQL user cannot be empty".to_string()));
         }
-        if config.databases.is_empty() {
+        if config.databases.is_empty() && config.databases_from.is_none() {
             return Err(Error::Config("At least one database must be specified".to_string()));
         }
         Ok(())
     }
 
+    fn validate_config(&self, config: &DatabaseConfig) -> Result<()> {
+        self.validate_config_static(config)?;
+        if let Some(pgpass_file) = &config.pgpass_file {
+            ensure_private_file(Path::new(pgpass_file))?;
+        }
+        Ok(())
+    }
+
+    async fn export_snapshots(&self) -> Result<ExportedSnapshots> {
+        // Every session connects before any exports, so the snapshots are taken back to back
+        // rather than spread over connection setup
+        let mut sessions = Vec::new();
+        for database in &self.config.databases {
+            let mut cmd = self.psql(database)?;
+            cmd.arg("--set=ON_ERROR_STOP=1");
+            cmd.kill_on_drop(true);
+            cmd.stdin(Stdio::piped());
+            cmd.stdout(Stdio::piped());
+            cmd.stderr(Stdio::piped());
+            let mut session = cmd.spawn()
+                .map_err(|e| Error::Database(format!("Failed to execute psql command: {}", e)))?;
+            let output = session.stdout.take()
+                .ok_or_else(|| Error::Database("Failed to capture psql output".to_string()))?;
+            let mut output = BufReader::new(output).lines();
+            send_to_session(database, &mut session, "SELECT 'ready';").await?;
+            read_reply(database, &mut session, &mut output).await?;
+            sessions.push((database, session, output));
+        }
+        for (database, session, _) in &mut sessions {
+            send_to_session(database, session, "BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY; SELECT pg_export_snapshot();").await?;
+        }
+        let mut snapshots = ExportedSnapshots::default();
+        for (database, mut session, mut output) in sessions {
+            let id = read_reply(database, &mut session, &mut output).await?;
+            snapshots.insert(database, id, session);
+        }
+        Ok(snapshots)
+    }
+
     async fn estimate_backup_size(&self) -> Result<u64> {
         let mut total_size = 0u64;
         
         for db_name in &self.config.databases {
             let size_query = "SELECT pg_database_size(current_database());";
-            let size_result = self.execute_psql_command(db_name, size_query).await?;
-            
-            if let Ok(size) = size_result.trim().parse::<u64>() {
+            if let Some(size) = self.query_value::<u64>(db_name, size_query).await? {
                 total_size += size;
             }
         }
@@ -174,4 +580,204 @@ impl<'a> DatabaseConnection for PostgreSQLDatabase<'a> {
         // Add 15% overhead for dump format
         Ok((total_size as f64 * 1.15) as u64)
     }
-}
\ No newline at end of file
+
+    async fn estimate_duration(&self, history: &DumpHistory) -> Result<Option<Duration>> {
+        Ok(history.predict_engine(self.database_type(), &self.get_database_info().await?))
+    }
+
+    async fn list_from_query(&self, query: &str) -> Result<Vec<String>> {
+        let database = self.config.databases_from_database.as_deref().unwrap_or("postgres");
+        let result = self.execute_psql_command(database, query).await?;
+        Ok(query_rows(&result).into_iter()
+            .filter_map(|row| row.split('|').next())
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Live tuple counts from the statistics collector, kept current by autovacuum/ANALYZE
+    async fn row_counts(&self, database: &str) -> Result<BTreeMap<String, u64>> {
+        let result = self.execute_psql_command(
+            database,
+            &format!("SELECT {}, n_live_tup FROM pg_stat_user_tables ORDER BY 1;", TABLE_KEY),
+        ).await?;
+        Ok(parse_row_counts(&result, '|'))
+    }
+
+    /// MD5 over the MD5s of each table's rows in their text form, sorted so the physical row order
+    /// doesn't matter. Reads every row of every table, all in one transaction: the dump's snapshot
+    /// when one was exported. Values are printed with fixed settings so the session's defaults
+    /// (TimeZone, DateStyle, ...) don't change the hashes.
+    async fn content_hashes(&self, database: &str) -> Result<BTreeMap<String, String>> {
+        let listed = self.execute_psql_command(
+            database,
+            &format!("SELECT {}, format('%I.%I', schemaname, relname) FROM pg_stat_user_tables ORDER BY 1;", TABLE_KEY),
+        ).await?;
+        let tables: Vec<(&str, &str)> = query_rows(&listed).into_iter()
+            .filter_map(|row| row.split_once('|'))
+            .collect();
+        if tables.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+
+        let mut script = String::from("BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY;\n");
+        if let Some(snapshot) = &self.config.snapshot_id {
+            script.push_str(&format!("SET TRANSACTION SNAPSHOT '{}';\n", snapshot.replace('\'', "''")));
+        }
+        script.push_str(CHECKSUM_SETTINGS);
+        for (_, table) in &tables {
+            script.push_str(&format!(
+                "SELECT md5(coalesce(string_agg(md5(t::text), '' ORDER BY md5(t::text)), '')) FROM {} t;\n",
+                table
+            ));
+        }
+        script.push_str("COMMIT;\n");
+        let output = self.run_script(database, &script).await?;
+
+        let hashes = query_rows(&output);
+        if hashes.len() != tables.len() {
+            return Err(Error::Database(format!(
+                "Expected {} table checksums from {}, got {}",
+                tables.len(), database, hashes.len()
+            )));
+        }
+        Ok(tables.into_iter()
+            .zip(hashes)
+            .map(|((key, _), hash)| (key.to_string(), hash.to_string()))
+            .collect())
+    }
+
+    async fn oversized_rows(&self, database: &str) -> Result<BTreeMap<String, u64>> {
+        let Some(max_bytes) = self.config.max_blob_bytes else {
+            return Ok(BTreeMap::new());
+        };
+        let mut left_out = BTreeMap::new();
+        for (table, columns) in self.blob_columns(database).await? {
+            let query = format!("SELECT count(*) FROM {} WHERE NOT ({});", table, blob_size_filter(&columns, max_bytes));
+            let rows: u64 = self.query_value(database, &query).await?
+                .ok_or_else(|| Error::Database(format!("Failed to count oversized rows of {}", table)))?;
+            if rows > 0 {
+                left_out.insert(table, rows);
+            }
+        }
+        Ok(left_out)
+    }
+
+    async fn find_missing_databases(&self) -> Result<Vec<String>> {
+        let result = self.execute_psql_command(
+            "postgres",
+            "SELECT datname FROM pg_database WHERE NOT datistemplate;",
+        ).await?;
+        let existing = query_rows(&result);
+        
+        Ok(self.config.databases.iter()
+            .filter(|db| !existing.contains(&db.as_str()))
+            .cloned()
+            .collect())
+    }
+
+    async fn verify_privileges(&self) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+        
+        for db_name in &self.config.databases {
+            let connect = self.execute_psql_command(
+                db_name,
+                "SELECT has_database_privilege(current_user, current_database(), 'CONNECT');",
+            ).await?;
+            if query_rows(&connect).first() != Some(&"t") {
+                missing.push(format!("CONNECT on database {}", db_name));
+                continue;
+            }
+            
+            // pg_dump reads every table and sequence outside the system schemas
+            let unreadable_query = "SELECT format('%I.%I', n.nspname, c.relname) FROM pg_class c \
+                JOIN pg_namespace n ON n.oid = c.relnamespace \
+                WHERE c.relkind IN ('r', 'p', 'm', 'S') \
+                AND n.nspname NOT IN ('pg_catalog', 'information_schema') AND n.nspname NOT LIKE 'pg_toast%' \
+                AND NOT has_table_privilege(c.oid, 'SELECT') ORDER BY 1;";
+            let unreadable = self.execute_psql_command(db_name, unreadable_query).await?;
+            let relations = query_rows(&unreadable);
+            if !relations.is_empty() {
+                missing.push(format!("SELECT in {} on {}", db_name, relations.join(", ")));
+            }
+        }
+        
+        Ok(missing)
+    }
+}
+
+/// Name a table is reported under in row counts and checksums: bare in the public schema, as
+/// `exclude_tables` names it, otherwise qualified with its schema
+const TABLE_KEY: &str = "CASE WHEN schemaname = 'public' THEN relname ELSE schemaname || '.' || relname END";
+
+/// Output settings that `t::text` depends on, fixed for checksums so they match across sessions
+const CHECKSUM_SETTINGS: &str = "SET LOCAL TimeZone = 'UTC';
+SET LOCAL DateStyle = 'ISO, YMD';
+SET LOCAL IntervalStyle = 'postgres';
+SET LOCAL extra_float_digits = 1;
+SET LOCAL bytea_output = 'hex';
+";
+
+/// Server messages psql can interleave with query output, depending on version and settings
+const MESSAGE_PREFIXES: &[&str] = &["NOTICE:", "WARNING:", "INFO:", "DETAIL:", "HINT:", "CONTEXT:"];
+
+/// What a finished psql printed, or its error when it failed
+fn psql_output(output: Output) -> Result<String> {
+    if !output.status.success() {
+        return Err(Error::Database(format!(
+            "psql command failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Write a line of SQL to a psql session reading its stdin
+async fn send_to_session(database: &str, session: &mut Child, sql: &str) -> Result<()> {
+    let input = session.stdin.as_mut()
+        .ok_or_else(|| Error::Database("Failed to open psql input".to_string()))?;
+    input.write_all(format!("{}\n", sql).as_bytes()).await
+        .map_err(|e| Error::Database(format!("Failed to write to the psql session in {}: {}", database, e)))
+}
+
+/// Next line a psql session prints, or its error once it has exited
+async fn read_reply(database: &str, session: &mut Child, output: &mut Lines<BufReader<ChildStdout>>) -> Result<String> {
+    if let Some(line) = output.next_line().await.map_err(Error::Io)? {
+        return Ok(line);
+    }
+    let mut stderr = String::new();
+    if let Some(mut errors) = session.stderr.take() {
+        errors.read_to_string(&mut stderr).await.map_err(Error::Io)?;
+    }
+    Err(Error::Database(format!("Exporting a snapshot in {} failed: {}", database, stderr.trim())))
+}
+
+/// Rows of unaligned, tuples-only psql output: trimmed, without blank lines, server messages
+/// or row-count footers such as `(1 row)`
+fn query_rows(output: &str) -> Vec<&str> {
+    output.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .filter(|line| !MESSAGE_PREFIXES.iter().any(|prefix| line.starts_with(prefix)))
+        .filter(|line| !(line.starts_with('(') && (line.ends_with(" row)") || line.ends_with(" rows)"))))
+        .collect()
+}
+
+/// First row of the output that parses as a single value
+fn parse_value<T: FromStr>(output: &str) -> Option<T> {
+    query_rows(output).into_iter().find_map(|row| row.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tolerates_messages_and_footers_around_values() {
+        let output = "\nWARNING:  database \"shop\" has a collation version mismatch\nDETAIL:  The database was created using collation version 2.31\n  7405103  \n(1 row)\n\n";
+        assert_eq!(parse_value::<u64>(output), Some(7405103));
+        assert_eq!(query_rows("NOTICE:  extension exists\nshop\npostgres\n"), ["shop", "postgres"]);
+        assert_eq!(parse_value::<u64>("count\n"), None);
+    }
+}
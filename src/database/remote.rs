@@ -0,0 +1,225 @@
+use crate::config::{DatabaseConfig, IoniceClass};
+use crate::database::template::shell_quote;
+use crate::error::{Error, Result};
+use crate::utils::user::apply_run_as_user;
+use flate2::write::GzDecoder;
+use log::info;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process::{Output, Stdio};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command as AsyncCommand};
+
+/// Environment `apply_run_as_user` sets for the local user, which doesn't apply on the SSH host
+const LOCAL_ONLY_ENV: &[&str] = &["HOME", "USER", "LOGNAME"];
+
+/// Prefix of the line the remote shell writes to stderr with the dump's exit status, which the
+/// pipe into gzip would otherwise hide
+const EXIT_MARKER: &str = "kronos-dump-exit:";
+
+/// Run `dump`, a prepared dump command writing to stdout, on the engine's `ssh_target` instead
+/// of here, at the engine's `nice_level`/`ionice_class` there. Its output is gzipped on that host
+/// and decompressed into `output` as it arrives, so only compressed bytes cross the network.
+/// Environment set on `dump` (passwords) is sent over ssh's stdin rather than on the remote
+/// command line.
+pub async fn run_dump_over_ssh(config: &DatabaseConfig, dump: &AsyncCommand, output: &Path, label: &str) -> Result<()> {
+    let target = ssh_target(config, label)?;
+    let mut remote = remote_command(dump, target, label)?;
+    remote.args.splice(0..0, priority_prefix(config));
+    let names: Vec<&str> = remote.env.iter().map(|(name, _)| name.as_str()).collect();
+    let mut child = spawn_ssh(config, target, &remote_script(&remote.args, &names), label)?;
+
+    // A few short lines, well within the pipe buffer, so this can't block on ssh
+    let mut stdin = child.stdin.take()
+        .ok_or_else(|| Error::Database("Failed to open ssh input".to_string()))?;
+    stdin.write_all(secrets(&remote.env).as_bytes()).await.map_err(Error::Io)?;
+    drop(stdin);
+
+    let mut stdout = child.stdout.take()
+        .ok_or_else(|| Error::Database("Failed to capture ssh output".to_string()))?;
+    let file = std::fs::File::create(output).map_err(Error::Io)?;
+    let buffer_size = config.io_buffer_bytes();
+    let copy = async {
+        let mut decoder = GzDecoder::new(BufWriter::with_capacity(buffer_size, file));
+        let mut buffer = vec![0; buffer_size];
+        let mut received = 0;
+        loop {
+            let read = stdout.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            received += read as u64;
+            decoder.write_all(&buffer[..read])?;
+        }
+        let mut writer = decoder.finish()?;
+        writer.flush()?;
+        Ok::<_, io::Error>((received, writer.get_ref().metadata()?.len()))
+    };
+    let (copied, finished) = tokio::join!(copy, child.wait_with_output());
+    let finished = finished.map_err(|e| Error::Database(format!("Failed to execute ssh for {}: {}", label, e)))?;
+
+    let stderr = String::from_utf8_lossy(&finished.stderr);
+    let exit = stderr.lines().rev().find_map(|line| line.strip_prefix(EXIT_MARKER));
+    let messages: Vec<&str> = stderr.lines().filter(|line| !line.starts_with(EXIT_MARKER)).collect();
+    if exit != Some("0") || !finished.status.success() {
+        let reason = match exit {
+            Some(code) => format!("exited with {}", code),
+            None => format!("ssh to {} failed with {}", target, finished.status),
+        };
+        return Err(Error::Database(format!("{} on {} {}: {}", label, target, reason, messages.join("\n"))));
+    }
+    let (received, written) = copied
+        .map_err(|e| Error::Database(format!("Failed to decompress {} from {}: {}", label, target, e)))?;
+    info!("Streamed {} from {}: {} compressed bytes for a {} byte dump", label, target, received, written);
+    Ok(())
+}
+
+/// Run `command`, a prepared client command such as a query, on the engine's `ssh_target` and
+/// collect its output as `Command::output` would, so checks and queries reach the server the
+/// dumps do. Its environment goes over ssh's stdin like a dump's, followed by `input` for the
+/// command itself to read.
+pub async fn output_over_ssh(config: &DatabaseConfig, command: &AsyncCommand, input: &[u8], label: &str) -> Result<Output> {
+    let target = ssh_target(config, label)?;
+    let remote = remote_command(command, target, label)?;
+    let names: Vec<&str> = remote.env.iter().map(|(name, _)| name.as_str()).collect();
+    let mut child = spawn_ssh(config, target, &remote_query_script(&remote.args, &names), label)?;
+
+    let mut stdin = child.stdin.take()
+        .ok_or_else(|| Error::Database("Failed to open ssh input".to_string()))?;
+    let mut sent = secrets(&remote.env).into_bytes();
+    sent.extend_from_slice(input);
+    // Written alongside reading the output, so a long input can't fill both pipes and stall
+    let (written, output) = tokio::join!(
+        async move { stdin.write_all(&sent).await },
+        child.wait_with_output(),
+    );
+    let output = output.map_err(|e| Error::Database(format!("Failed to execute ssh for {}: {}", label, e)))?;
+    if output.status.success() {
+        written.map_err(Error::Io)?;
+    }
+    Ok(output)
+}
+
+/// The engine's `ssh_target`; `label` names the command needing it
+fn ssh_target<'a>(config: &'a DatabaseConfig, label: &str) -> Result<&'a str> {
+    config.ssh_target.as_deref()
+        .ok_or_else(|| Error::Config(format!("{} has no ssh_target to run on", label)))
+}
+
+/// A command to run on the SSH host
+struct RemoteCommand {
+    args: Vec<String>,           // Program and arguments
+    env: Vec<(String, String)>,  // Variables sent over ssh's stdin, one line each
+}
+
+/// `command` as run on the SSH host. Its environment can't contain line breaks, since each value
+/// is sent as one line.
+fn remote_command(command: &AsyncCommand, target: &str, label: &str) -> Result<RemoteCommand> {
+    let command = command.as_std();
+    let mut env = Vec::new();
+    for (name, value) in command.get_envs() {
+        let (Some(name), Some(value)) = (name.to_str(), value.and_then(|value| value.to_str())) else {
+            continue;
+        };
+        if LOCAL_ONLY_ENV.contains(&name) {
+            continue;
+        }
+        if value.contains('\n') {
+            return Err(Error::Config(format!("{} can't send {} to {}: it contains a line break", label, name, target)));
+        }
+        env.push((name.to_string(), value.to_string()));
+    }
+    let args = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    Ok(RemoteCommand { args, env })
+}
+
+/// Lines the remote script reads into its variables
+fn secrets(env: &[(String, String)]) -> String {
+    env.iter().map(|(_, value)| format!("{}\n", value)).collect()
+}
+
+/// Start `script` on `target` with every stream piped
+fn spawn_ssh(config: &DatabaseConfig, target: &str, script: &str, label: &str) -> Result<Child> {
+    let mut cmd = AsyncCommand::new("ssh");
+    apply_run_as_user(&mut cmd, config)?;
+    cmd.kill_on_drop(true);
+    cmd.args(["-o", "BatchMode=yes", "-T", "--", target, script]);
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    cmd.spawn()
+        .map_err(|e| Error::Database(format!("Failed to execute ssh for {}: {}", label, e)))
+}
+
+/// `nice` and `ionice` invocations that start a remote dump at the engine's priority, as
+/// `apply_priority` does for a local one
+fn priority_prefix(config: &DatabaseConfig) -> Vec<String> {
+    let mut prefix = Vec::new();
+    if let Some(level) = config.nice_level {
+        prefix.extend(["nice".to_string(), "-n".to_string(), level.to_string()]);
+    }
+    match config.ionice_class {
+        Some(IoniceClass::Idle) => prefix.extend(["ionice", "-c", "3"].map(str::to_string)),
+        Some(IoniceClass::BestEffort) => {
+            let level = (config.nice_level.unwrap_or(0) + 20) / 5;
+            prefix.extend(["ionice".to_string(), "-c".to_string(), "2".to_string(), "-n".to_string(), level.to_string()]);
+        }
+        Some(IoniceClass::Realtime) => prefix.extend(["ionice", "-c", "1", "-n", "4"].map(str::to_string)),
+        None => {}
+    }
+    prefix
+}
+
+/// Shell script run on the SSH host: read each variable from stdin, run the dump into gzip, and
+/// report the dump's own exit status on stderr
+fn remote_script(command: &[String], env_names: &[&str]) -> String {
+    let quoted: Vec<String> = command.iter().map(|arg| shell_quote(arg)).collect();
+    format!(
+        "{}{{ {}; echo \"{}$?\" >&2; }} | gzip -c",
+        read_env(env_names), quoted.join(" "), EXIT_MARKER
+    )
+}
+
+/// Shell script run on the SSH host for a query: read each variable from stdin, then run the
+/// command on the rest of it. ssh exits with the command's status.
+fn remote_query_script(command: &[String], env_names: &[&str]) -> String {
+    let quoted: Vec<String> = command.iter().map(|arg| shell_quote(arg)).collect();
+    format!("{}{}", read_env(env_names), quoted.join(" "))
+}
+
+/// Shell reading one line of stdin into each variable. `read` takes a byte at a time from a
+/// pipe, so whatever follows is left for the command.
+fn read_env(env_names: &[&str]) -> String {
+    env_names.iter()
+        .map(|name| format!("IFS= read -r {name} && export {name}; ", name = name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_remote_script_with_secrets_from_stdin() {
+        let command = ["pg_dump".to_string(), "--dbname=shop's".to_string()];
+        assert_eq!(
+            remote_script(&command, &["PGPASSWORD"]),
+            "IFS= read -r PGPASSWORD && export PGPASSWORD; \
+             { 'pg_dump' '--dbname=shop'\\''s'; echo \"kronos-dump-exit:$?\" >&2; } | gzip -c"
+        );
+        assert_eq!(
+            remote_query_script(&["psql".to_string()], &["PGPASSWORD"]),
+            "IFS= read -r PGPASSWORD && export PGPASSWORD; 'psql'"
+        );
+    }
+
+    #[test]
+    fn starts_remote_dumps_at_the_configured_priority() {
+        let config = DatabaseConfig { nice_level: Some(10), ionice_class: Some(IoniceClass::BestEffort), ..Default::default() };
+        assert_eq!(priority_prefix(&config), ["nice", "-n", "10", "ionice", "-c", "2", "-n", "6"]);
+        assert!(priority_prefix(&DatabaseConfig::default()).is_empty());
+    }
+}
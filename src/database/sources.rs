@@ -0,0 +1,108 @@
+use crate::config::{Config, DatabaseConfig};
+use crate::database::connection::DatabaseConnectionFactory;
+use crate::error::{Error, Result};
+use log::info;
+use std::fs;
+use std::path::Path;
+
+/// Prefix of a `databases_from` value that is a SQL query rather than a file
+pub const QUERY_PREFIX: &str = "query:";
+
+/// Copy of the config with each engine's `databases_from` resolved and added to its `databases`,
+/// so everything after sees a fixed list. Fails if a source can't be read or an engine ends up
+/// with no databases.
+pub async fn resolve_database_sources(config: &Config) -> Result<Config> {
+    let mut resolved = config.clone();
+    let databases = &mut resolved.databases;
+    for (db_type, db_config) in [
+        ("sqlite", &mut databases.sqlite),
+        ("mysql", &mut databases.mysql),
+        ("postgres", &mut databases.postgres),
+        ("mongodb", &mut databases.mongodb),
+    ] {
+        let Some(db_config) = db_config.as_mut() else {
+            continue;
+        };
+        let Some(source) = db_config.databases_from.take() else {
+            continue;
+        };
+        let listed = list_databases_from(db_type, db_config, &source).await?;
+        if let Some(name) = listed.iter().find(|name| !is_plain_name(name)) {
+            return Err(Error::Config(format!(
+                "{} `databases_from` {:?} lists {:?}, which isn't a plain database name (no /, \\, .. or NUL)",
+                db_type, source, name
+            )));
+        }
+        info!("Resolved {} databases from {}: {}", db_type, source, listed.join(", "));
+        for name in listed {
+            if !db_config.databases.contains(&name) {
+                db_config.databases.push(name);
+            }
+        }
+        if db_config.databases.is_empty() {
+            return Err(Error::Config(format!("{} `databases_from` {:?} lists no databases", db_type, source)));
+        }
+    }
+    Ok(resolved)
+}
+
+/// Whether a listed name is safe to use in dump file paths: no path separators, `..` or NUL
+fn is_plain_name(name: &str) -> bool {
+    !name.contains(['/', '\\', '\0']) && !name.contains("..")
+}
+
+/// Check that a `databases_from` file can be read; queries are only checked when a backup runs
+pub fn check_database_source(db_config: &DatabaseConfig) -> Result<()> {
+    match &db_config.databases_from {
+        Some(source) if !source.starts_with(QUERY_PREFIX) => read_database_list(Path::new(source)).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+async fn list_databases_from(db_type: &str, db_config: &DatabaseConfig, source: &str) -> Result<Vec<String>> {
+    match source.strip_prefix(QUERY_PREFIX) {
+        Some(query) => {
+            let db = DatabaseConnectionFactory::create_connection(db_type, db_config)?;
+            db.list_from_query(query.trim()).await
+                .map_err(|e| Error::Database(format!("{} `databases_from` query failed: {}", db_type, e)))
+        }
+        None => read_database_list(Path::new(source)),
+    }
+}
+
+/// Database names from a file: one per line, blank lines and `#` comments ignored
+fn read_database_list(path: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Failed to read `databases_from` file {:?}: {}", path, e)))?;
+    Ok(parse_database_list(&contents))
+}
+
+fn parse_database_list(contents: &str) -> Vec<String> {
+    contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_one_database_per_line() {
+        assert_eq!(
+            parse_database_list("# tenants\ntenant_a\n\n  tenant_b  \r\n#tenant_c\n"),
+            ["tenant_a", "tenant_b"]
+        );
+    }
+
+    #[test]
+    fn only_plain_names_are_accepted() {
+        assert!(is_plain_name("tenant_a"));
+        assert!(is_plain_name("app.db"));
+        for name in ["../etc", "a/b", "a\\b", "a\0b", ".."] {
+            assert!(!is_plain_name(name), "{:?}", name);
+        }
+    }
+}
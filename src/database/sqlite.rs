@@ -1,12 +1,17 @@
-use crate::config::DatabaseConfig;
-use crate::database::connection::{DatabaseConnection, DatabaseInfo, ConnectionStatus};
+use crate::backup::history::DumpHistory;
+use crate::config::{DatabaseConfig, DumpMode, MissingDatabase};
+use crate::database::connection::{Capabilities, DatabaseConnection, DatabaseInfo, ConnectionStatus};
 use crate::error::{Error, Result};
 use async_trait::async_trait;
 use rusqlite::{Connection, OpenFlags, backup::Backup};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::fs;
 
+/// Files SQLite keeps next to a database; never matched by a pattern on their own
+const SIDECAR_SUFFIXES: &[&str] = &["-wal", "-shm", "-journal"];
+
 pub struct SQLiteDatabase<'a> {
     config: &'a DatabaseConfig,
 }
@@ -16,8 +21,13 @@ impl<'a> SQLiteDatabase<'a> {
         SQLiteDatabase { config }
     }
 
+    /// Configured database file names with glob patterns expanded
+    fn database_names(&self) -> Result<Vec<String>> {
+        resolve_names(Path::new(&self.config.host), &self.config.databases)
+    }
+
     async fn backup_database(&self, backup_path: &Path) -> Result<()> {
-        for db_name in &self.config.databases {
+        for db_name in &self.database_names()? {
             // Construct source database path
             let source_path = PathBuf::from(&self.config.host).join(db_name);
             if !source_path.exists() {
@@ -25,9 +35,10 @@ impl<'a> SQLiteDatabase<'a> {
             }
 
             // Construct destination backup path
+            // Files matched in subdirectories keep their relative path
             let backup_file_name = format!("{}.bak", db_name);
             let dest_path = backup_path.join(&backup_file_name);
-            fs::create_dir_all(backup_path)
+            fs::create_dir_all(dest_path.parent().unwrap_or(backup_path))
                 .await
                 .map_err(Error::Io)?;
 
@@ -38,6 +49,12 @@ impl<'a> SQLiteDatabase<'a> {
             )
                 .map_err(|e| Error::Database(format!("Failed to open source SQLite DB: {}", e)))?;
 
+            // Commits by other connections from here on mean the live source may no longer match the copy
+            let version_before = match self.config.deep_verify {
+                Some(true) => Some(Self::data_version(&source_conn)?),
+                _ => None,
+            };
+
             // Open or create destination database connection
             let mut dest_conn = Connection::open(&dest_path)
                 .map_err(|e| Error::Database(format!("Failed to open destination SQLite DB: {}", e)))?;
@@ -51,6 +68,13 @@ impl<'a> SQLiteDatabase<'a> {
                     .map_err(|e| Error::Database(format!("Failed to execute backup: {}", e)))?;
             } // `backup` is dropped here, ending the borrow
 
+            if let Some(version_before) = version_before {
+                let source_fingerprint = Self::fingerprint(&source_conn)?;
+                let dest_fingerprint = Self::fingerprint(&dest_conn)?;
+                let source_changed = Self::data_version(&source_conn)? != version_before;
+                check_fingerprints(db_name, &source_fingerprint, &dest_fingerprint, source_changed)?;
+            }
+
             // Now safe to close connections
             source_conn.close()
                 .map_err(|(_, e)| Error::Database(format!("Failed to close source connection: {}", e)))?;
@@ -61,6 +85,34 @@ impl<'a> SQLiteDatabase<'a> {
         Ok(())
     }
 
+    /// Logical fingerprint of a database: each table's schema and row count.
+    /// Counting rows scans every table, so this costs roughly one full read of the database.
+    fn fingerprint(conn: &Connection) -> Result<Vec<(String, String, i64)>> {
+        let mut stmt = conn
+            .prepare("SELECT name, sql FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name")
+            .map_err(|e| Error::Database(format!("Failed to list tables: {}", e)))?;
+        let tables = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?.unwrap_or_default())))
+            .and_then(|rows| rows.collect::<std::result::Result<Vec<_>, _>>())
+            .map_err(|e| Error::Database(format!("Failed to list tables: {}", e)))?;
+
+        let mut fingerprint = Vec::with_capacity(tables.len());
+        for (name, sql) in tables {
+            let count: i64 = conn
+                .query_row(&format!("SELECT COUNT(*) FROM \"{}\"", name.replace('"', "\"\"")), [], |row| row.get(0))
+                .map_err(|e| Error::Database(format!("Failed to count rows in {}: {}", name, e)))?;
+            fingerprint.push((name, sql, count));
+        }
+
+        Ok(fingerprint)
+    }
+
+    /// SQLite's counter of commits made to the database by other connections than `conn`
+    fn data_version(conn: &Connection) -> Result<i64> {
+        conn.query_row("PRAGMA data_version", [], |row| row.get(0))
+            .map_err(|e| Error::Database(format!("Failed to read data_version: {}", e)))
+    }
+
     fn get_database_file_size(&self, db_path: &Path) -> Result<u64> {
         let metadata = std::fs::metadata(db_path)
             .map_err(|e| Error::Database(format!("Failed to get database file size: {}", e)))?;
@@ -68,11 +120,16 @@ impl<'a> SQLiteDatabase<'a> {
     }
 
     fn test_database_connection(&self, db_path: &Path) -> Result<()> {
-        let _conn = Connection::open_with_flags(
+        let conn = Connection::open_with_flags(
             db_path,
             OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )
         .map_err(|e| Error::Database(format!("Failed to open SQLite database: {}", e)))?;
+        // Opening is lazy; a health query actually reads the file
+        if let Some(query) = &self.config.health_query {
+            conn.execute_batch(query)
+                .map_err(|e| Error::Database(format!("Health query failed on {:?}: {}", db_path, e)))?;
+        }
         Ok(())
     }
 }
@@ -80,8 +137,16 @@ impl<'a> SQLiteDatabase<'a> {
 #[async_trait]
 impl<'a> DatabaseConnection for SQLiteDatabase<'a> {
     async fn test_connection(&self) -> Result<ConnectionStatus> {
-        for db_name in &self.config.databases {
+        // Missing files are reported by find_missing_databases, which applies the missing_database policy
+        let names = match self.database_names() {
+            Ok(names) => names,
+            Err(e) => return Ok(ConnectionStatus::Error(e.to_string())),
+        };
+        for db_name in &names {
             let db_path = PathBuf::from(&self.config.host).join(db_name);
+            if !db_path.exists() {
+                continue;
+            }
             if let Err(e) = self.test_database_connection(&db_path) {
                 return Ok(ConnectionStatus::Error(e.to_string()));
             }
@@ -92,7 +157,7 @@ impl<'a> DatabaseConnection for SQLiteDatabase<'a> {
     async fn get_database_info(&self) -> Result<Vec<DatabaseInfo>> {
         let mut info = Vec::new();
         
-        for db_name in &self.config.databases {
+        for db_name in &self.database_names()? {
             let db_path = PathBuf::from(&self.config.host).join(db_name);
             
             let size = if db_path.exists() {
@@ -101,8 +166,8 @@ impl<'a> DatabaseConnection for SQLiteDatabase<'a> {
                 None
             };
             
-            // Get SQLite version
-            let version = if db_path.exists() {
+            // Get SQLite version and table count
+            let (version, table_count) = if db_path.exists() {
                 match Connection::open_with_flags(
                     &db_path,
                     OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
@@ -113,18 +178,24 @@ impl<'a> DatabaseConnection for SQLiteDatabase<'a> {
                             [],
                             |row| row.get(0)
                         );
-                        version.ok()
+                        let table_count: std::result::Result<u64, rusqlite::Error> = conn.query_row(
+                            "SELECT count(*) FROM sqlite_master WHERE type IN ('table', 'view') AND name NOT LIKE 'sqlite_%'",
+                            [],
+                            |row| row.get(0)
+                        );
+                        (version.ok(), table_count.ok())
                     }
-                    Err(_) => None,
+                    Err(_) => (None, None),
                 }
             } else {
-                None
+                (None, None)
             };
             
             info.push(DatabaseInfo {
                 name: db_name.clone(),
                 size,
                 schema_version: version,
+                table_count,
             });
         }
         
@@ -135,17 +206,58 @@ impl<'a> DatabaseConnection for SQLiteDatabase<'a> {
         self.backup_database(backup_path).await
     }
 
+    async fn restore(&self, backup_path: &Path) -> Result<()> {
+        for db_name in &self.config.databases {
+            let copy = backup_path.join(format!("{}.bak", db_name));
+            if !copy.is_file() {
+                return Err(Error::Restore(format!("No copy of {} in the backup", db_name)));
+            }
+            let target = PathBuf::from(&self.config.host).join(self.config.restore_as.as_deref().unwrap_or(db_name));
+            fs::create_dir_all(target.parent().unwrap_or(Path::new(&self.config.host)))
+                .await
+                .map_err(Error::Io)?;
+
+            // Copied next to the database and renamed over it, so it is never seen half written
+            let partial = PathBuf::from(format!("{}.restoring", target.display()));
+            fs::copy(&copy, &partial).await.map_err(Error::Io)?;
+            // A journal or WAL left by the old database would be replayed into the restored one
+            for suffix in SIDECAR_SUFFIXES {
+                match fs::remove_file(format!("{}{}", target.display(), suffix)).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Error::Io(e)),
+                    _ => {}
+                }
+            }
+            fs::rename(&partial, &target).await.map_err(Error::Io)?;
+        }
+        Ok(())
+    }
+
     fn database_type(&self) -> &'static str {
         "sqlite"
     }
 
-    fn validate_config(&self, config: &DatabaseConfig) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            database_patterns: true,
+            ..Default::default()
+        }
+    }
+
+    fn validate_config_static(&self, config: &DatabaseConfig) -> Result<()> {
         if config.host.is_empty() {
             return Err(Error::Config("SQLite host (directory path) cannot be empty".to_string()));
         }
-        if config.databases.is_empty() {
+        if config.databases.is_empty() && config.databases_from.is_none() {
             return Err(Error::Config("At least one database file must be specified".to_string()));
         }
+        if config.dump_mode != DumpMode::Full {
+            return Err(Error::Config("SQLite backups copy the whole database file; only dump_mode \"full\" is supported".to_string()));
+        }
+        Ok(())
+    }
+
+    fn validate_config(&self, config: &DatabaseConfig) -> Result<()> {
+        self.validate_config_static(config)?;
         
         // Check if the directory exists
         let host_path = Path::new(&config.host);
@@ -153,8 +265,18 @@ impl<'a> DatabaseConnection for SQLiteDatabase<'a> {
             return Err(Error::Config(format!("SQLite host directory does not exist: {}", config.host)));
         }
         
-        // Check if specified database files exist
-        for db_name in &config.databases {
+        // Check if specified database files exist (and patterns match some), unless missing ones are to be skipped
+        let required = match config.missing_database {
+            MissingDatabase::Error => config.databases.as_slice(),
+            MissingDatabase::Skip | MissingDatabase::Warn => &[],
+        };
+        for db_name in required {
+            if is_pattern(db_name) {
+                if match_pattern(host_path, db_name)?.is_empty() {
+                    return Err(Error::Config(format!("No SQLite database files in {} match {:?}", config.host, db_name)));
+                }
+                continue;
+            }
             let db_path = host_path.join(db_name);
             if !db_path.exists() {
                 return Err(Error::Config(format!("SQLite database file does not exist: {:?}", db_path)));
@@ -164,10 +286,32 @@ impl<'a> DatabaseConnection for SQLiteDatabase<'a> {
         Ok(())
     }
 
+    async fn row_counts(&self, database: &str) -> Result<BTreeMap<String, u64>> {
+        let conn = Connection::open_with_flags(
+            Path::new(&self.config.host).join(database),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(|e| Error::Database(format!("Failed to open SQLite database: {}", e)))?;
+        Ok(Self::fingerprint(&conn)?
+            .into_iter()
+            .map(|(name, _, count)| (name, count as u64))
+            .collect())
+    }
+
+    async fn find_missing_databases(&self) -> Result<Vec<String>> {
+        Ok(self.database_names()?.into_iter()
+            .filter(|db| !Path::new(&self.config.host).join(db).is_file())
+            .collect())
+    }
+
+    async fn resolve_databases(&self) -> Result<Option<Vec<String>>> {
+        Ok(Some(self.database_names()?))
+    }
+
     async fn estimate_backup_size(&self) -> Result<u64> {
         let mut total_size = 0u64;
         
-        for db_name in &self.config.databases {
+        for db_name in &self.database_names()? {
             let db_path = PathBuf::from(&self.config.host).join(db_name);
             if let Ok(size) = self.get_database_file_size(&db_path) {
                 total_size += size;
@@ -177,4 +321,104 @@ impl<'a> DatabaseConnection for SQLiteDatabase<'a> {
         // SQLite backup is nearly the same size as the original
         Ok(total_size)
     }
-}
\ No newline at end of file
+
+    async fn estimate_duration(&self, history: &DumpHistory) -> Result<Option<Duration>> {
+        Ok(history.predict_engine(self.database_type(), &self.get_database_info().await?))
+    }
+}
+/// Whether a `databases` entry is a glob pattern rather than a file name
+fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+/// Database files under `dir` matching a glob pattern, as paths relative to `dir`
+fn match_pattern(dir: &Path, pattern: &str) -> Result<Vec<String>> {
+    let full_pattern = format!("{}/{}", glob::Pattern::escape(&dir.to_string_lossy()), pattern);
+    let paths = glob::glob(&full_pattern)
+        .map_err(|e| Error::Config(format!("Invalid SQLite database pattern {:?}: {}", pattern, e)))?;
+
+    let mut names = Vec::new();
+    for path in paths.flatten() {
+        let Ok(relative) = path.strip_prefix(dir) else {
+            continue;
+        };
+        let name = relative.to_string_lossy().replace('\\', "/");
+        if path.is_file() && !SIDECAR_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Expand the glob patterns among configured database names. Patterns that match nothing are kept
+/// as they are, so they surface as missing databases.
+fn resolve_names(dir: &Path, databases: &[String]) -> Result<Vec<String>> {
+    let mut names: Vec<String> = Vec::new();
+    for entry in databases {
+        let mut matched = if is_pattern(entry) { match_pattern(dir, entry)? } else { Vec::new() };
+        if matched.is_empty() {
+            matched.push(entry.clone());
+        }
+        for name in matched {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Compare the fingerprints of the source and its copy for `deep_verify`. A mismatch fails the
+/// backup only when nothing was committed to the source while it was copied and checked; writes
+/// to a live source would show up as differences the copy is right to have, so then it is a warning.
+fn check_fingerprints(
+    db_name: &str,
+    source: &[(String, String, i64)],
+    copy: &[(String, String, i64)],
+    source_changed: bool,
+) -> Result<()> {
+    if source == copy {
+        log::info!("Deep verification passed for {} ({} tables)", db_name, source.len());
+    } else if source_changed {
+        log::warn!(
+            "Deep verification of {} inconclusive: the source was written to while it was copied and checked, so schema or row count differences may be newer writes",
+            db_name
+        );
+    } else {
+        return Err(Error::Backup(format!(
+            "Deep verification failed for {}: schema or row counts differ between source and backup",
+            db_name
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deep_verify_mismatch_only_fails_for_an_unchanged_source() {
+        let source = vec![("orders".to_string(), "CREATE TABLE orders (id)".to_string(), 12)];
+        let copy = vec![("orders".to_string(), "CREATE TABLE orders (id)".to_string(), 11)];
+        assert!(check_fingerprints("app.db", &source, &source, false).is_ok());
+        assert!(matches!(check_fingerprints("app.db", &source, &copy, false), Err(Error::Backup(_))));
+        assert!(check_fingerprints("app.db", &source, &copy, true).is_ok());
+    }
+
+    #[test]
+    fn expands_patterns_without_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["app.db", "app.db-wal", "app.db-shm", "users.db", "notes.txt"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        std::fs::create_dir_all(dir.path().join("data/tenants")).unwrap();
+        std::fs::write(dir.path().join("data/tenants/acme.sqlite"), b"").unwrap();
+
+        let databases = ["*.db", "data/**/*.sqlite", "users.db", "*.missing"].map(String::from);
+        assert_eq!(
+            resolve_names(dir.path(), &databases).unwrap(),
+            ["app.db", "users.db", "data/tenants/acme.sqlite", "*.missing"]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::config::DatabaseConfig;
+use crate::error::{Error, Result};
+use std::path::Path;
+use tokio::process::Command as AsyncCommand;
+
+/// Placeholders a `command_template` may use
+const PLACEHOLDERS: &[&str] = &["host", "port", "user", "db", "output"];
+
+/// Placeholders every template must use, or the dump would not be of the right database or
+/// would not end up where kronos collects it
+const REQUIRED_PLACEHOLDERS: &[&str] = &["db", "output"];
+
+/// Reject templates that leave out a required placeholder. Braces around anything else, such as
+/// `${HOME}` or awk's `{print $1}`, are the shell's and pass through untouched.
+pub fn check_command_template(db_type: &str, template: &str) -> Result<()> {
+    let used = placeholders(template);
+    if let Some(missing) = REQUIRED_PLACEHOLDERS.iter().find(|name| !used.contains(name)) {
+        return Err(Error::Config(format!(
+            "{} command_template must use {{{}}}; available: {}",
+            db_type,
+            missing,
+            PLACEHOLDERS.iter().map(|name| format!("{{{}}}", name)).collect::<Vec<_>>().join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Known placeholders used in a template
+fn placeholders(template: &str) -> Vec<&str> {
+    let mut used = Vec::new();
+    substitute(template, |name| {
+        used.push(name);
+        Some(String::new())
+    });
+    used
+}
+
+/// Replace each `{name}` in `template` for which `value` gives a value, in a single pass, so
+/// substituted values are never scanned for placeholders themselves. Only known placeholder
+/// names are offered to `value`.
+fn substitute<'a, F: FnMut(&'a str) -> Option<String>>(template: &'a str, mut value: F) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let replacement = after.split_once('}')
+            .map(|(name, _)| name)
+            .filter(|name| PLACEHOLDERS.contains(name))
+            .and_then(|name| Some((name.len(), value(name)?)));
+        match replacement {
+            Some((name_len, replacement)) => {
+                result.push_str(&replacement);
+                rest = &after[name_len + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// The configured `command_template` for dumping `database` to `output`, run through `sh -c`
+/// with every value shell-quoted. The password is never put on the command line; it is exported
+/// as KRONOS_PASSWORD, and engines add their client's own variable.
+pub fn template_command(config: &DatabaseConfig, database: &str, output: &Path) -> Option<AsyncCommand> {
+    let template = config.command_template.as_ref()?;
+    let port = config.port.to_string();
+    let output = output.to_string_lossy();
+    let values = [
+        ("host", config.host.as_str()),
+        ("port", port.as_str()),
+        ("user", config.user.as_str()),
+        ("db", database),
+        ("output", &output),
+    ];
+    let command = substitute(template, |name| {
+        values.iter().find(|(known, _)| *known == name).map(|(_, value)| shell_quote(value))
+    });
+
+    let mut cmd = AsyncCommand::new("sh");
+    cmd.kill_on_drop(true);
+    cmd.arg("-c").arg(command);
+    cmd.env("KRONOS_PASSWORD", &config.password);
+    Some(cmd)
+}
+
+/// Run a templated dump command, failing with its stderr if it exits unsuccessfully
+pub async fn run_template_command(mut cmd: AsyncCommand) -> Result<()> {
+    let output = cmd.output().await
+        .map_err(|e| Error::Database(format!("Failed to execute command_template: {}", e)))?;
+    if !output.status.success() {
+        return Err(Error::Database(format!(
+            "command_template failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(())
+}
+
+/// Quote a value as a single shell word
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_need_known_and_required_placeholders() {
+        assert!(check_command_template("mysql", "mysqldump -h {host} {db} > {output}").is_ok());
+        assert!(check_command_template("mysql", "mysqldump {db} > /tmp/out.sql").is_err());
+        assert!(check_command_template("mysql", "mysqldump {database} > {output}").is_err());
+        assert!(check_command_template("mysql", "mysqldump {db} | awk '{print $1}' > ${HOME}/{output}").is_ok());
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn substitutes_in_one_pass() {
+        let values = |name: &str| match name {
+            "db" => Some("{output}".to_string()),
+            "output" => Some("out.sql".to_string()),
+            _ => None,
+        };
+        assert_eq!(substitute("dump {db} {user} ${X} > {output}", values), "dump {output} {user} ${X} > out.sql");
+    }
+}
@@ -1,8 +1,10 @@
 use crate::config::DatabaseConfig;
-use crate::database::connection::{DatabaseConnectionFactory, DatabaseConnection};
+use crate::database::connection::DatabaseConnectionFactory;
 use crate::error::Result;
 
 pub async fn test_database_framework() -> Result<()> {
+    DatabaseConnectionFactory::register_builtins();
+
     // Test SQLite connection creation
     let sqlite_config = DatabaseConfig {
         host: "/tmp".to_string(),
@@ -10,6 +12,7 @@ pub async fn test_database_framework() -> Result<()> {
         user: "".to_string(),
         password: "".to_string(),
         databases: vec!["test.db".to_string()],
+        ..Default::default()
     };
     
     let _sqlite_db = DatabaseConnectionFactory::create_connection("sqlite", &sqlite_config)?;
@@ -22,6 +25,7 @@ pub async fn test_database_framework() -> Result<()> {
         user: "root".to_string(),
         password: "password".to_string(),
         databases: vec!["test_db".to_string()],
+        ..Default::default()
     };
     
     let _mysql_db = DatabaseConnectionFactory::create_connection("mysql", &mysql_config)?;
@@ -34,6 +38,7 @@ pub async fn test_database_framework() -> Result<()> {
         user: "postgres".to_string(),
         password: "password".to_string(),
         databases: vec!["test_db".to_string()],
+        ..Default::default()
     };
     
     let _postgres_db = DatabaseConnectionFactory::create_connection("postgres", &postgres_config)?;
@@ -46,6 +51,7 @@ pub async fn test_database_framework() -> Result<()> {
         user: "admin".to_string(),
         password: "password".to_string(),
         databases: vec!["test_db".to_string()],
+        ..Default::default()
     };
     
     let _mongodb_db = DatabaseConnectionFactory::create_connection("mongodb", &mongodb_config)?;
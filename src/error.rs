@@ -8,6 +8,7 @@ pub enum Error {
     Storage(String),
     Backup(String),
     Restore(String),
+    DiskFull(String), // The filesystem written to ran out of space or quota
     Io(std::io::Error),
 }
 
@@ -19,6 +20,7 @@ impl fmt::Display for Error {
             Error::Storage(msg) => write!(f, "Storage error: {}", msg),
             Error::Backup(msg) => write!(f, "Backup error: {}", msg),
             Error::Restore(msg) => write!(f, "Restore error: {}", msg),
+            Error::DiskFull(msg) => write!(f, "Out of disk space: {}", msg),
             Error::Io(err) => write!(f, "I/O error: {}", err),
         }
     }
@@ -39,5 +41,10 @@ impl From<std::io::Error> for Error {
     }
 }
 
+/// Whether an I/O error means the filesystem is full or the user's quota is used up (ENOSPC, EDQUOT)
+pub fn is_disk_full(err: &std::io::Error) -> bool {
+    matches!(err.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded)
+}
+
 // Type alias for Result with our custom Error
 pub type Result<T> = std::result::Result<T, Error>;
\ No newline at end of file
@@ -1,6 +1,192 @@
-use env_logger::Env;
+use crate::config::REDACTED;
+use crate::error::{Error, Result};
+use env_logger::{Env, Target, WriteStyle};
+use log::{Log, Metadata, Record};
+use regex::{NoExpand, Regex};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 
-pub fn init_logger() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
-        .init();
-}
\ No newline at end of file
+/// Compiled `log_redact_patterns`, applied to every message from when the config is loaded
+static REDACT_PATTERNS: RwLock<Vec<Regex>> = RwLock::new(Vec::new());
+
+/// Where log output goes and how the log file is rotated
+#[derive(Debug, Default)]
+pub struct LogOptions {
+    pub file: Option<PathBuf>,
+    pub max_size_bytes: Option<u64>, // Rotate once the file would grow past this size
+    pub max_files: usize,            // Rotated files (.1, .2, ...) to keep
+}
+
+pub fn init_logger(options: &LogOptions) -> Result<()> {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+
+    if let Some(path) = &options.file {
+        let file = RotatingFile::open(path, options.max_size_bytes, options.max_files)
+            .map_err(|e| Error::Config(format!("Failed to open log file {:?}: {}", path, e)))?;
+        builder.target(Target::Pipe(Box::new(file)));
+        builder.write_style(WriteStyle::Never);
+    }
+
+    let logger = builder.build();
+    let max_level = logger.filter();
+    log::set_boxed_logger(Box::new(RedactingLogger(logger)))
+        .map_err(|e| Error::Config(format!("Failed to initialize logging: {}", e)))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Compile `log_redact_patterns`, naming the first one that isn't a valid regex
+pub fn compile_redact_patterns(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns.iter()
+        .map(|pattern| {
+            Regex::new(pattern)
+                .map_err(|e| Error::Config(format!("Invalid log_redact_patterns entry {:?}: {}", pattern, e)))
+        })
+        .collect()
+}
+
+/// Redact matches of `patterns` from every message logged from now on
+pub fn set_redact_patterns(patterns: Vec<Regex>) {
+    *REDACT_PATTERNS.write().unwrap_or_else(|e| e.into_inner()) = patterns;
+}
+
+/// `text` with every match of `patterns` replaced
+fn redact(text: &str, patterns: &[Regex]) -> String {
+    patterns.iter()
+        .fold(text.to_string(), |text, pattern| pattern.replace_all(&text, NoExpand(REDACTED)).into_owned())
+}
+
+/// Passes records to env_logger with the redaction patterns applied to their message
+struct RedactingLogger(env_logger::Logger);
+
+impl Log for RedactingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.0.matches(record) {
+            return;
+        }
+        let patterns = REDACT_PATTERNS.read().unwrap_or_else(|e| e.into_inner());
+        if patterns.is_empty() {
+            return self.0.log(record);
+        }
+        let message = redact(&record.args().to_string(), &patterns);
+        self.0.log(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Log file writer that rotates to `<file>.1`, `<file>.2`, ... when a size limit is crossed
+struct RotatingFile {
+    path: PathBuf,
+    max_size_bytes: Option<u64>,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size_bytes: Option<u64>, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        // Drop the oldest file, then shift each remaining one up by one
+        let _ = fs::remove_file(self.rotated_path(self.max_files));
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(max_size) = self.max_size_bytes {
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_and_keeps_limited_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kronos.log");
+        let mut file = RotatingFile::open(&path, Some(10), 2).unwrap();
+
+        for line in ["first line\n", "second line\n", "third line\n", "fourth line\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(1)).unwrap(), "third line\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(2)).unwrap(), "second line\n");
+        assert!(!file.rotated_path(3).exists());
+    }
+
+    #[test]
+    fn redacts_pattern_matches() {
+        let patterns = compile_redact_patterns(&[
+            r"token=\w+".to_string(),
+            r"[a-z0-9-]+\.internal\.example".to_string(),
+        ]).unwrap();
+        assert_eq!(
+            redact("GET /sync?token=abc123 from db-7.internal.example failed ($1)", &patterns),
+            "GET /sync?*** from *** failed ($1)"
+        );
+        assert!(compile_redact_patterns(&["(unclosed".to_string()]).is_err());
+    }
+}
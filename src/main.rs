@@ -1,9 +1,26 @@
 use clap::{Parser, Subcommand};
-use commands::backup::run_backup;
-use config::Config;
+use backup::manifest::parse_tags;
+use commands::backup::{run_backup, BackupOptions};
+use commands::cat::run_cat;
+use commands::compare::run_compare;
+use commands::engines::run_engines;
+use commands::list::{parse_since, run_list};
+use commands::output::OutputFormat;
+use commands::print_config::{run_print_config, ConfigFormat};
+use commands::prune::run_prune;
+use commands::restore::{run_restore, RestoreOptions};
+use commands::transform::{run_transform, TransformOptions};
+use commands::validate::run_validate;
+use commands::verify::run_verify;
+use config::{ArchiveFormat, Config, DumpLayout};
+use database::connection::DatabaseConnectionFactory;
 use error::Result;
-use logger::init_logger;
+use logger::{init_logger, LogOptions};
 use log::info;
+use scheduler::run_scheduler;
+use utils::failpoint::{enable_failure_injection, FailurePhase};
+use std::path::PathBuf;
+use std::time::Duration;
 
 mod config;
 mod error;
@@ -13,12 +30,26 @@ mod utils;
 mod storage;
 mod backup;
 mod database;
+mod scheduler;
 
 #[derive(Parser)]
 #[clap(name = "kronos", about = "A database backup utility tool")]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+    /// Write logs to this file instead of stderr
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+    /// Rotate the log file once it would exceed this many bytes
+    #[clap(long, global = true, requires = "log_file")]
+    log_max_size_bytes: Option<u64>,
+    /// Number of rotated log files to keep
+    #[clap(long, global = true, default_value_t = 5)]
+    log_max_files: usize,
+    /// Let environment variables named <PREFIX>_<KEY> override config values, e.g.
+    /// KRONOS_STORAGE_PATH for storage.path; overrides win over the config file
+    #[clap(long, global = true, value_name = "PREFIX")]
+    config_env_prefix: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -27,23 +58,233 @@ enum Commands {
     Backup {
         #[clap(long, default_value = "config.toml")]
         config: String,
+        /// Label the backup with a key=value tag (repeatable)
+        #[clap(long = "tag", value_name = "KEY=VALUE")]
+        tags: Vec<String>,
+        /// Wait up to this many seconds for the databases to become reachable before backing up
+        #[clap(long, value_name = "SECS")]
+        wait_for_db: Option<u64>,
+        /// Show a progress bar while compressing (only on a terminal)
+        #[clap(long)]
+        progress: bool,
+        /// Arrange dumps in the archive this way instead of the configured dump_layout
+        #[clap(long, value_enum, value_name = "LAYOUT")]
+        dump_dir_layout: Option<DumpLayout>,
+        /// Fail any mysql, postgres or mongodb dump that runs longer than this, overriding the configured timeout_secs.
+        /// SQLite copies are not limited
+        #[clap(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        timeout_per_db: Option<u64>,
+        /// Continue an interrupted backup, skipping databases it already dumped; use the dump layout it started with
+        #[clap(long, value_name = "BACKUP_ID")]
+        resume: Option<String>,
+        /// Write a JSON report of the run to this file, replacing it atomically, even when the run fails
+        #[clap(long, value_name = "PATH")]
+        report_file: Option<PathBuf>,
+        /// Sample peak concurrent dumps, memory and scratch disk use during the run and add them to the report
+        #[clap(long)]
+        concurrency_report: bool,
+        /// Testing aid: fail every attempt at this phase to exercise alerting, retries and cleanup
+        /// (debug builds, or KRONOS_ALLOW_FAILURE_INJECTION=1)
+        #[clap(long, value_enum, hide = true, value_name = "PHASE")]
+        inject_failure: Option<FailurePhase>,
+    },
+    /// List stored backups
+    List {
+        #[clap(long, default_value = "config.toml")]
+        config: String,
+        /// Only show backups carrying this key=value tag (repeatable)
+        #[clap(long = "tag", value_name = "KEY=VALUE")]
+        tags: Vec<String>,
+        /// Only show backups taken at or after this date (YYYY-MM-DD) or RFC 3339 timestamp
+        #[clap(long, value_name = "DATE")]
+        since: Option<String>,
+        #[clap(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Write one database's dump from a stored backup to stdout
+    Cat {
+        #[clap(long, default_value = "config.toml")]
+        config: String,
+        /// Backup to read from
+        backup_id: String,
+        /// Database whose dump to emit; required when the backup holds several
+        #[clap(long = "db")]
+        database: Option<String>,
+    },
+    /// Remove backups outside the configured retention policy without taking a new backup
+    Prune {
+        #[clap(long, default_value = "config.toml")]
+        config: String,
+        /// Only list the backups that would be removed
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Repack a stored backup in another archive format, compression or encryption key, stored under a new id
+    /// (local storage only)
+    Transform {
+        #[clap(long, default_value = "config.toml")]
+        config: String,
+        /// Backup to repack; it is kept unless --replace is given
+        backup_id: String,
+        /// Archive format of the new archive instead of archive_format
+        #[clap(long, value_enum, value_name = "FORMAT")]
+        to_format: Option<ArchiveFormat>,
+        /// Pipe the tar stream through this command (extension: compressor_extension), or "gzip" for the built-in compression
+        #[clap(long, value_name = "COMMAND")]
+        to_compression: Option<String>,
+        /// Encrypt the new archive with this key file; the backup is still read with encryption_key_file,
+        /// and the new one needs encryption_key_file switched to this key
+        #[clap(long, value_name = "KEY_FILE")]
+        rekey: Option<String>,
+        /// Remove the original backup once the new archive is stored
+        #[clap(long)]
+        replace: bool,
+    },
+    /// Check restored databases against the table checksums a backup recorded (capture_checksums)
+    Compare {
+        #[clap(long, default_value = "config.toml")]
+        config: String,
+        /// Backup the databases were restored from
+        backup_id: String,
+        #[clap(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Check that stored backups read back intact and, with verify_key_file set, that their signatures match
+    Verify {
+        #[clap(long, default_value = "config.toml")]
+        config: String,
+        /// Backups to check
+        #[clap(required_unless_present = "all")]
+        backup_ids: Vec<String>,
+        /// Check every stored backup
+        #[clap(long, conflicts_with = "backup_ids")]
+        all: bool,
+        /// Check this many archives at once
+        #[clap(long, default_value_t = 4)]
+        jobs: usize,
+        #[clap(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Check a config without connecting to anything, e.g. to lint configs for other hosts in CI
+    Validate {
+        #[clap(long, default_value = "config.toml")]
+        config: String,
+        /// Also check files the config refers to on this host (SQLite databases, credential files)
+        #[clap(long)]
+        strict: bool,
+    },
+    /// List the supported database engines and the optional features of each
+    Engines {
+        #[clap(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Print the fully-resolved effective config with secrets redacted
+    PrintConfig {
+        #[clap(long, default_value = "config.toml")]
+        config: String,
+        #[clap(long, value_enum, default_value = "toml")]
+        format: ConfigFormat,
+    },
+    /// Start the scheduler for automatic backups
+    Schedule {
+        #[clap(long, default_value = "config.toml")]
+        config: String,
+        /// Write a JSON report of each run to this file, replacing it atomically. Each schedule gets
+        /// its own file, named with the schedule before the extension (report.json -> report.nightly.json)
+        #[clap(long, value_name = "PATH")]
+        report_file: Option<PathBuf>,
+    },
+    /// Load a stored backup back into the configured databases
+    Restore {
+        #[clap(long, default_value = "config.toml")]
+        config: String,
+        /// Backup to restore
+        backup_id: String,
+        /// Overwrite databases that already exist
+        #[clap(long)]
+        force: bool,
+        /// Don't ask before replacing databases; required when stdin is not a terminal
+        #[clap(long)]
+        yes: bool,
+        /// Restore the backup's one database under this name, e.g. next to the original for checking
+        #[clap(long, value_name = "NAME")]
+        target_db: Option<String>,
     },
-    // Start the scheduler for automatic backups (Incoming Features)
-    // Restore from a backup (Incoming Features)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     // Initialize logging
-    init_logger();
+    init_logger(&LogOptions {
+        file: cli.log_file.clone(),
+        max_size_bytes: cli.log_max_size_bytes,
+        max_files: cli.log_max_files,
+    })?;
     info!("Starting kronos");
-
-    let cli = Cli::parse();
+    DatabaseConnectionFactory::register_builtins();
 
     match cli.command {
-        Commands::Backup { config } => {
-            let cfg = Config::load(&config)?;
-            run_backup(&cfg).await?;
+        Commands::Backup { config, tags, wait_for_db, progress, dump_dir_layout, timeout_per_db, resume, report_file, concurrency_report, inject_failure } => {
+            if let Some(phase) = inject_failure {
+                enable_failure_injection(phase)?;
+            }
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            let options = BackupOptions {
+                tags: parse_tags(&tags)?,
+                wait_for_db: wait_for_db.map(Duration::from_secs),
+                progress,
+                layout: dump_dir_layout,
+                timeout_per_db: timeout_per_db.map(Duration::from_secs),
+                resume,
+                report_file,
+                concurrency_report,
+                ..Default::default()
+            };
+            run_backup(&cfg, &options).await?;
+        }
+        Commands::List { config, tags, since, format } => {
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            let since = since.as_deref().map(parse_since).transpose()?;
+            run_list(&cfg, &parse_tags(&tags)?, since, format)?;
+        }
+        Commands::Cat { config, backup_id, database } => {
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            run_cat(&cfg, &backup_id, database.as_deref())?;
+        }
+        Commands::Compare { config, backup_id, format } => {
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            run_compare(&cfg, &backup_id, format).await?;
+        }
+        Commands::Verify { config, backup_ids, all, jobs, format } => {
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            run_verify(&cfg, &backup_ids, all, jobs, format)?;
+        }
+        Commands::Validate { config, strict } => {
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            run_validate(&cfg, strict)?;
+        }
+        Commands::Engines { format } => run_engines(format)?,
+        Commands::PrintConfig { config, format } => {
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            run_print_config(&cfg, format)?;
+        }
+        Commands::Schedule { config, report_file } => {
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            run_scheduler(&cfg, report_file.as_deref()).await?;
+        }
+        Commands::Transform { config, backup_id, to_format, to_compression, rekey, replace } => {
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            run_transform(&cfg, &backup_id, &TransformOptions { to_format, to_compression, rekey, replace }).await?;
+        }
+        Commands::Prune { config, dry_run } => {
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            run_prune(&cfg, dry_run)?;
+        }
+        Commands::Restore { config, backup_id, force, yes, target_db } => {
+            let cfg = Config::load(&config, cli.config_env_prefix.as_deref())?;
+            run_restore(&cfg, &backup_id, &RestoreOptions { force, yes, target_db }).await?;
         }
     }
 
@@ -0,0 +1,485 @@
+use crate::backup::history::DumpHistory;
+use crate::backup::performer::{estimate_run_duration, BackupFilter};
+use crate::commands::backup::{run_backup, BackupOptions};
+use crate::config::{Config, Schedule, DEFAULT_SCHEDULER_STATE_FILE};
+use crate::error::{Error, Result};
+use crate::storage::create_backend;
+use crate::utils::clock::{Clock, SystemClock};
+use chrono::{DateTime, Utc};
+use cron::Schedule as CronSchedule;
+use futures::future::join_all;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of upcoming fire times inspected when enforcing `min_interval_secs`
+const CRON_SAMPLE_SIZE: usize = 100;
+
+/// Parse a cron expression, accepting the classic 5-field form as well as the
+/// 6/7-field form with seconds
+pub fn parse_cron(expression: &str) -> Result<CronSchedule> {
+    let normalized = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    CronSchedule::from_str(&normalized)
+        .map_err(|e| Error::Config(format!("Invalid cron expression {:?}: {}", expression, e)))
+}
+
+/// Reject a cron whose upcoming fire times (sampled from `from`) are closer together than `min_interval`
+fn check_min_interval(expression: &str, cron: &CronSchedule, min_interval: Duration, from: DateTime<Utc>) -> Result<()> {
+    let fire_times: Vec<DateTime<Utc>> = cron.after(&from).take(CRON_SAMPLE_SIZE).collect();
+    for pair in fire_times.windows(2) {
+        let gap = (pair[1] - pair[0]).to_std().unwrap_or_default();
+        if gap < min_interval {
+            return Err(Error::Config(format!(
+                "Cron {:?} fires {}s apart (at {} and {}), more often than min_interval_secs = {}",
+                expression, gap.as_secs(), pair[0], pair[1], min_interval.as_secs()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Run every configured schedule independently until all of them stop
+pub async fn run_scheduler(config: &Config, report_file: Option<&Path>) -> Result<()> {
+    let schedules = config.all_schedules();
+    if schedules.is_empty() {
+        return Err(Error::Config("No [schedule] or [[schedules]] configured".to_string()));
+    }
+
+    // Validate everything up front so a typo fails at startup rather than at fire time
+    let clock = SystemClock;
+    let parsed = check_schedules(config, &clock)?;
+
+    let state = StateFile::load(state_path(config));
+    let results = join_all(
+        parsed.into_iter().map(|(schedule, cron)| run_schedule(config, schedule, cron, report_file, &state, &clock)),
+    )
+    .await;
+    let failures: Vec<String> = results.into_iter().filter_map(|r| r.err()).map(|e| e.to_string()).collect();
+    Err(Error::Backup(format!("All schedules stopped: {}", failures.join("; "))))
+}
+
+/// Check every configured schedule's name, engines, databases and cron, returning each with its parsed cron.
+/// `min_interval_secs` is checked against the fire times following `clock`'s time.
+pub fn check_schedules<'a>(config: &'a Config, clock: &dyn Clock) -> Result<Vec<(&'a Schedule, CronSchedule)>> {
+    let configured_engines: Vec<&str> = config.all_configured().iter().map(|(t, _)| *t).collect();
+    let mut names = HashSet::new();
+    let mut parsed = Vec::new();
+    for schedule in config.all_schedules() {
+        if !names.insert(schedule.name.as_str()) {
+            return Err(Error::Config(format!("Duplicate schedule name {:?}", schedule.name)));
+        }
+        if let Some(engine) = schedule.engines.iter().find(|e| !configured_engines.contains(&e.as_str())) {
+            return Err(Error::Config(format!(
+                "Schedule {:?} references engine {:?}, which is not configured",
+                schedule.name, engine
+            )));
+        }
+        check_schedule_databases(config, schedule)?;
+        let cron = parse_cron(&schedule.cron)?;
+        if let Some(min_interval) = schedule.min_interval_secs {
+            check_min_interval(&schedule.cron, &cron, Duration::from_secs(min_interval), clock.now())?;
+        }
+        parsed.push((schedule, cron));
+    }
+    Ok(parsed)
+}
+
+/// Check that each database a schedule names is listed by one of the engines it backs up. Names
+/// can't be checked when one of those engines reads its list with `databases_from` at backup time.
+fn check_schedule_databases(config: &Config, schedule: &Schedule) -> Result<()> {
+    let engines: Vec<_> = config.all_configured().into_iter()
+        .filter(|(db_type, _)| schedule.engines.is_empty() || schedule.engines.iter().any(|engine| engine == db_type))
+        .map(|(_, db_config)| db_config)
+        .collect();
+    if engines.iter().any(|db_config| db_config.databases_from.is_some()) {
+        return Ok(());
+    }
+    match schedule.databases.iter().find(|database| !engines.iter().any(|db_config| db_config.databases.contains(database))) {
+        Some(database) => Err(Error::Config(format!(
+            "Schedule {:?} references database {:?}, which is not configured{}",
+            schedule.name,
+            database,
+            if schedule.engines.is_empty() { String::new() } else { format!(" for {}", schedule.engines.join(", ")) }
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Where one schedule writes its run reports: `report_file` with the schedule name before the
+/// extension, so schedules firing independently don't overwrite each other's reports
+fn schedule_report_file(report_file: &Path, schedule: &str) -> PathBuf {
+    let file_name = match (report_file.file_stem(), report_file.extension()) {
+        (Some(stem), Some(extension)) => format!("{}.{}.{}", stem.to_string_lossy(), schedule, extension.to_string_lossy()),
+        _ => format!("{}.{}", report_file.file_name().unwrap_or_default().to_string_lossy(), schedule),
+    };
+    report_file.with_file_name(file_name)
+}
+
+/// Fire backups for one schedule until its failure limit is reached
+async fn run_schedule(
+    config: &Config,
+    schedule: &Schedule,
+    cron: CronSchedule,
+    report_file: Option<&Path>,
+    state: &StateFile,
+    clock: &dyn Clock,
+) -> Result<()> {
+    let options = BackupOptions {
+        filter: BackupFilter {
+            engines: schedule.engines.clone(),
+            databases: schedule.databases.clone(),
+        },
+        report_file: report_file.map(|path| schedule_report_file(path, &schedule.name)),
+        // Subset backups are retained apart from full ones, which they'd otherwise push out
+        schedule: (!schedule.engines.is_empty() || !schedule.databases.is_empty()).then(|| schedule.name.clone()),
+        ..Default::default()
+    };
+    let mut failures = FailureStreak::new(schedule.max_consecutive_failures);
+
+    info!("Schedule {:?} started with cron {:?}", schedule.name, schedule.cron);
+    let mut catchup = None;
+    let (last_fire, last_success) = (state.last_fire(&schedule.name), state.last_success(&schedule.name));
+    if let Some((missed, count)) = missed_runs(&cron, last_fire, last_success, clock.now()) {
+        if schedule.catchup == Some(true) {
+            info!(
+                "Schedule {:?} has {} run(s) without a successful backup since, the latest at {}; running it now",
+                schedule.name, count, missed
+            );
+            catchup = Some(missed);
+        } else {
+            warn!(
+                "Schedule {:?} has {} run(s) without a successful backup since, the latest at {}; waiting for the next one (set catchup = true to run it at startup)",
+                schedule.name, count, missed
+            );
+        }
+    }
+    loop {
+        let fire_time = match catchup.take() {
+            Some(missed) => missed,
+            None => {
+                let next_run = next_run(&cron, clock, state.last_fire(&schedule.name))
+                    .ok_or_else(|| Error::Config(format!("Cron {:?} has no upcoming runs", schedule.cron)))?;
+                info!("Next {:?} backup scheduled at {}", schedule.name, next_run);
+                check_expected_duration(config, schedule, &options.filter, &cron, clock).await;
+                let wait = (next_run - clock.now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+                next_run
+            }
+        };
+        state.update(&schedule.name, |recorded| recorded.last_fire = Some(fire_time.to_rfc3339()));
+
+        match run_backup(config, &options).await {
+            Ok(()) => {
+                failures.succeeded();
+                state.update(&schedule.name, |recorded| recorded.last_success = Some(clock.now().to_rfc3339()));
+            }
+            Err(e) => {
+                let give_up = failures.failed();
+                warn!("Scheduled backup {:?} failed ({} in a row): {}", schedule.name, failures.count, e);
+
+                if give_up {
+                    error!(
+                        "Giving up on schedule {:?}: {} consecutive backups failed; fix the configuration and restart the scheduler",
+                        schedule.name, failures.count
+                    );
+                    return Err(Error::Backup(format!(
+                        "Schedule {:?} stopped after {} consecutive failures, last error: {}",
+                        schedule.name, failures.count, e
+                    )));
+                }
+            }
+        }
+    }
+}
+
+/// Failed runs of a schedule since its last success, against its `max_consecutive_failures`
+struct FailureStreak {
+    count: u32,
+    max: Option<u32>,
+}
+
+impl FailureStreak {
+    fn new(max: Option<u32>) -> Self {
+        FailureStreak { count: 0, max }
+    }
+
+    fn succeeded(&mut self) {
+        self.count = 0;
+    }
+
+    /// Count a failed run, returning whether the schedule has used up its attempts
+    fn failed(&mut self) -> bool {
+        self.count += 1;
+        self.max.is_some_and(|max| self.count >= max)
+    }
+}
+
+/// First time `cron` fires after `clock`'s time and after `last_fire`, so a fire time that was
+/// already run is never run again, e.g. when the scheduler restarts within it
+fn next_run(cron: &CronSchedule, clock: &dyn Clock, last_fire: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    let now = clock.now();
+    cron.after(&last_fire.map_or(now, |last_fire| last_fire.max(now))).next()
+}
+
+/// The latest fire time of `cron` that didn't lead to a successful backup, and how many there
+/// were: those that passed after `last_fire` while the scheduler was down, plus `last_fire` itself
+/// when its run failed or was cut short, finishing after `last_success`. None when nothing was
+/// missed or the schedule never fired before.
+fn missed_runs(
+    cron: &CronSchedule,
+    last_fire: Option<DateTime<Utc>>,
+    last_success: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Option<(DateTime<Utc>, usize)> {
+    let last_fire = last_fire?;
+    let unfinished = last_success.is_none_or(|success| success < last_fire).then_some(last_fire);
+    let mut missed = unfinished.into_iter().chain(cron.after(&last_fire).take_while(|time| *time <= now));
+    let first = missed.next()?;
+    Some(missed.fold((first, 1), |(_, count), time| (time, count + 1)))
+}
+
+/// Where scheduler state is kept: `scheduler_state_file`, which `Config::load` resolves next to
+/// the config file when it isn't set
+fn state_path(config: &Config) -> PathBuf {
+    PathBuf::from(config.scheduler_state_file.as_deref().unwrap_or(DEFAULT_SCHEDULER_STATE_FILE))
+}
+
+/// When each schedule last fired and last succeeded, as RFC 3339 times
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SchedulerState {
+    #[serde(default)]
+    schedules: BTreeMap<String, ScheduleState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ScheduleState {
+    last_fire: Option<String>, // Fire time of the latest run started
+    last_success: Option<String>, // When the latest successful run finished
+}
+
+/// Scheduler state shared by the schedules of one process, saved after every change so a
+/// restarted scheduler knows which runs it missed
+struct StateFile {
+    path: PathBuf,
+    state: Mutex<SchedulerState>,
+}
+
+impl StateFile {
+    /// Load the state, starting afresh when there is none or it can't be read
+    fn load(path: PathBuf) -> Self {
+        let state = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable scheduler state {:?}: {}", path, e);
+                SchedulerState::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => SchedulerState::default(),
+            Err(e) => {
+                warn!("Ignoring unreadable scheduler state {:?}: {}", path, e);
+                SchedulerState::default()
+            }
+        };
+        StateFile { path, state: Mutex::new(state) }
+    }
+
+    fn last_fire(&self, schedule: &str) -> Option<DateTime<Utc>> {
+        let state = self.state.lock().unwrap();
+        parse_time(state.schedules.get(schedule)?.last_fire.as_deref()?)
+    }
+
+    fn last_success(&self, schedule: &str) -> Option<DateTime<Utc>> {
+        let state = self.state.lock().unwrap();
+        parse_time(state.schedules.get(schedule)?.last_success.as_deref()?)
+    }
+
+    /// Change one schedule's state and save it; failing to save is only logged
+    fn update<F: FnOnce(&mut ScheduleState)>(&self, schedule: &str, change: F) {
+        let mut state = self.state.lock().unwrap();
+        change(state.schedules.entry(schedule.to_string()).or_default());
+        if let Err(e) = save_state(&self.path, &state) {
+            warn!("Failed to save scheduler state to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time).ok().map(|time| time.with_timezone(&Utc))
+}
+
+/// Write the state, replacing the previous file atomically
+fn save_state(path: &Path, state: &SchedulerState) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let contents = serde_json::to_string_pretty(state)?;
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)
+}
+
+/// Predict the next run's duration from earlier dumps and warn when it is likely to still be
+/// running at the following fire time. Failures only skip the check.
+async fn check_expected_duration(
+    config: &Config,
+    schedule: &Schedule,
+    filter: &BackupFilter,
+    cron: &CronSchedule,
+    clock: &dyn Clock,
+) {
+    let estimate = async {
+        let history = DumpHistory::from_backups(&create_backend(&config.storage)?.list()?);
+        estimate_run_duration(config, filter, &history).await
+    };
+    let expected = match estimate.await {
+        Ok(Some(expected)) => expected,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Could not estimate the duration of {:?} backups: {}", schedule.name, e);
+            return;
+        }
+    };
+
+    let fire_times: Vec<DateTime<Utc>> = cron.after(&clock.now()).take(2).collect();
+    let gap = match fire_times.as_slice() {
+        [next, after] => (*after - *next).to_std().unwrap_or_default(),
+        _ => return,
+    };
+    if expected > gap {
+        warn!(
+            "Schedule {:?} backups are expected to take about {}s based on recent dumps, longer than the {}s between runs; the next run will likely overlap the one after it",
+            schedule.name, expected.as_secs(), gap.as_secs()
+        );
+    } else {
+        info!("Next {:?} backup is expected to take about {}s", schedule.name, expected.as_secs());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::MockClock;
+
+    #[test]
+    fn parses_five_and_six_field_expressions() {
+        assert!(parse_cron("0 2 * * *").is_ok());
+        assert!(parse_cron("0 0 2 * * *").is_ok());
+        assert!(parse_cron("not a cron").is_err());
+    }
+
+    #[test]
+    fn gives_up_after_max_consecutive_failures() {
+        let mut failures = FailureStreak::new(Some(3));
+        assert!(!failures.failed());
+        assert!(!failures.failed());
+        failures.succeeded();
+
+        // A success starts the count over, so it takes three more failures in a row
+        assert!(!failures.failed());
+        assert!(!failures.failed());
+        assert!(failures.failed());
+        assert_eq!(failures.count, 3);
+
+        let mut unlimited = FailureStreak::new(None);
+        assert!((0..100).all(|_| !unlimited.failed()));
+    }
+
+    #[test]
+    fn each_schedule_writes_its_own_report() {
+        assert_eq!(schedule_report_file(Path::new("/var/log/kronos/report.json"), "nightly"), PathBuf::from("/var/log/kronos/report.nightly.json"));
+        assert_eq!(schedule_report_file(Path::new("report"), "hourly"), PathBuf::from("report.hourly"));
+    }
+
+    #[test]
+    fn enforces_min_interval() {
+        let from = MockClock::at("2024-01-10T12:00:00Z").now();
+        let hour = Duration::from_secs(3600);
+        let every_minute = parse_cron("* * * * *").unwrap();
+        assert!(matches!(check_min_interval("* * * * *", &every_minute, hour, from), Err(Error::Config(_))));
+
+        // Irregular spacing: fine most of the day, but 10 minutes apart around 2 AM
+        let clustered = parse_cron("0 0,10 2 * * *").unwrap();
+        assert!(check_min_interval("0 0,10 2 * * *", &clustered, hour, from).is_err());
+
+        let daily = parse_cron("0 2 * * *").unwrap();
+        assert!(check_min_interval("0 2 * * *", &daily, hour, from).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_schedule_databases() {
+        let config = |databases: &str| -> Config {
+            toml::from_str(&format!(
+                "[databases.mysql]\nhost = \"db\"\nport = 3306\nuser = \"kronos\"\ndatabases = [\"shop\", \"crm\"]\n\
+                 [databases.sqlite]\nhost = \"/var/lib\"\nport = 0\nuser = \"\"\ndatabases = [\"app.db\"]\n\
+                 [storage]\ntype_ = \"local\"\n\
+                 [[schedules]]\nname = \"hourly\"\ncron = \"0 * * * *\"\nengines = [\"mysql\"]\ndatabases = {}\n",
+                databases
+            ))
+            .unwrap()
+        };
+        let clock = MockClock::at("2024-01-10T12:00:00Z");
+        assert!(check_schedules(&config("[\"shop\"]"), &clock).is_ok());
+        assert!(matches!(check_schedules(&config("[\"shpo\"]"), &clock), Err(Error::Config(_))));
+        // app.db is configured, but not for the schedule's engines
+        assert!(matches!(check_schedules(&config("[\"app.db\"]"), &clock), Err(Error::Config(_))));
+
+        let mut from_file = config("[\"shpo\"]");
+        from_file.databases.mysql.as_mut().unwrap().databases_from = Some("/etc/kronos/databases".to_string());
+        assert!(check_schedules(&from_file, &clock).is_ok());
+    }
+
+    #[test]
+    fn computes_next_run_from_the_clock() {
+        let daily = parse_cron("0 2 * * *").unwrap();
+        let clock = MockClock::at("2024-01-10T01:59:30Z");
+        assert_eq!(next_run(&daily, &clock, None).unwrap().to_rfc3339(), "2024-01-10T02:00:00+00:00");
+
+        // Restarted just before a fire time that already ran: it isn't run twice
+        let ran = next_run(&daily, &clock, None);
+        clock.advance(chrono::Duration::seconds(-10));
+        assert_eq!(next_run(&daily, &clock, ran).unwrap().to_rfc3339(), "2024-01-11T02:00:00+00:00");
+
+        // A run that fires exactly now is already past; the next one is a day later
+        clock.advance(chrono::Duration::seconds(40));
+        assert_eq!(next_run(&daily, &clock, None).unwrap().to_rfc3339(), "2024-01-11T02:00:00+00:00");
+    }
+
+    #[test]
+    fn finds_runs_missed_while_down() {
+        let daily = parse_cron("0 2 * * *").unwrap();
+        let at = |time: &str| MockClock::at(time).now();
+        let last_fire = Some(at("2024-01-10T02:00:00Z"));
+        let succeeded = Some(at("2024-01-10T02:30:00Z"));
+
+        assert_eq!(missed_runs(&daily, last_fire, succeeded, at("2024-01-11T01:00:00Z")), None);
+        assert_eq!(missed_runs(&daily, last_fire, succeeded, at("2024-01-11T02:00:00Z")), Some((at("2024-01-11T02:00:00Z"), 1)));
+        assert_eq!(missed_runs(&daily, last_fire, succeeded, at("2024-01-13T09:00:00Z")), Some((at("2024-01-13T02:00:00Z"), 3)));
+        // A schedule that never fired has nothing to catch up on
+        assert_eq!(missed_runs(&daily, None, None, at("2024-01-13T09:00:00Z")), None);
+    }
+
+    #[test]
+    fn catches_up_on_a_run_that_failed_or_was_cut_short() {
+        let daily = parse_cron("0 2 * * *").unwrap();
+        let at = |time: &str| MockClock::at(time).now();
+        let last_fire = Some(at("2024-01-10T02:00:00Z"));
+        let now = at("2024-01-10T03:00:00Z");
+
+        assert_eq!(missed_runs(&daily, last_fire, None, now), Some((at("2024-01-10T02:00:00Z"), 1)));
+        assert_eq!(missed_runs(&daily, last_fire, Some(at("2024-01-09T02:30:00Z")), now), Some((at("2024-01-10T02:00:00Z"), 1)));
+        assert_eq!(missed_runs(&daily, last_fire, Some(at("2024-01-10T02:30:00Z")), now), None);
+        // The failed run and the ones missed after it
+        assert_eq!(
+            missed_runs(&daily, last_fire, None, at("2024-01-12T09:00:00Z")),
+            Some((at("2024-01-12T02:00:00Z"), 3))
+        );
+    }
+}
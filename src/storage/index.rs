@@ -0,0 +1,190 @@
+use crate::backup::manifest::{Manifest, MANIFEST_FILE};
+use crate::error::{Error, Result};
+use crate::utils::compression::{read_archive_file, ReadOptions};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// File name of the index kept next to the archives in storage
+pub const INDEX_FILE: &str = "index.json";
+
+/// Lock file guarding read-modify-write updates of the index
+pub const INDEX_LOCK_FILE: &str = "index.lock";
+
+/// How often, and how long, a contended index lock is retried (about 30 seconds in total)
+const LOCK_ATTEMPTS: u32 = 300;
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Cached manifests of stored archives, so listing doesn't have to open every archive.
+/// Archives missing from the index are read and added the next time storage is listed.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Index {
+    pub archives: BTreeMap<String, IndexEntry>, // Keyed by archive file name
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexEntry {
+    pub size: u64, // Archive size when indexed; a different size means the entry is stale
+    pub manifest: Option<Manifest>,
+}
+
+impl Index {
+    /// Load the index from a storage directory; a missing or unreadable index is treated as empty
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(INDEX_FILE);
+        match fs::read(&path) {
+            Ok(contents) => Index::parse(&contents, &path.to_string_lossy()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(e) => {
+                warn!("Failed to read index {:?}: {}", path, e);
+                Index::default()
+            }
+        }
+    }
+
+    /// Parse an index read from `origin`; an unreadable index is treated as empty
+    pub fn parse(contents: &[u8], origin: &str) -> Self {
+        serde_json::from_slice(contents).unwrap_or_else(|e| {
+            warn!("Rebuilding unreadable index {}: {}", origin, e);
+            Index::default()
+        })
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec_pretty(self)
+            .map_err(|e| Error::Storage(format!("Failed to serialize index: {}", e)))
+    }
+
+    /// Whether both indexes cover the same archives at the same sizes
+    pub fn matches(&self, other: &Index) -> bool {
+        self.archives.len() == other.archives.len()
+            && self.archives.iter().all(|(file_name, entry)| other.get(file_name, entry.size).is_some())
+    }
+
+    /// Cached entry for an archive, if it is still the same size
+    pub fn get(&self, file_name: &str, size: u64) -> Option<&IndexEntry> {
+        self.archives.get(file_name).filter(|entry| entry.size == size)
+    }
+
+    /// Read-modify-write the index of a storage directory while holding its lock, so concurrent
+    /// backups and prunes never overwrite each other's changes
+    pub fn update<F: FnOnce(&mut Index)>(dir: &Path, modify: F) -> Result<()> {
+        let _lock = IndexLock::acquire(dir)?;
+
+        // A leftover temporary file means a writer died mid-save; the index itself is intact
+        let tmp_path = dir.join(INDEX_TMP_FILE);
+        if tmp_path.exists() {
+            warn!("Discarding partially written index {:?}", tmp_path);
+            fs::remove_file(&tmp_path).map_err(Error::Io)?;
+        }
+
+        let mut index = Index::load(dir);
+        modify(&mut index);
+        index.save(dir)
+    }
+
+    /// Write the index into a storage directory, replacing the previous one atomically.
+    /// Callers other than `update` must hold the index lock.
+    fn save(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(INDEX_TMP_FILE);
+        let mut file = File::create(&tmp_path).map_err(Error::Io)?;
+        file.write_all(&self.to_json()?).map_err(Error::Io)?;
+        file.sync_all().map_err(Error::Io)?;
+        fs::rename(&tmp_path, dir.join(INDEX_FILE)).map_err(Error::Io)?;
+        Ok(())
+    }
+}
+
+/// Temporary file the index is written to before being renamed into place
+pub const INDEX_TMP_FILE: &str = ".index.json.tmp";
+
+/// Exclusive advisory lock on a storage directory's index, released when dropped. Content-addressed
+/// storage also holds it while adding or dropping references to objects; the index can't be
+/// updated until it is released.
+pub struct IndexLock {
+    _file: File,
+}
+
+impl IndexLock {
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        let path = dir.join(INDEX_LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| Error::Storage(format!("Failed to open index lock {:?}: {}", path, e)))?;
+        retry_lock(&path.to_string_lossy(), || match file.try_lock() {
+            Ok(()) => Ok(true),
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(e)) => Err(Error::Storage(format!("Failed to lock {:?}: {}", path, e))),
+        })?;
+        Ok(IndexLock { _file: file })
+    }
+}
+
+/// Call `try_lock` until it reports the lock was taken, sleeping between attempts
+pub fn retry_lock<F: FnMut() -> Result<bool>>(lock: &str, mut try_lock: F) -> Result<()> {
+    for attempt in 1..=LOCK_ATTEMPTS {
+        if try_lock()? {
+            return Ok(());
+        }
+        if attempt == 1 {
+            info!("Waiting for another kronos process to release {}", lock);
+        }
+        thread::sleep(LOCK_RETRY_INTERVAL);
+    }
+    Err(Error::Storage(format!(
+        "Timed out after {}s waiting for {}",
+        (LOCK_RETRY_INTERVAL * LOCK_ATTEMPTS).as_secs(),
+        lock
+    )))
+}
+
+impl IndexEntry {
+    /// Index a local copy of an archive by reading its embedded manifest
+    pub fn read(path: &Path, size: u64, options: &ReadOptions) -> Self {
+        let manifest = match read_archive_file(path, MANIFEST_FILE, options) {
+            Ok(Some(contents)) => serde_json::from_slice(&contents)
+                .map_err(|e| warn!("Ignoring unreadable manifest in {:?}: {}", path, e))
+                .ok(),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Failed to read {:?}: {}", path, e);
+                None
+            }
+        };
+        IndexEntry { size, manifest }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(INDEX_TMP_FILE), b"{\"archi").unwrap();
+
+        thread::scope(|scope| {
+            for i in 0..8 {
+                let dir = dir.path();
+                scope.spawn(move || {
+                    Index::update(dir, |index| {
+                        index.archives.insert(format!("backup-{}.tar.gz", i), IndexEntry { size: i, manifest: None });
+                    })
+                    .unwrap();
+                });
+            }
+        });
+
+        assert_eq!(Index::load(dir.path()).archives.len(), 8);
+        assert!(!dir.path().join(INDEX_TMP_FILE).exists());
+    }
+}
@@ -1,31 +1,670 @@
-use crate::error::{Error, Result};
-use crate::utils::compression::compress_directory;
+use crate::backup::manifest::{Manifest, MANIFEST_FILE};
+use crate::config::Storage;
+use crate::error::{is_disk_full, Error, Result};
+use crate::storage::index::{Index, IndexEntry, IndexLock};
+use crate::storage::{
+    archive_extensions, backup_id_from_file_name, is_partial_archive, remove_stale_files, StorageBackend, StoredArchive,
+    StoredBackup, SIGNATURE_EXTENSION,
+};
+use crate::utils::checksum::{checksum_file, sha256_tree};
+use crate::utils::compression::{compress_directory, ArchiveOptions, ReadOptions};
+use crate::utils::encryption::EncryptionKey;
+use crate::utils::failpoint::{fail_point, FailurePhase};
+use crate::utils::permissions::set_file_mode;
+use async_trait::async_trait;
+use log::{info, warn};
 use std::fs;
-use std::path::{Path, PathBuf};
-use tokio::fs as async_fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+/// Directories of the content-addressed layout: archives named by their SHA-256, and one
+/// file per backup holding the path of its archive relative to the storage directory
+const OBJECTS_DIR: &str = "objects";
+const REFS_DIR: &str = "refs";
 
 pub struct LocalStorage {
     base_path: String,
+    work_dir: Option<PathBuf>, // Where archives are assembled; the storage directory when unset
+    external_extension: Option<String>, // Extension of archives written by `compressor_command`
+    read_options: ReadOptions, // How stored archives are read back
+    content_addressed: bool, // Store new archives as objects with a ref per backup
+    archive_mode: u32, // Permission bits of stored archives
 }
 
 impl LocalStorage {
     pub fn new(base_path: &str) -> Self {
         LocalStorage {
             base_path: base_path.to_string(),
+            work_dir: None,
+            external_extension: None,
+            read_options: ReadOptions::default(),
+            content_addressed: false,
+            archive_mode: 0o600,
         }
     }
 
-    pub async fn store(&self, source_dir: &Path, backup_id: &str) -> Result<()> {
-        let backup_filename = format!("{}.tar.gz", backup_id);
-        let temp_output = PathBuf::from(&backup_filename);
-        compress_directory(source_dir, &temp_output)?;
+    /// Local storage as described by the `[storage]` section
+    pub fn from_config(storage: &Storage) -> Self {
+        LocalStorage {
+            external_extension: storage.compressor_extension.clone(),
+            read_options: ReadOptions::from_storage(storage),
+            content_addressed: storage.content_addressed == Some(true),
+            archive_mode: storage.archive_mode(),
+            ..LocalStorage::new(storage.local_path())
+        }
+        .with_work_dir(storage.archive_temp_dir.as_deref())
+    }
 
-        let final_path = PathBuf::from(&self.base_path).join(&backup_filename);
+    /// How archives are read back: the decompressor command and encryption key
+    pub fn read_options(&self) -> &ReadOptions {
+        &self.read_options
+    }
+
+    /// Assemble archives in `work_dir` instead of the storage directory
+    pub fn with_work_dir(mut self, work_dir: Option<&str>) -> Self {
+        self.work_dir = work_dir.map(PathBuf::from);
+        self
+    }
+
+    fn store_with<F>(&self, source_dir: &Path, backup_id: &str, options: &ArchiveOptions, rename: F) -> Result<StoredArchive>
+    where
+        F: Fn(&Path, &Path) -> io::Result<()>,
+    {
         fs::create_dir_all(&self.base_path).map_err(Error::Io)?;
-        async_fs::rename(&temp_output, &final_path)
-            .await
-            .map_err(Error::Io)?;
+        let (final_path, index_key, checksum) = if self.content_addressed {
+            self.store_object(source_dir, backup_id, options, rename)?
+        } else {
+            let backup_filename = format!("{}.{}", backup_id, options.extension());
+            let temp_output = self.build_archive(source_dir, &backup_filename, options)?;
+            let checksum = checksum_file(temp_output.path(), options.checksum)?;
+            fail_point(FailurePhase::Store)?;
+            let final_path = PathBuf::from(&self.base_path).join(&backup_filename);
+            move_file(temp_output.path(), &final_path, rename)?;
+            (final_path, backup_filename, checksum)
+        };
+
+        // Index the new archive now so the next listing doesn't have to open it
+        let size = fs::metadata(&final_path).map_err(Error::Io)?.len();
+        let index_entry = IndexEntry::read(&final_path, size, self.read_options());
+        self.update_index(|index| {
+            index.archives.insert(index_key, index_entry);
+        });
+
+        Ok(StoredArchive {
+            location: final_path.to_string_lossy().to_string(),
+            size,
+            checksum,
+            checksum_algorithm: options.checksum,
+        })
+    }
+
+    /// Write the archive of `source_dir` to a partial file in the work directory
+    fn build_archive(&self, source_dir: &Path, backup_filename: &str, options: &ArchiveOptions) -> Result<PartialFile> {
+        // By default build the archive inside the destination so the final rename never crosses filesystems
+        let work_dir = self.work_dir.clone().unwrap_or_else(|| PathBuf::from(&self.base_path));
+        fs::create_dir_all(&work_dir).map_err(Error::Io)?;
+        let temp_output = PartialFile::new(work_dir.join(format!(".{}.partial", backup_filename)));
+        compress_directory(source_dir, temp_output.path(), options)?;
+        set_file_mode(temp_output.path(), self.archive_mode)?;
+        Ok(temp_output)
+    }
+
+    /// Store a backup as a ref to the object named by its dumps, writing the object unless a
+    /// backup with the same dumps stored it already. Returns the object's path, its path relative
+    /// to the storage directory and the archive's checksum.
+    fn store_object<F>(&self, source_dir: &Path, backup_id: &str, options: &ArchiveOptions, rename: F) -> Result<(PathBuf, String, String)>
+    where
+        F: Fn(&Path, &Path) -> io::Result<()>,
+    {
+        let relative = object_path(&content_key(source_dir, options)?, options.extension());
+        let object = PathBuf::from(&self.base_path).join(&relative);
+        let manifest = fs::read(source_dir.join(MANIFEST_FILE)).map_err(Error::Io)?;
+        let mut built = None;
+        loop {
+            // Archives are built outside the lock, which other runs would otherwise wait on
+            if built.is_none() && !object.is_file() {
+                built = Some(self.build_archive(source_dir, &format!("{}.{}", backup_id, options.extension()), options)?);
+            }
+            fail_point(FailurePhase::Store)?;
+            // A prune removes an object along with its last ref under the same lock, so the object
+            // can't disappear between finding it here and referring to it
+            let _lock = IndexLock::acquire(Path::new(&self.base_path))?;
+            if object.is_file() {
+                info!("Backup {} has the same dumps as stored archive {:?}; adding a reference only", backup_id, object);
+                if let Some(built) = &built {
+                    fs::remove_file(built.path()).map_err(Error::Io)?;
+                }
+            } else if let Some(built) = &built {
+                if let Some(parent) = object.parent() {
+                    fs::create_dir_all(parent).map_err(Error::Io)?;
+                }
+                move_file(built.path(), &object, &rename)?;
+                make_read_only(&object)?;
+            } else {
+                // Pruned since it was found; build it after all
+                continue;
+            }
+            self.write_ref(backup_id, &relative, &manifest)?;
+            break;
+        }
+        let checksum = checksum_file(&object, options.checksum)?;
+        Ok((object, relative, checksum))
+    }
+
+    /// Path of a stored backup archive in any supported format, failing if it doesn't exist.
+    /// Backups with a ref resolve to the object it points to.
+    pub fn archive_path(&self, backup_id: &str) -> Result<PathBuf> {
+        if let Some(relative) = self.read_ref(backup_id)? {
+            let object = PathBuf::from(&self.base_path).join(&relative);
+            if !object.is_file() {
+                return Err(Error::Storage(format!("Backup {} refers to missing archive {:?}", backup_id, object)));
+            }
+            return Ok(object);
+        }
+        archive_extensions(self.external_extension.as_deref()).into_iter()
+            .map(|extension| PathBuf::from(&self.base_path).join(format!("{}.{}", backup_id, extension)))
+            .find(|path| path.is_file())
+            .ok_or_else(|| Error::Storage(format!("Backup {} not found in {}", backup_id, self.base_path)))
+    }
+
+    /// Path of a backup's detached signature, whether or not it exists
+    pub fn signature_path(&self, backup_id: &str) -> PathBuf {
+        PathBuf::from(&self.base_path).join(format!("{}.{}", backup_id, SIGNATURE_EXTENSION))
+    }
+
+    fn ref_path(&self, backup_id: &str) -> PathBuf {
+        PathBuf::from(&self.base_path).join(REFS_DIR).join(backup_id)
+    }
+
+    /// Archive a backup's ref points to, relative to the storage directory; None without a ref
+    fn read_ref(&self, backup_id: &str) -> Result<Option<String>> {
+        let path = self.ref_path(backup_id);
+        let target = match fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().next().unwrap_or_default().trim().to_string(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Storage(format!("Failed to read ref {:?}: {}", path, e))),
+        };
+        let inside_objects = Path::new(&target).starts_with(OBJECTS_DIR)
+            && Path::new(&target).components().all(|c| matches!(c, Component::Normal(_)));
+        if !inside_objects {
+            return Err(Error::Storage(format!("Ref {:?} points outside {}/: {:?}", path, OBJECTS_DIR, target)));
+        }
+        Ok(Some(target))
+    }
+
+    /// The backup's own manifest, kept in its ref after the object path: the object's embedded
+    /// one is that of whichever backup stored it first. None for refs written without one.
+    fn ref_manifest(&self, backup_id: &str) -> Option<Manifest> {
+        let contents = fs::read_to_string(self.ref_path(backup_id)).ok()?;
+        let (_, manifest) = contents.split_once('\n')?;
+        serde_json::from_str(manifest)
+            .map_err(|e| warn!("Ignoring unreadable manifest in the ref of {}: {}", backup_id, e))
+            .ok()
+    }
+
+    /// Point a backup's ref at an object, followed by the backup's manifest, replacing it atomically
+    fn write_ref(&self, backup_id: &str, relative: &str, manifest: &[u8]) -> Result<()> {
+        let refs = PathBuf::from(&self.base_path).join(REFS_DIR);
+        fs::create_dir_all(&refs).map_err(Error::Io)?;
+        let tmp_path = refs.join(format!(".{}.tmp", backup_id));
+        let mut contents = format!("{}\n", relative).into_bytes();
+        contents.extend_from_slice(manifest);
+        fs::write(&tmp_path, contents).map_err(Error::Io)?;
+        fs::rename(&tmp_path, self.ref_path(backup_id))
+            .map_err(|e| Error::Storage(format!("Failed to write ref for {}: {}", backup_id, e)))
+    }
+
+    /// Backups stored by ref, as (backup id, archive path relative to the storage directory)
+    fn list_refs(&self) -> Result<Vec<(String, String)>> {
+        let entries = match fs::read_dir(PathBuf::from(&self.base_path).join(REFS_DIR)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let mut refs = Vec::new();
+        for entry in entries {
+            let backup_id = entry.map_err(Error::Io)?.file_name().to_string_lossy().to_string();
+            if backup_id.starts_with('.') {
+                continue;
+            }
+            if let Some(relative) = self.read_ref(&backup_id)? {
+                refs.push((backup_id, relative));
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Delete a backup's ref, and the object it points to once no other ref does
+    fn remove_ref(&self, backup_id: &str, relative: &str) -> Result<()> {
+        let lock = IndexLock::acquire(Path::new(&self.base_path))?;
+        remove_if_present(&self.ref_path(backup_id))?;
+        match fs::remove_file(self.signature_path(backup_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to remove signature of {}: {}", backup_id, e);
+            }
+            _ => {}
+        }
 
+        if self.list_refs()?.iter().any(|(_, other)| other == relative) {
+            return Ok(());
+        }
+        remove_if_present(&PathBuf::from(&self.base_path).join(relative))?;
+        drop(lock);
+        self.update_index(|index| {
+            index.archives.remove(relative);
+        });
+        Ok(())
+    }
+
+    /// Update the index; it is only a cache, so failures are logged rather than returned
+    fn update_index<F: FnOnce(&mut Index)>(&self, modify: F) {
+        if let Err(e) = Index::update(Path::new(&self.base_path), modify) {
+            warn!("Failed to update backup index in {}: {}", self.base_path, e);
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn store(&self, source_dir: &Path, backup_id: &str, options: &ArchiveOptions) -> Result<StoredArchive> {
+        self.store_with(source_dir, backup_id, options, |from, to| fs::rename(from, to))
+    }
+
+    /// Manifests come from the index; archives it doesn't know yet are read and backfilled
+    fn list(&self) -> Result<Vec<StoredBackup>> {
+        let entries = match fs::read_dir(&self.base_path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let extensions = archive_extensions(self.external_extension.as_deref());
+        let cached = Index::load(Path::new(&self.base_path));
+        let mut index = Index::default();
+        let mut backups = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(Error::Io)?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some(backup_id) = backup_id_from_file_name(&file_name, &extensions) else {
+                continue;
+            };
+
+            let path = entry.path();
+            let size = entry.metadata().map_err(Error::Io)?.len();
+            let index_entry = match cached.get(&file_name, size) {
+                Some(index_entry) => index_entry.clone(),
+                None => IndexEntry::read(&path, size, self.read_options()),
+            };
+
+            backups.push(StoredBackup {
+                backup_id: backup_id.to_string(),
+                path,
+                size,
+                manifest: index_entry.manifest.clone(),
+            });
+            index.archives.insert(file_name, index_entry);
+        }
+
+        // Content-addressed backups, listed whichever mode new backups are stored in
+        for (backup_id, relative) in self.list_refs()? {
+            let path = PathBuf::from(&self.base_path).join(&relative);
+            let size = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) => {
+                    warn!("Backup {} refers to unreadable archive {:?}: {}", backup_id, path, e);
+                    continue;
+                }
+            };
+            let index_entry = match cached.get(&relative, size) {
+                Some(index_entry) => index_entry.clone(),
+                None => IndexEntry::read(&path, size, self.read_options()),
+            };
+            let manifest = self.ref_manifest(&backup_id).or_else(|| index_entry.manifest.clone());
+            backups.push(StoredBackup { backup_id, path, size, manifest });
+            index.archives.insert(relative, index_entry);
+        }
+
+        // Backfill new archives and forget removed ones, keeping whatever other processes added meanwhile
+        if !index.matches(&cached) {
+            let base_path = Path::new(&self.base_path);
+            self.update_index(|current| {
+                current.archives.extend(index.archives);
+                current.archives.retain(|file_name, _| base_path.join(file_name).is_file());
+            });
+        }
+
+        backups.sort_by(|a, b| a.backup_id.cmp(&b.backup_id));
+        Ok(backups)
+    }
+
+    fn store_signature(&self, backup_id: &str, signature: &[u8]) -> Result<()> {
+        let path = self.signature_path(backup_id);
+        fs::write(&path, signature)
+            .map_err(|e| Error::Storage(format!("Failed to write signature {:?}: {}", path, e)))
+    }
+
+    fn remove(&self, backup: &StoredBackup) -> Result<()> {
+        if let Some(relative) = self.read_ref(&backup.backup_id)? {
+            return self.remove_ref(&backup.backup_id, &relative);
+        }
+        remove_if_present(&backup.path)?;
+        match fs::remove_file(self.signature_path(&backup.backup_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to remove signature of {}: {}", backup.backup_id, e);
+            }
+            _ => {}
+        }
+
+        if let Some(file_name) = backup.path.file_name() {
+            self.update_index(|index| {
+                index.archives.remove(file_name.to_string_lossy().as_ref());
+            });
+        }
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn remove_stale_partials(&self, grace: Duration) -> Result<Vec<String>> {
+        let mut removed = remove_stale_files(Path::new(&self.base_path), grace, is_partial_archive)?;
+        if let Some(work_dir) = self.work_dir.as_deref().filter(|dir| *dir != Path::new(&self.base_path)) {
+            removed.extend(remove_stale_files(work_dir, grace, is_partial_archive)?);
+        }
+        Ok(removed)
+    }
+}
+
+/// Remove a file; one that is already gone was removed by a concurrent prune, which is fine
+fn remove_if_present(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(Error::Storage(format!("Failed to remove {:?}: {}", path, e)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Name of the object a backup is stored as: a SHA-256 over its dumps and what decides how the
+/// archive is written (format, compressor and encryption key), so backups with the same dumps
+/// share one object. The manifest is left out, as it differs between runs, except for what a
+/// restore reads from it. The archive root isn't covered; readers strip it whatever it is.
+fn content_key(source_dir: &Path, options: &ArchiveOptions) -> Result<String> {
+    let manifest: Option<Manifest> = fs::read(source_dir.join(MANIFEST_FILE)).ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok());
+    let restored = manifest.map(|manifest| serde_json::json!({
+        "host": manifest.host,
+        "layout": manifest.layout,
+        "engines": manifest.engines,
+    }));
+    let header = serde_json::json!({
+        "extension": options.extension(),
+        "encryption": options.encryption.as_ref().map(EncryptionKey::fingerprint),
+        "manifest": restored,
+    });
+    sha256_tree(source_dir, &[MANIFEST_FILE], header.to_string().as_bytes())
+}
+
+/// Object path of an archive, relative to the storage directory: objects/ab/cd/<sha256>.<extension>
+fn object_path(sha256: &str, extension: &str) -> String {
+    format!("{}/{}/{}/{}.{}", OBJECTS_DIR, &sha256[..2], &sha256[2..4], sha256, extension)
+}
+
+/// Stored objects are never modified; the mode only guards against accidental writes
+fn make_read_only(path: &Path) -> Result<()> {
+    let mut permissions = fs::metadata(path).map_err(Error::Io)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions).map_err(Error::Io)
+}
+
+/// Partially written archive that is removed on drop unless it was moved into place
+struct PartialFile {
+    path: PathBuf,
+}
+
+impl PartialFile {
+    fn new(path: PathBuf) -> Self {
+        PartialFile { path }
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        // After a successful move the file is already gone, so NotFound is expected
+        match fs::remove_file(&self.path) {
+            Ok(()) => warn!("Removed incomplete archive {:?}", self.path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove incomplete archive {:?}: {}", self.path, e),
+        }
+    }
+}
+
+/// Move a file into place, falling back to copy-then-remove when a rename would cross filesystems
+/// (EXDEV on Unix, ERROR_NOT_SAME_DEVICE between drives on Windows)
+fn move_file<F>(from: &Path, to: &Path, rename: F) -> Result<()>
+where
+    F: Fn(&Path, &Path) -> io::Result<()>,
+{
+    match rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            warn!("{:?} and {:?} are on different filesystems; copying instead of renaming", from, to);
+            if let Err(e) = fs::copy(from, to) {
+                let _ = fs::remove_file(to);
+                if is_disk_full(&e) {
+                    return Err(Error::DiskFull(format!("ran out of disk space copying archive to {:?}; free space there", to)));
+                }
+                return Err(Error::Storage(format!("Failed to copy archive to {:?}: {}", to, e)));
+            }
+            fs::remove_file(from).map_err(Error::Io)
+        }
+        Err(e) if is_disk_full(&e) => {
+            Err(Error::DiskFull(format!("ran out of disk space moving archive to {:?}; free space there", to)))
+        }
+        Err(e) => Err(Error::Storage(format!("Failed to move archive to {:?}: {}", to, e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RetentionConfig;
+    use crate::storage::index::{INDEX_FILE, INDEX_LOCK_FILE};
+    use crate::utils::clock::MockClock;
+
+    #[test]
+    fn move_file_falls_back_to_copy_across_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("archive.partial");
+        let to = dir.path().join("archive.tar.gz");
+        fs::write(&from, b"archive").unwrap();
+
+        move_file(&from, &to, |_, _| Err(io::Error::from(io::ErrorKind::CrossesDevices))).unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"archive");
+    }
+
+    #[tokio::test]
+    async fn store_leaves_only_the_final_archive() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("app.bak"), b"data").unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(destination.path().to_str().unwrap());
+
+        storage.store(source.path(), "backup-test", &ArchiveOptions::default()).await.unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(destination.path()).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["backup-test.tar.gz", INDEX_FILE, INDEX_LOCK_FILE]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stored_archives_get_the_configured_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("app.bak"), b"data").unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let storage = LocalStorage { archive_mode: 0o640, ..LocalStorage::new(destination.path().to_str().unwrap()) };
+
+        let stored = storage.store(source.path(), "backup-test", &ArchiveOptions::default()).await.unwrap();
+
+        assert_eq!(fs::metadata(&stored.location).unwrap().permissions().mode() & 0o777, 0o640);
+    }
+
+    #[test]
+    fn removes_only_stale_partial_archives() {
+        let destination = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(destination.path().to_str().unwrap());
+        let stale = destination.path().join(".backup-old.tar.gz.partial");
+        let fresh = destination.path().join(".backup-new.tar.gz.partial");
+        fs::write(&stale, b"half").unwrap();
+        fs::write(&fresh, b"half").unwrap();
+        fs::write(destination.path().join("backup-old.tar.gz"), b"archive").unwrap();
+        let two_hours_ago = std::time::SystemTime::now() - Duration::from_secs(7200);
+        fs::File::options().write(true).open(&stale).unwrap().set_modified(two_hours_ago).unwrap();
+
+        let removed = storage.remove_stale_partials(Duration::from_secs(3600)).unwrap();
+
+        assert_eq!(removed, [stale.to_string_lossy()]);
+        assert!(fresh.exists());
+        assert!(destination.path().join("backup-old.tar.gz").exists());
+    }
+
+    #[tokio::test]
+    async fn prunes_by_age_as_of_the_clock() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("app.bak"), b"data").unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(destination.path().to_str().unwrap());
+        for backup_id in ["backup-20240101T000000", "backup-20240105T000000", "backup-20240109T000000"] {
+            storage.store(source.path(), backup_id, &ArchiveOptions::default()).await.unwrap();
+        }
+        let retention = RetentionConfig { keep_last: None, max_age_days: Some(7) };
+        let clock = MockClock::at("2024-01-10T00:00:00Z");
+
+        assert_eq!(storage.prune(&retention, true, &clock).unwrap(), ["backup-20240101T000000"]);
+        clock.advance(chrono::Duration::days(30));
+        // The newest backup is kept however old it is
+        assert_eq!(
+            storage.prune(&retention, false, &clock).unwrap(),
+            ["backup-20240101T000000", "backup-20240105T000000"]
+        );
+        assert_eq!(storage.list().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn removing_a_backup_another_prune_removed_succeeds() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("app.bak"), b"data").unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(destination.path().to_str().unwrap());
+        storage.store(source.path(), "backup-a", &ArchiveOptions::default()).await.unwrap();
+        let backups = storage.list().unwrap();
+
+        storage.remove(&backups[0]).unwrap();
+        storage.remove(&backups[0]).unwrap();
+        assert!(storage.list().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn list_backfills_and_prunes_the_index() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join(MANIFEST_FILE), b"{\"backup_id\": \"x\"}").unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(destination.path().to_str().unwrap());
+        storage.store(source.path(), "backup-a", &ArchiveOptions::default()).await.unwrap();
+        storage.store(source.path(), "backup-b", &ArchiveOptions::default()).await.unwrap();
+
+        // Archives stored before the index existed are picked up on the next listing
+        fs::remove_file(destination.path().join(INDEX_FILE)).unwrap();
+        assert_eq!(storage.list().unwrap().len(), 2);
+        assert_eq!(Index::load(destination.path()).archives.len(), 2);
+
+        fs::remove_file(destination.path().join("backup-a.tar.gz")).unwrap();
+        assert_eq!(storage.list().unwrap().len(), 1);
+        assert_eq!(Index::load(destination.path()).archives.keys().collect::<Vec<_>>(), ["backup-b.tar.gz"]);
+    }
+
+    #[tokio::test]
+    async fn content_addressed_backups_share_objects_of_identical_dumps() {
+        let destination = tempfile::tempdir().unwrap();
+        let storage = LocalStorage {
+            content_addressed: true,
+            ..LocalStorage::new(destination.path().to_str().unwrap())
+        };
+        let store = |backup_id: &'static str, dump: &'static [u8]| {
+            let storage = &storage;
+            async move {
+                let source = tempfile::tempdir().unwrap();
+                Manifest::new(backup_id, Default::default(), Default::default(), Vec::new()).write(source.path()).unwrap();
+                fs::write(source.path().join("app.db.bak"), dump).unwrap();
+                storage.store(source.path(), backup_id, &ArchiveOptions::default()).await.unwrap()
+            }
+        };
+        let a = store("backup-a", b"data").await;
+        let b = store("backup-b", b"data").await;
+        let c = store("backup-c", b"changed").await;
+
+        // The manifests differ, the dumps don't
+        assert_eq!(a.location, b.location);
+        assert_eq!(a.checksum, b.checksum);
+        assert_ne!(a.location, c.location);
+        assert!(a.location.contains("/objects/"));
+        let backups = storage.list().unwrap();
+        assert_eq!(backups.iter().map(|b| b.backup_id.as_str()).collect::<Vec<_>>(), ["backup-a", "backup-b", "backup-c"]);
+        assert_eq!(backups[1].manifest.as_ref().map(|m| m.backup_id.as_str()), Some("backup-b"));
+        assert_eq!(storage.archive_path("backup-b").unwrap(), PathBuf::from(&b.location));
+        storage.remove(&backups[2]).unwrap();
+
+        storage.remove(&backups[0]).unwrap();
+        assert!(Path::new(&a.location).is_file());
+        storage.remove(&backups[1]).unwrap();
+        assert!(!Path::new(&a.location).exists());
+        assert!(storage.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_store_removes_partial_archive() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("app.bak"), b"data").unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(destination.path().to_str().unwrap());
+
+        let result = storage.store_with(source.path(), "backup-test", &ArchiveOptions::default(), |_, _| {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_dir(destination.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn move_file_reports_other_rename_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("archive.partial");
+        fs::write(&from, b"archive").unwrap();
+
+        let result = move_file(&from, &dir.path().join("out"), |_, _| {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+
+        assert!(matches!(result, Err(Error::Storage(_))));
+    }
+
+    #[test]
+    fn move_file_reports_a_full_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("archive.partial");
+        fs::write(&from, b"archive").unwrap();
+
+        let result = move_file(&from, &dir.path().join("out"), |_, _| Err(io::Error::from(io::ErrorKind::StorageFull)));
+
+        assert!(matches!(result, Err(Error::DiskFull(_))));
+    }
+}
@@ -1 +1,141 @@
-pub mod local;
\ No newline at end of file
+pub mod index;
+pub mod local;
+pub mod retention;
+pub mod sftp;
+
+use crate::backup::manifest::Manifest;
+use crate::config::{ArchiveFormat, ChecksumAlgorithm, RetentionConfig, Storage};
+use crate::error::{Error, Result};
+use crate::utils::clock::Clock;
+use crate::utils::compression::ArchiveOptions;
+use async_trait::async_trait;
+use local::LocalStorage;
+use log::{info, warn};
+use retention::select_expired;
+use sftp::SftpStorage;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Archive formats recognised when looking up stored backups
+const ARCHIVE_FORMATS: [ArchiveFormat; 2] = [ArchiveFormat::TarGz, ArchiveFormat::Zip];
+
+/// Extension of the detached signature stored next to a signed archive
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// A backup archive found in storage
+#[derive(Debug)]
+pub struct StoredBackup {
+    pub backup_id: String,
+    pub path: PathBuf, // Location within the backend; remote for SFTP
+    pub size: u64,
+    pub manifest: Option<Manifest>, // None for archives written before manifests existed
+}
+
+/// A newly stored archive
+#[derive(Debug, Clone)]
+pub struct StoredArchive {
+    pub location: String, // Path, prefixed with the host for remote backends
+    pub size: u64,
+    pub checksum: String, // Hex digest of the archive, by `checksum_algorithm`
+    pub checksum_algorithm: ChecksumAlgorithm,
+}
+
+/// Where backup archives are kept
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Archive `source_dir` and store it as `backup_id`
+    async fn store(&self, source_dir: &Path, backup_id: &str, options: &ArchiveOptions) -> Result<StoredArchive>;
+
+    /// Stored backups, oldest first
+    fn list(&self) -> Result<Vec<StoredBackup>>;
+
+    /// Store the detached signature of a stored archive as `<backup_id>.sig`
+    fn store_signature(&self, backup_id: &str, signature: &[u8]) -> Result<()>;
+
+    /// Delete one stored backup, along with its signature
+    fn remove(&self, backup: &StoredBackup) -> Result<()>;
+
+    /// Remove archives left half-written by interrupted runs, returning where they were. Only files
+    /// untouched for `grace` are removed, since younger ones may belong to a run still in progress.
+    fn remove_stale_partials(&self, grace: Duration) -> Result<Vec<String>>;
+
+    /// Remove backups outside the retention policy as of `clock`'s time, returning their ids.
+    /// With `dry_run` nothing is deleted and the ids that would be removed are returned.
+    fn prune(&self, retention: &RetentionConfig, dry_run: bool, clock: &dyn Clock) -> Result<Vec<String>> {
+        let backups = self.list()?;
+        let expired = select_expired(&backups, retention, clock.now());
+
+        let mut removed = Vec::new();
+        for backup in expired {
+            if !dry_run {
+                self.remove(backup)?;
+                info!("Pruned backup {}", backup.backup_id);
+            }
+            removed.push(backup.backup_id.clone());
+        }
+
+        Ok(removed)
+    }
+}
+
+/// The backend selected by the `[storage]` section's `type_`
+pub fn create_backend(storage: &Storage) -> Result<Box<dyn StorageBackend>> {
+    match storage.type_.as_str() {
+        "local" => Ok(Box::new(LocalStorage::from_config(storage))),
+        "sftp" => Ok(Box::new(SftpStorage::from_config(storage)?)),
+        "s3" => Err(Error::Config("S3 storage is not supported yet".to_string())),
+        other => Err(Error::Config(format!(
+            "Unknown storage type {:?}; expected \"local\" or \"sftp\"",
+            other
+        ))),
+    }
+}
+
+/// Archive extensions recognised in storage: the built-in formats plus any external compressor's
+fn archive_extensions(external_extension: Option<&str>) -> Vec<&str> {
+    ARCHIVE_FORMATS.iter()
+        .map(|format| format.extension())
+        .chain(external_extension)
+        .collect()
+}
+
+/// Whether a file name is that of an archive still being written (`.<archive>.partial`)
+fn is_partial_archive(file_name: &str) -> bool {
+    file_name.starts_with('.') && file_name.ends_with(".partial")
+}
+
+/// Remove files in the local directory `dir` matching `is_leftover` that weren't modified for `grace`
+fn remove_stale_files<F: Fn(&str) -> bool>(dir: &Path, grace: Duration, is_leftover: F) -> Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    let mut removed = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(Error::Io)?;
+        if !is_leftover(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let metadata = entry.metadata().map_err(Error::Io)?;
+        let age = metadata.modified().ok().and_then(|modified| modified.elapsed().ok()).unwrap_or_default();
+        if !metadata.is_file() || age < grace {
+            continue;
+        }
+        let path = entry.path();
+        fs::remove_file(&path)
+            .map_err(|e| Error::Storage(format!("Failed to remove {:?}: {}", path, e)))?;
+        warn!("Removed {:?}, left {}s ago by an interrupted run", path, age.as_secs());
+        removed.push(path.to_string_lossy().to_string());
+    }
+    Ok(removed)
+}
+
+/// Backup id of an archive file name, or None for anything that isn't a finished archive
+fn backup_id_from_file_name<'a>(file_name: &'a str, extensions: &[&str]) -> Option<&'a str> {
+    extensions.iter()
+        .find_map(|extension| file_name.strip_suffix(extension)?.strip_suffix('.'))
+        .filter(|id| !id.starts_with('.'))
+}
@@ -0,0 +1,192 @@
+use crate::config::RetentionConfig;
+use crate::storage::StoredBackup;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// When a stored backup was taken, from its manifest or else its backup_id timestamp
+pub fn backup_time(backup: &StoredBackup) -> Option<DateTime<Utc>> {
+    backup.manifest.as_ref()
+        .and_then(|m| DateTime::parse_from_rfc3339(&m.created_at).ok())
+        .map(|t| t.with_timezone(&Utc))
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(&backup.backup_id, "backup-%Y%m%dT%H%M%S")
+                .ok()
+                .map(|t| t.and_utc())
+        })
+}
+
+/// Select backups that fall outside the retention policy.
+///
+/// `backups` must be sorted oldest first. A backup is expired when it is not among the
+/// `keep_last` most recent or is older than `max_age_days`; the newest backup is always kept.
+/// Backups of each `[[hosts]]` entry, and the subset backups of each schedule with an
+/// `engines`/`databases` filter, are counted separately from the rest, so an hourly backup of
+/// one database never pushes the nightly full backups out. A backup that a retained one refers to (the base of a SQLite delta, or the
+/// dump of an unchanged MongoDB collection) is kept too. The result is oldest first.
+pub fn select_expired<'a>(
+    backups: &'a [StoredBackup],
+    retention: &RetentionConfig,
+    now: DateTime<Utc>,
+) -> Vec<&'a StoredBackup> {
+    let mut groups: BTreeMap<(Option<&str>, Option<&str>), Vec<&StoredBackup>> = BTreeMap::new();
+    for backup in backups {
+        let host = backup.manifest.as_ref().and_then(|m| m.host.as_deref());
+        let schedule = backup.manifest.as_ref().and_then(|m| m.schedule.as_deref());
+        groups.entry((host, schedule)).or_default().push(backup);
+    }
+    let mut expired: Vec<_> = groups.values()
+        .flat_map(|group| select_expired_in(group, retention, now))
+        .collect();
+    let needed: BTreeSet<&str> = backups.iter()
+        .filter(|backup| !expired.iter().any(|expired| std::ptr::eq(*expired, *backup)))
+        .filter_map(|backup| backup.manifest.as_ref())
+        .flat_map(|manifest| manifest.engines.iter())
+        .flat_map(|engine| engine.referenced_backups())
+        .collect();
+    expired.retain(|backup| !needed.contains(backup.backup_id.as_str()));
+    expired.sort_by_key(|expired| backups.iter().position(|backup| std::ptr::eq(backup, *expired)));
+    expired
+}
+
+fn select_expired_in<'a>(
+    backups: &[&'a StoredBackup],
+    retention: &RetentionConfig,
+    now: DateTime<Utc>,
+) -> Vec<&'a StoredBackup> {
+    let Some((_newest, older)) = backups.split_last() else {
+        return Vec::new();
+    };
+    let keep_from = retention.keep_last
+        .map(|keep| backups.len().saturating_sub(keep))
+        .unwrap_or(0);
+    let cutoff = retention.max_age_days.map(|days| now - Duration::days(days as i64));
+
+    older.iter()
+        .enumerate()
+        .filter(|(index, backup)| {
+            let beyond_count = *index < keep_from;
+            let too_old = match (cutoff, backup_time(backup)) {
+                (Some(cutoff), Some(time)) => time < cutoff,
+                _ => false,
+            };
+            beyond_count || too_old
+        })
+        .map(|(_, backup)| *backup)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::manifest::Manifest;
+    use std::path::PathBuf;
+
+    fn backup(id: &str) -> StoredBackup {
+        StoredBackup {
+            backup_id: id.to_string(),
+            path: PathBuf::from(format!("{}.tar.gz", id)),
+            size: 0,
+            manifest: None,
+        }
+    }
+
+    fn ids(expired: Vec<&StoredBackup>) -> Vec<&str> {
+        expired.iter().map(|b| b.backup_id.as_str()).collect()
+    }
+
+    #[test]
+    fn keeps_last_n_and_drops_old() {
+        let backups = vec![
+            backup("backup-20240101T000000"),
+            backup("backup-20240105T000000"),
+            backup("backup-20240109T000000"),
+            backup("backup-20240110T000000"),
+        ];
+        let now = NaiveDateTime::parse_from_str("20240110T000000", "%Y%m%dT%H%M%S").unwrap().and_utc();
+
+        let keep_two = RetentionConfig { keep_last: Some(2), max_age_days: None };
+        assert_eq!(ids(select_expired(&backups, &keep_two, now)), ["backup-20240101T000000", "backup-20240105T000000"]);
+
+        let week = RetentionConfig { keep_last: None, max_age_days: Some(7) };
+        assert_eq!(ids(select_expired(&backups, &week, now)), ["backup-20240101T000000"]);
+
+        let nothing = RetentionConfig { keep_last: Some(0), max_age_days: Some(0) };
+        assert_eq!(select_expired(&backups, &nothing, now).len(), 3);
+    }
+
+    #[test]
+    fn counts_each_host_separately() {
+        let on_host = |id: &str, host: &str| {
+            let mut manifest = Manifest::new(id, Default::default(), Default::default(), Vec::new());
+            manifest.host = Some(host.to_string());
+            StoredBackup { manifest: Some(manifest), ..backup(id) }
+        };
+        let backups = vec![
+            on_host("backup-20240101T000000-db1", "db1"),
+            on_host("backup-20240101T000000-db2", "db2"),
+            on_host("backup-20240102T000000-db1", "db1"),
+            on_host("backup-20240102T000000-db2", "db2"),
+            on_host("backup-20240103T000000-db1", "db1"),
+        ];
+        let now = NaiveDateTime::parse_from_str("20240103T000000", "%Y%m%dT%H%M%S").unwrap().and_utc();
+
+        let keep_two = RetentionConfig { keep_last: Some(2), max_age_days: None };
+        assert_eq!(ids(select_expired(&backups, &keep_two, now)), ["backup-20240101T000000-db1"]);
+    }
+
+    #[test]
+    fn counts_subset_schedules_apart_from_full_backups() {
+        let by_schedule = |id: &str, schedule: Option<&str>| {
+            let mut manifest = Manifest::new(id, Default::default(), Default::default(), Vec::new());
+            manifest.schedule = schedule.map(str::to_string);
+            StoredBackup { manifest: Some(manifest), ..backup(id) }
+        };
+        // Nightly full backups, and hourly ones of a single database, in one store
+        let backups = vec![
+            by_schedule("backup-20240101T020000", None),
+            by_schedule("backup-20240102T020000", None),
+            by_schedule("backup-20240102T030000", Some("orders-hourly")),
+            by_schedule("backup-20240102T040000", Some("orders-hourly")),
+            by_schedule("backup-20240102T050000", Some("orders-hourly")),
+            by_schedule("backup-20240102T060000", Some("orders-hourly")),
+        ];
+        let now = NaiveDateTime::parse_from_str("20240102T060000", "%Y%m%dT%H%M%S").unwrap().and_utc();
+
+        let keep_two = RetentionConfig { keep_last: Some(2), max_age_days: None };
+        assert_eq!(
+            ids(select_expired(&backups, &keep_two, now)),
+            ["backup-20240102T030000", "backup-20240102T040000"]
+        );
+    }
+
+    #[test]
+    fn keeps_the_base_of_a_retained_delta() {
+        let delta = |id: &str, base: &str| {
+            let manifest = serde_json::from_value(serde_json::json!({
+                "backup_id": id,
+                "created_at": "2024-01-01T00:00:00Z",
+                "kronos_version": "0.1.0",
+                "engines": [{
+                    "engine": "sqlite",
+                    "dump_mode": "full",
+                    "databases": ["app.db"],
+                    "differential_bases": { "app.db": base },
+                }],
+            })).unwrap();
+            StoredBackup { manifest: Some(manifest), ..backup(id) }
+        };
+        let backups = vec![
+            backup("backup-20240101T000000"),
+            delta("backup-20240102T000000", "backup-20240101T000000"),
+            backup("backup-20240103T000000"),
+            delta("backup-20240104T000000", "backup-20240103T000000"),
+        ];
+        let now = NaiveDateTime::parse_from_str("20240104T000000", "%Y%m%dT%H%M%S").unwrap().and_utc();
+
+        let keep_one = RetentionConfig { keep_last: Some(1), max_age_days: None };
+        assert_eq!(
+            ids(select_expired(&backups, &keep_one, now)),
+            ["backup-20240101T000000", "backup-20240102T000000"]
+        );
+    }
+}
@@ -0,0 +1,448 @@
+use crate::config::Storage;
+use crate::error::{Error, Result};
+use crate::storage::index::{retry_lock, Index, IndexEntry, INDEX_FILE, INDEX_LOCK_FILE, INDEX_TMP_FILE};
+use crate::storage::{
+    archive_extensions, backup_id_from_file_name, is_partial_archive, remove_stale_files, StorageBackend, StoredArchive,
+    StoredBackup, SIGNATURE_EXTENSION,
+};
+use crate::utils::checksum::checksum_file;
+use crate::utils::compression::{compress_directory, ArchiveOptions, ReadOptions};
+use crate::utils::failpoint::{fail_point, FailurePhase};
+use async_trait::async_trait;
+use log::{info, warn};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DEFAULT_SFTP_PORT: u16 = 22;
+
+/// How long a connect or SFTP operation may go unanswered when `sftp_timeout_secs` is unset
+const DEFAULT_SFTP_TIMEOUT_SECS: u64 = 60;
+
+/// SFTP status code for a path that doesn't exist
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+/// Remote index locks older than this are assumed to belong to a process that died
+const STALE_LOCK_SECS: u64 = 600;
+
+/// Prefix of the local files archives are assembled in before upload
+const LOCAL_ARCHIVE_PREFIX: &str = ".kronos-";
+
+/// Stores archives on a remote host over SFTP. Archives are assembled locally, then uploaded.
+#[derive(Clone)]
+pub struct SftpStorage {
+    host: String,
+    port: u16,
+    user: String,
+    password: Option<String>,
+    key_file: Option<PathBuf>,
+    known_hosts: PathBuf,
+    archive_mode: u32, // Permission bits uploaded archives are created with
+    timeout: Duration, // Applied to the TCP connect and every blocking SSH call
+    remote_path: PathBuf,
+    work_dir: PathBuf, // Local directory archives are assembled in before upload
+    external_extension: Option<String>,
+    read_options: ReadOptions,
+}
+
+impl SftpStorage {
+    /// SFTP storage as described by the `[storage]` section
+    pub fn from_config(storage: &Storage) -> Result<Self> {
+        let required = |value: &Option<String>, name: &str| {
+            value.clone().ok_or_else(|| Error::Config(format!("SFTP storage requires `{}`", name)))
+        };
+        let known_hosts = match &storage.sftp_known_hosts {
+            Some(path) => PathBuf::from(path),
+            None => std::env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".ssh").join("known_hosts"))
+                .ok_or_else(|| Error::Config("HOME is not set; configure `sftp_known_hosts`".to_string()))?,
+        };
+
+        Ok(SftpStorage {
+            host: required(&storage.sftp_host, "sftp_host")?,
+            port: storage.sftp_port.unwrap_or(DEFAULT_SFTP_PORT),
+            user: required(&storage.sftp_user, "sftp_user")?,
+            password: storage.sftp_password.clone(),
+            key_file: storage.sftp_key_file.as_ref().map(PathBuf::from),
+            known_hosts,
+            archive_mode: storage.archive_mode(),
+            timeout: Duration::from_secs(storage.sftp_timeout_secs.unwrap_or(DEFAULT_SFTP_TIMEOUT_SECS)),
+            remote_path: PathBuf::from(required(&storage.path, "path")?),
+            work_dir: storage.archive_temp_dir.as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(std::env::temp_dir),
+            external_extension: storage.compressor_extension.clone(),
+            read_options: ReadOptions::from_storage(storage),
+        })
+    }
+
+    /// Open an authenticated SFTP session after checking the server's host key
+    fn connect(&self) -> Result<Sftp> {
+        let connect_error = |e: io::Error| Error::Storage(format!("Failed to connect to {}:{}: {}", self.host, self.port, e));
+        let address = (self.host.as_str(), self.port).to_socket_addrs().map_err(connect_error)?
+            .next()
+            .ok_or_else(|| connect_error(io::Error::new(io::ErrorKind::NotFound, "no address found")))?;
+        let tcp = TcpStream::connect_timeout(&address, self.timeout).map_err(connect_error)?;
+        let mut session = Session::new().map_err(|e| self.error("start SSH session", e))?;
+        // Without a timeout a server that stops answering would hang the run forever
+        session.set_timeout(self.timeout.as_millis().try_into().unwrap_or(u32::MAX));
+        session.set_tcp_stream(tcp);
+        session.handshake().map_err(|e| self.error("complete SSH handshake", e))?;
+        self.verify_host_key(&session)?;
+
+        let authenticated = match (&self.key_file, &self.password) {
+            (Some(key_file), passphrase) => session.userauth_pubkey_file(&self.user, None, key_file, passphrase.as_deref()),
+            (None, Some(password)) => session.userauth_password(&self.user, password),
+            (None, None) => session.userauth_agent(&self.user),
+        };
+        authenticated.map_err(|e| self.error(&format!("authenticate as {}", self.user), e))?;
+
+        session.sftp().map_err(|e| self.error("start SFTP subsystem", e))
+    }
+
+    /// Refuse servers whose host key isn't listed, or doesn't match, in known_hosts
+    fn verify_host_key(&self, session: &Session) -> Result<()> {
+        let (key, _) = session.host_key()
+            .ok_or_else(|| Error::Storage(format!("{} did not present a host key", self.host)))?;
+        let mut known_hosts = session.known_hosts().map_err(|e| self.error("load known hosts", e))?;
+        known_hosts.read_file(&self.known_hosts, KnownHostFileKind::OpenSSH)
+            .map_err(|e| Error::Storage(format!("Failed to read known_hosts file {:?}: {}", self.known_hosts, e)))?;
+
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(Error::Storage(format!(
+                "Host key for {} does not match {:?}; refusing to connect",
+                self.host, self.known_hosts
+            ))),
+            CheckResult::NotFound => Err(Error::Storage(format!(
+                "{} is not listed in {:?}; add its host key (e.g. with ssh-keyscan) after verifying it",
+                self.host, self.known_hosts
+            ))),
+            CheckResult::Failure => Err(Error::Storage(format!("Failed to check host key for {}", self.host))),
+        }
+    }
+
+    fn error(&self, action: &str, e: ssh2::Error) -> Error {
+        Error::Storage(format!("Failed to {} on {}: {}", action, self.host, e))
+    }
+
+    /// Create the remote directory and any missing parents
+    fn ensure_remote_dir(&self, sftp: &Sftp) -> Result<()> {
+        let mut missing: Vec<&Path> = self.remote_path.ancestors()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .take_while(|dir| sftp.stat(dir).is_err())
+            .collect();
+        missing.reverse();
+        for dir in missing {
+            sftp.mkdir(dir, 0o700)
+                .map_err(|e| Error::Storage(format!("Failed to create {:?} on {}: {}", dir, self.host, e)))?;
+        }
+        Ok(())
+    }
+
+    /// Copy a local file to the remote host with the configured `archive_mode`
+    fn upload(&self, sftp: &Sftp, local: &Path, remote: &Path) -> Result<()> {
+        let mut source = File::open(local).map_err(Error::Io)?;
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let mut destination = sftp
+            .open_mode(remote, flags, self.archive_mode as i32, OpenType::File)
+            .map_err(|e| Error::Storage(format!("Failed to create {:?} on {}: {}", remote, self.host, e)))?;
+        io::copy(&mut source, &mut destination)
+            .map_err(|e| Error::Storage(format!("Failed to upload to {:?} on {}: {}", remote, self.host, e)))?;
+        Ok(())
+    }
+
+    /// Read a remote archive's manifest by downloading it to the work directory
+    fn read_remote_entry(&self, sftp: &Sftp, remote: &Path, file_name: &str, size: u64) -> Result<IndexEntry> {
+        let dir = tempfile::tempdir_in(&self.work_dir).map_err(Error::Io)?;
+        let local = dir.path().join(file_name);
+        let mut source = sftp.open(remote)
+            .map_err(|e| Error::Storage(format!("Failed to open {:?} on {}: {}", remote, self.host, e)))?;
+        io::copy(&mut source, &mut File::create(&local).map_err(Error::Io)?)
+            .map_err(|e| Error::Storage(format!("Failed to download {:?} from {}: {}", remote, self.host, e)))?;
+        Ok(IndexEntry::read(&local, size, &self.read_options))
+    }
+
+    fn load_index(&self, sftp: &Sftp) -> Index {
+        let path = self.remote_path.join(INDEX_FILE);
+        let mut contents = Vec::new();
+        match sftp.open(&path) {
+            Ok(mut file) => match file.read_to_end(&mut contents) {
+                Ok(_) => Index::parse(&contents, &format!("{}:{}", self.host, path.display())),
+                Err(e) => {
+                    warn!("Failed to read index {:?} on {}: {}", path, self.host, e);
+                    Index::default()
+                }
+            },
+            Err(e) if e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => Index::default(),
+            Err(e) => {
+                warn!("Failed to read index {:?} on {}: {}", path, self.host, e);
+                Index::default()
+            }
+        }
+    }
+
+    /// Update the remote index under its lock; the index is only a cache, so failures are logged
+    fn update_index<F: FnOnce(&mut Index)>(&self, sftp: &Sftp, modify: F) {
+        let updated = self.lock_index(sftp).and_then(|_lock| {
+            let tmp_path = self.remote_path.join(INDEX_TMP_FILE);
+            if sftp.unlink(&tmp_path).is_ok() {
+                warn!("Discarded partially written index {:?} on {}", tmp_path, self.host);
+            }
+            let mut index = self.load_index(sftp);
+            modify(&mut index);
+            self.write_index(sftp, &index)
+        });
+        if let Err(e) = updated {
+            warn!("Failed to update backup index on {}: {}", self.host, e);
+        }
+    }
+
+    /// Take the remote index lock: a lock file created exclusively, removed when the guard drops.
+    /// SFTP has no advisory locks, so a lock left by a crashed process is broken once it is stale.
+    fn lock_index<'a>(&self, sftp: &'a Sftp) -> Result<RemoteLock<'a>> {
+        let path = self.remote_path.join(INDEX_LOCK_FILE);
+        let lock = format!("{}:{}", self.host, path.display());
+        retry_lock(&lock, || {
+            let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+            let error = match sftp.open_mode(&path, flags, 0o600, OpenType::File) {
+                Ok(_) => return Ok(true),
+                Err(e) => e,
+            };
+            let Ok(stat) = sftp.stat(&path) else {
+                return Err(self.error("create index lock", error));
+            };
+            let age = stat.mtime
+                .and_then(|mtime| chrono::Utc::now().timestamp().checked_sub(mtime as i64))
+                .unwrap_or(0);
+            if age > STALE_LOCK_SECS as i64 {
+                warn!("Breaking stale index lock {} ({}s old)", lock, age);
+                let _ = sftp.unlink(&path);
+            }
+            Ok(false)
+        })?;
+        Ok(RemoteLock { sftp, path })
+    }
+
+    /// Write the index via a temporary file; callers hold the index lock
+    fn write_index(&self, sftp: &Sftp, index: &Index) -> Result<()> {
+        let path = self.remote_path.join(INDEX_FILE);
+        let tmp_path = self.remote_path.join(INDEX_TMP_FILE);
+        let mut file = sftp.create(&tmp_path).map_err(|e| self.error("write index", e))?;
+        file.write_all(&index.to_json()?).map_err(Error::Io)?;
+        drop(file);
+
+        // SFTP v3 servers (including OpenSSH) refuse to rename over an existing file
+        if sftp.rename(&tmp_path, &path, None).is_err() {
+            let _ = sftp.unlink(&path);
+            sftp.rename(&tmp_path, &path, None).map_err(|e| self.error("replace index", e))?;
+        }
+        Ok(())
+    }
+
+    /// Archive `source_dir` locally and upload it as `backup_id`, blocking until done
+    fn store_blocking(&self, source_dir: &Path, backup_id: &str, options: &ArchiveOptions) -> Result<StoredArchive> {
+        let file_name = format!("{}.{}", backup_id, options.extension());
+        fs::create_dir_all(&self.work_dir).map_err(Error::Io)?;
+        let local = tempfile::Builder::new()
+            .prefix(LOCAL_ARCHIVE_PREFIX)
+            .suffix(&format!(".{}", file_name))
+            .tempfile_in(&self.work_dir)
+            .map_err(Error::Io)?;
+        compress_directory(source_dir, local.path(), options)?;
+        let size = fs::metadata(local.path()).map_err(Error::Io)?.len();
+        let checksum = checksum_file(local.path(), options.checksum)?;
+        let index_entry = IndexEntry::read(local.path(), size, &self.read_options);
+        fail_point(FailurePhase::Store)?;
+
+        // Upload under a temporary name so a half-transferred archive is never listed
+        let sftp = self.connect()?;
+        self.ensure_remote_dir(&sftp)?;
+        let partial = self.remote_path.join(format!(".{}.partial", file_name));
+        let final_path = self.remote_path.join(&file_name);
+        let uploaded = self.upload(&sftp, local.path(), &partial)
+            .and_then(|()| sftp.rename(&partial, &final_path, None).map_err(|e| self.error("rename upload", e)));
+        if let Err(e) = uploaded {
+            let _ = sftp.unlink(&partial);
+            return Err(e);
+        }
+        info!("Uploaded {} to {}:{}", file_name, self.host, self.remote_path.display());
+
+        self.update_index(&sftp, |index| {
+            index.archives.insert(file_name, index_entry);
+        });
+
+        Ok(StoredArchive {
+            location: format!("{}:{}", self.host, final_path.display()),
+            size,
+            checksum,
+            checksum_algorithm: options.checksum,
+        })
+    }
+
+    /// Manifests come from the remote index; archives it doesn't know yet are downloaded once to backfill it
+    fn list_blocking(&self) -> Result<Vec<StoredBackup>> {
+        let sftp = self.connect()?;
+        let entries = match sftp.readdir(&self.remote_path) {
+            Ok(entries) => entries,
+            Err(e) if e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => return Ok(Vec::new()),
+            Err(e) => return Err(self.error(&format!("list {:?}", self.remote_path), e)),
+        };
+
+        let extensions = archive_extensions(self.external_extension.as_deref());
+        let cached = self.load_index(&sftp);
+        let mut index = Index::default();
+        let mut backups = Vec::new();
+        for (path, stat) in entries {
+            let Some(file_name) = path.file_name().map(|name| name.to_string_lossy().to_string()) else {
+                continue;
+            };
+            let Some(backup_id) = backup_id_from_file_name(&file_name, &extensions) else {
+                continue;
+            };
+            if !stat.is_file() {
+                continue;
+            }
+
+            let size = stat.size.unwrap_or(0);
+            let index_entry = match cached.get(&file_name, size) {
+                Some(index_entry) => index_entry.clone(),
+                None => self.read_remote_entry(&sftp, &path, &file_name, size)?,
+            };
+
+            backups.push(StoredBackup {
+                backup_id: backup_id.to_string(),
+                path,
+                size,
+                manifest: index_entry.manifest.clone(),
+            });
+            index.archives.insert(file_name, index_entry);
+        }
+
+        // Keep entries other processes added meanwhile, dropping only archives that are gone
+        if !index.matches(&cached) {
+            let listed: Vec<String> = index.archives.keys().cloned().collect();
+            let stored_since = |file_name: &str| sftp.stat(&self.remote_path.join(file_name)).is_ok();
+            self.update_index(&sftp, |current| {
+                current.archives.retain(|file_name, _| listed.contains(file_name) || stored_since(file_name));
+                current.archives.extend(index.archives);
+            });
+        }
+
+        backups.sort_by(|a, b| a.backup_id.cmp(&b.backup_id));
+        Ok(backups)
+    }
+
+    fn store_signature_blocking(&self, backup_id: &str, signature: &[u8]) -> Result<()> {
+        let sftp = self.connect()?;
+        let path = self.remote_path.join(format!("{}.{}", backup_id, SIGNATURE_EXTENSION));
+        let mut file = sftp
+            .open_mode(&path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE, 0o600, OpenType::File)
+            .map_err(|e| Error::Storage(format!("Failed to create {:?} on {}: {}", path, self.host, e)))?;
+        file.write_all(signature)
+            .map_err(|e| Error::Storage(format!("Failed to write {:?} on {}: {}", path, self.host, e)))
+    }
+
+    fn remove_blocking(&self, backup: &StoredBackup) -> Result<()> {
+        let sftp = self.connect()?;
+        // Already gone means a concurrent prune removed it
+        match sftp.unlink(&backup.path) {
+            Err(e) if e.code() != ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => {
+                return Err(Error::Storage(format!("Failed to remove {:?} on {}: {}", backup.path, self.host, e)));
+            }
+            _ => {}
+        }
+        let signature = self.remote_path.join(format!("{}.{}", backup.backup_id, SIGNATURE_EXTENSION));
+        if let Err(e) = sftp.unlink(&signature) {
+            if e.code() != ErrorCode::SFTP(SFTP_NO_SUCH_FILE) {
+                warn!("Failed to remove signature of {} on {}: {}", backup.backup_id, self.host, e);
+            }
+        }
+
+        if let Some(file_name) = backup.path.file_name() {
+            self.update_index(&sftp, |index| {
+                index.archives.remove(file_name.to_string_lossy().as_ref());
+            });
+        }
+        Ok(())
+    }
+
+    /// Clean up both ends: archives assembled locally before upload, and interrupted uploads
+    fn remove_stale_partials_blocking(&self, grace: Duration) -> Result<Vec<String>> {
+        let mut removed = remove_stale_files(&self.work_dir, grace, |name| name.starts_with(LOCAL_ARCHIVE_PREFIX))?;
+
+        let sftp = self.connect()?;
+        let entries = match sftp.readdir(&self.remote_path) {
+            Ok(entries) => entries,
+            Err(e) if e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => return Ok(removed),
+            Err(e) => return Err(self.error(&format!("list {:?}", self.remote_path), e)),
+        };
+        let now = chrono::Utc::now().timestamp();
+        for (path, stat) in entries {
+            let is_partial = path.file_name().is_some_and(|name| is_partial_archive(&name.to_string_lossy()));
+            let age = stat.mtime.and_then(|mtime| now.checked_sub(mtime as i64)).unwrap_or(0);
+            if !is_partial || !stat.is_file() || age < grace.as_secs() as i64 {
+                continue;
+            }
+            sftp.unlink(&path)
+                .map_err(|e| Error::Storage(format!("Failed to remove {:?} on {}: {}", path, self.host, e)))?;
+            warn!("Removed {}:{}, left {}s ago by an interrupted upload", self.host, path.display(), age);
+            removed.push(format!("{}:{}", self.host, path.display()));
+        }
+        Ok(removed)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SftpStorage {
+    /// Compression and the upload block, so they run off the async runtime's worker threads
+    async fn store(&self, source_dir: &Path, backup_id: &str, options: &ArchiveOptions) -> Result<StoredArchive> {
+        let (storage, source_dir, backup_id, options) =
+            (self.clone(), source_dir.to_path_buf(), backup_id.to_string(), options.clone());
+        tokio::task::spawn_blocking(move || storage.store_blocking(&source_dir, &backup_id, &options))
+            .await
+            .map_err(|e| Error::Storage(format!("Upload to {} did not finish: {}", self.host, e)))?
+    }
+
+    fn list(&self) -> Result<Vec<StoredBackup>> {
+        off_runtime(|| self.list_blocking())
+    }
+
+    fn store_signature(&self, backup_id: &str, signature: &[u8]) -> Result<()> {
+        off_runtime(|| self.store_signature_blocking(backup_id, signature))
+    }
+
+    fn remove(&self, backup: &StoredBackup) -> Result<()> {
+        off_runtime(|| self.remove_blocking(backup))
+    }
+
+    fn remove_stale_partials(&self, grace: Duration) -> Result<Vec<String>> {
+        off_runtime(|| self.remove_stale_partials_blocking(grace))
+    }
+}
+
+/// Run blocking SSH calls on a runtime worker thread without stalling the other tasks scheduled there. The
+/// storage trait's synchronous methods can't hand work to `spawn_blocking`, so they tell the runtime instead.
+fn off_runtime<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(tokio::runtime::RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+/// Remote index lock file, removed when dropped
+struct RemoteLock<'a> {
+    sftp: &'a Sftp,
+    path: PathBuf,
+}
+
+impl Drop for RemoteLock<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.sftp.unlink(&self.path) {
+            warn!("Failed to release index lock {:?}: {}", self.path, e);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::config::ChecksumAlgorithm;
+use crate::error::{Error, Result};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Hex-encoded SHA-256 digest of a file's contents
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hash_file(path, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Hex-encoded digest of a file's contents with the given algorithm
+pub fn checksum_file(path: &Path, algorithm: ChecksumAlgorithm) -> Result<String> {
+    match algorithm {
+        ChecksumAlgorithm::Sha256 => sha256_file(path),
+        #[cfg(feature = "blake3")]
+        ChecksumAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            hash_file(path, &mut hasher)?;
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+        #[cfg(not(feature = "blake3"))]
+        ChecksumAlgorithm::Blake3 => Err(Error::Config("BLAKE3 checksums need kronos built with the `blake3` feature".to_string())),
+    }
+}
+
+/// Hex-encoded SHA-256 over `header` and every file under `dir` but those named in `skip` at its
+/// top level: each file's path relative to `dir`, length and contents, in path order. Two trees
+/// hash the same exactly when they hold the same files.
+pub fn sha256_tree(dir: &Path, skip: &[&str], header: &[u8]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(header);
+    let mut files = Vec::new();
+    list_files(dir, Path::new(""), &mut files)?;
+    files.retain(|relative| !skip.iter().any(|name| relative == Path::new(name)));
+    files.sort();
+    for relative in files {
+        let path = dir.join(&relative);
+        let len = std::fs::metadata(&path).map_err(Error::Io)?.len();
+        hasher.update(relative.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(len.to_le_bytes());
+        hash_file(&path, &mut hasher)?;
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Paths, relative to the tree's root, of the regular files under `dir`
+fn list_files(dir: &Path, relative: &Path, files: &mut Vec<std::path::PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir).map_err(Error::Io)? {
+        let entry = entry.map_err(Error::Io)?;
+        let file_type = entry.file_type().map_err(Error::Io)?;
+        let name = relative.join(entry.file_name());
+        if file_type.is_dir() {
+            list_files(&entry.path(), &name, files)?;
+        } else if file_type.is_file() {
+            files.push(name);
+        }
+    }
+    Ok(())
+}
+
+fn hash_file<W: io::Write>(path: &Path, hasher: &mut W) -> Result<()> {
+    let mut file = File::open(path)
+        .map_err(|e| Error::Storage(format!("Failed to open {:?} for checksumming: {}", path, e)))?;
+    io::copy(&mut file, hasher).map_err(Error::Io)?;
+    Ok(())
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_trees_by_their_files() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        for (dir, manifest) in [(&a, "{\"backup_id\": \"a\"}"), (&b, "{\"backup_id\": \"b\"}")] {
+            std::fs::create_dir(dir.path().join("shop")).unwrap();
+            std::fs::write(dir.path().join("shop/orders.sql"), b"rows").unwrap();
+            std::fs::write(dir.path().join("manifest.json"), manifest).unwrap();
+        }
+        let digest = |dir: &Path, header: &[u8]| sha256_tree(dir, &["manifest.json"], header).unwrap();
+        assert_eq!(digest(a.path(), b"tar.gz"), digest(b.path(), b"tar.gz"));
+        assert_ne!(digest(a.path(), b"tar.gz"), digest(a.path(), b"zip"));
+
+        std::fs::write(b.path().join("shop/orders.sql"), b"more rows").unwrap();
+        assert_ne!(digest(a.path(), b"tar.gz"), digest(b.path(), b"tar.gz"));
+    }
+
+    #[test]
+    fn hashes_file_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive");
+        std::fs::write(&path, b"abc").unwrap();
+
+        assert_eq!(
+            sha256_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(checksum_file(&path, ChecksumAlgorithm::Sha256).unwrap(), sha256_file(&path).unwrap());
+        #[cfg(feature = "blake3")]
+        assert_eq!(
+            checksum_file(&path, ChecksumAlgorithm::Blake3).unwrap(),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+}
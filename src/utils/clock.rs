@@ -0,0 +1,40 @@
+use chrono::{DateTime, Utc};
+
+/// Source of the current time. Time-dependent logic (backup ids, retention, schedules) takes one
+/// so tests can fix or advance time instead of depending on the wall clock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system's wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Clock standing still at a set time until moved
+#[cfg(test)]
+pub struct MockClock(std::sync::Mutex<DateTime<Utc>>);
+
+#[cfg(test)]
+impl MockClock {
+    /// Clock at an RFC 3339 time, e.g. "2024-01-10T02:00:00Z"
+    pub fn at(time: &str) -> Self {
+        MockClock(std::sync::Mutex::new(DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)))
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
@@ -0,0 +1,55 @@
+use crate::error::{Error, Result};
+use log::info;
+use std::env;
+use std::path::Path;
+
+/// Ensure the program a shell command line starts with can be found, either as a path or on PATH.
+pub fn ensure_command_exists(command: &str) -> Result<()> {
+    let program = command.split_whitespace().next()
+        .ok_or_else(|| Error::Config("Command cannot be empty".to_string()))?;
+
+    let found = if program.contains('/') {
+        Path::new(program).is_file()
+    } else {
+        env::var_os("PATH")
+            .map(|paths| env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+            .unwrap_or(false)
+    };
+
+    if !found {
+        return Err(Error::Config(format!("Command {:?} not found (from {:?})", program, command)));
+    }
+    Ok(())
+}
+
+/// Run a user-supplied hook command through the shell, failing when it exits unsuccessfully
+pub async fn run_hook(name: &str, command: &str, env: &[(&str, &str)]) -> Result<()> {
+    info!("Running {}: {}", name, command);
+    let output = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().copied())
+        .output()
+        .await
+        .map_err(|e| Error::Backup(format!("Failed to run {}: {}", name, e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let detail = if stderr.trim().is_empty() { String::new() } else { format!(": {}", stderr.trim()) };
+        return Err(Error::Backup(format!("{} failed ({}){}", name, output.status, detail)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn finds_programs_on_path() {
+        assert!(ensure_command_exists("sh -c true").is_ok());
+        assert!(ensure_command_exists("/bin/sh").is_ok());
+        assert!(ensure_command_exists("kronos-no-such-program --flag").is_err());
+        assert!(ensure_command_exists("  ").is_err());
+    }
+}
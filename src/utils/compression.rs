@@ -1,19 +1,970 @@
-use crate::error::{Error, Result};
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use std::fs::File;
-use std::path::{Path, PathBuf};
-use tar::Builder;
+use crate::backup::manifest::MANIFEST_FILE;
+use crate::config::{ArchiveFormat, ChecksumAlgorithm, EntryCompression, Storage};
+use crate::error::{is_disk_full, Error, Result};
+use crate::utils::encryption::{load_encryption_key, DecryptReader, EncryptWriter, EncryptionKey, ENCRYPTION_MAGIC};
+use crate::utils::failpoint::{fail_point, FailurePhase};
+use flate2::read::GzDecoder;
+use flate2::{Compression, GzBuilder};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Component, Path};
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use tar::{Archive, Builder};
+use zip::result::ZipError;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
-pub fn compress_directory(source_dir: &Path, output_path: &Path) -> Result<()> {
-    let tar_gz = File::create(output_path).map_err(Error::Io)?;
-    let enc = GzEncoder::new(tar_gz, Compression::default());
-    let mut tar = Builder::new(enc);
+/// How a backup directory is packed into an archive
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveOptions {
+    pub format: ArchiveFormat,
+    pub entry_compression: BTreeMap<String, EntryCompression>, // Entries (and everything under them) not deflated (zip only)
+    pub show_progress: bool,      // Show a progress bar when stderr is a terminal
+    pub root: Option<String>,     // Top-level directory every entry is nested under; `./` when unset
+    pub external: Option<ExternalCompressor>, // Replaces the built-in compression when set
+    pub encryption: Option<EncryptionKey>, // Encrypt the compressed stream on its way to the file (tar streams only)
+    pub comment: Option<String>, // Stored in the gzip header or as the zip archive comment
+    pub checksum: ChecksumAlgorithm, // Digest the stored archive is checksummed with
+}
 
-    tar.append_dir_all(".", source_dir)
-        .map_err(|e| Error::Backup(format!("Failed to create tar archive: {}", e)))?;
-    tar.finish()
-        .map_err(|e| Error::Backup(format!("Failed to finish tar archive: {}", e)))?;
+/// Shell command the tar stream is piped through instead of a built-in compressor
+#[derive(Debug, Clone)]
+pub struct ExternalCompressor {
+    pub command: String,
+    pub extension: String, // Archive file extension, e.g. "tar.lz4"
+}
 
+impl ArchiveOptions {
+    /// File extension of the archive these options produce
+    pub fn extension(&self) -> &str {
+        match &self.external {
+            Some(external) => &external.extension,
+            None => self.format.extension(),
+        }
+    }
+}
+
+/// Reject an archive root that isn't a single directory name
+pub fn check_archive_root(root: &str) -> Result<()> {
+    if root.is_empty() || root == "." || root == ".." || root.contains(['/', '\\']) {
+        return Err(Error::Config(format!("Invalid archive root {:?}; it must be a single directory name", root)));
+    }
+    Ok(())
+}
+
+/// Compress a directory into an archive, optionally showing a progress bar on a terminal
+pub fn compress_directory(source_dir: &Path, output_path: &Path, options: &ArchiveOptions) -> Result<()> {
+    fail_point(FailurePhase::Compress)?;
+    let entry_compression = &options.entry_compression;
+    let root = options.root.as_deref();
+    if let Some(root) = root {
+        check_archive_root(root)?;
+    }
+    let progress = if options.show_progress && io::stderr().is_terminal() {
+        let total = directory_size(source_dir)?;
+        let bar = ProgressBar::new(total);
+        bar.set_style(
+            ProgressStyle::with_template("Compressing [{bar:40}] {bytes}/{total_bytes} ({eta})")
+                .map_err(|e| Error::Backup(format!("Invalid progress template: {}", e)))?,
+        );
+        Some(bar)
+    } else {
+        None
+    };
+
+    let key = options.encryption.as_ref();
+    match (&options.external, options.format) {
+        (Some(external), _) => {
+            if options.comment.is_some() {
+                debug!("Archive comment not written: compressor_command output has no header to hold it");
+            }
+            compress_external(source_dir, output_path, &external.command, root, key, progress.as_ref())?;
+        }
+        (None, ArchiveFormat::TarGz) => {
+            if !entry_compression.is_empty() {
+                warn!(
+                    "tar.gz archives compress every entry alike; set archive_format = \"zip\" to compress {} separately",
+                    entry_compression.keys().cloned().collect::<Vec<_>>().join(", ")
+                );
+            }
+            compress_tar_gz(source_dir, output_path, root, options.comment.as_deref(), key, progress.as_ref())?;
+        }
+        (None, ArchiveFormat::Zip) if key.is_some() => {
+            return Err(Error::Config("zip archives cannot be encrypted; use archive_format = \"tar_gz\"".to_string()));
+        }
+        (None, ArchiveFormat::Zip) => compress_zip(source_dir, output_path, root, options.comment.as_deref(), entry_compression, progress.as_ref())?,
+    }
+
+    if let Some(bar) = progress {
+        bar.finish_and_clear();
+    }
+
+    Ok(())
+}
+
+fn compress_tar_gz(
+    source_dir: &Path,
+    output_path: &Path,
+    root: Option<&str>,
+    comment: Option<&str>,
+    key: Option<&EncryptionKey>,
+    progress: Option<&ProgressBar>,
+) -> Result<()> {
+    let tar_gz = ArchiveSink::create(output_path, key)?;
+    let mut header = GzBuilder::new();
+    if let Some(comment) = comment {
+        header = header.comment(comment);
+    }
+    write_tar(header.write(tar_gz, Compression::default()), source_dir, root, progress)
+        .map_err(|e| archive_write_error(output_path, "Failed to create tar archive", e))?
+        .finish()
+        .and_then(ArchiveSink::finish)
+        .map_err(|e| archive_write_error(output_path, "Failed to finish tar archive", e))?;
+
+    Ok(())
+}
+
+/// File an archive is written to, encrypting everything on the way when a key is set, so the
+/// compressed but unencrypted archive never touches the disk
+enum ArchiveSink {
+    Plain(File),
+    Encrypted(Box<EncryptWriter<File>>),
+}
+
+impl ArchiveSink {
+    fn create(path: &Path, key: Option<&EncryptionKey>) -> Result<Self> {
+        let file = File::create(path).map_err(Error::Io)?;
+        match key {
+            Some(key) => {
+                let writer = EncryptWriter::new(file, key).map_err(|e| archive_write_error(path, "Failed to encrypt archive", e))?;
+                Ok(ArchiveSink::Encrypted(Box::new(writer)))
+            }
+            None => Ok(ArchiveSink::Plain(file)),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            ArchiveSink::Plain(_) => Ok(()),
+            ArchiveSink::Encrypted(writer) => writer.finish().map(drop),
+        }
+    }
+}
+
+impl Write for ArchiveSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ArchiveSink::Plain(file) => file.write(buf),
+            ArchiveSink::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveSink::Plain(file) => file.flush(),
+            ArchiveSink::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Pipe the tar stream through a shell command, writing the command's output to the archive
+fn compress_external(
+    source_dir: &Path,
+    output_path: &Path,
+    command: &str,
+    root: Option<&str>,
+    key: Option<&EncryptionKey>,
+    progress: Option<&ProgressBar>,
+) -> Result<()> {
+    // The compressor's output is copied into the archive (encrypting it when there is a key) by a
+    // thread of ours rather than written by the compressor, so a full disk surfaces here as ENOSPC
+    // instead of only the compressor's exit status
+    let mut sink = ArchiveSink::create(output_path, key)?;
+    let mut child = shell_command(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Backup(format!("Failed to run compressor command {:?}: {}", command, e)))?;
+    let stdin = child.stdin.take()
+        .ok_or_else(|| Error::Backup("Failed to open compressor input".to_string()))?;
+    let mut compressed = child.stdout.take()
+        .ok_or_else(|| Error::Backup("Failed to read compressor output".to_string()))?;
+    let copier = thread::spawn(move || io::copy(&mut compressed, &mut sink).and_then(|_| sink.finish()));
+
+    // Close stdin before waiting so the compressor sees end of input
+    let written = write_tar(stdin, source_dir, root, progress).map(drop);
+    let status = child.wait()
+        .map_err(|e| Error::Backup(format!("Failed to run compressor command {:?}: {}", command, e)))?;
+    // A failed copy makes the compressor fail on a closed pipe, so its error comes first
+    join_copier(copier).map_err(|e| archive_write_error(output_path, "Failed to write archive", e))?;
+    written.map_err(|e| Error::Backup(format!("Failed to create tar archive: {}", e)))?;
+    if !status.success() {
+        return Err(Error::Backup(format!("Compressor command {:?} failed with {}", command, status)));
+    }
+
+    Ok(())
+}
+
+/// Wait for a thread copying between a process and a file
+fn join_copier(copier: JoinHandle<io::Result<()>>) -> io::Result<()> {
+    copier.join().unwrap_or_else(|_| Err(io::Error::other("copier thread panicked")))
+}
+
+/// Write the backup directory as a tar stream, returning the underlying writer
+fn write_tar<W: Write>(writer: W, source_dir: &Path, root: Option<&str>, progress: Option<&ProgressBar>) -> io::Result<W> {
+    let mut tar = Builder::new(writer);
+
+    // The root directory is always the first entry; readers rely on that to strip it
+    tar.append_dir(root.unwrap_or("."), source_dir)?;
+    append_tree(&mut tar, source_dir, Path::new(root.unwrap_or("")), progress)?;
+    tar.into_inner()
+}
+
+/// Error for a failed write to the archive at `output_path`: DiskFull, naming where space ran
+/// out, when its filesystem is full, otherwise `context` with the cause
+pub fn archive_write_error(output_path: &Path, context: &str, e: io::Error) -> Error {
+    if is_disk_full(&e) {
+        Error::DiskFull(format!(
+            "ran out of disk space writing archive to {:?}; free space there or set archive_temp_dir to a larger filesystem",
+            output_path
+        ))
+    } else {
+        Error::Backup(format!("{}: {}", context, e))
+    }
+}
+
+/// archive_write_error for the zip writer, whose I/O failures come wrapped in ZipError::Io
+fn zip_write_error(output_path: &Path, context: &str, e: ZipError) -> Error {
+    match e {
+        ZipError::Io(e) => archive_write_error(output_path, context, e),
+        e => Error::Backup(format!("{}: {}", context, e)),
+    }
+}
+
+fn compress_zip(
+    source_dir: &Path,
+    output_path: &Path,
+    root: Option<&str>,
+    comment: Option<&str>,
+    entry_compression: &BTreeMap<String, EntryCompression>,
+    progress: Option<&ProgressBar>,
+) -> Result<()> {
+    let file = File::create(output_path).map_err(Error::Io)?;
+    let mut zip = ZipWriter::new(file);
+    if let Some(comment) = comment {
+        zip.set_comment(comment);
+    }
+
+    let root = match root {
+        Some(root) => {
+            zip.add_directory(root, SimpleFileOptions::default())
+                .map_err(|e| zip_write_error(output_path, "Failed to create zip archive", e))?;
+            format!("{}/", root)
+        }
+        None => String::new(),
+    };
+    append_zip_tree(&mut zip, source_dir, &root, "", entry_compression, progress)
+        .map_err(|e| zip_write_error(output_path, "Failed to create zip archive", e))?;
+    zip.finish()
+        .map_err(|e| zip_write_error(output_path, "Failed to finish zip archive", e))?;
+
+    Ok(())
+}
+
+/// Append a directory's contents to a zip archive, deflating every file except those in or under `entry_compression` entries.
+/// Entry names are `root` followed by the path relative to the backup directory (`prefix`).
+fn append_zip_tree<W: Write + io::Seek>(
+    zip: &mut ZipWriter<W>,
+    dir: &Path,
+    root: &str,
+    prefix: &str,
+    entry_compression: &BTreeMap<String, EntryCompression>,
+    progress: Option<&ProgressBar>,
+) -> zip::result::ZipResult<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let relative = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let name = format!("{}{}", root, relative);
+        let compression = entry_compression.iter()
+            .find(|(entry, _)| {
+                relative == **entry || relative.strip_prefix(entry.as_str()).is_some_and(|rest| rest.starts_with('/'))
+            })
+            .map_or(EntryCompression::Deflate, |(_, compression)| *compression);
+        let method = match compression {
+            EntryCompression::Deflate => CompressionMethod::Deflated,
+            EntryCompression::Zstd => CompressionMethod::Zstd,
+            EntryCompression::Store => CompressionMethod::Stored,
+        };
+
+        if path.is_dir() {
+            zip.add_directory(name.as_str(), SimpleFileOptions::default())?;
+            append_zip_tree(zip, &path, root, &format!("{}/", relative), entry_compression, progress)?;
+        } else {
+            let size = fs::metadata(&path)?.len();
+            let options = SimpleFileOptions::default()
+                .compression_method(method)
+                .large_file(size >= u32::MAX as u64);
+            zip.start_file(name.as_str(), options)?;
+            io::copy(&mut File::open(&path)?, zip)?;
+            if let Some(bar) = progress {
+                bar.inc(size);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Append a directory's contents recursively, reporting each file's size to the progress bar
+fn append_tree<W: Write>(
+    tar: &mut Builder<W>,
+    dir: &Path,
+    prefix: &Path,
+    progress: Option<&ProgressBar>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = prefix.join(entry.file_name());
+        if path.is_dir() {
+            tar.append_dir(&name, &path)?;
+            append_tree(tar, &path, &name, progress)?;
+        } else {
+            tar.append_path_with_name(&path, &name)?;
+            if let Some(bar) = progress {
+                bar.inc(fs::metadata(&path)?.len());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Total size in bytes of the files under a directory
+fn directory_size(dir: &Path) -> Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir).map_err(Error::Io)? {
+        let path = entry.map_err(Error::Io)?.path();
+        total += if path.is_dir() {
+            directory_size(&path)?
+        } else {
+            fs::metadata(&path).map_err(Error::Io)?.len()
+        };
+    }
+    Ok(total)
+}
+
+/// Leading bytes of each container kronos can read natively
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const TAR_MAGIC_OFFSET: usize = 257; // "ustar" in the first header block
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// How an existing archive is read, judged by its leading bytes
+enum ArchiveKind<'a> {
+    Gzip,
+    Zstd,
+    Zip,
+    Tar,
+    External(&'a str), // Tar stream behind a decompressor command
+}
+
+/// Archives named with the custom compressor's extension always go through the decompressor, since
+/// its output may well start like a format read natively (a gzip-based pipeline, say)
+fn archive_kind<'a>(archive_path: &Path, header: &[u8], options: &'a ReadOptions) -> Result<ArchiveKind<'a>> {
+    let decompressor = options.decompressor.as_deref();
+    let external_name = options.external_extension.as_ref().is_some_and(|extension| {
+        archive_path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(&format!(".{}", extension)))
+    });
+    let kind = if let Some(command) = decompressor.filter(|_| external_name) {
+        ArchiveKind::External(command)
+    } else if header.starts_with(GZIP_MAGIC) {
+        ArchiveKind::Gzip
+    } else if header.starts_with(ZSTD_MAGIC) {
+        ArchiveKind::Zstd
+    } else if header.starts_with(ZIP_MAGIC) {
+        ArchiveKind::Zip
+    } else if header.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC) {
+        ArchiveKind::Tar
+    } else if let Some(command) = decompressor {
+        ArchiveKind::External(command)
+    } else {
+        return Err(Error::Storage(format!(
+            "Unrecognised archive format in {:?}; set decompressor_command to read archives from a custom compressor",
+            archive_path
+        )));
+    };
+    Ok(kind)
+}
+
+/// How stored archives are read back
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    pub decompressor: Option<String>, // Command that turns archives from a custom compressor back into a tar stream
+    pub external_extension: Option<String>, // Extension of archives from the custom compressor, read with `decompressor` first
+    pub encryption_key_file: Option<String>, // Key for encrypted archives, loaded when one is read
+}
+
+impl ReadOptions {
+    pub fn from_storage(storage: &Storage) -> Self {
+        ReadOptions {
+            decompressor: storage.decompressor_command.clone(),
+            external_extension: storage.compressor_extension.clone(),
+            encryption_key_file: storage.encryption_key_file.clone(),
+        }
+    }
+
+    fn encryption_key(&self, archive_path: &Path) -> Result<EncryptionKey> {
+        match &self.encryption_key_file {
+            Some(path) => load_encryption_key(Path::new(path)),
+            None => Err(Error::Config(format!(
+                "{:?} is encrypted; set encryption_key_file to read it",
+                archive_path
+            ))),
+        }
+    }
+}
+
+/// A stored archive opened for reading
+pub enum OpenArchive {
+    Tar(TarSource),
+    Zip(ZipArchive<File>),
+}
+
+/// Open an archive for reading whatever its format: gzip, zstd or plain tar streams and zip
+/// files are recognised by content, anything else is piped through the decompressor. Encrypted
+/// archives are decrypted as they are read. Every command that reads stored archives goes through here.
+pub fn open_archive(archive_path: &Path, options: &ReadOptions) -> Result<OpenArchive> {
+    let file = File::open(archive_path).map_err(Error::Io)?;
+    let (mut header, mut stream) = peek(Box::new(file))?;
+    let encrypted = header.starts_with(ENCRYPTION_MAGIC);
+    if encrypted {
+        let key = options.encryption_key(archive_path)?;
+        let decrypted = DecryptReader::new(stream, &key)
+            .map_err(|e| Error::Storage(format!("Failed to read archive {:?}: {}", archive_path, e)))?;
+        (header, stream) = peek(Box::new(decrypted))?;
+    }
+
+    match archive_kind(archive_path, &header, options)? {
+        ArchiveKind::Zip if encrypted => Err(Error::Storage(format!("Encrypted zip archive {:?} is not supported", archive_path))),
+        ArchiveKind::Zip => Ok(OpenArchive::Zip(open_zip(archive_path)?)),
+        kind => Ok(OpenArchive::Tar(TarSource::open(stream, kind)?)),
+    }
+}
+
+/// Read the first bytes of a stream to tell its format, returning them along with a stream
+/// that still starts at the beginning
+fn peek(mut stream: Box<dyn Read + Send>) -> Result<(Vec<u8>, Box<dyn Read + Send>)> {
+    let mut header = Vec::with_capacity(512);
+    stream.by_ref().take(512).read_to_end(&mut header).map_err(Error::Io)?;
+    Ok((header.clone(), Box::new(io::Cursor::new(header).chain(stream))))
+}
+
+/// Comment stored in an archive's gzip header or zip archive comment, read without extracting.
+/// None for archives without one and for formats that can't hold one (encrypted or external).
+pub fn read_archive_comment(archive_path: &Path) -> Result<Option<String>> {
+    let mut header = [0u8; 4];
+    let read = File::open(archive_path)
+        .and_then(|mut file| file.read(&mut header))
+        .map_err(Error::Io)?;
+    let comment = match &header[..read] {
+        [0x1f, 0x8b, ..] => {
+            let decoder = GzDecoder::new(File::open(archive_path).map_err(Error::Io)?);
+            decoder.header().and_then(|header| header.comment()).map(|comment| comment.to_vec())
+        }
+        [b'P', b'K', 3, 4] => Some(open_zip(archive_path)?.comment().to_vec()),
+        _ => None,
+    };
+    Ok(comment.filter(|comment| !comment.is_empty()).map(|comment| String::from_utf8_lossy(&comment).into_owned()))
+}
+
+/// Read a single top-level file from an archive without extracting it. `options` supply the
+/// decompressor for archives written with a custom compressor and the key for encrypted ones.
+pub fn read_archive_file(archive_path: &Path, file_name: &str, options: &ReadOptions) -> Result<Option<Vec<u8>>> {
+    let mut contents = Vec::new();
+    if copy_archive_file(archive_path, file_name, &mut contents, options)? {
+        Ok(Some(contents))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Stream a single file from an archive into `out`; returns false if it isn't there
+pub fn copy_archive_file<W: Write>(
+    archive_path: &Path,
+    file_name: &str,
+    out: &mut W,
+    options: &ReadOptions,
+) -> Result<bool> {
+    let mut source = match open_archive(archive_path, options)? {
+        OpenArchive::Zip(mut archive) => {
+            let name = format!("{}{}", zip_root(&archive), file_name);
+            return match archive.by_name(&name) {
+                Ok(mut entry) if entry.is_file() => {
+                    io::copy(&mut entry, out).map_err(Error::Io)?;
+                    Ok(true)
+                }
+                Ok(_) | Err(ZipError::FileNotFound) => Ok(false),
+                Err(e) => Err(Error::Storage(format!("Failed to read archive {:?}: {}", archive_path, e))),
+            };
+        }
+        OpenArchive::Tar(source) => source,
+    };
+    let entries = source.archive.entries()
+        .map_err(|e| Error::Storage(format!("Failed to read archive {:?}: {}", archive_path, e)))?;
+
+    let mut root = None;
+    for entry in entries {
+        let mut entry = entry
+            .map_err(|e| Error::Storage(format!("Failed to read archive {:?}: {}", archive_path, e)))?;
+        let path = entry.path().map_err(Error::Io)?.into_owned();
+        let Some(name) = relative_name(&mut root, &entry, &path) else {
+            continue;
+        };
+        if entry.header().entry_type().is_file() && name == file_name {
+            io::copy(&mut entry, out).map_err(Error::Io)?;
+            source.finish(false)?;
+            return Ok(true);
+        }
+    }
+
+    source.finish(true)?;
+    Ok(false)
+}
+
+/// Names of the regular files in an archive, relative to its root
+pub fn list_archive_files(archive_path: &Path, options: &ReadOptions) -> Result<Vec<String>> {
+    let mut source = match open_archive(archive_path, options)? {
+        OpenArchive::Zip(archive) => {
+            let root = zip_root(&archive);
+            return Ok(archive.file_names()
+                .filter(|name| !name.ends_with('/'))
+                .filter_map(|name| name.strip_prefix(&root))
+                .map(str::to_string)
+                .collect());
+        }
+        OpenArchive::Tar(source) => source,
+    };
+    let entries = source.archive.entries()
+        .map_err(|e| Error::Storage(format!("Failed to read archive {:?}: {}", archive_path, e)))?;
+
+    let mut names = Vec::new();
+    let mut root = None;
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| Error::Storage(format!("Failed to read archive {:?}: {}", archive_path, e)))?;
+        let path = entry.path().map_err(Error::Io)?.into_owned();
+        if let Some(name) = relative_name(&mut root, &entry, &path) {
+            if entry.header().entry_type().is_file() {
+                names.push(name);
+            }
+        }
+    }
+
+    source.finish(true)?;
+    Ok(names)
+}
+
+/// Unpack every regular file of an archive into `dest`, relative to the archive root. Returns
+/// the files a zip archive doesn't deflate, so repacking it can compress them the same way.
+pub fn extract_archive(archive_path: &Path, dest: &Path, options: &ReadOptions) -> Result<BTreeMap<String, EntryCompression>> {
+    let mut source = match open_archive(archive_path, options)? {
+        OpenArchive::Zip(mut archive) => {
+            let root = zip_root(&archive);
+            let mut entry_compression = BTreeMap::new();
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)
+                    .map_err(|e| Error::Storage(format!("Failed to read archive {:?}: {}", archive_path, e)))?;
+                let Some(name) = entry.name().strip_prefix(&root).map(str::to_string) else {
+                    continue;
+                };
+                if !entry.is_file() {
+                    continue;
+                }
+                match entry.compression() {
+                    CompressionMethod::Stored => entry_compression.insert(name.clone(), EntryCompression::Store),
+                    CompressionMethod::Zstd => entry_compression.insert(name.clone(), EntryCompression::Zstd),
+                    _ => None,
+                };
+                extract_entry(&mut entry, dest, &name)?;
+            }
+            return Ok(entry_compression);
+        }
+        OpenArchive::Tar(source) => source,
+    };
+    let entries = source.archive.entries()
+        .map_err(|e| Error::Storage(format!("Failed to read archive {:?}: {}", archive_path, e)))?;
+
+    let mut root = None;
+    for entry in entries {
+        let mut entry = entry
+            .map_err(|e| Error::Storage(format!("Failed to read archive {:?}: {}", archive_path, e)))?;
+        let path = entry.path().map_err(Error::Io)?.into_owned();
+        if let Some(name) = relative_name(&mut root, &entry, &path) {
+            if entry.header().entry_type().is_file() {
+                extract_entry(&mut entry, dest, &name)?;
+            }
+        }
+    }
+
+    source.finish(true)?;
+    Ok(BTreeMap::new())
+}
+
+/// Write one archive member to `dest/name`, refusing names that would land outside `dest`
+fn extract_entry<R: Read>(reader: &mut R, dest: &Path, name: &str) -> Result<()> {
+    let relative = Path::new(name);
+    if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(Error::Storage(format!("Refusing to extract {:?} outside the target directory", name)));
+    }
+    let path = dest.join(relative);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(Error::Io)?;
+    }
+    let mut file = File::create(&path).map_err(Error::Io)?;
+    io::copy(reader, &mut file).map_err(Error::Io)?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// A tar stream read from a file, through a built-in decoder or a decompressor command's output
+pub struct TarSource {
+    pub archive: Archive<Box<dyn Read>>,
+    decompressor: Option<Decompressor>,
+}
+
+/// A decompressor command and the thread feeding it the archive
+struct Decompressor {
+    command: String,
+    child: Child,
+    feeder: JoinHandle<io::Result<()>>,
+}
+
+impl TarSource {
+    fn open(stream: Box<dyn Read + Send>, kind: ArchiveKind) -> Result<Self> {
+        let stream: Box<dyn Read> = match kind {
+            ArchiveKind::External(command) => {
+                let mut child = shell_command(command)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| Error::Storage(format!("Failed to run decompressor command {:?}: {}", command, e)))?;
+                let (Some(mut stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+                    return Err(Error::Storage("Failed to connect to the decompressor command".to_string()));
+                };
+                let mut stream = stream;
+                let feeder = thread::spawn(move || io::copy(&mut stream, &mut stdin).map(drop));
+                return Ok(TarSource {
+                    archive: Archive::new(Box::new(stdout)),
+                    decompressor: Some(Decompressor { command: command.to_string(), child, feeder }),
+                });
+            }
+            ArchiveKind::Gzip => Box::new(GzDecoder::new(stream)),
+            ArchiveKind::Zstd => Box::new(zstd::Decoder::new(stream).map_err(Error::Io)?),
+            ArchiveKind::Tar => stream,
+            ArchiveKind::Zip => unreachable!("zip archives are not tar streams"),
+        };
+        Ok(TarSource { archive: Archive::new(stream), decompressor: None })
+    }
+
+    /// Reap the decompressor. After a complete read its exit status is checked; after stopping
+    /// early it is killed, since it may be blocked writing output nobody will read.
+    pub fn finish(self, complete: bool) -> Result<()> {
+        let TarSource { archive, decompressor } = self;
+        drop(archive);
+        let Some(Decompressor { command, mut child, feeder }) = decompressor else {
+            return Ok(());
+        };
+        if !complete {
+            let _ = child.kill();
+        }
+        let status = child.wait().map_err(Error::Io)?;
+        let fed = join_copier(feeder);
+        if complete && !status.success() {
+            return Err(Error::Storage(format!("Decompressor command {:?} failed with {}", command, status)));
+        }
+        match fed {
+            // The decompressor may stop reading once it has seen the end of the tar stream
+            Err(e) if complete && e.kind() != io::ErrorKind::BrokenPipe => {
+                Err(Error::Storage(format!("Failed to read archive: {}", e)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `sh -c <command>`, so configured commands can use pipes and arguments
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+/// Name of a tar entry relative to the archive root, or None for the root entry itself.
+/// The first entry of every archive kronos writes is its root directory (`./` or a named root).
+fn relative_name<R: io::Read>(root: &mut Option<String>, entry: &tar::Entry<R>, path: &Path) -> Option<String> {
+    let name = entry_name(path);
+    match root {
+        None if entry.header().entry_type().is_dir() => {
+            *root = Some(if name.is_empty() { String::new() } else { format!("{}/", name) });
+            None
+        }
+        None => {
+            *root = Some(String::new());
+            Some(name)
+        }
+        Some(root) => name.strip_prefix(root.as_str()).map(str::to_string),
+    }
+}
+
+/// Root prefix (`<dir>/` or empty) of a zip archive, located through its manifest
+fn zip_root(archive: &ZipArchive<File>) -> String {
+    archive.file_names()
+        .filter_map(|name| name.strip_suffix(MANIFEST_FILE))
+        .find(|prefix| prefix.is_empty() || (prefix.ends_with('/') && prefix.matches('/').count() == 1))
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn open_zip(archive_path: &Path) -> Result<ZipArchive<File>> {
+    let file = File::open(archive_path).map_err(Error::Io)?;
+    ZipArchive::new(file)
+        .map_err(|e| Error::Storage(format!("Failed to read archive {:?}: {}", archive_path, e)))
+}
+
+/// Archive entry path without any leading `./`, using `/` separators
+fn entry_name(path: &Path) -> String {
+    path.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zip_compresses_entries_as_configured() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("media.bak"), vec![b'a'; 4096]).unwrap();
+        fs::write(source.path().join("shop.sql"), vec![b'a'; 4096]).unwrap();
+        fs::write(source.path().join("crm.sql"), vec![b'a'; 4096]).unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let archive_path = output.path().join("backup.zip");
+        let options = ArchiveOptions {
+            format: ArchiveFormat::Zip,
+            entry_compression: BTreeMap::from([
+                ("media.bak".to_string(), EntryCompression::Store),
+                ("crm.sql".to_string(), EntryCompression::Zstd),
+            ]),
+            ..Default::default()
+        };
+
+        compress_directory(source.path(), &archive_path, &options).unwrap();
+
+        let mut archive = open_zip(&archive_path).unwrap();
+        assert_eq!(archive.by_name("media.bak").unwrap().compression(), CompressionMethod::Stored);
+        assert_eq!(archive.by_name("shop.sql").unwrap().compression(), CompressionMethod::Deflated);
+        assert_eq!(archive.by_name("crm.sql").unwrap().compression(), CompressionMethod::Zstd);
+        assert_eq!(read_archive_file(&archive_path, "media.bak", &ReadOptions::default()).unwrap().unwrap().len(), 4096);
+        assert_eq!(read_archive_file(&archive_path, "crm.sql", &ReadOptions::default()).unwrap().unwrap(), vec![b'a'; 4096]);
+    }
+
+    #[test]
+    fn archive_comment_is_stored_in_the_header() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join(MANIFEST_FILE), b"{}").unwrap();
+        let output = tempfile::tempdir().unwrap();
+
+        for format in [ArchiveFormat::TarGz, ArchiveFormat::Zip] {
+            let archive_path = output.path().join(format!("backup.{}", format.extension()));
+            let comment = Some("kronos backup-1 created 2026-01-01".to_string());
+            compress_directory(source.path(), &archive_path, &ArchiveOptions { format, comment: comment.clone(), ..Default::default() }).unwrap();
+            assert_eq!(read_archive_comment(&archive_path).unwrap(), comment, "{:?}", format);
+
+            compress_directory(source.path(), &archive_path, &ArchiveOptions { format, ..Default::default() }).unwrap();
+            assert_eq!(read_archive_comment(&archive_path).unwrap(), None, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn archive_root_is_stripped_when_reading() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join(MANIFEST_FILE), b"{}").unwrap();
+        fs::create_dir(source.path().join("app_data")).unwrap();
+        fs::write(source.path().join("app_data").join("orders.bson.gz"), b"bson").unwrap();
+        let output = tempfile::tempdir().unwrap();
+
+        for format in [ArchiveFormat::TarGz, ArchiveFormat::Zip] {
+            for root in [None, Some("backup-test".to_string())] {
+                let archive_path = output.path().join(format!("backup.{}", format.extension()));
+                let options = ArchiveOptions { format, root: root.clone(), ..Default::default() };
+                compress_directory(source.path(), &archive_path, &options).unwrap();
+
+                let mut names = list_archive_files(&archive_path, &ReadOptions::default()).unwrap();
+                names.sort();
+                assert_eq!(names, ["app_data/orders.bson.gz", MANIFEST_FILE], "{:?} {:?}", format, root);
+                assert_eq!(read_archive_file(&archive_path, MANIFEST_FILE, &ReadOptions::default()).unwrap().unwrap(), b"{}");
+            }
+        }
+    }
+
+    #[test]
+    fn extracts_every_file_under_the_root() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join(MANIFEST_FILE), b"{}").unwrap();
+        fs::create_dir(source.path().join("app_data")).unwrap();
+        fs::write(source.path().join("app_data").join("orders.bson.gz"), b"bson").unwrap();
+        let output = tempfile::tempdir().unwrap();
+
+        for format in [ArchiveFormat::TarGz, ArchiveFormat::Zip] {
+            let archive_path = output.path().join(format!("backup.{}", format.extension()));
+            let options = ArchiveOptions {
+                format,
+                root: Some("backup-test".to_string()),
+                entry_compression: BTreeMap::from([("app_data".to_string(), EntryCompression::Store)]),
+                ..Default::default()
+            };
+            compress_directory(source.path(), &archive_path, &options).unwrap();
+
+            let dest = tempfile::tempdir().unwrap();
+            let entry_compression = extract_archive(&archive_path, dest.path(), &ReadOptions::default()).unwrap();
+            assert_eq!(fs::read(dest.path().join(MANIFEST_FILE)).unwrap(), b"{}", "{:?}", format);
+            assert_eq!(fs::read(dest.path().join("app_data").join("orders.bson.gz")).unwrap(), b"bson", "{:?}", format);
+            let expected = match format {
+                ArchiveFormat::Zip => BTreeMap::from([("app_data/orders.bson.gz".to_string(), EntryCompression::Store)]),
+                ArchiveFormat::TarGz => BTreeMap::new(),
+            };
+            assert_eq!(entry_compression, expected, "{:?}", format);
+        }
+    }
+
+    #[cfg(unix)]
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn external_compressor_reports_a_full_disk() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join(MANIFEST_FILE), b"{}").unwrap();
+        let options = ArchiveOptions {
+            external: Some(ExternalCompressor { command: "gzip -c".to_string(), extension: "tar.gz".to_string() }),
+            ..Default::default()
+        };
+
+        let result = compress_directory(source.path(), Path::new("/dev/full"), &options);
+        assert!(matches!(result, Err(Error::DiskFull(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn external_compressor_round_trips() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join(MANIFEST_FILE), b"{}").unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let archive_path = output.path().join("backup.tar.gz.b64");
+        let options = ArchiveOptions {
+            external: Some(ExternalCompressor { command: "gzip -c | base64".to_string(), extension: "tar.gz.b64".to_string() }),
+            ..Default::default()
+        };
+
+        compress_directory(source.path(), &archive_path, &options).unwrap();
+
+        let read = |decompressor: Option<&str>| ReadOptions { decompressor: decompressor.map(str::to_string), ..Default::default() };
+        let decompressor = read(Some("base64 -d | gzip -dc"));
+        assert_eq!(list_archive_files(&archive_path, &decompressor).unwrap(), [MANIFEST_FILE]);
+        assert_eq!(read_archive_file(&archive_path, MANIFEST_FILE, &decompressor).unwrap().unwrap(), b"{}");
+        assert!(list_archive_files(&archive_path, &read(Some("false"))).is_err());
+        assert!(list_archive_files(&archive_path, &read(None)).is_err());
+    }
+
+    #[test]
+    fn decompressor_comes_before_sniffing_for_its_archives() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join(MANIFEST_FILE), b"{}").unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let archive_path = output.path().join("backup.tar.gz.gz");
+        let options = ArchiveOptions {
+            external: Some(ExternalCompressor { command: "gzip -c | gzip -c".to_string(), extension: "tar.gz.gz".to_string() }),
+            ..Default::default()
+        };
+        compress_directory(source.path(), &archive_path, &options).unwrap();
+
+        // The output starts with the gzip magic, so sniffing alone would read it as a single gzip layer
+        let read = ReadOptions {
+            decompressor: Some("gzip -dc | gzip -dc".to_string()),
+            external_extension: Some("tar.gz.gz".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(list_archive_files(&archive_path, &read).unwrap(), [MANIFEST_FILE]);
+
+        // Archives in a built-in format are still recognised by content
+        let native_path = output.path().join("older.tar.gz");
+        compress_directory(source.path(), &native_path, &ArchiveOptions::default()).unwrap();
+        assert_eq!(list_archive_files(&native_path, &read).unwrap(), [MANIFEST_FILE]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn encrypted_archives_round_trip() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join(MANIFEST_FILE), b"{}").unwrap();
+        fs::write(source.path().join("app.sql"), "INSERT INTO t VALUES (1);\n".repeat(20_000)).unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let key_file = output.path().join("archive.key");
+        fs::write(&key_file, "0f".repeat(32)).unwrap();
+        crate::utils::permissions::restrict_to_owner(&key_file).unwrap();
+        let key = load_encryption_key(&key_file).unwrap();
+        let read = ReadOptions {
+            decompressor: Some("base64 -d | gzip -dc".to_string()),
+            external_extension: None,
+            encryption_key_file: Some(key_file.to_string_lossy().into_owned()),
+        };
+
+        let external = ExternalCompressor { command: "gzip -c | base64".to_string(), extension: "tar.gz.b64".to_string() };
+        for external in [None, Some(external)] {
+            let archive_path = output.path().join("backup.archive");
+            let options = ArchiveOptions { external, encryption: Some(key.clone()), ..Default::default() };
+            compress_directory(source.path(), &archive_path, &options).unwrap();
+
+            assert!(fs::read(&archive_path).unwrap().starts_with(ENCRYPTION_MAGIC));
+            assert_eq!(list_archive_files(&archive_path, &read).unwrap(), ["app.sql", MANIFEST_FILE]);
+            let mut dump = Vec::new();
+            assert!(copy_archive_file(&archive_path, "app.sql", &mut dump, &read).unwrap());
+            assert_eq!(dump.len(), 26 * 20_000);
+            assert!(list_archive_files(&archive_path, &ReadOptions::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn formats_are_detected_by_content() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join(MANIFEST_FILE), b"{}").unwrap();
+        let output = tempfile::tempdir().unwrap();
+
+        // Names deliberately say nothing about the format
+        let tar_gz = output.path().join("gzip.archive");
+        compress_directory(source.path(), &tar_gz, &ArchiveOptions::default()).unwrap();
+        let zip = output.path().join("zip.archive");
+        let options = ArchiveOptions { format: ArchiveFormat::Zip, ..Default::default() };
+        compress_directory(source.path(), &zip, &options).unwrap();
+        let tar = output.path().join("tar.archive");
+        write_tar(File::create(&tar).unwrap(), source.path(), None, None).unwrap();
+        let tar_zst = output.path().join("zstd.archive");
+        let encoder = zstd::Encoder::new(File::create(&tar_zst).unwrap(), 0).unwrap();
+        write_tar(encoder, source.path(), None, None).unwrap().finish().unwrap();
+
+        for archive_path in [tar_gz, zip, tar, tar_zst] {
+            assert_eq!(list_archive_files(&archive_path, &ReadOptions::default()).unwrap(), [MANIFEST_FILE], "{:?}", archive_path);
+            assert_eq!(read_archive_file(&archive_path, MANIFEST_FILE, &ReadOptions::default()).unwrap().unwrap(), b"{}");
+        }
+    }
+}
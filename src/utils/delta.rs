@@ -0,0 +1,298 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Block size deltas are computed with: SQLite's default page size, so a changed page
+/// invalidates one block
+pub const BLOCK_SIZE: usize = 4096;
+
+/// Marks a delta file and its format version
+const MAGIC: &[u8; 8] = b"KRDELTA1";
+const OP_END: u8 = 0;
+const OP_COPY: u8 = 1;
+const OP_LITERAL: u8 = 2;
+
+/// Bytes read from the new file at a time
+const READ_CHUNK: usize = 64 * 1024;
+/// Longest literal kept in memory before it is written out
+const MAX_LITERAL: usize = 1024 * 1024;
+
+/// Checksums of each whole block of a base file, built by writing the file into it
+pub struct Signature {
+    block_size: usize,
+    blocks: HashMap<u32, Vec<(u64, [u8; 32])>>, // Weak checksum -> (block index, SHA-256) of the blocks having it
+    pending: Vec<u8>,
+    count: u64,
+}
+
+/// How much of a new file a delta copies from the base and how much it carries itself
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaStats {
+    pub copied: u64,
+    pub literal: u64,
+}
+
+impl Signature {
+    pub fn new(block_size: usize) -> Self {
+        Signature { block_size, blocks: HashMap::new(), pending: Vec::new(), count: 0 }
+    }
+
+    fn find(&self, weak: u32, block: &[u8]) -> Option<u64> {
+        let candidates = self.blocks.get(&weak)?;
+        let strong: [u8; 32] = Sha256::digest(block).into();
+        candidates.iter().find(|(_, hash)| *hash == strong).map(|(index, _)| *index)
+    }
+
+    fn add_block(&mut self, block: &[u8]) {
+        let weak = Rolling::new(block).digest();
+        self.blocks.entry(weak).or_default().push((self.count, Sha256::digest(block).into()));
+        self.count += 1;
+    }
+}
+
+impl Write for Signature {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(data);
+        let whole = self.pending.len() / self.block_size * self.block_size;
+        let pending = std::mem::take(&mut self.pending);
+        for block in pending[..whole].chunks(self.block_size) {
+            self.add_block(block);
+        }
+        // A short last block is never matched; those bytes end up as a literal
+        self.pending = pending[whole..].to_vec();
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// rsync's weak checksum, which slides along a file one byte at a time
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let mut rolling = Rolling { a: 0, b: 0, len };
+        for (i, &byte) in block.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(byte as u32);
+            rolling.b = rolling.b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        rolling
+    }
+
+    /// Move the window one byte on, dropping `out` and taking in `into`
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Encode `new` as a delta against the base `signature` was built from: runs of base blocks to
+/// copy and literal bytes in between, followed by the SHA-256 of `new` so applying it to the
+/// wrong base is caught
+pub fn write_delta<R: Read, W: Write>(signature: &Signature, mut new: R, mut out: W) -> io::Result<DeltaStats> {
+    let block_size = signature.block_size;
+    out.write_all(MAGIC)?;
+    out.write_all(&(block_size as u32).to_le_bytes())?;
+
+    let mut hasher = Sha256::new();
+    let mut stats = DeltaStats::default();
+    let mut buf: Vec<u8> = Vec::new();
+    let mut pos = 0; // Start of the window in `buf`
+    let mut literal_start = 0; // Start of the bytes not yet written in `buf`
+    let mut copy_run: Option<(u64, u32)> = None; // First block and length of the copy being extended
+    let mut weak: Option<Rolling> = None;
+    let mut eof = false;
+
+    loop {
+        // Keep the window plus the byte after it in `buf`, dropping what has been written
+        while !eof && buf.len() < pos + block_size + 1 {
+            if literal_start >= READ_CHUNK {
+                buf.drain(..literal_start);
+                pos -= literal_start;
+                literal_start = 0;
+            }
+            let filled = buf.len();
+            buf.resize(filled + READ_CHUNK, 0);
+            let read = new.read(&mut buf[filled..])?;
+            buf.truncate(filled + read);
+            hasher.update(&buf[filled..]);
+            eof = read == 0;
+        }
+        if buf.len() < pos + block_size {
+            break;
+        }
+
+        let window = &buf[pos..pos + block_size];
+        let checksum = weak.get_or_insert_with(|| Rolling::new(window)).digest();
+        if let Some(index) = signature.find(checksum, window) {
+            write_literal(&mut out, &buf[literal_start..pos], &mut copy_run, &mut stats)?;
+            copy_run = match copy_run {
+                Some((first, count)) if first + count as u64 == index && count < u32::MAX => Some((first, count + 1)),
+                run => {
+                    write_copy(&mut out, run)?;
+                    Some((index, 1))
+                }
+            };
+            stats.copied += block_size as u64;
+            pos += block_size;
+            literal_start = pos;
+            weak = None;
+            continue;
+        }
+
+        if pos + block_size == buf.len() {
+            break;
+        }
+        if let Some(weak) = &mut weak {
+            weak.roll(buf[pos], buf[pos + block_size]);
+        }
+        pos += 1;
+        if pos - literal_start >= MAX_LITERAL {
+            write_literal(&mut out, &buf[literal_start..pos], &mut copy_run, &mut stats)?;
+            literal_start = pos;
+        }
+    }
+
+    write_literal(&mut out, &buf[literal_start..], &mut copy_run, &mut stats)?;
+    write_copy(&mut out, copy_run)?;
+    out.write_all(&[OP_END])?;
+    out.write_all(&hasher.finalize())?;
+    out.flush()?;
+    Ok(stats)
+}
+
+fn write_copy<W: Write>(out: &mut W, run: Option<(u64, u32)>) -> io::Result<()> {
+    if let Some((first, count)) = run {
+        out.write_all(&[OP_COPY])?;
+        out.write_all(&first.to_le_bytes())?;
+        out.write_all(&count.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_literal<W: Write>(out: &mut W, bytes: &[u8], copy_run: &mut Option<(u64, u32)>, stats: &mut DeltaStats) -> io::Result<()> {
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    write_copy(out, copy_run.take())?;
+    for chunk in bytes.chunks(u32::MAX as usize) {
+        out.write_all(&[OP_LITERAL])?;
+        out.write_all(&(chunk.len() as u32).to_le_bytes())?;
+        out.write_all(chunk)?;
+    }
+    stats.literal += bytes.len() as u64;
+    Ok(())
+}
+
+/// Rebuild the file a delta was made from out of its base, returning its size. Fails if the
+/// result doesn't match the checksum recorded in the delta.
+pub fn apply_delta<B: Read + Seek, R: Read, W: Write>(mut base: B, mut delta: R, mut out: W) -> io::Result<u64> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let mut magic = [0; 8];
+    delta.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a kronos delta file"));
+    }
+    let block_size = read_u32(&mut delta)? as u64;
+
+    let mut hasher = Sha256::new();
+    let mut written = 0;
+    loop {
+        let mut op = [0; 1];
+        delta.read_exact(&mut op)?;
+        let copied = match op[0] {
+            OP_COPY => {
+                let mut first = [0; 8];
+                delta.read_exact(&mut first)?;
+                let length = read_u32(&mut delta)? as u64 * block_size;
+                base.seek(SeekFrom::Start(u64::from_le_bytes(first) * block_size))?;
+                copy_hashed(&mut (&mut base).take(length), &mut out, &mut hasher, length)?
+            }
+            OP_LITERAL => {
+                let length = read_u32(&mut delta)? as u64;
+                copy_hashed(&mut (&mut delta).take(length), &mut out, &mut hasher, length)?
+            }
+            OP_END => break,
+            _ => return Err(invalid("corrupt delta file")),
+        };
+        written += copied;
+    }
+
+    let mut expected = [0; 32];
+    delta.read_exact(&mut expected)?;
+    if hasher.finalize().as_slice() != expected {
+        return Err(invalid("rebuilt file doesn't match the delta's checksum; wrong base?"));
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Copy exactly `length` bytes, hashing them on the way
+fn copy_hashed<R: Read, W: Write>(from: &mut R, out: &mut W, hasher: &mut Sha256, length: u64) -> io::Result<u64> {
+    let mut buf = vec![0; READ_CHUNK];
+    let mut copied = 0;
+    while copied < length {
+        let read = from.read(&mut buf)?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "delta or base ended early"));
+        }
+        hasher.update(&buf[..read]);
+        out.write_all(&buf[..read])?;
+        copied += read as u64;
+    }
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn delta(base: &[u8], new: &[u8], block_size: usize) -> (Vec<u8>, DeltaStats) {
+        let mut signature = Signature::new(block_size);
+        signature.write_all(base).unwrap();
+        let mut delta = Vec::new();
+        let stats = write_delta(&signature, new, &mut delta).unwrap();
+        (delta, stats)
+    }
+
+    #[test]
+    fn rebuilds_shifted_and_changed_data() {
+        let base: Vec<u8> = (0..10_000u32).flat_map(|i| (i * 7919).to_le_bytes()).collect();
+        // Insert bytes at the front (shifting every block), change one byte and append a tail
+        let mut new = b"header".to_vec();
+        new.extend_from_slice(&base);
+        new[20_000] ^= 0xff;
+        new.extend_from_slice(b"tail");
+
+        let (encoded, stats) = delta(&base, &new, 512);
+        assert!(stats.literal < 1024, "only the changed block and the new bytes are literal: {:?}", stats);
+        assert_eq!(stats.copied + stats.literal, new.len() as u64);
+
+        let mut rebuilt = Vec::new();
+        assert_eq!(apply_delta(Cursor::new(&base), encoded.as_slice(), &mut rebuilt).unwrap(), new.len() as u64);
+        assert_eq!(rebuilt, new);
+
+        let mut other_base = base.clone();
+        other_base[0] ^= 0xff;
+        assert!(apply_delta(Cursor::new(&other_base), encoded.as_slice(), &mut Vec::new()).is_err());
+    }
+}
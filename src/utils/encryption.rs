@@ -0,0 +1,290 @@
+use crate::error::{Error, Result};
+use crate::utils::permissions::ensure_private_file;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, Nonce, OsRng};
+use aes_gcm::Aes256Gcm;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Leading bytes of an encrypted archive, followed by the random nonce prefix
+pub const ENCRYPTION_MAGIC: &[u8] = b"KRONOSE1";
+
+/// Archives are sealed in chunks of this much plaintext, each with its own tag, so neither
+/// side holds more than one chunk in memory
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const NONCE_PREFIX_LEN: usize = 7;
+
+/// AES-256-GCM key for archive encryption
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    /// A fresh random key, e.g. the data key of one database's dump
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        EncryptionKey(key)
+    }
+
+    /// Hex SHA-256 that identifies the key without revealing it
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"kronos key fingerprint\n");
+        hasher.update(self.0);
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.0.into())
+    }
+}
+
+/// Load a 256-bit key written as 64 hex characters (`openssl rand -hex 32`) from a file that
+/// must be readable by its owner only
+pub fn load_encryption_key(path: &Path) -> Result<EncryptionKey> {
+    ensure_private_file(path)?;
+    let text = fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Failed to read encryption key {:?}: {}", path, e)))?;
+    parse_key(text.trim())
+        .ok_or_else(|| Error::Config(format!("Encryption key {:?} must be 64 hex characters (openssl rand -hex 32)", path)))
+}
+
+fn parse_key(hex: &str) -> Option<EncryptionKey> {
+    decode_hex(hex)?.try_into().ok().map(EncryptionKey)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Seal a data key with a master key for storing in the manifest: hex of a random nonce
+/// followed by the sealed key
+pub fn wrap_key(master: &EncryptionKey, data_key: &EncryptionKey) -> Result<String> {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let sealed = master.cipher().encrypt(&nonce.into(), data_key.0.as_slice())
+        .map_err(|_| Error::Backup("Failed to wrap data key".to_string()))?;
+    Ok(nonce.iter().chain(&sealed).map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Recover a data key sealed by `wrap_key`, failing when `master` isn't the key it was sealed with
+pub fn unwrap_key(master: &EncryptionKey, wrapped: &str) -> Result<EncryptionKey> {
+    let bytes = decode_hex(wrapped).filter(|bytes| bytes.len() > 12)
+        .ok_or_else(|| Error::Config("Wrapped data key is not valid hex".to_string()))?;
+    let (nonce, sealed) = bytes.split_at(12);
+    let key = master.cipher().decrypt(Nonce::<Aes256Gcm>::from_slice(nonce), sealed)
+        .map_err(|_| Error::Config("Failed to unwrap data key: wrong master key".to_string()))?;
+    key.try_into().map(EncryptionKey)
+        .map_err(|_| Error::Config("Wrapped data key has the wrong length".to_string()))
+}
+
+/// Encrypt a file in place: it is written encrypted next to itself, then renamed over the original
+pub fn encrypt_file(path: &Path, key: &EncryptionKey) -> Result<()> {
+    let file_name = path.file_name()
+        .ok_or_else(|| Error::Backup(format!("Cannot encrypt {:?}: not a file", path)))?;
+    let tmp_path = path.with_file_name(format!(".{}.enc.tmp", file_name.to_string_lossy()));
+    let encrypted = (|| {
+        let mut source = File::open(path)?;
+        let mut writer = EncryptWriter::new(BufWriter::new(File::create(&tmp_path)?), key)?;
+        io::copy(&mut source, &mut writer)?;
+        writer.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()
+    })();
+    if let Err(e) = encrypted.and_then(|()| fs::rename(&tmp_path, path)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(Error::Backup(format!("Failed to encrypt {:?}: {}", path, e)));
+    }
+    Ok(())
+}
+
+/// Nonce of one chunk: the stream's random prefix, the chunk counter and a final-chunk flag,
+/// so chunks can't be reordered, dropped or the stream cut short without failing decryption
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u32, last: bool) -> Nonce<Aes256Gcm> {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce.into()
+}
+
+/// Encrypts everything written to it into `inner`. `finish` must be called to seal the final
+/// chunk; a stream dropped without it reads back as truncated.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, key: &EncryptionKey) -> io::Result<Self> {
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        inner.write_all(ENCRYPTION_MAGIC)?;
+        inner.write_all(&prefix)?;
+        Ok(EncryptWriter { inner, cipher: key.cipher(), prefix, counter: 0, buffer: Vec::with_capacity(CHUNK_SIZE) })
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        let sealed = self.cipher.encrypt(&nonce, self.buffer.as_slice())
+            .map_err(|_| io::Error::other("Failed to encrypt archive chunk"))?;
+        self.inner.write_all(&sealed)?;
+        self.buffer.clear();
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| io::Error::other("Archive is too large to encrypt"))?;
+        Ok(())
+    }
+
+    /// Seal the final chunk and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        // Full chunks are sealed as soon as they fill, so the final one is always shorter
+        self.seal_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.seal_chunk(false)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Partial chunks can't be sealed early; they are written once full or at `finish`
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream written by `EncryptWriter`, failing on a wrong key, modified data or a
+/// stream cut short
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    cipher: Aes256Gcm,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    chunk: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, key: &EncryptionKey) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if magic != ENCRYPTION_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an encrypted kronos archive"));
+        }
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        inner.read_exact(&mut prefix)?;
+        Ok(DecryptReader { inner, cipher: key.cipher(), prefix, counter: 0, chunk: Vec::new(), position: 0, done: false })
+    }
+
+    fn open_chunk(&mut self) -> io::Result<()> {
+        let mut sealed = vec![0u8; CHUNK_SIZE + TAG_LEN];
+        let mut len = 0;
+        while len < sealed.len() {
+            match self.inner.read(&mut sealed[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        sealed.truncate(len);
+        let last = len < CHUNK_SIZE + TAG_LEN;
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        self.chunk = self.cipher.decrypt(&nonce, sealed.as_slice()).map_err(|_| io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Failed to decrypt archive: wrong encryption key, or the archive was modified or truncated",
+        ))?;
+        self.position = 0;
+        self.done = last;
+        self.counter = self.counter.wrapping_add(1);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(key: &EncryptionKey, data: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), key).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(key: &EncryptionKey, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        DecryptReader::new(sealed, key)?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn chunked_stream_round_trips_and_detects_tampering() {
+        let key = parse_key(&"ab".repeat(32)).unwrap();
+        for len in [0, 10, CHUNK_SIZE, 2 * CHUNK_SIZE + 7] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let sealed = encrypt(&key, &data);
+            assert_eq!(decrypt(&key, &sealed).unwrap(), data);
+
+            // Cut at a chunk boundary, so only the final-chunk flag catches it
+            let truncated = &sealed[..sealed.len() - (len % CHUNK_SIZE) - TAG_LEN];
+            assert!(decrypt(&key, truncated).is_err());
+        }
+
+        let mut sealed = encrypt(&key, b"dump");
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(decrypt(&key, &sealed).is_err());
+        assert!(decrypt(&parse_key(&"cd".repeat(32)).unwrap(), &encrypt(&key, b"dump")).is_err());
+        assert!(parse_key("abcd").is_none());
+    }
+
+    #[test]
+    fn wrapped_data_key_needs_its_master_key() {
+        let master = parse_key(&"ab".repeat(32)).unwrap();
+        let data_key = EncryptionKey::generate();
+        let wrapped = wrap_key(&master, &data_key).unwrap();
+
+        let unwrapped = unwrap_key(&master, &wrapped).unwrap();
+        assert_eq!(decrypt(&unwrapped, &encrypt(&data_key, b"tenant")).unwrap(), b"tenant");
+        assert!(unwrap_key(&parse_key(&"cd".repeat(32)).unwrap(), &wrapped).is_err());
+    }
+}
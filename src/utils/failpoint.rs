@@ -0,0 +1,48 @@
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use std::sync::OnceLock;
+
+/// Environment variable that allows `--inject-failure` in release builds
+pub const ALLOW_FAILURE_INJECTION_ENV: &str = "KRONOS_ALLOW_FAILURE_INJECTION";
+
+/// Phase of a backup run that `--inject-failure` makes fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FailurePhase {
+    /// Connecting to each engine, before anything is dumped
+    Connect,
+    /// After a database's dump finished, before it is checkpointed
+    Dump,
+    /// Building the archive
+    Compress,
+    /// Moving or uploading the finished archive into storage
+    Store,
+}
+
+static INJECTED: OnceLock<FailurePhase> = OnceLock::new();
+
+/// Make every later `fail_point(phase)` of this process fail, to exercise alerting, retries and
+/// cleanup without a real failure. A testing aid, not a production feature: release builds
+/// refuse it unless KRONOS_ALLOW_FAILURE_INJECTION=1 is set.
+pub fn enable_failure_injection(phase: FailurePhase) -> Result<()> {
+    let allowed = cfg!(debug_assertions)
+        || std::env::var(ALLOW_FAILURE_INJECTION_ENV).is_ok_and(|value| value == "1");
+    if !allowed {
+        return Err(Error::Config(format!(
+            "--inject-failure is a testing aid; set {}=1 to use it with a release build",
+            ALLOW_FAILURE_INJECTION_ENV
+        )));
+    }
+    log::warn!("Injecting a failure at the {:?} phase of every backup attempt", phase);
+    let _ = INJECTED.set(phase);
+    Ok(())
+}
+
+/// Fail when a failure was injected at `phase`
+pub fn fail_point(phase: FailurePhase) -> Result<()> {
+    match INJECTED.get() {
+        Some(injected) if *injected == phase => {
+            Err(Error::Backup(format!("Injected failure at the {:?} phase (--inject-failure)", phase)))
+        }
+        _ => Ok(()),
+    }
+}
@@ -1 +1,11 @@
-pub mod compression;
\ No newline at end of file
+pub mod checksum;
+pub mod clock;
+pub mod command;
+pub mod compression;
+pub mod delta;
+pub mod encryption;
+pub mod failpoint;
+pub mod permissions;
+pub mod priority;
+pub mod signing;
+pub mod user;
\ No newline at end of file
@@ -0,0 +1,96 @@
+use crate::error::{Error, Result};
+use std::path::Path;
+
+/// Ensure a credentials file exists and is not readable by group or others.
+pub fn ensure_private_file(path: &Path) -> Result<()> {
+    let metadata = std::fs::metadata(path)
+        .map_err(|e| Error::Config(format!("Credentials file {:?} is not accessible: {}", path, e)))?;
+    if !metadata.is_file() {
+        return Err(Error::Config(format!("Credentials file {:?} is not a regular file", path)));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = metadata.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(Error::Config(format!(
+                "Credentials file {:?} has mode {:o}; it must not be group/world accessible (use chmod 600)",
+                path,
+                mode & 0o777
+            )));
+        }
+    }
+
+    // Windows has no mode bits; ACLs are left to the administrator
+    Ok(())
+}
+
+/// Restrict a file to its owner (mode 0600). No-op on platforms without Unix modes.
+pub fn restrict_to_owner(path: &Path) -> Result<()> {
+    set_file_mode(path, 0o600)
+}
+
+/// Set a file's Unix permission bits. No-op on platforms without Unix modes.
+pub fn set_file_mode(path: &Path, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(Error::Io)?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+
+    Ok(())
+}
+
+/// Create a directory only its owner can access (mode 0700 on Unix), failing if it already exists.
+pub fn create_private_dir(path: &Path) -> Result<()> {
+    try_create_private_dir(path)
+        .map_err(|e| Error::Backup(format!("Failed to create directory {:?}: {}", path, e)))
+}
+
+/// `create_private_dir`, keeping the I/O error so callers can tell an existing directory apart
+pub fn try_create_private_dir(path: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(path)
+}
+
+/// Ensure a directory exists (creating it if needed) and that files can be created in it.
+pub fn ensure_writable_dir(path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path)
+        .map_err(|e| Error::Config(format!("Directory {:?} cannot be created: {}", path, e)))?;
+    tempfile::tempfile_in(path)
+        .map_err(|e| Error::Config(format!("Directory {:?} is not writable: {}", path, e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_group_readable_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("creds");
+        std::fs::write(&path, "secret").unwrap();
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        assert!(ensure_private_file(&path).is_err());
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(ensure_private_file(&path).is_ok());
+    }
+
+    #[test]
+    fn rejects_missing_file() {
+        assert!(ensure_private_file(Path::new("/nonexistent/kronos/creds")).is_err());
+    }
+}
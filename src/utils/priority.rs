@@ -0,0 +1,74 @@
+use crate::config::{DatabaseConfig, IoniceClass};
+use tokio::process::Command as AsyncCommand;
+
+/// Lowest and highest nice values `nice_level` accepts, as for nice(1)
+pub const NICE_RANGE: std::ops::RangeInclusive<i32> = -20..=19;
+
+/// Make a dump command start with the engine's `nice_level` and `ionice_class`, so it yields CPU
+/// and disk to the server it dumps. The priority is set in the child just before it runs the dump
+/// program, after any `run_as_user` switch; failing to set it fails the spawn.
+#[cfg(unix)]
+pub fn apply_priority(cmd: &mut AsyncCommand, config: &DatabaseConfig) {
+    let nice_level = config.nice_level;
+    let ionice_class = config.ionice_class;
+    if nice_level.is_none() && ionice_class.is_none() {
+        return;
+    }
+    // SAFETY: the closure only makes async-signal-safe system calls between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(level) = nice_level {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, level) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(class) = ionice_class {
+                set_io_priority(class, nice_level)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Not supported outside Unix; dumps run at kronos's own priority
+#[cfg(not(unix))]
+pub fn apply_priority(_cmd: &mut AsyncCommand, _config: &DatabaseConfig) {}
+
+/// Whether dump commands started here may get a negative `nice_level`: only root can raise a
+/// process's priority
+#[cfg(unix)]
+pub fn can_raise_priority() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Priorities aren't applied outside Unix, so there is nothing to refuse
+#[cfg(not(unix))]
+pub fn can_raise_priority() -> bool {
+    true
+}
+
+/// ioprio_set(2) on the calling process. Best-effort uses the level the kernel derives from the
+/// nice value, as ionice does when no level is given.
+#[cfg(target_os = "linux")]
+fn set_io_priority(class: IoniceClass, nice_level: Option<i32>) -> std::io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    let (class, level) = match class {
+        IoniceClass::Realtime => (1, 4),
+        IoniceClass::BestEffort => (2, (nice_level.unwrap_or(0) + 20) / 5),
+        IoniceClass::Idle => (3, 0),
+    };
+    // SAFETY: ioprio_set takes plain integers
+    let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, (class << IOPRIO_CLASS_SHIFT) | level) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// I/O scheduling classes are Linux only; elsewhere `ionice_class` changes nothing
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_io_priority(_class: IoniceClass, _nice_level: Option<i32>) -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use crate::error::{Error, Result};
+use crate::utils::permissions::ensure_private_file;
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::fs;
+use std::path::Path;
+
+/// Load an Ed25519 private key from a PKCS#8 PEM file (`openssl genpkey -algorithm ed25519`),
+/// which must be readable by its owner only
+pub fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    ensure_private_file(path)?;
+    let pem = fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Failed to read signing key {:?}: {}", path, e)))?;
+    SigningKey::from_pkcs8_pem(&pem)
+        .map_err(|e| Error::Config(format!("Signing key {:?} is not a PKCS#8 PEM Ed25519 private key: {}", path, e)))
+}
+
+/// Load an Ed25519 public key from a PEM file (`openssl pkey -pubout`)
+pub fn load_verifying_key(path: &Path) -> Result<VerifyingKey> {
+    let pem = fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Failed to read verify key {:?}: {}", path, e)))?;
+    VerifyingKey::from_public_key_pem(&pem)
+        .map_err(|e| Error::Config(format!("Verify key {:?} is not a PEM Ed25519 public key: {}", path, e)))
+}
+
+/// Detached signature over an archive, identified by its hex SHA-256 digest. Signing the digest
+/// rather than the archive keeps large archives out of memory. The backup id is signed with it,
+/// so a signed archive can't be passed off as another backup along with its signature.
+pub fn sign_archive(key: &SigningKey, backup_id: &str, sha256: &str) -> Vec<u8> {
+    key.sign(&signed_message(backup_id, sha256)).to_bytes().to_vec()
+}
+
+/// Check a detached signature made by `sign_archive` for `backup_id`
+pub fn verify_archive(key: &VerifyingKey, backup_id: &str, sha256: &str, signature: &[u8]) -> Result<()> {
+    let signature = Signature::from_slice(signature)
+        .map_err(|e| Error::Storage(format!("Malformed signature: {}", e)))?;
+    key.verify(&signed_message(backup_id, sha256), &signature)
+        .map_err(|_| Error::Storage(format!(
+            "Signature does not match backup {}; it was modified, renamed or signed with another key",
+            backup_id
+        )))
+}
+
+/// The signed message: the backup id and the hex digest, one per line
+fn signed_message(backup_id: &str, sha256: &str) -> Vec<u8> {
+    format!("{}\n{}", backup_id, sha256).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_detects_tampering() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let signature = sign_archive(&key, "backup-1", digest);
+
+        assert!(verify_archive(&key.verifying_key(), "backup-1", digest, &signature).is_ok());
+        assert!(verify_archive(&key.verifying_key(), "backup-1", &digest.replace('a', "b"), &signature).is_err());
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert!(verify_archive(&other, "backup-1", digest, &signature).is_err());
+        // The same archive and signature stored under another id
+        assert!(verify_archive(&key.verifying_key(), "backup-2", digest, &signature).is_err());
+    }
+}
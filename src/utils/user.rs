@@ -0,0 +1,146 @@
+use crate::config::DatabaseConfig;
+use crate::error::{Error, Result};
+use std::path::{Path, PathBuf};
+use tokio::process::Command as AsyncCommand;
+
+/// An OS account dump commands can run as
+#[derive(Debug, Clone)]
+pub struct OsUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32, // Primary group; supplementary groups are dropped when switching from root
+    pub home: PathBuf,
+}
+
+/// Look up an account in the system user database (getpwnam_r, so NSS sources such as LDAP count)
+#[cfg(unix)]
+pub fn lookup_user(name: &str) -> Result<OsUser> {
+    use std::ffi::{CStr, CString};
+
+    let c_name = CString::new(name)
+        .map_err(|_| Error::Config(format!("Invalid OS user name {:?}", name)))?;
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        // SAFETY: passwd is plain data filled in by getpwnam_r; its strings point into `buffer`,
+        // which outlives every read below
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let status = unsafe {
+            libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut found)
+        };
+        if status == libc::ERANGE && buffer.len() < 1 << 20 {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+        if status != 0 {
+            return Err(Error::Config(format!(
+                "Failed to look up OS user {:?}: {}",
+                name, std::io::Error::from_raw_os_error(status)
+            )));
+        }
+        if found.is_null() {
+            return Err(Error::Config(format!("OS user {:?} does not exist", name)));
+        }
+        let home = unsafe { CStr::from_ptr(passwd.pw_dir) }.to_string_lossy().to_string();
+        return Ok(OsUser { name: name.to_string(), uid: passwd.pw_uid, gid: passwd.pw_gid, home: PathBuf::from(home) });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn lookup_user(name: &str) -> Result<OsUser> {
+    Err(Error::Config(format!("run_as_user ({:?}) is only supported on Unix", name)))
+}
+
+/// The engine's `run_as_user`, looked up, or None when dumps run as kronos itself
+pub fn run_as_user(config: &DatabaseConfig) -> Result<Option<OsUser>> {
+    config.run_as_user.as_deref().map(lookup_user).transpose()
+}
+
+/// Make `cmd` run as the engine's `run_as_user`, if set, with that user's HOME, USER and LOGNAME
+/// so client option files (~/.pgpass, ~/.my.cnf) are looked up in its home
+pub fn apply_run_as_user(cmd: &mut AsyncCommand, config: &DatabaseConfig) -> Result<()> {
+    let Some(user) = run_as_user(config)? else {
+        return Ok(());
+    };
+    #[cfg(unix)]
+    {
+        // Switching to the current user needs no privileges, so skip it
+        if user.uid != current_uid() {
+            cmd.uid(user.uid).gid(user.gid);
+        }
+    }
+    cmd.env("HOME", &user.home).env("USER", &user.name).env("LOGNAME", &user.name);
+    Ok(())
+}
+
+/// Prepare the scratch directory a dump command writes into when it runs as `user`: it is
+/// created and given to the user, and the directories above it up to `run_dir` (private to
+/// kronos) are handed to the user's group, which may traverse but not list them (0710)
+#[cfg(unix)]
+pub fn hand_over_scratch_dir(user: &OsUser, scratch: &Path, run_dir: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::create_dir_all(scratch).map_err(Error::Io)?;
+    if user.uid == current_uid() {
+        return Ok(());
+    }
+    std::os::unix::fs::chown(scratch, Some(user.uid), Some(user.gid))
+        .map_err(|e| Error::Backup(format!("Failed to give {:?} to OS user {}: {}", scratch, user.name, e)))?;
+    for dir in scratch.ancestors().skip(1) {
+        std::os::unix::fs::chown(dir, None, Some(user.gid))
+            .map_err(|e| Error::Backup(format!("Failed to give {:?} to the group of OS user {}: {}", dir, user.name, e)))?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o710)).map_err(Error::Io)?;
+        if dir == run_dir {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn hand_over_scratch_dir(user: &OsUser, _scratch: &Path, _run_dir: &Path) -> Result<()> {
+    lookup_user(&user.name).map(|_| ())
+}
+
+#[cfg(unix)]
+fn current_uid() -> u32 {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_root_and_rejects_unknown_users() {
+        let root = lookup_user("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert!(lookup_user("kronos-no-such-user").is_err());
+    }
+
+    #[test]
+    fn scratch_dirs_are_only_traversable_by_the_users_group() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        if current_uid() != 0 {
+            return;
+        }
+        let nobody = match lookup_user("nobody") {
+            Ok(nobody) => nobody,
+            Err(_) => return,
+        };
+        let root = tempfile::tempdir().unwrap();
+        let run_dir = root.path().join("backup-1");
+        let scratch = run_dir.join("dumps").join("tab");
+        crate::utils::permissions::create_private_dir(&run_dir).unwrap();
+        hand_over_scratch_dir(&nobody, &scratch, &run_dir).unwrap();
+
+        assert_eq!(std::fs::metadata(&scratch).unwrap().uid(), nobody.uid);
+        for dir in [run_dir.join("dumps"), run_dir.clone()] {
+            let metadata = std::fs::metadata(&dir).unwrap();
+            assert_eq!((metadata.gid(), metadata.permissions().mode() & 0o777), (nobody.gid, 0o710));
+        }
+        assert_ne!(std::fs::metadata(root.path()).unwrap().gid(), nobody.gid);
+    }
+}